@@ -0,0 +1,291 @@
+//! `__cxa_atexit` / `__cxa_finalize`: the exit-time destructor table.
+//!
+//! The compiler registers each static object's destructor as it is
+//! constructed, tagged with the `__dso_handle` of the module it lives in.
+//! `__cxa_finalize(dso)` runs a module's entries in reverse order of
+//! registration when it is unloaded; the whole table runs from this
+//! module's `.fini_array` at exit, after the executable's own.
+//!
+//! Handlers run with the lock released, so they may register more handlers;
+//! those run next.
+//!
+//! Both symbols are exported whatever the features, like the rest of the
+//! C++ ABI this crate provides: a module that links it gets this table
+//! instead of the C library's. The sanitizer depends on that, since the
+//! exit-time leak report must be ordered after every static destructor,
+//! and a table shared by all modules is the only place that order is
+//! known. The C library's `__cxa_finalize` is still called for each
+//! module so its own per-module state is dropped.
+
+use crate::platform::spinlock::SpinLock;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// Maximum number of registered handlers. Registrations past this are
+/// dropped with a note.
+const CAPACITY: usize = 2048;
+
+#[derive(Clone, Copy)]
+struct Entry {
+    func: extern "C" fn(*mut u8),
+    arg: usize,
+    dso: usize,
+}
+
+struct Table {
+    entries: [Option<Entry>; CAPACITY],
+    /// One past the highest slot in use; slots below may be holes left by
+    /// `__cxa_finalize` of a single module.
+    len: usize,
+}
+
+impl Table {
+    const fn new() -> Self {
+        Self {
+            entries: [None; CAPACITY],
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, entry: Entry) -> bool {
+        if self.len == CAPACITY {
+            self.compact();
+        }
+        if self.len == CAPACITY {
+            return false;
+        }
+        self.entries[self.len] = Some(entry);
+        self.len += 1;
+        true
+    }
+
+    /// Close the holes, keeping registration order.
+    fn compact(&mut self) {
+        let mut kept = 0;
+        for i in 0..self.len {
+            if let Some(entry) = self.entries[i].take() {
+                self.entries[kept] = Some(entry);
+                kept += 1;
+            }
+        }
+        self.len = kept;
+    }
+
+    /// Remove and return the most recently registered entry for `dso`
+    /// (any module when 0).
+    fn pop(&mut self, dso: usize) -> Option<Entry> {
+        while self.len > 0 && self.entries[self.len - 1].is_none() {
+            self.len -= 1;
+        }
+        let slot = self.entries[..self.len]
+            .iter_mut()
+            .rev()
+            .find(|e| e.is_some_and(|e| dso == 0 || e.dso == dso))?;
+        slot.take()
+    }
+}
+
+static TABLE: SpinLock<Table> = SpinLock::new(Table::new());
+
+static FULL_NOTED: AtomicBool = AtomicBool::new(false);
+
+/// Free the table lock, e.g. in a `fork` child.
+///
+/// # Safety
+/// No other thread may be using the table.
+// Only the fork handler calls this, which needs `sanitize`.
+#[cfg_attr(not(feature = "sanitize"), allow(dead_code))]
+pub unsafe fn force_unlock() {
+    // SAFETY: Forwarded from the caller.
+    unsafe { TABLE.force_unlock() };
+}
+
+/// `__cxa_atexit(void (*)(void*), void*, void*)`
+///
+/// Returns 0 on success and -1 if the table is full.
+#[unsafe(no_mangle)]
+pub extern "C" fn __cxa_atexit(func: extern "C" fn(*mut u8), arg: *mut u8, dso: *const u8) -> i32 {
+    let entry = Entry {
+        func,
+        arg: arg as usize,
+        dso: dso as usize,
+    };
+    if TABLE.lock().push(entry) {
+        return 0;
+    }
+    if !FULL_NOTED.swap(true, Ordering::Relaxed) {
+        let msg = b"libcplusplus: __cxa_atexit table full; further exit handlers will not run\n";
+        // SAFETY: sys_write to fd 2 (stderr) is always valid.
+        unsafe { crate::platform::syscall::sys_write(2, msg.as_ptr(), msg.len()) };
+    }
+    -1
+}
+
+/// `__cxa_finalize(void*)`
+///
+/// Runs the handlers registered for `dso`, or all of them when it is null,
+/// then passes the call on to the C library's own `__cxa_finalize` for a
+/// module so it can drop that module's other registrations (fork handlers).
+///
+/// # Safety
+/// `dso` must be null or the `__dso_handle` of a module being unloaded;
+/// the handlers' arguments must still be valid.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn __cxa_finalize(dso: *mut u8) {
+    run(dso as usize);
+    if dso.is_null() {
+        // The C library's table holds its own exit teardown; leave that to
+        // exit().
+        return;
+    }
+    // SAFETY: RTLD_NEXT looks the symbol up in the modules after this one.
+    let next =
+        unsafe { crate::platform::dlsym(crate::platform::RTLD_NEXT, c"__cxa_finalize".as_ptr()) };
+    if !next.is_null() {
+        // SAFETY: The symbol found is the C library's `__cxa_finalize`,
+        // which takes the same argument.
+        let next = unsafe { core::mem::transmute::<*mut u8, unsafe extern "C" fn(*mut u8)>(next) };
+        // SAFETY: Forwarded from the caller.
+        unsafe { next(dso) };
+    }
+}
+
+/// Run and remove the handlers for `dso` (all when 0), newest first.
+fn run(dso: usize) {
+    loop {
+        let Some(entry) = TABLE.lock().pop(dso) else {
+            return;
+        };
+        (entry.func)(entry.arg as *mut u8);
+    }
+}
+
+#[cfg(all(target_os = "linux", not(test)))]
+mod fini {
+    extern "C" fn finalize_all() {
+        super::run(0);
+    }
+
+    #[used]
+    #[unsafe(link_section = ".fini_array")]
+    static FINALIZE: extern "C" fn() = finalize_all;
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use core::sync::atomic::AtomicUsize;
+
+    /// Handler arguments in the order they ran.
+    struct Log {
+        args: [AtomicUsize; 8],
+        len: AtomicUsize,
+    }
+
+    impl Log {
+        const fn new() -> Self {
+            Self {
+                args: [const { AtomicUsize::new(0) }; 8],
+                len: AtomicUsize::new(0),
+            }
+        }
+
+        fn push(&self, arg: usize) {
+            let i = self.len.fetch_add(1, Ordering::Relaxed);
+            self.args[i].store(arg, Ordering::Relaxed);
+        }
+
+        fn take(&self) -> std::vec::Vec<usize> {
+            let len = self.len.swap(0, Ordering::Relaxed);
+            self.args[..len]
+                .iter()
+                .map(|a| a.load(Ordering::Relaxed))
+                .collect()
+        }
+    }
+
+    static ORDER: Log = Log::new();
+
+    /// Stand-ins for two modules' `__dso_handle`.
+    static DSO_A: u8 = 0;
+    static DSO_B: u8 = 0;
+
+    extern "C" fn record(arg: *mut u8) {
+        ORDER.push(arg.addr());
+    }
+
+    /// Registers another handler while the table is being run.
+    extern "C" fn record_and_register(arg: *mut u8) {
+        ORDER.push(arg.addr());
+        __cxa_atexit(
+            record,
+            core::ptr::without_provenance_mut(99),
+            &raw const DSO_A,
+        );
+    }
+
+    #[test]
+    fn finalize_runs_one_module_newest_first() {
+        let (a, b) = (&raw const DSO_A, &raw const DSO_B);
+        assert_eq!(
+            __cxa_atexit(record, core::ptr::without_provenance_mut(1), a),
+            0
+        );
+        assert_eq!(
+            __cxa_atexit(record, core::ptr::without_provenance_mut(2), b),
+            0
+        );
+        assert_eq!(
+            __cxa_atexit(record_and_register, core::ptr::without_provenance_mut(3), a),
+            0
+        );
+        assert_eq!(
+            __cxa_atexit(record, core::ptr::without_provenance_mut(4), a),
+            0
+        );
+
+        // SAFETY: The fake handles belong to no loaded module, so the C
+        // library has nothing to drop for them.
+        unsafe { __cxa_finalize(a.cast_mut()) };
+        assert_eq!(ORDER.take(), [4, 3, 99, 1]);
+
+        // SAFETY: As above.
+        unsafe { __cxa_finalize(a.cast_mut()) };
+        assert_eq!(ORDER.take(), []);
+        // SAFETY: As above.
+        unsafe { __cxa_finalize(b.cast_mut()) };
+        assert_eq!(ORDER.take(), [2]);
+    }
+
+    // The child-process harness lives with the sanitizer.
+    #[cfg(feature = "sanitize")]
+    #[test]
+    fn full_table_refuses_with_one_note_and_recovers() {
+        use crate::testing;
+
+        extern "C" fn ignore(_arg: *mut u8) {}
+
+        const TEST: &str = "abi::atexit::tests::full_table_refuses_with_one_note_and_recovers";
+        if testing::in_child(TEST) {
+            let dso = &raw const DSO_A;
+            let accepted = (0..=CAPACITY)
+                .take_while(|_| __cxa_atexit(ignore, core::ptr::null_mut(), dso) == 0)
+                .count();
+            assert_eq!(accepted, CAPACITY);
+            assert_eq!(__cxa_atexit(ignore, core::ptr::null_mut(), dso), -1);
+            // SAFETY: The fake handle belongs to no loaded module.
+            unsafe { __cxa_finalize(dso.cast_mut()) };
+            assert_eq!(__cxa_atexit(ignore, core::ptr::null_mut(), dso), 0);
+            return;
+        }
+        let child = testing::spawn(TEST, &[]);
+        let stderr = child.stderr();
+        assert_eq!(child.code(), Some(0), "{stderr}");
+        assert_eq!(
+            stderr.matches("__cxa_atexit table full").count(),
+            1,
+            "{stderr}"
+        );
+    }
+}
@@ -0,0 +1,41 @@
+//! Itanium C++ ABI runtime hooks.
+//!
+//! The compiler fills vtable slots it cannot call with the handlers below:
+//! a pure virtual function reached through a partly constructed or
+//! destroyed object, or a `= delete` virtual. Both are fatal.
+
+pub mod atexit;
+
+/// Report a bad virtual call and abort.
+fn bad_virtual_call(deleted: bool) -> ! {
+    #[cfg(feature = "sanitize")]
+    {
+        crate::sanitize::diagnostic::bad_virtual_call(deleted)
+    }
+    #[cfg(not(feature = "sanitize"))]
+    {
+        let msg: &[u8] = if deleted {
+            b"libcplusplus: deleted virtual function called\n"
+        } else {
+            b"libcplusplus: pure virtual function called\n"
+        };
+        // SAFETY: sys_write to fd 2 (stderr) is always valid, and abort is
+        // provided by the C runtime.
+        unsafe {
+            crate::platform::syscall::sys_write(2, msg.as_ptr(), msg.len());
+            crate::platform::abort()
+        }
+    }
+}
+
+/// `__cxa_pure_virtual()`
+#[unsafe(no_mangle)]
+pub extern "C" fn __cxa_pure_virtual() -> ! {
+    bad_virtual_call(false)
+}
+
+/// `__cxa_deleted_virtual()`
+#[unsafe(no_mangle)]
+pub extern "C" fn __cxa_deleted_virtual() -> ! {
+    bad_virtual_call(true)
+}
@@ -0,0 +1,151 @@
+//! C++17 over-aligned `operator new` / `operator delete`
+//! (`std::align_val_t` overloads).
+
+use super::{NewKind, delete_impl, delete_sized_impl, new_impl, try_new};
+
+/// `operator new(size_t, std::align_val_t)`
+#[unsafe(export_name = "_ZnwmSt11align_val_t")]
+pub extern "C" fn operator_new_aligned(size: usize, align: usize) -> *mut u8 {
+    new_impl(size, align, NewKind::Scalar)
+}
+
+/// `operator new[](size_t, std::align_val_t)`
+#[unsafe(export_name = "_ZnamSt11align_val_t")]
+pub extern "C" fn operator_new_array_aligned(size: usize, align: usize) -> *mut u8 {
+    new_impl(size, align, NewKind::Array)
+}
+
+/// `operator new(size_t, std::align_val_t, const std::nothrow_t&)`: null
+/// instead of aborting.
+#[unsafe(export_name = "_ZnwmSt11align_val_tRKSt9nothrow_t")]
+pub extern "C" fn operator_new_aligned_nothrow(
+    size: usize,
+    align: usize,
+    _tag: *const u8,
+) -> *mut u8 {
+    try_new(size, align, NewKind::Scalar)
+}
+
+/// `operator new[](size_t, std::align_val_t, const std::nothrow_t&)`: null
+/// instead of aborting.
+#[unsafe(export_name = "_ZnamSt11align_val_tRKSt9nothrow_t")]
+pub extern "C" fn operator_new_array_aligned_nothrow(
+    size: usize,
+    align: usize,
+    _tag: *const u8,
+) -> *mut u8 {
+    try_new(size, align, NewKind::Array)
+}
+
+/// `operator delete(void*, std::align_val_t)`
+///
+/// The tracker recorded the block's base at allocation time, so the
+/// alignment is not needed to find it again.
+///
+/// # Safety
+/// `ptr` must be null or a live pointer returned by aligned `operator new`.
+#[unsafe(export_name = "_ZdlPvSt11align_val_t")]
+pub unsafe extern "C" fn operator_delete_aligned(ptr: *mut u8, _align: usize) {
+    // SAFETY: Forwarded from the caller.
+    unsafe { delete_impl(ptr, NewKind::Scalar) };
+}
+
+/// `operator delete[](void*, std::align_val_t)`
+///
+/// # Safety
+/// `ptr` must be null or a live pointer returned by aligned `operator new[]`.
+#[unsafe(export_name = "_ZdaPvSt11align_val_t")]
+pub unsafe extern "C" fn operator_delete_array_aligned(ptr: *mut u8, _align: usize) {
+    // SAFETY: Forwarded from the caller.
+    unsafe { delete_impl(ptr, NewKind::Array) };
+}
+
+/// `operator delete(void*, size_t, std::align_val_t)`
+///
+/// # Safety
+/// `ptr` must be null or a live pointer returned by aligned `operator new`.
+#[unsafe(export_name = "_ZdlPvmSt11align_val_t")]
+pub unsafe extern "C" fn operator_delete_sized_aligned(ptr: *mut u8, size: usize, _align: usize) {
+    // SAFETY: Forwarded from the caller.
+    unsafe { delete_sized_impl(ptr, size, NewKind::Scalar) };
+}
+
+/// `operator delete[](void*, size_t, std::align_val_t)`
+///
+/// # Safety
+/// `ptr` must be null or a live pointer returned by aligned `operator new[]`.
+#[unsafe(export_name = "_ZdaPvmSt11align_val_t")]
+pub unsafe extern "C" fn operator_delete_array_sized_aligned(
+    ptr: *mut u8,
+    size: usize,
+    _align: usize,
+) {
+    // SAFETY: Forwarded from the caller.
+    unsafe { delete_sized_impl(ptr, size, NewKind::Array) };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `struct alignas(128) T { char bytes[200]; }`
+    const ALIGN: usize = 128;
+    const SIZE: usize = 256;
+
+    fn fill(ptr: *mut u8, size: usize) {
+        assert!(!ptr.is_null());
+        assert_eq!(ptr as usize % ALIGN, 0, "{ptr:p} is not {ALIGN}-aligned");
+        // SAFETY: ptr is a live block of at least size bytes.
+        unsafe { ptr.write_bytes(0xa5, size) };
+    }
+
+    #[test]
+    fn alignas_128_round_trips_through_every_overload() {
+        let ptr = operator_new_aligned(SIZE, ALIGN);
+        fill(ptr, SIZE);
+        // SAFETY: ptr came from aligned operator new.
+        unsafe { operator_delete_aligned(ptr, ALIGN) };
+
+        let ptr = operator_new_aligned_nothrow(SIZE, ALIGN, core::ptr::null());
+        fill(ptr, SIZE);
+        // SAFETY: ptr came from aligned operator new with SIZE bytes.
+        unsafe { operator_delete_sized_aligned(ptr, SIZE, ALIGN) };
+
+        let ptr = operator_new_array_aligned(SIZE * 4, ALIGN);
+        fill(ptr, SIZE * 4);
+        // SAFETY: ptr came from aligned operator new[].
+        unsafe { operator_delete_array_aligned(ptr, ALIGN) };
+
+        let ptr = operator_new_array_aligned_nothrow(SIZE * 4, ALIGN, core::ptr::null());
+        fill(ptr, SIZE * 4);
+        // SAFETY: ptr came from aligned operator new[] with SIZE * 4 bytes.
+        unsafe { operator_delete_array_sized_aligned(ptr, SIZE * 4, ALIGN) };
+    }
+
+    #[test]
+    fn nothrow_rejects_a_bad_alignment() {
+        let ptr = operator_new_aligned_nothrow(SIZE, 3 * ALIGN, core::ptr::null());
+        assert!(ptr.is_null());
+    }
+
+    #[cfg(feature = "sanitize")]
+    #[test]
+    fn sized_aligned_delete_checks_the_size() {
+        const TEST: &str = "allocator::aligned::tests::sized_aligned_delete_checks_the_size";
+        if crate::testing::in_child(TEST) {
+            let ptr = operator_new_aligned(SIZE, ALIGN);
+            // SAFETY: ptr came from aligned operator new; the wrong size is
+            // reported before anything is released.
+            unsafe { operator_delete_sized_aligned(ptr, SIZE + 8, ALIGN) };
+            return;
+        }
+        let child = crate::testing::spawn(TEST, &[("LIBCPP_EXITCODE", "23")]);
+        assert_eq!(child.code(), Some(23), "{}", child.stderr());
+        let expected = if cfg!(feature = "minimal-diagnostics") {
+            "SAN 10 "
+        } else {
+            "sized delete size mismatch"
+        };
+        assert!(child.stderr().contains(expected), "{}", child.stderr());
+    }
+}
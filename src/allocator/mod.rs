@@ -0,0 +1,376 @@
+//! C++ `operator new` / `operator delete`.
+//!
+//! With `sanitize`, blocks are tracked with their allocation family so a
+//! `delete` of something that did not come from `new` is reported. With
+//! `sanitize-lite` they only get red zones.
+
+mod aligned;
+
+use core::alloc::Layout;
+
+#[cfg(feature = "sanitize")]
+use crate::sanitize::tracker::AllocKind;
+
+/// `__STDCPP_DEFAULT_NEW_ALIGNMENT__` on x86_64.
+const DEFAULT_NEW_ALIGNMENT: usize = 16;
+
+/// Allocate for operator new. A zero-byte request still returns a unique
+/// pointer, as the standard requires. Returns null if the request cannot
+/// be satisfied or `align` is not a power of two.
+fn try_new(size: usize, align: usize, #[allow(unused_variables)] kind: NewKind) -> *mut u8 {
+    let Ok(layout) = Layout::from_size_align(size.max(1), align) else {
+        return core::ptr::null_mut();
+    };
+
+    #[cfg(feature = "sanitize")]
+    // SAFETY: The block is released by delete_impl, through dealloc_inner.
+    let ptr = unsafe { crate::sanitize::alloc_inner(layout, kind.into()) };
+    #[cfg(feature = "sanitize-lite")]
+    // SAFETY: The block is released by delete_impl, through lite::dealloc.
+    let ptr = unsafe { crate::lite::alloc(layout) };
+    #[cfg(not(any(feature = "sanitize", feature = "sanitize-lite")))]
+    let ptr = if layout.align() > crate::platform::MIN_ALIGN {
+        // SAFETY: aligned_alloc is provided by the C runtime; the padded
+        // size is a multiple of the power-of-two alignment.
+        unsafe { crate::platform::aligned_alloc(layout.align(), layout.pad_to_align().size()) }
+    } else {
+        // SAFETY: malloc is provided by the C runtime.
+        unsafe { crate::platform::malloc(layout.size()) }
+    };
+
+    ptr
+}
+
+/// Throwing operator new. There is no `std::bad_alloc` yet, so running
+/// out of memory aborts.
+fn new_impl(size: usize, align: usize, kind: NewKind) -> *mut u8 {
+    let ptr = try_new(size, align, kind);
+    if ptr.is_null() {
+        // SAFETY: abort is provided by the C runtime.
+        unsafe { crate::platform::abort() }
+    }
+    ptr
+}
+
+/// # Safety
+/// `ptr` must be null or a live pointer from `try_new`.
+unsafe fn delete_impl(ptr: *mut u8, #[allow(unused_variables)] kind: NewKind) {
+    #[cfg(feature = "sanitize")]
+    {
+        // SAFETY: Forwarded from the caller.
+        unsafe { crate::sanitize::dealloc_inner(ptr, kind.into()) };
+    }
+    #[cfg(feature = "sanitize-lite")]
+    {
+        // SAFETY: Forwarded from the caller.
+        unsafe { crate::lite::dealloc(ptr) };
+    }
+    #[cfg(not(any(feature = "sanitize", feature = "sanitize-lite")))]
+    {
+        // SAFETY: ptr was allocated by malloc in try_new (free accepts null).
+        unsafe { crate::platform::free(ptr) };
+    }
+}
+
+/// Sized delete: `size` is what the compiler believes was allocated.
+///
+/// # Safety
+/// `ptr` must be null or a live pointer from `try_new`.
+unsafe fn delete_sized_impl(
+    ptr: *mut u8,
+    #[allow(unused_variables)] size: usize,
+    #[allow(unused_variables)] kind: NewKind,
+) {
+    #[cfg(feature = "sanitize")]
+    {
+        // SAFETY: Forwarded from the caller.
+        unsafe { crate::sanitize::dealloc_sized_inner(ptr, kind.into(), size) };
+    }
+    #[cfg(feature = "sanitize-lite")]
+    {
+        // SAFETY: Forwarded from the caller.
+        unsafe { crate::lite::dealloc(ptr) };
+    }
+    #[cfg(not(any(feature = "sanitize", feature = "sanitize-lite")))]
+    {
+        // SAFETY: ptr was allocated by malloc in try_new (free accepts null).
+        unsafe { crate::platform::free(ptr) };
+    }
+}
+
+/// Which form of operator new a block came from.
+#[derive(Clone, Copy)]
+enum NewKind {
+    Scalar,
+    Array,
+}
+
+#[cfg(feature = "sanitize")]
+impl From<NewKind> for AllocKind {
+    fn from(kind: NewKind) -> Self {
+        match kind {
+            NewKind::Scalar => AllocKind::ScalarNew,
+            NewKind::Array => AllocKind::ArrayNew,
+        }
+    }
+}
+
+/// `operator new(size_t)`
+#[unsafe(export_name = "_Znwm")]
+pub extern "C" fn operator_new(size: usize) -> *mut u8 {
+    new_impl(size, DEFAULT_NEW_ALIGNMENT, NewKind::Scalar)
+}
+
+/// `operator new(size_t, const std::nothrow_t&)`: null instead of aborting.
+#[unsafe(export_name = "_ZnwmRKSt9nothrow_t")]
+pub extern "C" fn operator_new_nothrow(size: usize, _tag: *const u8) -> *mut u8 {
+    try_new(size, DEFAULT_NEW_ALIGNMENT, NewKind::Scalar)
+}
+
+/// `operator delete(void*)`
+///
+/// # Safety
+/// `ptr` must be null or a live pointer returned by `operator new`.
+#[unsafe(export_name = "_ZdlPv")]
+pub unsafe extern "C" fn operator_delete(ptr: *mut u8) {
+    // SAFETY: Forwarded from the caller.
+    unsafe { delete_impl(ptr, NewKind::Scalar) };
+}
+
+/// `operator new[](size_t)`
+#[unsafe(export_name = "_Znam")]
+pub extern "C" fn operator_new_array(size: usize) -> *mut u8 {
+    new_impl(size, DEFAULT_NEW_ALIGNMENT, NewKind::Array)
+}
+
+/// `operator new[](size_t, const std::nothrow_t&)`: null instead of aborting.
+#[unsafe(export_name = "_ZnamRKSt9nothrow_t")]
+pub extern "C" fn operator_new_array_nothrow(size: usize, _tag: *const u8) -> *mut u8 {
+    try_new(size, DEFAULT_NEW_ALIGNMENT, NewKind::Array)
+}
+
+/// `operator delete[](void*)`
+///
+/// # Safety
+/// `ptr` must be null or a live pointer returned by `operator new[]`.
+#[unsafe(export_name = "_ZdaPv")]
+pub unsafe extern "C" fn operator_delete_array(ptr: *mut u8) {
+    // SAFETY: Forwarded from the caller.
+    unsafe { delete_impl(ptr, NewKind::Array) };
+}
+
+/// `operator delete(void*, size_t)`
+///
+/// # Safety
+/// `ptr` must be null or a live pointer returned by `operator new`.
+#[unsafe(export_name = "_ZdlPvm")]
+pub unsafe extern "C" fn operator_delete_sized(ptr: *mut u8, size: usize) {
+    // SAFETY: Forwarded from the caller.
+    unsafe { delete_sized_impl(ptr, size, NewKind::Scalar) };
+}
+
+/// `operator delete[](void*, size_t)`
+///
+/// # Safety
+/// `ptr` must be null or a live pointer returned by `operator new[]`.
+#[unsafe(export_name = "_ZdaPvm")]
+pub unsafe extern "C" fn operator_delete_array_sized(ptr: *mut u8, size: usize) {
+    // SAFETY: Forwarded from the caller.
+    unsafe { delete_sized_impl(ptr, size, NewKind::Array) };
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::process::Command;
+    use std::string::String;
+
+    /// Every `operator new` / `operator delete` a C++17 compiler references
+    /// for plain, array, nothrow, sized and over-aligned allocations.
+    const CXX_SOURCE: &str = r#"
+        #include <new>
+        struct alignas(64) Wide { char bytes[64]; };
+        struct Base { virtual ~Base() {} int v; };
+        int main() {
+            int *p = new int(1); delete p;
+            int *a = new int[4]; delete[] a;
+            int *q = new (std::nothrow) int; delete q;
+            int *r = new (std::nothrow) int[2]; delete[] r;
+            Wide *w = new Wide; delete w;
+            Wide *ws = new Wide[2]; delete[] ws;
+            Base *b = new Base; delete b;
+        }
+    "#;
+
+    fn nm(args: &[&str]) -> String {
+        let out = Command::new("nm").args(args).output().expect("run nm");
+        assert!(out.status.success(), "nm {args:?}");
+        String::from_utf8(out.stdout).unwrap()
+    }
+
+    /// Compiles C++ that uses each form of new and delete and checks that
+    /// every operator symbol it needs is one this crate defines, so a C++
+    /// object links against it without libstdc++.
+    #[test]
+    #[ignore = "needs a C++ compiler"]
+    fn cxx_operator_references_resolve_here() {
+        let dir = std::env::temp_dir().join(std::format!("libcpp-link-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (src, obj) = (dir.join("use_new.cpp"), dir.join("use_new.o"));
+        std::fs::write(&src, CXX_SOURCE).unwrap();
+        let compiled = Command::new("c++")
+            .args(["-std=c++17", "-fsized-deallocation", "-c"])
+            .arg(&src)
+            .arg("-o")
+            .arg(&obj)
+            .status()
+            .expect("run c++");
+        assert!(compiled.success(), "c++ failed");
+
+        let needed = nm(&["-u", obj.to_str().unwrap()]);
+        std::fs::remove_dir_all(&dir).unwrap();
+        // Unreferenced exports are dropped from the test binary; keep them.
+        core::hint::black_box([
+            super::operator_new as *const (),
+            super::operator_new_nothrow as *const (),
+            super::operator_delete as *const (),
+            super::operator_new_array as *const (),
+            super::operator_new_array_nothrow as *const (),
+            super::operator_delete_array as *const (),
+            super::operator_delete_sized as *const (),
+            super::operator_delete_array_sized as *const (),
+            super::aligned::operator_new_aligned as *const (),
+            super::aligned::operator_new_array_aligned as *const (),
+            super::aligned::operator_new_aligned_nothrow as *const (),
+            super::aligned::operator_new_array_aligned_nothrow as *const (),
+            super::aligned::operator_delete_aligned as *const (),
+            super::aligned::operator_delete_array_aligned as *const (),
+            super::aligned::operator_delete_sized_aligned as *const (),
+            super::aligned::operator_delete_array_sized_aligned as *const (),
+        ]);
+        let exe = std::env::current_exe().unwrap();
+        let defined = nm(&["--defined-only", exe.to_str().unwrap()]);
+        let defined: std::vec::Vec<&str> = defined
+            .lines()
+            .filter_map(|l| l.split_whitespace().nth(2))
+            .collect();
+
+        let operators: std::vec::Vec<&str> = needed
+            .split_whitespace()
+            .filter(|s| s.starts_with("_Zn") || s.starts_with("_Zd"))
+            .collect();
+        assert_eq!(operators.len(), 10, "{needed}");
+        for symbol in operators {
+            assert!(defined.contains(&symbol), "{symbol} is not defined");
+        }
+    }
+
+    /// `delete` of a `new[]` block and `delete[]` of a `new` block are
+    /// each reported as mismatched. Recoverable mode keeps the child going
+    /// so both reports are seen.
+    #[cfg(feature = "sanitize")]
+    #[test]
+    fn scalar_and_array_forms_must_pair() {
+        const TEST: &str = "allocator::tests::scalar_and_array_forms_must_pair";
+        if crate::testing::in_child(TEST) {
+            crate::sanitize::set_halt_on_error(false);
+            let array = super::operator_new_array(64);
+            let scalar = super::operator_new(64);
+            // SAFETY: Both deletes are the wrong form on purpose; the
+            // reported frees are skipped, then the right forms run.
+            unsafe {
+                super::operator_delete(array);
+                super::operator_delete_array(scalar);
+                super::operator_delete_array(array);
+                super::operator_delete(scalar);
+            }
+            assert_eq!(crate::sanitize::diagnostic::error_count(), 2);
+            return;
+        }
+        let child = crate::testing::spawn(TEST, &[]);
+        let stderr = child.stderr();
+        assert_eq!(child.code(), Some(0), "{stderr}");
+        if cfg!(feature = "minimal-diagnostics") {
+            assert_eq!(stderr.matches("SAN 3 ").count(), 2, "{stderr}");
+        } else {
+            let first = "allocated with: operator new[]\n  freed with:     operator delete\n";
+            let second = "allocated with: operator new\n  freed with:     operator delete[]\n";
+            assert_eq!(
+                stderr.matches("ERROR: mismatched deallocation").count(),
+                2,
+                "{stderr}"
+            );
+            assert!(
+                stderr.contains(first) && stderr.contains(second),
+                "{stderr}"
+            );
+        }
+    }
+
+    /// The size a sized delete passes must match the allocation, except
+    /// for 0, which some toolchains pass when they do not know it.
+    #[cfg(feature = "sanitize")]
+    #[test]
+    fn sized_delete_checks_the_size_unless_zero() {
+        const TEST: &str = "allocator::tests::sized_delete_checks_the_size_unless_zero";
+        if crate::testing::in_child(TEST) {
+            crate::sanitize::set_halt_on_error(false);
+            // SAFETY: Each block is deleted with its own form; only the
+            // sizes vary.
+            unsafe {
+                super::operator_delete_sized(super::operator_new(24), 24);
+                super::operator_delete_array_sized(super::operator_new_array(40), 0);
+                assert_eq!(crate::sanitize::diagnostic::error_count(), 0);
+
+                let ptr = super::operator_new_array(40);
+                super::operator_delete_array_sized(ptr, 48);
+                super::operator_delete_array_sized(ptr, 40);
+            }
+            assert_eq!(crate::sanitize::diagnostic::error_count(), 1);
+            return;
+        }
+        let child = crate::testing::spawn(TEST, &[]);
+        let stderr = child.stderr();
+        assert_eq!(child.code(), Some(0), "{stderr}");
+        if cfg!(feature = "minimal-diagnostics") {
+            assert_eq!(stderr.matches("SAN 10 ").count(), 1, "{stderr}");
+        } else {
+            let sizes = "allocated:    40 bytes\n  delete size:  48 bytes\n";
+            assert_eq!(
+                stderr.matches("sized delete size mismatch").count(),
+                1,
+                "{stderr}"
+            );
+            assert!(stderr.contains(sizes), "{stderr}");
+        }
+    }
+
+    /// More than any machine can map, but a valid layout.
+    const HUGE: usize = 1 << 62;
+
+    #[test]
+    fn nothrow_new_returns_null_for_a_huge_request() {
+        assert!(super::operator_new_nothrow(HUGE, core::ptr::null()).is_null());
+        assert!(super::operator_new_array_nothrow(HUGE, core::ptr::null()).is_null());
+        // The null is not remembered: a normal request still succeeds.
+        let ptr = super::operator_new_nothrow(16, core::ptr::null());
+        assert!(!ptr.is_null());
+        // SAFETY: ptr came from operator new.
+        unsafe { super::operator_delete(ptr) };
+    }
+
+    #[cfg(feature = "sanitize")]
+    #[test]
+    fn throwing_new_aborts_on_a_huge_request() {
+        use std::os::unix::process::ExitStatusExt;
+
+        const TEST: &str = "allocator::tests::throwing_new_aborts_on_a_huge_request";
+        if crate::testing::in_child(TEST) {
+            super::operator_new(HUGE);
+            return;
+        }
+        let child = crate::testing::spawn(TEST, &[]);
+        assert_eq!(child.output.status.signal(), Some(6), "{}", child.stderr());
+    }
+}
@@ -2,34 +2,89 @@
 
 extern crate alloc;
 
+#[cfg(all(feature = "sanitize", feature = "sanitize-lite"))]
+compile_error!("`sanitize-lite` replaces `sanitize`; build with --no-default-features");
+
+mod abi;
+mod allocator;
+#[cfg(feature = "sanitize-lite")]
+mod lite;
 mod platform;
 
 #[cfg(feature = "sanitize")]
 pub mod sanitize;
+// Not every feature set has a test that runs in a child.
+#[cfg(test)]
+#[allow(dead_code)]
+mod testing;
 
 use core::alloc::{GlobalAlloc, Layout};
 
+// Only registered as the global allocator outside tests.
+#[cfg_attr(test, allow(dead_code))]
 struct CAllocator;
 
+#[cfg_attr(test, allow(dead_code))]
 unsafe impl GlobalAlloc for CAllocator {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         #[cfg(feature = "sanitize")]
         {
             unsafe { sanitize::sanitized_alloc(layout) }
         }
-        #[cfg(not(feature = "sanitize"))]
+        #[cfg(feature = "sanitize-lite")]
+        {
+            unsafe { lite::alloc(layout) }
+        }
+        #[cfg(not(any(feature = "sanitize", feature = "sanitize-lite")))]
         {
+            if layout.align() > platform::MIN_ALIGN {
+                // aligned_alloc wants a size that is a multiple of the alignment.
+                let size = layout.pad_to_align().size();
+                // SAFETY: aligned_alloc is provided by the C runtime; align is
+                // a power of two and size a multiple of it.
+                return unsafe { platform::aligned_alloc(layout.align(), size) };
+            }
             // SAFETY: malloc is provided by the C runtime.
             unsafe { platform::malloc(layout.size()) }
         }
     }
 
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        #[cfg(feature = "sanitize")]
+        {
+            unsafe { sanitize::sanitized_alloc_zeroed(layout) }
+        }
+        #[cfg(feature = "sanitize-lite")]
+        {
+            unsafe { lite::alloc_zeroed(layout) }
+        }
+        #[cfg(not(any(feature = "sanitize", feature = "sanitize-lite")))]
+        {
+            if layout.align() > platform::MIN_ALIGN {
+                // SAFETY: Forwarded from the caller.
+                let ptr = unsafe { self.alloc(layout) };
+                if !ptr.is_null() {
+                    // SAFETY: ptr points to layout.size() writable bytes.
+                    unsafe { core::ptr::write_bytes(ptr, 0, layout.size()) };
+                }
+                return ptr;
+            }
+            // calloc can hand back fresh, already-zero pages without touching them.
+            // SAFETY: calloc is provided by the C runtime.
+            unsafe { platform::calloc(1, layout.size()) }
+        }
+    }
+
     unsafe fn dealloc(&self, ptr: *mut u8, #[allow(unused_variables)] layout: Layout) {
         #[cfg(feature = "sanitize")]
         {
             unsafe { sanitize::sanitized_dealloc(ptr, layout) };
         }
-        #[cfg(not(feature = "sanitize"))]
+        #[cfg(feature = "sanitize-lite")]
+        {
+            unsafe { lite::dealloc(ptr) };
+        }
+        #[cfg(not(any(feature = "sanitize", feature = "sanitize-lite")))]
         {
             // SAFETY: ptr was allocated by malloc via our alloc() above.
             unsafe { platform::free(ptr) }
@@ -46,8 +101,31 @@ unsafe impl GlobalAlloc for CAllocator {
         {
             unsafe { sanitize::sanitized_realloc(ptr, layout, new_size) }
         }
-        #[cfg(not(feature = "sanitize"))]
+        #[cfg(feature = "sanitize-lite")]
+        {
+            unsafe { lite::realloc(ptr, layout, new_size) }
+        }
+        #[cfg(not(any(feature = "sanitize", feature = "sanitize-lite")))]
         {
+            if layout.align() > platform::MIN_ALIGN {
+                // realloc only guarantees MIN_ALIGN; move the block by hand.
+                // SAFETY: The caller guarantees new_size, rounded up to
+                // align, does not overflow isize.
+                let new_layout =
+                    unsafe { Layout::from_size_align_unchecked(new_size, layout.align()) };
+                // SAFETY: new_layout has a non-zero size.
+                let new_ptr = unsafe { self.alloc(new_layout) };
+                if !new_ptr.is_null() {
+                    debug_assert!((new_ptr as usize).is_multiple_of(layout.align()));
+                    // SAFETY: Both blocks are valid for the smaller size and
+                    // distinct; ptr was allocated by this allocator.
+                    unsafe {
+                        core::ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+                        self.dealloc(ptr, layout);
+                    }
+                }
+                return new_ptr;
+            }
             // SAFETY: ptr was allocated by malloc, new_size is the requested size.
             unsafe { platform::realloc(ptr, new_size) }
         }
@@ -66,3 +144,35 @@ fn panic(_info: &core::panic::PanicInfo) -> ! {
     // SAFETY: abort is provided by the C runtime and never returns.
     unsafe { platform::abort() }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[repr(align(64))]
+    struct Wide([u8; 24]);
+
+    #[test]
+    fn over_aligned_types_get_their_alignment() {
+        let layout = Layout::new::<Wide>();
+        assert_eq!(layout.align(), 64);
+        // SAFETY: Each block is used within its size and freed once with
+        // the layout it has at that point.
+        unsafe {
+            let ptr = CAllocator.alloc(layout);
+            assert_eq!(ptr as usize % 64, 0);
+            ptr.cast::<Wide>().write(Wide([7; 24]));
+
+            let zeroed = CAllocator.alloc_zeroed(layout);
+            assert_eq!(zeroed as usize % 64, 0);
+            assert_eq!((*zeroed.cast::<Wide>()).0, [0; 24]);
+            CAllocator.dealloc(zeroed, layout);
+
+            // Growing past what realloc could keep in place must stay aligned.
+            let grown = CAllocator.realloc(ptr, layout, 4096);
+            assert_eq!(grown as usize % 64, 0);
+            assert_eq!(core::slice::from_raw_parts(grown, 24), [7; 24]);
+            CAllocator.dealloc(grown, Layout::from_size_align(4096, 64).unwrap());
+        }
+    }
+}
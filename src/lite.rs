@@ -0,0 +1,193 @@
+//! `sanitize-lite`: red zone checks without the tracker or quarantine.
+//!
+//! Meant for staging and canary deployments where the full sanitizer's
+//! per-allocation table is too expensive. Each block carries its own
+//! header, so free needs no lookup and takes no lock:
+//!
+//! ```text
+//! base                              user
+//! | slack | header | prefix red zone | user data | suffix red zone |
+//! ```
+//!
+//! The header holds the user size, the distance back to the base and a
+//! check word derived from both and the user address. Free validates the
+//! check word and both red zones, marks the prefix red zone as freed and
+//! hands the block straight back to the platform allocator. A pointer whose
+//! header does not check out is an invalid free, or a double free while
+//! the freed mark survives. Nothing is kept after free, so use-after-free
+//! and leaks go unnoticed. Every error prints one line and aborts.
+
+use crate::platform::{MIN_ALIGN, syscall::sys_write};
+use core::alloc::Layout;
+
+/// Size of each red zone, in bytes.
+const REDZONE: usize = 16;
+
+/// Byte pattern written into live red zones.
+const CANARY_BYTE: u8 = 0xAB;
+
+/// Byte pattern left in the prefix red zone of a freed block.
+const FREED_BYTE: u8 = 0xFD;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Header {
+    size: usize,
+    /// Distance from the base to the user pointer.
+    offset: u32,
+    check: u32,
+}
+
+const HEADER: usize = core::mem::size_of::<Header>();
+
+/// Check word for a live block; never 0.
+fn check(user: usize, size: usize, offset: u32) -> u32 {
+    let mixed = (user ^ size.rotate_left(17) ^ offset as usize) as u64;
+    (mixed.wrapping_mul(0x9E37_79B9_7F4A_7C15) >> 32) as u32 | 1
+}
+
+/// # Safety
+/// Same as `GlobalAlloc::alloc`; the block must be released with `dealloc`.
+pub unsafe fn alloc(layout: Layout) -> *mut u8 {
+    let size = layout.size();
+    let align = layout.align().max(MIN_ALIGN);
+    let prefix = (HEADER + REDZONE).next_multiple_of(align);
+    let Ok(offset) = u32::try_from(prefix) else {
+        return core::ptr::null_mut();
+    };
+    let Some(total) = prefix
+        .checked_add(size)
+        .and_then(|t| t.checked_add(REDZONE))
+    else {
+        return core::ptr::null_mut();
+    };
+    let base = if align > MIN_ALIGN {
+        let Some(padded) = total.checked_next_multiple_of(align) else {
+            return core::ptr::null_mut();
+        };
+        // SAFETY: aligned_alloc is provided by the C runtime; align is a
+        // power of two and padded a multiple of it.
+        unsafe { crate::platform::aligned_alloc(align, padded) }
+    } else {
+        // SAFETY: malloc is provided by the C runtime.
+        unsafe { crate::platform::malloc(total) }
+    };
+    if base.is_null() {
+        return base;
+    }
+    // SAFETY: prefix + size + REDZONE == total, within the block, and the
+    // header and prefix red zone are the last HEADER + REDZONE bytes of the
+    // prefix.
+    unsafe {
+        let user = base.add(prefix);
+        core::ptr::write_bytes(user.sub(REDZONE), CANARY_BYTE, REDZONE);
+        core::ptr::write_bytes(user.add(size), CANARY_BYTE, REDZONE);
+        (user.sub(REDZONE + HEADER) as *mut Header).write_unaligned(Header {
+            size,
+            offset,
+            check: check(user as usize, size, offset),
+        });
+        user
+    }
+}
+
+/// # Safety
+/// Same as `GlobalAlloc::alloc_zeroed`.
+pub unsafe fn alloc_zeroed(layout: Layout) -> *mut u8 {
+    // SAFETY: Forwarded from the caller.
+    let ptr = unsafe { alloc(layout) };
+    if !ptr.is_null() {
+        // SAFETY: ptr points to layout.size() writable bytes.
+        unsafe { core::ptr::write_bytes(ptr, 0, layout.size()) };
+    }
+    ptr
+}
+
+/// Check a block and release it. Null is ignored.
+///
+/// # Safety
+/// `ptr` must be null or have `REDZONE + HEADER` readable bytes before it,
+/// as every pointer from `alloc` does.
+pub unsafe fn dealloc(ptr: *mut u8) {
+    if ptr.is_null() {
+        return;
+    }
+    let user = ptr as usize;
+    // SAFETY: The caller guarantees the bytes before ptr are readable.
+    let (header, zone) = unsafe {
+        let zone = ptr.sub(REDZONE);
+        ((zone.sub(HEADER) as *const Header).read_unaligned(), zone)
+    };
+    // SAFETY: zone is REDZONE readable bytes.
+    let prefix = unsafe { core::slice::from_raw_parts(zone, REDZONE) };
+    if header.check != check(user, header.size, header.offset) {
+        if prefix.iter().all(|&b| b == FREED_BYTE) {
+            fail(b"double free", user);
+        }
+        // The header sits just below the prefix red zone, so an underflow
+        // that reached it looks the same.
+        fail(b"invalid free (or header corrupted by an underflow)", user);
+    }
+    if prefix.iter().any(|&b| b != CANARY_BYTE) {
+        fail(b"buffer underflow (prefix red zone corrupted)", user);
+    }
+    // SAFETY: The check word vouches for the size, so the suffix red zone
+    // is within the block.
+    let suffix = unsafe { core::slice::from_raw_parts(ptr.add(header.size), REDZONE) };
+    if suffix.iter().any(|&b| b != CANARY_BYTE) {
+        fail(b"buffer overflow (suffix red zone corrupted)", user);
+    }
+    // SAFETY: The block is still ours until freed below; the base is
+    // `offset` bytes before ptr.
+    unsafe {
+        core::ptr::write_bytes(zone, FREED_BYTE, REDZONE);
+        crate::platform::free(ptr.sub(header.offset as usize));
+    }
+}
+
+/// Move a block to a new size by hand, checking the old one on the way.
+///
+/// # Safety
+/// Same as `GlobalAlloc::realloc`.
+pub unsafe fn realloc(ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+    // SAFETY: The caller guarantees new_size, rounded up to align, does not
+    // overflow isize.
+    let new_layout = unsafe { Layout::from_size_align_unchecked(new_size, layout.align()) };
+    // SAFETY: Forwarded from the caller.
+    let new_ptr = unsafe { alloc(new_layout) };
+    if !new_ptr.is_null() {
+        // SAFETY: Both blocks are valid for the smaller size and distinct.
+        unsafe {
+            core::ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+            dealloc(ptr);
+        }
+    }
+    new_ptr
+}
+
+/// `libcplusplus: <what> at 0x...`, in one write, then abort.
+fn fail(what: &[u8], addr: usize) -> ! {
+    let mut line = [0u8; 96];
+    let mut len = 0;
+    for part in [b"libcplusplus: ".as_slice(), what, b" at 0x"] {
+        let n = part.len().min(line.len() - len);
+        line[len..len + n].copy_from_slice(&part[..n]);
+        len += n;
+    }
+    for shift in (0..16).rev() {
+        if len < line.len() {
+            line[len] = b"0123456789abcdef"[(addr >> (shift * 4)) & 0xF];
+            len += 1;
+        }
+    }
+    if len < line.len() {
+        line[len] = b'\n';
+        len += 1;
+    }
+    // SAFETY: sys_write to fd 2 (stderr) is always valid, and abort is
+    // provided by the C runtime.
+    unsafe {
+        sys_write(2, line.as_ptr(), len);
+        crate::platform::abort()
+    }
+}
@@ -0,0 +1,77 @@
+use core::ops::Deref;
+
+/// Wrapper that gives a static its own cache line when the `cache-aligned`
+/// feature is enabled, so locks taken by different threads (tracker shards,
+/// the quarantine, the mmap heap) and hot counters do not false-share.
+/// Without the feature it is a plain newtype with no size cost.
+///
+/// `SpinLock` itself stays unpadded: it is also embedded per object, as in
+/// `Epoch`.
+#[cfg_attr(feature = "cache-aligned", repr(align(64)))]
+pub struct CacheAligned<T>(pub T);
+
+#[cfg(feature = "cache-aligned")]
+const _: () = assert!(core::mem::align_of::<CacheAligned<u8>>() == 64);
+
+impl<T> CacheAligned<T> {
+    pub const fn new(value: T) -> Self {
+        Self(value)
+    }
+}
+
+impl<T> Deref for CacheAligned<T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    static FIRST: CacheAligned<AtomicUsize> = CacheAligned::new(AtomicUsize::new(0));
+    static SECOND: CacheAligned<AtomicUsize> = CacheAligned::new(AtomicUsize::new(0));
+
+    #[test]
+    fn aligned_statics_get_their_own_line() {
+        let (first, second) = (&raw const FIRST as usize, &raw const SECOND as usize);
+        if cfg!(feature = "cache-aligned") {
+            assert!(first.is_multiple_of(64) && second.is_multiple_of(64));
+            assert_ne!(first / 64, second / 64);
+        } else {
+            assert_eq!(size_of::<CacheAligned<AtomicUsize>>(), size_of::<usize>());
+        }
+    }
+
+    /// Two threads each bumping their own counter, `FIRST` and `SECOND`.
+    /// Run with `--ignored --nocapture` with and without `cache-aligned`
+    /// to compare; the time is printed rather than asserted, as it depends
+    /// on the machine.
+    #[test]
+    #[ignore = "timing benchmark"]
+    fn neighbouring_counters_under_contention() {
+        const ROUNDS: usize = 20_000_000;
+        let start = std::time::Instant::now();
+        let threads = [&FIRST, &SECOND].map(|counter| {
+            std::thread::spawn(move || {
+                for _ in 0..ROUNDS {
+                    counter.fetch_add(1, Ordering::Relaxed);
+                }
+            })
+        });
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        std::eprintln!(
+            "cache-aligned={}: {:?}",
+            cfg!(feature = "cache-aligned"),
+            start.elapsed()
+        );
+        assert_eq!(FIRST.load(Ordering::Relaxed), ROUNDS);
+        assert_eq!(SECOND.load(Ordering::Relaxed), ROUNDS);
+    }
+}
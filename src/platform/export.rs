@@ -0,0 +1,454 @@
+//! C allocator entry points, exported under their libc names.
+//!
+//! Blocks are tracked as `AllocKind::Malloc`. Pointers the sanitizer never
+//! saw (allocated by libc before or around us) are passed through to the
+//! C runtime so programs that mix allocators keep working.
+//!
+//! With `preload` `malloc`, `calloc`, `realloc` and `aligned_alloc` are
+//! exported too, so the shared library can be injected into an unmodified
+//! program with `LD_PRELOAD`. The bytes underneath still come from glibc's
+//! `__libc_*` entry points rather than `dlsym(RTLD_NEXT, "malloc")`:
+//! `dlsym` may itself allocate, which would recurse before the lookup
+//! returned.
+//!
+//! With `weak-c-alloc` the functions are defined as `libcplusplus_free`
+//! and so on, and each libc name is a weak symbol that jumps to its
+//! counterpart (see `weak`). A strong definition elsewhere in a static link
+//! then wins instead of clashing.
+
+use crate::sanitize::tracker::AllocKind;
+use crate::sanitize::{alloc_inner, dealloc_inner, diagnostic, quarantine, tracker};
+#[cfg(feature = "preload")]
+use crate::sanitize::{alloc_zeroed_inner, realloc_inner};
+use core::alloc::Layout;
+
+const ENOMEM: i32 = 12;
+const EINVAL: i32 = 22;
+
+/// # Safety
+/// Same as `sanitize::alloc_inner`; the result is freed with `free`.
+unsafe fn alloc_aligned(size: usize, align: usize) -> *mut u8 {
+    let Ok(layout) = Layout::from_size_align(size, align) else {
+        return core::ptr::null_mut();
+    };
+    // SAFETY: Forwarded from the caller.
+    unsafe { alloc_inner(layout, AllocKind::Malloc) }
+}
+
+/// `int posix_memalign(void **memptr, size_t alignment, size_t size)`
+///
+/// # Safety
+/// `memptr` must be valid for a pointer-sized write.
+#[cfg_attr(not(feature = "weak-c-alloc"), unsafe(no_mangle))]
+#[cfg_attr(
+    feature = "weak-c-alloc",
+    unsafe(export_name = "libcplusplus_posix_memalign")
+)]
+pub unsafe extern "C" fn posix_memalign(memptr: *mut *mut u8, align: usize, size: usize) -> i32 {
+    if !align.is_power_of_two() || !align.is_multiple_of(size_of::<usize>()) {
+        return EINVAL;
+    }
+    // SAFETY: Released through the free export.
+    let ptr = unsafe { alloc_aligned(size, align) };
+    if ptr.is_null() {
+        return ENOMEM;
+    }
+    // SAFETY: The caller guarantees memptr is writable.
+    unsafe { *memptr = ptr };
+    0
+}
+
+/// `void *memalign(size_t alignment, size_t size)`
+///
+/// Like glibc, a non-power-of-two alignment is rounded up.
+///
+/// # Safety
+/// The result must be released with `free`.
+#[cfg_attr(not(feature = "weak-c-alloc"), unsafe(no_mangle))]
+#[cfg_attr(
+    feature = "weak-c-alloc",
+    unsafe(export_name = "libcplusplus_memalign")
+)]
+pub unsafe extern "C" fn memalign(align: usize, size: usize) -> *mut u8 {
+    let Some(align) = align.checked_next_power_of_two() else {
+        return core::ptr::null_mut();
+    };
+    // SAFETY: Forwarded from the caller.
+    unsafe { alloc_aligned(size, align) }
+}
+
+/// `void free(void *ptr)`
+///
+/// # Safety
+/// `ptr` must be null or a live pointer from a C allocation function.
+#[cfg_attr(not(feature = "weak-c-alloc"), unsafe(no_mangle))]
+#[cfg_attr(feature = "weak-c-alloc", unsafe(export_name = "libcplusplus_free"))]
+pub unsafe extern "C" fn free(ptr: *mut u8) {
+    if ptr.is_null() {
+        return;
+    }
+    let addr = ptr as usize;
+    if tracker::lookup(addr).is_none() && !quarantine::contains(addr) {
+        // Inside one of our blocks: handing it to the C runtime would
+        // corrupt its heap.
+        if let Some((block, _)) = tracker::try_find_containing(addr) {
+            diagnostic::interior_free(addr, block);
+            return;
+        }
+        // Not ours: allocated by the C runtime directly.
+        // SAFETY: The caller guarantees ptr came from a C allocation function.
+        unsafe { super::free(ptr) };
+        return;
+    }
+    // SAFETY: ptr is tracked, or a quarantined block dealloc_inner reports.
+    unsafe { dealloc_inner(ptr, AllocKind::Malloc) };
+}
+
+/// `void *malloc(size_t size)`
+///
+/// # Safety
+/// The result must be released with `free`.
+#[cfg(feature = "preload")]
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn malloc(size: usize) -> *mut u8 {
+    // SAFETY: Forwarded from the caller.
+    unsafe { alloc_aligned(size, super::MIN_ALIGN) }
+}
+
+/// `void *calloc(size_t nmemb, size_t size)`
+///
+/// # Safety
+/// The result must be released with `free`.
+#[cfg(feature = "preload")]
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn calloc(nmemb: usize, size: usize) -> *mut u8 {
+    let Some(Ok(layout)) = nmemb
+        .checked_mul(size)
+        .map(|total| Layout::from_size_align(total, super::MIN_ALIGN))
+    else {
+        return core::ptr::null_mut();
+    };
+    // SAFETY: Released through the free export.
+    unsafe { alloc_zeroed_inner(layout, AllocKind::Malloc) }
+}
+
+/// `void *aligned_alloc(size_t alignment, size_t size)`
+///
+/// Like glibc, any power-of-two alignment is accepted, whether or not
+/// `size` is a multiple of it.
+///
+/// # Safety
+/// The result must be released with `free`.
+#[cfg(feature = "preload")]
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn aligned_alloc(align: usize, size: usize) -> *mut u8 {
+    if !align.is_power_of_two() {
+        return core::ptr::null_mut();
+    }
+    // SAFETY: Forwarded from the caller.
+    unsafe { alloc_aligned(size, align) }
+}
+
+/// `void *realloc(void *ptr, size_t size)`
+///
+/// A null `ptr` allocates and a zero `size` frees, as in glibc. Blocks the
+/// sanitizer never saw are resized by the C runtime; a quarantined block is
+/// reported as a double free and the realloc fails.
+///
+/// # Safety
+/// `ptr` must be null or a live pointer from a C allocation function.
+#[cfg(feature = "preload")]
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn realloc(ptr: *mut u8, size: usize) -> *mut u8 {
+    if ptr.is_null() {
+        // SAFETY: Forwarded from the caller.
+        return unsafe { malloc(size) };
+    }
+    if size == 0 {
+        // SAFETY: Forwarded from the caller.
+        unsafe { free(ptr) };
+        return core::ptr::null_mut();
+    }
+    let addr = ptr as usize;
+    let Some((tracked_size, _)) = tracker::lookup(addr) else {
+        if quarantine::contains(addr) {
+            // SAFETY: dealloc_inner reports the quarantined block.
+            unsafe { dealloc_inner(ptr, AllocKind::Malloc) };
+            return core::ptr::null_mut();
+        }
+        // SAFETY: The caller guarantees ptr came from a C allocation function.
+        return unsafe { super::realloc(ptr, size) };
+    };
+    // C realloc only promises malloc's alignment for the new block.
+    // SAFETY: tracked_size was allocated, so it fits a layout.
+    let layout = unsafe { Layout::from_size_align_unchecked(tracked_size, super::MIN_ALIGN) };
+    // SAFETY: ptr is tracked with that size.
+    unsafe { realloc_inner(ptr, layout, size, AllocKind::Malloc) }
+}
+
+/// `void malloc_stats(void)`
+///
+/// Prints the sanitizer's allocation counters to stderr in place of
+/// glibc's per-arena summary.
+#[cfg_attr(not(feature = "weak-c-alloc"), unsafe(no_mangle))]
+#[cfg_attr(
+    feature = "weak-c-alloc",
+    unsafe(export_name = "libcplusplus_malloc_stats")
+)]
+pub extern "C" fn malloc_stats() {
+    crate::sanitize::diagnostic::malloc_stats(&tracker::stats(), &quarantine::stats());
+}
+
+/// `size_t malloc_usable_size(void *ptr)`
+///
+/// The tracked user size: writing past it would hit the suffix red zone,
+/// so the padding malloc may have added is not reported. Blocks freed
+/// into the quarantine give 0.
+///
+/// Untracked pointers came from the C runtime and are passed on to its own
+/// `malloc_usable_size`. glibc has no `__libc_malloc_usable_size` alias, so
+/// unlike the other entry points it is found with `dlsym(RTLD_NEXT)`.
+///
+/// # Safety
+/// `ptr` must be null or a live pointer from a C allocation function.
+#[cfg_attr(not(feature = "weak-c-alloc"), unsafe(no_mangle))]
+#[cfg_attr(
+    feature = "weak-c-alloc",
+    unsafe(export_name = "libcplusplus_malloc_usable_size")
+)]
+pub unsafe extern "C" fn malloc_usable_size(ptr: *mut u8) -> usize {
+    let addr = ptr as usize;
+    if let Some((size, _)) = tracker::lookup(addr) {
+        return size;
+    }
+    if ptr.is_null() || quarantine::contains(addr) {
+        return 0;
+    }
+    // SAFETY: RTLD_NEXT looks the symbol up in the modules after this one.
+    let next = unsafe { super::dlsym(super::RTLD_NEXT, c"malloc_usable_size".as_ptr()) };
+    if next.is_null() {
+        return 0;
+    }
+    // SAFETY: The symbol found is the C library's `malloc_usable_size`,
+    // which has the same signature.
+    let next =
+        unsafe { core::mem::transmute::<*mut u8, unsafe extern "C" fn(*mut u8) -> usize>(next) };
+    // SAFETY: Forwarded from the caller.
+    unsafe { next(ptr) }
+}
+
+/// Tail call to `{f}` from a trampoline.
+#[cfg(all(feature = "weak-c-alloc", target_arch = "x86_64"))]
+macro_rules! jump {
+    () => {
+        "jmp {f}"
+    };
+}
+#[cfg(all(feature = "weak-c-alloc", target_arch = "aarch64"))]
+macro_rules! jump {
+    () => {
+        "b {f}"
+    };
+}
+#[cfg(all(feature = "weak-c-alloc", target_arch = "riscv64"))]
+macro_rules! jump {
+    () => {
+        "tail {f}"
+    };
+}
+
+/// Define each function's libc name as a weak trampoline to it.
+///
+/// The dynamic linker ignores weakness, and a weak definition in the
+/// executable still beats one in a shared library, so this only settles
+/// static links. Rust's version script does not list the trampolines, so
+/// the shared library exports the `libcplusplus_` names alone.
+#[cfg(feature = "weak-c-alloc")]
+macro_rules! weak {
+    ($($name:ident),*) => {
+        $(core::arch::global_asm!(
+            concat!(".weak ", stringify!($name)),
+            concat!(".type ", stringify!($name), ", @function"),
+            concat!(stringify!($name), ":"),
+            jump!(),
+            concat!(".size ", stringify!($name), ", . - ", stringify!($name)),
+            f = sym $name,
+        );)*
+    };
+}
+
+#[cfg(feature = "weak-c-alloc")]
+weak!(
+    posix_memalign,
+    memalign,
+    free,
+    malloc_stats,
+    malloc_usable_size
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn free_passes_foreign_pointers_to_the_c_runtime() {
+        // SAFETY: Allocated by the C runtime itself, behind the tracker's back.
+        let ptr = unsafe { super::super::malloc(64) };
+        assert!(!ptr.is_null());
+        assert!(tracker::lookup(ptr as usize).is_none());
+        // SAFETY: ptr is a live C runtime block; free hands it back there.
+        unsafe { free(ptr) };
+    }
+
+    #[test]
+    fn usable_size_of_tracked_and_foreign_blocks() {
+        let mut ours = core::ptr::null_mut();
+        // SAFETY: ours is a valid out-pointer.
+        assert_eq!(unsafe { posix_memalign(&mut ours, 16, 100) }, 0);
+        // SAFETY: ours is a live block from posix_memalign.
+        assert_eq!(unsafe { malloc_usable_size(ours) }, 100);
+
+        // SAFETY: Allocated by the C runtime itself, behind the tracker's back.
+        let foreign = unsafe { super::super::malloc(100) };
+        assert!(!foreign.is_null());
+        // SAFETY: foreign is a live C runtime block.
+        assert!(unsafe { malloc_usable_size(foreign) } >= 100);
+
+        // SAFETY: Both blocks are live and released once.
+        unsafe {
+            free(ours);
+            free(foreign);
+        }
+        // SAFETY: Null is always accepted.
+        assert_eq!(unsafe { malloc_usable_size(core::ptr::null_mut()) }, 0);
+    }
+
+    #[test]
+    fn free_reports_an_interior_pointer() {
+        const TEST: &str = "platform::export::tests::free_reports_an_interior_pointer";
+        if crate::testing::in_child(TEST) {
+            let mut ptr = core::ptr::null_mut();
+            // SAFETY: ptr is a valid out-pointer.
+            assert_eq!(unsafe { posix_memalign(&mut ptr, 16, 64) }, 0);
+            // SAFETY: The interior pointer is reported, not released.
+            unsafe { free(ptr.wrapping_add(8)) };
+            return;
+        }
+        let child = crate::testing::spawn(TEST, &[("LIBCPP_EXITCODE", "23")]);
+        assert_eq!(child.code(), Some(23), "{}", child.stderr());
+        let expected = if cfg!(feature = "minimal-diagnostics") {
+            "SAN 2 "
+        } else {
+            "free of interior pointer"
+        };
+        assert!(child.stderr().contains(expected), "{}", child.stderr());
+    }
+
+    #[test]
+    #[cfg(feature = "weak-c-alloc")]
+    fn libc_names_are_weak_trampolines() {
+        extern crate std;
+        let exe = std::env::current_exe().unwrap();
+        let out = std::process::Command::new("nm")
+            .arg(&exe)
+            .output()
+            .expect("run nm");
+        assert!(out.status.success());
+        let symbols = std::string::String::from_utf8(out.stdout).unwrap();
+        let kind = |name: &str| {
+            symbols.lines().find_map(|l| {
+                let mut fields = l.split_whitespace().rev();
+                (fields.next() == Some(name)).then(|| fields.next().unwrap())
+            })
+        };
+        // Every name passed to `weak!`.
+        for name in [
+            "posix_memalign",
+            "memalign",
+            "free",
+            "malloc_stats",
+            "malloc_usable_size",
+        ] {
+            assert_eq!(kind(name), Some("W"), "{name}");
+            let real = std::format!("libcplusplus_{name}");
+            assert_eq!(kind(&real), Some("T"), "{real}");
+        }
+    }
+
+    /// Builds the static library with and without `weak-c-alloc` and links
+    /// each into a C program that defines its own `free`. Only the weak
+    /// build links; there the program's `free` is called while `memalign`
+    /// still comes from here.
+    #[test]
+    #[ignore = "needs a C compiler; builds the crate twice"]
+    fn strong_free_in_a_static_link_wins_over_the_weak_one() {
+        extern crate std;
+        use std::process::Command;
+
+        const PROGRAM: &str = r#"
+            #include <malloc.h>
+            #include <stdint.h>
+            #include <stdio.h>
+            #include <stdlib.h>
+            extern void __libc_free(void *);
+            static int ours;
+            void free(void *p) { ours++; __libc_free(p); }
+            int main(void) {
+                void *kept = memalign(64, 100);
+                free(malloc(10));
+                printf("aligned=%d ours=%d\n", (int)((uintptr_t)kept % 64 == 0), ours);
+                return 0;
+            }
+        "#;
+        let dir = std::env::temp_dir().join(std::format!("libcpp-weak-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let src = dir.join("own_free.c");
+        std::fs::write(&src, PROGRAM).unwrap();
+        let link = |features: &str| {
+            let target = dir.join(features);
+            let built = Command::new(env!("CARGO"))
+                .args(["build", "--release", "--lib", "--features", features])
+                .arg("--manifest-path")
+                .arg(concat!(env!("CARGO_MANIFEST_DIR"), "/Cargo.toml"))
+                .arg("--target-dir")
+                .arg(&target)
+                .status()
+                .expect("run cargo");
+            assert!(built.success(), "build with {features}");
+            let exe = target.join("own_free");
+            let linked = Command::new("cc")
+                .arg(&src)
+                .arg(target.join("release/liblibcplusplus.a"))
+                .arg("-o")
+                .arg(&exe)
+                .output()
+                .expect("run cc");
+            (linked, exe)
+        };
+
+        let (linked, _) = link("c-alloc");
+        let stderr = std::string::String::from_utf8_lossy(&linked.stderr);
+        assert!(!linked.status.success());
+        assert!(stderr.contains("multiple definition of `free'"), "{stderr}");
+
+        let (linked, exe) = link("weak-c-alloc");
+        assert!(
+            linked.status.success(),
+            "{}",
+            std::string::String::from_utf8_lossy(&linked.stderr)
+        );
+        let run = Command::new(&exe).output().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        let stdout = std::string::String::from_utf8_lossy(&run.stdout);
+        let stderr = std::string::String::from_utf8_lossy(&run.stderr);
+        assert!(run.status.success(), "{stderr}");
+        assert_eq!(stdout, "aligned=1 ours=1\n");
+        // memalign is still sanitized: its block shows up as a leak.
+        let leak = if cfg!(feature = "minimal-diagnostics") {
+            "SAN 7 "
+        } else {
+            "size=100  via=malloc"
+        };
+        assert!(stderr.contains(leak), "{stderr}");
+    }
+}
@@ -0,0 +1,395 @@
+//! mmap-backed replacement for the C allocator.
+//!
+//! Blocks above `LARGE_THRESHOLD` get their own anonymous mapping and are
+//! unmapped on free. Smaller ones come from power-of-two size classes
+//! carved out of 1 MiB arenas and are recycled through per-class free
+//! lists; arenas are never returned to the kernel.
+//!
+//! Every user pointer is preceded by a two-word header:
+//!
+//! ```text
+//! start              user - 16        user
+//! | (align padding) | tag | offset | user data ... |
+//! ```
+//!
+//! `tag` is the size class index for arena blocks and the mapping length
+//! for large ones (always far above the class count); `offset` is
+//! `user - start`.
+
+use super::MIN_ALIGN;
+use super::cache::CacheAligned;
+use super::spinlock::SpinLock;
+use super::syscall::{MAP_ANONYMOUS, MAP_PRIVATE, PROT_READ, PROT_WRITE, sys_mmap, sys_munmap};
+
+const PAGE_SIZE: usize = 4096;
+const HEADER: usize = 2 * size_of::<usize>();
+const ARENA_SIZE: usize = 1 << 20;
+/// Smallest class is `1 << MIN_SHIFT` bytes, header included.
+const MIN_SHIFT: u32 = 5;
+const CLASSES: usize = 13;
+/// Blocks (header and padding included) larger than this are mapped
+/// individually: 128 KiB, glibc's default `M_MMAP_THRESHOLD`.
+const LARGE_THRESHOLD: usize = 1 << (MIN_SHIFT as usize + CLASSES - 1);
+
+const _: () = assert!(HEADER == MIN_ALIGN);
+
+struct Heap {
+    /// Head of each class's free list; the first word of a free block links
+    /// to the next.
+    free: [usize; CLASSES],
+    /// Unused tail of the current arena.
+    bump: usize,
+    end: usize,
+}
+
+static HEAP: CacheAligned<SpinLock<Heap>> = CacheAligned::new(SpinLock::new(Heap {
+    free: [0; CLASSES],
+    bump: 0,
+    end: 0,
+}));
+
+/// Free the heap lock, e.g. in a `fork` child.
+///
+/// # Safety
+/// No other thread may be allocating.
+// Only the fork handler calls this, which needs `sanitize`.
+#[cfg_attr(not(feature = "sanitize"), allow(dead_code))]
+pub unsafe fn force_unlock() {
+    // SAFETY: Forwarded from the caller.
+    unsafe { HEAP.force_unlock() };
+}
+
+fn class_size(class: usize) -> usize {
+    1 << (MIN_SHIFT as usize + class)
+}
+
+fn class_of(block: usize) -> usize {
+    let bits = block
+        .max(1 << MIN_SHIFT)
+        .next_power_of_two()
+        .trailing_zeros();
+    (bits - MIN_SHIFT) as usize
+}
+
+/// A fresh zeroed mapping of `len` bytes, or 0.
+fn map(len: usize) -> usize {
+    // SAFETY: A fresh anonymous mapping aliases nothing.
+    let addr = unsafe {
+        sys_mmap(
+            0,
+            len,
+            PROT_READ | PROT_WRITE,
+            MAP_PRIVATE | MAP_ANONYMOUS,
+            usize::MAX,
+            0,
+        )
+    };
+    if addr < 0 { 0 } else { addr as usize }
+}
+
+impl Heap {
+    /// Start address of a free block of `class`, or 0.
+    fn take(&mut self, class: usize) -> usize {
+        let head = self.free[class];
+        if head != 0 {
+            // SAFETY: Free blocks hold the address of the next one in their
+            // first word.
+            self.free[class] = unsafe { *(head as *const usize) };
+            return head;
+        }
+        let size = class_size(class);
+        if self.end - self.bump < size {
+            let arena = map(ARENA_SIZE);
+            if arena == 0 {
+                return 0;
+            }
+            self.bump = arena;
+            self.end = arena + ARENA_SIZE;
+        }
+        let start = self.bump;
+        self.bump += size;
+        start
+    }
+
+    fn give(&mut self, class: usize, start: usize) {
+        // SAFETY: The block is at least 32 bytes and no longer in use.
+        unsafe { *(start as *mut usize) = self.free[class] };
+        self.free[class] = start;
+    }
+}
+
+/// Fill in the header for a block at `start` whose user pointer is the
+/// first `align`-aligned address past the header.
+///
+/// # Safety
+/// The block must be large enough for the header and padding.
+unsafe fn finish(start: usize, align: usize, tag: usize) -> *mut u8 {
+    let user = (start + HEADER).next_multiple_of(align);
+    let header = (user - HEADER) as *mut usize;
+    // SAFETY: The header lies inside the block, per the caller.
+    unsafe {
+        header.write(tag);
+        header.add(1).write(user - start);
+    }
+    user as *mut u8
+}
+
+/// `(tag, offset)` from the header before `ptr`.
+///
+/// # Safety
+/// `ptr` must be a live pointer from this allocator.
+unsafe fn header(ptr: *mut u8) -> (usize, usize) {
+    let header = (ptr as usize - HEADER) as *const usize;
+    // SAFETY: Every block is preceded by its header.
+    unsafe { (header.read(), header.add(1).read()) }
+}
+
+/// Bytes usable from `ptr` on.
+///
+/// # Safety
+/// `ptr` must be a live pointer from this allocator.
+#[allow(dead_code)]
+unsafe fn usable(ptr: *mut u8) -> usize {
+    // SAFETY: Forwarded from the caller.
+    let (tag, offset) = unsafe { header(ptr) };
+    let len = if tag < CLASSES { class_size(tag) } else { tag };
+    len - offset
+}
+
+/// # Safety
+/// `align` must be a power of two no less than `MIN_ALIGN`.
+unsafe fn alloc(size: usize, align: usize) -> *mut u8 {
+    let Some(block) = size.checked_add(HEADER + align - MIN_ALIGN) else {
+        return core::ptr::null_mut();
+    };
+    if block > LARGE_THRESHOLD {
+        let Some(len) = block.checked_next_multiple_of(PAGE_SIZE) else {
+            return core::ptr::null_mut();
+        };
+        let start = map(len);
+        if start == 0 {
+            return core::ptr::null_mut();
+        }
+        // SAFETY: The mapping covers the header, padding and size bytes.
+        return unsafe { finish(start, align, len) };
+    }
+    let class = class_of(block);
+    let start = HEAP.lock().take(class);
+    if start == 0 {
+        return core::ptr::null_mut();
+    }
+    // SAFETY: The class holds at least `block` bytes.
+    unsafe { finish(start, align, class) }
+}
+
+/// `malloc(size)`
+///
+/// # Safety
+/// The result must be released with this module's `free`.
+pub unsafe fn malloc(size: usize) -> *mut u8 {
+    // SAFETY: MIN_ALIGN is a power of two.
+    unsafe { alloc(size, MIN_ALIGN) }
+}
+
+/// `calloc(nmemb, size)`
+///
+/// # Safety
+/// The result must be released with this module's `free`.
+#[allow(dead_code)]
+pub unsafe fn calloc(nmemb: usize, size: usize) -> *mut u8 {
+    let Some(total) = nmemb.checked_mul(size) else {
+        return core::ptr::null_mut();
+    };
+    // SAFETY: Forwarded from the caller.
+    let ptr = unsafe { malloc(total) };
+    // SAFETY: A class block may be recycled; large ones are fresh mappings
+    // and already zero.
+    if !ptr.is_null() && unsafe { header(ptr) }.0 < CLASSES {
+        // SAFETY: The block holds at least `total` bytes from ptr.
+        unsafe { core::ptr::write_bytes(ptr, 0, total) };
+    }
+    ptr
+}
+
+/// `aligned_alloc(align, size)`
+///
+/// # Safety
+/// `align` must be a power of two; the result must be released with this
+/// module's `free`.
+#[cfg_attr(feature = "guard-pages", allow(dead_code))]
+pub unsafe fn aligned_alloc(align: usize, size: usize) -> *mut u8 {
+    // SAFETY: align is a power of two, raised to at least MIN_ALIGN.
+    unsafe { alloc(size, align.max(MIN_ALIGN)) }
+}
+
+/// `realloc(ptr, size)`: grows in place while the block has room.
+///
+/// # Safety
+/// `ptr` must be null or a live pointer from this allocator.
+#[allow(dead_code)]
+pub unsafe fn realloc(ptr: *mut u8, size: usize) -> *mut u8 {
+    if ptr.is_null() {
+        // SAFETY: Forwarded from the caller.
+        return unsafe { malloc(size) };
+    }
+    if size == 0 {
+        // SAFETY: Forwarded from the caller.
+        unsafe { free(ptr) };
+        return core::ptr::null_mut();
+    }
+    // SAFETY: ptr is live, per the caller.
+    let old = unsafe { usable(ptr) };
+    if size <= old {
+        return ptr;
+    }
+    // SAFETY: Released by the caller.
+    let new = unsafe { malloc(size) };
+    if !new.is_null() {
+        // SAFETY: Both blocks hold at least `old` bytes and are distinct.
+        unsafe {
+            core::ptr::copy_nonoverlapping(ptr, new, old);
+            free(ptr);
+        }
+    }
+    new
+}
+
+/// `free(ptr)`
+///
+/// # Safety
+/// `ptr` must be null or a live pointer from this allocator.
+pub unsafe fn free(ptr: *mut u8) {
+    if ptr.is_null() {
+        return;
+    }
+    // SAFETY: ptr is live, per the caller.
+    let (tag, offset) = unsafe { header(ptr) };
+    let start = ptr as usize - offset;
+    if tag < CLASSES {
+        HEAP.lock().give(tag, start);
+    } else {
+        // SAFETY: The mapping was made for this block alone.
+        unsafe { sys_munmap(start, tag) };
+    }
+}
+
+#[cfg(all(test, feature = "mmap-backend"))]
+mod tests {
+    extern crate std;
+
+    use super::*;
+
+    const TEST: &str = "platform::heap::tests::";
+
+    /// Run `body` in a child, alone with the heap: with other tests
+    /// allocating beside it, a freed block may go to them first.
+    fn alone(name: &str, body: impl FnOnce()) {
+        let test = std::format!("{TEST}{name}");
+        if crate::testing::in_child(&test) {
+            body();
+            return;
+        }
+        let child = crate::testing::spawn(&test, &[]);
+        assert_eq!(child.code(), Some(0), "{}", child.stderr());
+    }
+
+    #[test]
+    fn freed_block_is_reused_by_its_class() {
+        alone("freed_block_is_reused_by_its_class", || {
+            // SAFETY: Every block is freed below.
+            unsafe {
+                let a = malloc(100);
+                assert_eq!(header(a).0, class_of(100 + HEADER));
+                free(a);
+                // Same class, different size: the same block comes back.
+                let b = malloc(90);
+                assert_eq!(b, a);
+                // Another class does not take it.
+                let c = malloc(400);
+                assert_ne!(c, a);
+                free(b);
+                free(c);
+            }
+        });
+    }
+
+    #[test]
+    fn calloc_zeroes_a_recycled_block() {
+        alone("calloc_zeroes_a_recycled_block", || {
+            // SAFETY: Every block is freed below.
+            unsafe {
+                let a = malloc(200);
+                core::ptr::write_bytes(a, 0xAB, 200);
+                free(a);
+                let b = calloc(25, 8);
+                assert_eq!(b, a);
+                assert!(core::slice::from_raw_parts(b, 200).iter().all(|&x| x == 0));
+                free(b);
+            }
+        });
+    }
+
+    #[test]
+    fn large_block_has_its_own_mapping() {
+        let size = LARGE_THRESHOLD + 1;
+        // SAFETY: Freed below.
+        unsafe {
+            let ptr = calloc(1, size);
+            assert!(!ptr.is_null());
+            assert_eq!(ptr as usize % MIN_ALIGN, 0);
+            let (tag, offset) = header(ptr);
+            assert_eq!(offset, HEADER);
+            assert!(tag >= size + HEADER && tag.is_multiple_of(PAGE_SIZE));
+            assert_eq!((ptr as usize - offset) % PAGE_SIZE, 0);
+            assert!(usable(ptr) >= size);
+            let bytes = core::slice::from_raw_parts_mut(ptr, size);
+            assert!(bytes.iter().all(|&x| x == 0));
+            bytes[size - 1] = 1;
+            free(ptr);
+        }
+    }
+
+    #[test]
+    fn realloc_moves_to_a_larger_class_and_keeps_the_data() {
+        // SAFETY: Every block is freed below.
+        unsafe {
+            let ptr = malloc(24);
+            for i in 0..24 {
+                ptr.add(i).write(i as u8);
+            }
+            // Within the block's room it stays put.
+            let same = realloc(ptr, usable(ptr));
+            assert_eq!(same, ptr);
+            let grown = realloc(same, 1000);
+            assert_ne!(grown, ptr);
+            assert_eq!(header(grown).0, class_of(1000 + HEADER));
+            for i in 0..24 {
+                assert_eq!(grown.add(i).read(), i as u8);
+            }
+            let large = realloc(grown, LARGE_THRESHOLD * 2);
+            assert!(header(large).0 >= CLASSES);
+            for i in 0..24 {
+                assert_eq!(large.add(i).read(), i as u8);
+            }
+            assert!(realloc(large, 0).is_null());
+        }
+    }
+
+    #[test]
+    fn over_aligned_requests_are_aligned() {
+        for align in [32, 64, 4096, 1 << 16] {
+            for size in [1, 100, LARGE_THRESHOLD] {
+                // SAFETY: align is a power of two; freed below.
+                unsafe {
+                    let ptr = aligned_alloc(align, size);
+                    assert!(!ptr.is_null());
+                    assert_eq!(ptr as usize % align, 0, "align {align} size {size}");
+                    assert!(usable(ptr) >= size);
+                    ptr.write(1);
+                    ptr.add(size - 1).write(1);
+                    free(ptr);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,158 @@
+//! `memcpy`, `memmove`, `memset` and `memcmp` for freestanding links.
+//!
+//! The compiler lowers copies, fills and comparisons to calls to these
+//! four symbols, which only a C library normally provides. They are plain
+//! byte loops: correct and small, not fast. Exporting them next to a libc
+//! would replace its tuned versions process-wide, hence the feature gate.
+//!
+//! LLVM does not turn a loop back into a call to the function that
+//! contains it, so none of these recurse; `memmove` may call `memcpy`.
+
+/// `void *memcpy(void *dest, const void *src, size_t n)`
+///
+/// # Safety
+/// `dest` and `src` must be valid for `n` bytes and must not overlap.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn memcpy(dest: *mut u8, src: *const u8, n: usize) -> *mut u8 {
+    let mut i = 0;
+    while i < n {
+        // SAFETY: i < n, within both buffers per the caller.
+        unsafe { *dest.add(i) = *src.add(i) };
+        i += 1;
+    }
+    dest
+}
+
+/// `void *memmove(void *dest, const void *src, size_t n)`
+///
+/// # Safety
+/// `dest` and `src` must be valid for `n` bytes; they may overlap.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn memmove(dest: *mut u8, src: *const u8, n: usize) -> *mut u8 {
+    if (dest as usize) <= (src as usize) || (dest as usize) >= (src as usize).wrapping_add(n) {
+        // Copying forwards never overwrites source bytes not yet read.
+        let mut i = 0;
+        while i < n {
+            // SAFETY: i < n, within both buffers per the caller.
+            unsafe { *dest.add(i) = *src.add(i) };
+            i += 1;
+        }
+    } else {
+        // dest overlaps the tail of src: copy backwards.
+        let mut i = n;
+        while i > 0 {
+            i -= 1;
+            // SAFETY: i < n, within both buffers per the caller.
+            unsafe { *dest.add(i) = *src.add(i) };
+        }
+    }
+    dest
+}
+
+/// `void *memset(void *s, int c, size_t n)`
+///
+/// # Safety
+/// `s` must be valid for `n` bytes of writes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn memset(s: *mut u8, c: i32, n: usize) -> *mut u8 {
+    let mut i = 0;
+    while i < n {
+        // SAFETY: i < n, within the buffer per the caller.
+        unsafe { *s.add(i) = c as u8 };
+        i += 1;
+    }
+    s
+}
+
+/// `int memcmp(const void *s1, const void *s2, size_t n)`
+///
+/// Bytes compare as `unsigned char`, as C requires.
+///
+/// # Safety
+/// `s1` and `s2` must be valid for `n` bytes of reads.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn memcmp(s1: *const u8, s2: *const u8, n: usize) -> i32 {
+    let mut i = 0;
+    while i < n {
+        // SAFETY: i < n, within both buffers per the caller.
+        let (a, b) = unsafe { (*s1.add(i), *s2.add(i)) };
+        if a != b {
+            return a as i32 - b as i32;
+        }
+        i += 1;
+    }
+    0
+}
+
+/// `int bcmp(const void *s1, const void *s2, size_t n)`
+///
+/// LLVM emits this for equality-only comparisons. Zero iff equal.
+///
+/// # Safety
+/// `s1` and `s2` must be valid for `n` bytes of reads.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bcmp(s1: *const u8, s2: *const u8, n: usize) -> i32 {
+    // SAFETY: Forwarded from the caller.
+    unsafe { memcmp(s1, s2, n) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn memmove_handles_overlap_in_both_directions() {
+        let mut buf = *b"abcdefgh";
+        let p = buf.as_mut_ptr();
+        // SAFETY: Both ranges lie within buf.
+        unsafe {
+            // dest after src: must copy backwards.
+            assert_eq!(memmove(p.add(2), p, 5), p.add(2));
+            assert_eq!(&buf, b"ababcdeh");
+            // dest before src: must copy forwards.
+            assert_eq!(memmove(p, p.add(3), 5), p);
+            assert_eq!(&buf, b"bcdehdeh");
+            // Same buffer, and zero length, change nothing.
+            memmove(p, p, 8);
+            memmove(p.add(1), p, 0);
+        }
+        assert_eq!(&buf, b"bcdehdeh");
+    }
+
+    #[test]
+    fn memcmp_orders_bytes_as_unsigned() {
+        let cmp = |a: &[u8], b: &[u8]| {
+            // SAFETY: Both slices are at least a.len() long.
+            unsafe { memcmp(a.as_ptr(), b.as_ptr(), a.len()) }
+        };
+        assert_eq!(cmp(b"same", b"same"), 0);
+        assert_eq!(cmp(b"", b""), 0);
+        assert!(cmp(b"abc", b"abd") < 0);
+        assert!(cmp(b"abd", b"abc") > 0);
+        // 0x80 is above 0x7f as unsigned char, though not as signed char.
+        assert!(cmp(&[0x80], &[0x7f]) > 0);
+        assert!(cmp(&[0x01, 0xff], &[0x01, 0x00]) > 0);
+        // Only the first difference counts.
+        assert!(cmp(&[0x00, 0xff], &[0x01, 0x00]) < 0);
+        // SAFETY: Both arrays are two bytes long.
+        unsafe {
+            assert_eq!(bcmp([1u8, 2].as_ptr(), [1u8, 2].as_ptr(), 2), 0);
+            assert_ne!(bcmp([1u8, 2].as_ptr(), [1u8, 3].as_ptr(), 2), 0);
+        }
+    }
+
+    #[test]
+    fn memset_and_memcpy_return_dest() {
+        let mut buf = [0u8; 6];
+        let p = buf.as_mut_ptr();
+        // SAFETY: All ranges lie within buf or src.
+        unsafe {
+            // The fill value is converted to unsigned char.
+            assert_eq!(memset(p.add(1), 0x1ab, 4), p.add(1));
+            assert_eq!(buf, [0, 0xab, 0xab, 0xab, 0xab, 0]);
+            let src = [1u8, 2, 3];
+            assert_eq!(memcpy(p.add(2), src.as_ptr(), 3), p.add(2));
+        }
+        assert_eq!(buf, [0, 0xab, 1, 2, 3, 0]);
+    }
+}
@@ -1,10 +1,77 @@
+#[cfg(all(feature = "c-alloc", feature = "mmap-backend"))]
+compile_error!(
+    "`mmap-backend` cannot back `c-alloc`: blocks from the C runtime would be freed here"
+);
+
+#[cfg(all(feature = "preload", feature = "weak-c-alloc"))]
+compile_error!("`weak-c-alloc` hides the exports `preload` needs from the dynamic linker");
+
+#[allow(dead_code)]
+pub mod cache;
+#[cfg(feature = "c-alloc")]
+mod export;
+#[cfg(feature = "mmap-backend")]
+mod heap;
+#[cfg(feature = "freestanding-mem")]
+mod mem;
+#[allow(dead_code)]
+pub mod spinlock;
+#[cfg(feature = "freestanding-mem")]
+mod str;
 #[allow(dead_code)]
 pub mod syscall;
 
+/// Alignment malloc guarantees (`alignof(max_align_t)` on x86_64, aarch64 and riscv64).
+#[cfg_attr(feature = "guard-pages", allow(dead_code))]
+pub const MIN_ALIGN: usize = 16;
+
+// With `c-alloc` this crate exports the C allocator symbols itself, so the
+// underlying allocator is reached through glibc's internal aliases instead
+// of recursing into our own exports.
+#[cfg(not(feature = "mmap-backend"))]
 unsafe extern "C" {
+    #[cfg_attr(feature = "c-alloc", link_name = "__libc_malloc")]
     pub fn malloc(size: usize) -> *mut u8;
     #[allow(dead_code)]
+    #[cfg_attr(feature = "c-alloc", link_name = "__libc_calloc")]
+    pub fn calloc(nmemb: usize, size: usize) -> *mut u8;
+    /// C11: `size` must be a multiple of `align`.
+    #[cfg_attr(feature = "guard-pages", allow(dead_code))]
+    #[cfg_attr(feature = "c-alloc", link_name = "__libc_memalign")]
+    pub fn aligned_alloc(align: usize, size: usize) -> *mut u8;
+    #[allow(dead_code)]
+    #[cfg_attr(feature = "c-alloc", link_name = "__libc_realloc")]
     pub fn realloc(ptr: *mut u8, size: usize) -> *mut u8;
+    #[cfg_attr(feature = "c-alloc", link_name = "__libc_free")]
     pub fn free(ptr: *mut u8);
+}
+
+#[cfg(feature = "mmap-backend")]
+#[allow(unused_imports)]
+pub use heap::{aligned_alloc, calloc, force_unlock as force_unlock_heap, free, malloc, realloc};
+
+unsafe extern "C" {
     pub fn abort() -> !;
 }
+
+/// Handle for `dlsym` that searches the modules loaded after the caller's.
+pub const RTLD_NEXT: *mut u8 = usize::MAX as *mut u8;
+
+unsafe extern "C" {
+    pub fn dlsym(handle: *mut u8, symbol: *const core::ffi::c_char) -> *mut u8;
+}
+
+/// `Dl_info`, as filled in by `dladdr`.
+#[cfg(feature = "backtrace")]
+#[repr(C)]
+pub struct DlInfo {
+    pub fname: *const u8,
+    pub fbase: *mut u8,
+    pub sname: *const u8,
+    pub saddr: *mut u8,
+}
+
+#[cfg(feature = "backtrace")]
+unsafe extern "C" {
+    pub fn dladdr(addr: *const u8, info: *mut DlInfo) -> i32;
+}
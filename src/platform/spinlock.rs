@@ -0,0 +1,314 @@
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+/// Backoff doublings (1, 2, ... 32 spins per wait) before waiting turns
+/// into yielding the CPU.
+const SPIN_LIMIT: u32 = 6;
+
+/// A minimal spin lock for protecting crate-internal data structures.
+///
+/// Uses a test-and-test-and-set pattern: spin on a relaxed load before
+/// attempting the expensive compare_exchange. Under contention the spins
+/// between loads double each round, and once they reach `1 << SPIN_LIMIT`
+/// each wait yields the CPU instead so a preempted holder can run.
+pub struct SpinLock<T> {
+    locked: AtomicBool,
+    data: UnsafeCell<T>,
+}
+
+// SAFETY: The spin lock ensures only one thread accesses the inner data at a time.
+unsafe impl<T: Send> Sync for SpinLock<T> {}
+unsafe impl<T: Send> Send for SpinLock<T> {}
+
+impl<T> SpinLock<T> {
+    pub const fn new(data: T) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn lock(&self) -> SpinLockGuard<'_, T> {
+        let mut round = 0;
+        loop {
+            if self
+                .locked
+                .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+            {
+                return SpinLockGuard { lock: self };
+            }
+            // Spin on relaxed loads to reduce cache-line contention.
+            while self.locked.load(Ordering::Relaxed) {
+                backoff(round);
+                round += 1;
+            }
+        }
+    }
+
+    /// Take the lock only if it is free. For contexts that must never
+    /// spin, such as signal handlers that may have interrupted the holder.
+    pub fn try_lock(&self) -> Option<SpinLockGuard<'_, T>> {
+        self.locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| SpinLockGuard { lock: self })
+    }
+
+    /// Mark the lock free, whoever holds it.
+    ///
+    /// # Safety
+    /// No holder may still use the data or drop its guard, as in the child
+    /// of a `fork` where the holding thread was not copied.
+    pub unsafe fn force_unlock(&self) {
+        self.locked.store(false, Ordering::Release);
+    }
+}
+
+/// Wait before looking at the lock again after `round` failed looks.
+fn backoff(round: u32) {
+    if round < SPIN_LIMIT {
+        for _ in 0..1u32 << round {
+            core::hint::spin_loop();
+        }
+    } else {
+        // SAFETY: sched_yield has no preconditions.
+        unsafe { crate::platform::syscall::sys_sched_yield() };
+    }
+}
+
+pub struct SpinLockGuard<'a, T> {
+    lock: &'a SpinLock<T>,
+}
+
+impl<T> Deref for SpinLockGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        // SAFETY: The lock is held, granting exclusive access.
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> DerefMut for SpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // SAFETY: The lock is held, granting exclusive access.
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for SpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.locked.store(false, Ordering::Release);
+    }
+}
+
+/// Reader-writer variant of `SpinLock`: any number of readers, or one
+/// writer.
+///
+/// A writer that finds readers inside sets `WAITING`, which holds off new
+/// readers until it gets in, so a steady stream of lookups cannot starve
+/// it. Readers must not nest: an inner `read` would wait on that writer.
+pub struct RwSpinLock<T> {
+    /// `WRITER` and `WAITING` flags plus `READER` times the reader count.
+    state: AtomicUsize,
+    data: UnsafeCell<T>,
+}
+
+const WRITER: usize = 1;
+const WAITING: usize = 2;
+const READER: usize = 4;
+
+// SAFETY: Readers only get shared access, writers exclusive access.
+unsafe impl<T: Send + Sync> Sync for RwSpinLock<T> {}
+unsafe impl<T: Send> Send for RwSpinLock<T> {}
+
+impl<T> RwSpinLock<T> {
+    pub const fn new(data: T) -> Self {
+        Self {
+            state: AtomicUsize::new(0),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// Drop every reader and writer, and any waiting writer's claim.
+    ///
+    /// # Safety
+    /// Same as `SpinLock::force_unlock`.
+    pub unsafe fn force_unlock(&self) {
+        self.state.store(0, Ordering::Release);
+    }
+
+    pub fn read(&self) -> RwReadGuard<'_, T> {
+        let mut round = 0;
+        loop {
+            let state = self.state.load(Ordering::Relaxed);
+            if state & (WRITER | WAITING) == 0
+                && self
+                    .state
+                    .compare_exchange_weak(
+                        state,
+                        state + READER,
+                        Ordering::Acquire,
+                        Ordering::Relaxed,
+                    )
+                    .is_ok()
+            {
+                return RwReadGuard { lock: self };
+            }
+            backoff(round);
+            round += 1;
+        }
+    }
+
+    /// `read` that gives up instead of waiting for a writer.
+    pub fn try_read(&self) -> Option<RwReadGuard<'_, T>> {
+        let mut state = self.state.load(Ordering::Relaxed);
+        while state & (WRITER | WAITING) == 0 {
+            match self.state.compare_exchange_weak(
+                state,
+                state + READER,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return Some(RwReadGuard { lock: self }),
+                Err(now) => state = now,
+            }
+        }
+        None
+    }
+
+    pub fn write(&self) -> RwWriteGuard<'_, T> {
+        let mut round = 0;
+        loop {
+            let state = self.state.load(Ordering::Relaxed);
+            if state & !WAITING == 0 {
+                // Taking the lock clears WAITING; other waiting writers
+                // set it again on their next look.
+                if self
+                    .state
+                    .compare_exchange_weak(state, WRITER, Ordering::Acquire, Ordering::Relaxed)
+                    .is_ok()
+                {
+                    return RwWriteGuard { lock: self };
+                }
+            } else if state & WAITING == 0 {
+                self.state.fetch_or(WAITING, Ordering::Relaxed);
+            }
+            backoff(round);
+            round += 1;
+        }
+    }
+}
+
+pub struct RwReadGuard<'a, T> {
+    lock: &'a RwSpinLock<T>,
+}
+
+impl<T> Deref for RwReadGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        // SAFETY: A read lock is held, so no writer has access.
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for RwReadGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.state.fetch_sub(READER, Ordering::Release);
+    }
+}
+
+pub struct RwWriteGuard<'a, T> {
+    lock: &'a RwSpinLock<T>,
+}
+
+impl<T> Deref for RwWriteGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        // SAFETY: The write lock is held, granting exclusive access.
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> DerefMut for RwWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // SAFETY: The write lock is held, granting exclusive access.
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for RwWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.state.fetch_and(!WRITER, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use std::vec::Vec;
+
+    #[test]
+    fn readers_share_and_writers_exclude() {
+        let lock = RwSpinLock::new(5);
+        let (a, b) = (lock.read(), lock.read());
+        assert_eq!(*a + *b, 10);
+        drop((a, b));
+        let mut w = lock.write();
+        *w += 1;
+        assert!(lock.try_read().is_none());
+        drop(w);
+        assert_eq!(*lock.try_read().unwrap(), 6);
+    }
+
+    #[test]
+    fn waiting_writer_holds_off_new_readers() {
+        let lock = RwSpinLock::new(0u32);
+        let reader = lock.read();
+        std::thread::scope(|s| {
+            let writer = s.spawn(|| *lock.write() = 1);
+            while lock.state.load(Ordering::Relaxed) & WAITING == 0 {
+                std::thread::yield_now();
+            }
+            // The writer is queued behind `reader`; a newcomer must wait.
+            assert!(lock.try_read().is_none());
+            drop(reader);
+            writer.join().unwrap();
+        });
+        assert_eq!(*lock.read(), 1);
+    }
+
+    #[test]
+    fn concurrent_readers_never_see_a_partial_write() {
+        const WORDS: usize = 16;
+        const UPDATES: usize = 20_000;
+        let lock = RwSpinLock::new([0usize; WORDS]);
+        let done = AtomicBool::new(false);
+        std::thread::scope(|s| {
+            let readers: Vec<_> = (0..4)
+                .map(|_| {
+                    s.spawn(|| {
+                        while !done.load(Ordering::Relaxed) {
+                            let words = lock.read();
+                            assert!(words.iter().all(|&w| w == words[0]), "{:?}", *words);
+                        }
+                    })
+                })
+                .collect();
+            for n in 1..=UPDATES {
+                let mut words = lock.write();
+                for w in words.iter_mut() {
+                    *w = n;
+                }
+            }
+            done.store(true, Ordering::Relaxed);
+            for reader in readers {
+                reader.join().unwrap();
+            }
+        });
+        assert_eq!(*lock.read(), [UPDATES; WORDS]);
+    }
+}
@@ -0,0 +1,156 @@
+//! `strlen`, `strnlen` and `strcmp` for freestanding links, alongside the
+//! `mem` builtins. Each reads one byte at a time and stops at the first
+//! NUL, so nothing past the terminator (or past `maxlen`) is touched.
+
+/// `size_t strlen(const char *s)`
+///
+/// # Safety
+/// `s` must point to a NUL-terminated string.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn strlen(s: *const u8) -> usize {
+    let mut len = 0;
+    // SAFETY: Every byte up to and including the NUL is readable.
+    while unsafe { *s.add(len) } != 0 {
+        len += 1;
+    }
+    len
+}
+
+/// `size_t strnlen(const char *s, size_t maxlen)`
+///
+/// # Safety
+/// `s` must be readable up to its NUL or `maxlen` bytes, whichever is first.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn strnlen(s: *const u8, maxlen: usize) -> usize {
+    let mut len = 0;
+    // SAFETY: len < maxlen and no NUL seen yet, so the byte is readable.
+    while len < maxlen && unsafe { *s.add(len) } != 0 {
+        len += 1;
+    }
+    len
+}
+
+/// `int strcmp(const char *s1, const char *s2)`
+///
+/// Bytes compare as `unsigned char`, as C requires.
+///
+/// # Safety
+/// `s1` and `s2` must point to NUL-terminated strings.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn strcmp(s1: *const u8, s2: *const u8) -> i32 {
+    let mut i = 0;
+    loop {
+        // SAFETY: Neither string has ended before i, so both bytes are readable.
+        let (a, b) = unsafe { (*s1.add(i), *s2.add(i)) };
+        if a != b || a == 0 {
+            return a as i32 - b as i32;
+        }
+        i += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::platform::syscall;
+    use std::vec::Vec;
+
+    fn ptr(bytes: &[u8]) -> *const u8 {
+        bytes.as_ptr()
+    }
+
+    fn cmp(a: &[u8], b: &[u8]) -> i32 {
+        // SAFETY: The callers pass NUL-terminated byte strings.
+        unsafe { strcmp(a.as_ptr(), b.as_ptr()) }
+    }
+
+    #[test]
+    fn known_answers() {
+        // SAFETY: Every string is NUL-terminated within its literal.
+        unsafe {
+            assert_eq!(strlen(ptr(b"\0")), 0);
+            assert_eq!(strlen(ptr(b"hello\0")), 5);
+            assert_eq!(strlen(ptr(b"ab\0cd\0")), 2);
+            assert_eq!(strnlen(ptr(b"hello\0"), 3), 3);
+            assert_eq!(strnlen(ptr(b"hello\0"), 5), 5);
+            assert_eq!(strnlen(ptr(b"hello\0"), 99), 5);
+            assert_eq!(strnlen(ptr(b"ab\0cd\0"), 5), 2);
+            // maxlen 0 reads nothing, so any pointer will do.
+            assert_eq!(strnlen(core::ptr::null(), 0), 0);
+        }
+        assert_eq!(cmp(b"\0", b"\0"), 0);
+        assert_eq!(cmp(b"abc\0", b"abc\0"), 0);
+        assert!(cmp(b"abc\0", b"abd\0") < 0);
+        assert!(cmp(b"ab\0", b"abc\0") < 0);
+        assert!(cmp(b"abc\0", b"ab\0") > 0);
+        // Bytes after the first NUL are not compared.
+        assert_eq!(cmp(b"ab\0x\0", b"ab\0y\0"), 0);
+        // 0xe9 is above 'e' as unsigned char, though not as signed char.
+        assert!(cmp(b"\xe9\0", b"e\0") > 0);
+    }
+
+    #[test]
+    fn random_strings_match_their_lengths() {
+        let mut state = 0x9e37_79b9_7f4a_7c15u64;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        for _ in 0..1000 {
+            let len = (next() % 300) as usize;
+            // Non-zero bytes, the terminator, then junk that must be ignored.
+            let mut a: Vec<u8> = (0..len).map(|_| (next() % 255) as u8 + 1).collect();
+            a.push(0);
+            a.extend((0..8).map(|_| next() as u8));
+            let maxlen = (next() % 320) as usize;
+            // SAFETY: a is NUL-terminated at index len.
+            unsafe {
+                assert_eq!(strlen(a.as_ptr()), len);
+                assert_eq!(strnlen(a.as_ptr(), maxlen), len.min(maxlen));
+            }
+
+            let mut b = a.clone();
+            if len != 0 {
+                let at = (next() as usize) % len;
+                b[at] = (next() % 255) as u8 + 1;
+            }
+            let expected = a[..=len].cmp(&b[..=len]);
+            assert_eq!(cmp(&a, &b).cmp(&0), expected);
+            assert_eq!(cmp(&b, &a).cmp(&0), expected.reverse());
+        }
+    }
+
+    #[test]
+    fn nothing_past_the_terminator_is_read() {
+        const PAGE: usize = 4096;
+        // SAFETY: A fresh private anonymous mapping; its second page is
+        // made inaccessible, so a read past the first page faults.
+        unsafe {
+            let map = syscall::sys_mmap(
+                0,
+                2 * PAGE,
+                syscall::PROT_READ | syscall::PROT_WRITE,
+                syscall::MAP_PRIVATE | syscall::MAP_ANONYMOUS,
+                usize::MAX,
+                0,
+            );
+            assert!(map > 0, "mmap failed: {map}");
+            let map = map as usize;
+            assert_eq!(
+                syscall::sys_mprotect(map + PAGE, PAGE, syscall::PROT_NONE),
+                0
+            );
+            let s = (map + PAGE - 4) as *mut u8;
+            s.copy_from(ptr(b"abc\0"), 4);
+            assert_eq!(strlen(s), 3);
+            assert_eq!(strnlen(s, usize::MAX), 3);
+            assert_eq!(strcmp(s, s), 0);
+            assert!(strcmp(s, ptr(b"abcd\0")) < 0);
+            assert_eq!(syscall::sys_munmap(map, 2 * PAGE), 0);
+        }
+    }
+}
@@ -1,7 +1,14 @@
-//! Raw Linux x86_64 syscall wrappers.
+//! Raw Linux syscall wrappers for x86_64, aarch64 and riscv64.
 //!
 //! These bypass all Rust and C library layers, issuing syscalls directly
-//! via the `syscall` instruction.
+//! via the `syscall` instruction (x86_64), `svc #0` (aarch64) or `ecall`
+//! (riscv64). All three architectures expose the same signatures. aarch64
+//! and riscv64 share the generic syscall table, so their numbers match;
+//! on riscv64 the kernel preserves every register but `a0`, so no
+//! clobbers are listed.
+//!
+//! Everywhere else the same functions are backed by the C library (see
+//! `fallback`), so the crate still builds and can print diagnostics.
 
 /// Write bytes to a file descriptor.
 /// Returns the number of bytes written, or a negative errno on failure.
@@ -26,18 +33,1114 @@ pub unsafe fn sys_write(fd: usize, buf: *const u8, len: usize) -> isize {
     ret
 }
 
-/// Terminate the calling process and all its threads.
+#[cfg(all(target_os = "linux", target_arch = "aarch64"))]
+#[inline(always)]
+pub unsafe fn sys_write(fd: usize, buf: *const u8, len: usize) -> isize {
+    let ret: isize;
+    // SAFETY: Caller guarantees buf points to len readable bytes.
+    unsafe {
+        core::arch::asm!(
+            "svc #0",
+            in("x8") 64_usize,
+            inlateout("x0") fd as isize => ret,
+            in("x1") buf,
+            in("x2") len,
+            options(nostack),
+        );
+    }
+    ret
+}
+
+#[cfg(all(target_os = "linux", target_arch = "riscv64"))]
+#[inline(always)]
+pub unsafe fn sys_write(fd: usize, buf: *const u8, len: usize) -> isize {
+    let ret: isize;
+    // SAFETY: Caller guarantees buf points to len readable bytes.
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            in("a7") 64_usize,
+            inlateout("a0") fd as isize => ret,
+            in("a1") buf,
+            in("a2") len,
+            options(nostack),
+        );
+    }
+    ret
+}
+
+/// Read up to `len` bytes from a file descriptor.
+/// Returns the number of bytes read (0 at EOF), or a negative errno.
 #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
 #[inline(always)]
-pub unsafe fn sys_exit_group(code: i32) -> ! {
-    // SAFETY: This terminates the process. Caller is responsible for
-    // ensuring this is the intended behavior.
+pub unsafe fn sys_read(fd: usize, buf: *mut u8, len: usize) -> isize {
+    let ret: isize;
+    // SAFETY: Caller guarantees buf points to len writable bytes.
     unsafe {
         core::arch::asm!(
             "syscall",
-            in("rax") 231_usize,
-            in("rdi") code as usize,
-            options(noreturn, nostack),
+            inlateout("rax") 0_isize => ret,
+            in("rdi") fd,
+            in("rsi") buf,
+            in("rdx") len,
+            lateout("rcx") _,
+            lateout("r11") _,
+            options(nostack),
+        );
+    }
+    ret
+}
+
+#[cfg(all(target_os = "linux", target_arch = "aarch64"))]
+#[inline(always)]
+pub unsafe fn sys_read(fd: usize, buf: *mut u8, len: usize) -> isize {
+    let ret: isize;
+    // SAFETY: Caller guarantees buf points to len writable bytes.
+    unsafe {
+        core::arch::asm!(
+            "svc #0",
+            in("x8") 63_usize,
+            inlateout("x0") fd as isize => ret,
+            in("x1") buf,
+            in("x2") len,
+            options(nostack),
+        );
+    }
+    ret
+}
+
+#[cfg(all(target_os = "linux", target_arch = "riscv64"))]
+#[inline(always)]
+pub unsafe fn sys_read(fd: usize, buf: *mut u8, len: usize) -> isize {
+    let ret: isize;
+    // SAFETY: Caller guarantees buf points to len writable bytes.
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            in("a7") 63_usize,
+            inlateout("a0") fd as isize => ret,
+            in("a1") buf,
+            in("a2") len,
+            options(nostack),
+        );
+    }
+    ret
+}
+
+/// Open a file. `path` must be NUL-terminated.
+/// Returns the new file descriptor, or a negative errno on failure.
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+#[inline(always)]
+pub unsafe fn sys_open(path: *const u8, flags: usize, mode: usize) -> isize {
+    let ret: isize;
+    // SAFETY: Caller guarantees path is a valid NUL-terminated string.
+    unsafe {
+        core::arch::asm!(
+            "syscall",
+            inlateout("rax") 2_isize => ret,
+            in("rdi") path,
+            in("rsi") flags,
+            in("rdx") mode,
+            lateout("rcx") _,
+            lateout("r11") _,
+            options(nostack),
+        );
+    }
+    ret
+}
+
+#[cfg(all(target_os = "linux", target_arch = "aarch64"))]
+#[inline(always)]
+pub unsafe fn sys_open(path: *const u8, flags: usize, mode: usize) -> isize {
+    // aarch64 has no open(2); openat relative to the cwd is equivalent.
+    const AT_FDCWD: isize = -100;
+    let ret: isize;
+    // SAFETY: Caller guarantees path is a valid NUL-terminated string.
+    unsafe {
+        core::arch::asm!(
+            "svc #0",
+            in("x8") 56_usize,
+            inlateout("x0") AT_FDCWD => ret,
+            in("x1") path,
+            in("x2") flags,
+            in("x3") mode,
+            options(nostack),
+        );
+    }
+    ret
+}
+
+#[cfg(all(target_os = "linux", target_arch = "riscv64"))]
+#[inline(always)]
+pub unsafe fn sys_open(path: *const u8, flags: usize, mode: usize) -> isize {
+    // riscv64 has no open(2) either.
+    const AT_FDCWD: isize = -100;
+    let ret: isize;
+    // SAFETY: Caller guarantees path is a valid NUL-terminated string.
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            in("a7") 56_usize,
+            inlateout("a0") AT_FDCWD => ret,
+            in("a1") path,
+            in("a2") flags,
+            in("a3") mode,
+            options(nostack),
+        );
+    }
+    ret
+}
+
+/// Close a file descriptor.
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+#[inline(always)]
+pub unsafe fn sys_close(fd: usize) -> isize {
+    let ret: isize;
+    // SAFETY: Closing an fd has no memory-safety requirements; the caller
+    // must own fd.
+    unsafe {
+        core::arch::asm!(
+            "syscall",
+            inlateout("rax") 3_isize => ret,
+            in("rdi") fd,
+            lateout("rcx") _,
+            lateout("r11") _,
+            options(nostack),
+        );
+    }
+    ret
+}
+
+#[cfg(all(target_os = "linux", target_arch = "aarch64"))]
+#[inline(always)]
+pub unsafe fn sys_close(fd: usize) -> isize {
+    let ret: isize;
+    // SAFETY: Closing an fd has no memory-safety requirements; the caller
+    // must own fd.
+    unsafe {
+        core::arch::asm!(
+            "svc #0",
+            in("x8") 57_usize,
+            inlateout("x0") fd as isize => ret,
+            options(nostack),
+        );
+    }
+    ret
+}
+
+#[cfg(all(target_os = "linux", target_arch = "riscv64"))]
+#[inline(always)]
+pub unsafe fn sys_close(fd: usize) -> isize {
+    let ret: isize;
+    // SAFETY: Closing an fd has no memory-safety requirements; the caller
+    // must own fd.
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            in("a7") 57_usize,
+            inlateout("a0") fd as isize => ret,
+            options(nostack),
         );
     }
+    ret
+}
+
+/// Terminal attributes request: succeeds only when the fd is a terminal.
+#[cfg(not(any(target_os = "macos", target_os = "freebsd")))]
+pub const TCGETS: usize = 0x5401;
+#[cfg(target_os = "macos")]
+pub const TCGETS: usize = 0x4048_7413;
+#[cfg(target_os = "freebsd")]
+pub const TCGETS: usize = 0x402c_7413;
+
+/// Device control. Returns the request's result or a negative errno.
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+#[inline(always)]
+pub unsafe fn sys_ioctl(fd: usize, request: usize, arg: *mut u8) -> isize {
+    let ret: isize;
+    // SAFETY: Caller guarantees arg is valid for what request writes.
+    unsafe {
+        core::arch::asm!(
+            "syscall",
+            inlateout("rax") 16_isize => ret,
+            in("rdi") fd,
+            in("rsi") request,
+            in("rdx") arg,
+            lateout("rcx") _,
+            lateout("r11") _,
+            options(nostack),
+        );
+    }
+    ret
+}
+
+#[cfg(all(target_os = "linux", target_arch = "aarch64"))]
+#[inline(always)]
+pub unsafe fn sys_ioctl(fd: usize, request: usize, arg: *mut u8) -> isize {
+    let ret: isize;
+    // SAFETY: Caller guarantees arg is valid for what request writes.
+    unsafe {
+        core::arch::asm!(
+            "svc #0",
+            in("x8") 29_usize,
+            inlateout("x0") fd as isize => ret,
+            in("x1") request,
+            in("x2") arg,
+            options(nostack),
+        );
+    }
+    ret
+}
+
+#[cfg(all(target_os = "linux", target_arch = "riscv64"))]
+#[inline(always)]
+pub unsafe fn sys_ioctl(fd: usize, request: usize, arg: *mut u8) -> isize {
+    let ret: isize;
+    // SAFETY: Caller guarantees arg is valid for what request writes.
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            in("a7") 29_usize,
+            inlateout("a0") fd as isize => ret,
+            in("a1") request,
+            in("a2") arg,
+            options(nostack),
+        );
+    }
+    ret
+}
+
+/// `getrandom` flag: fail with EAGAIN instead of blocking before the
+/// kernel's entropy pool is initialized.
+pub const GRND_NONBLOCK: usize = 1;
+
+/// Fill `buf` with `len` random bytes from the kernel. Returns the number
+/// of bytes written or a negative errno.
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+#[inline(always)]
+pub unsafe fn sys_getrandom(buf: *mut u8, len: usize, flags: usize) -> isize {
+    let ret: isize;
+    // SAFETY: Caller guarantees buf points to len writable bytes.
+    unsafe {
+        core::arch::asm!(
+            "syscall",
+            inlateout("rax") 318_isize => ret,
+            in("rdi") buf,
+            in("rsi") len,
+            in("rdx") flags,
+            lateout("rcx") _,
+            lateout("r11") _,
+            options(nostack),
+        );
+    }
+    ret
+}
+
+#[cfg(all(target_os = "linux", target_arch = "aarch64"))]
+#[inline(always)]
+pub unsafe fn sys_getrandom(buf: *mut u8, len: usize, flags: usize) -> isize {
+    let ret: isize;
+    // SAFETY: Caller guarantees buf points to len writable bytes.
+    unsafe {
+        core::arch::asm!(
+            "svc #0",
+            in("x8") 278_usize,
+            inlateout("x0") buf as isize => ret,
+            in("x1") len,
+            in("x2") flags,
+            options(nostack),
+        );
+    }
+    ret
+}
+
+#[cfg(all(target_os = "linux", target_arch = "riscv64"))]
+#[inline(always)]
+pub unsafe fn sys_getrandom(buf: *mut u8, len: usize, flags: usize) -> isize {
+    let ret: isize;
+    // SAFETY: Caller guarantees buf points to len writable bytes.
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            in("a7") 278_usize,
+            inlateout("a0") buf as isize => ret,
+            in("a1") len,
+            in("a2") flags,
+            options(nostack),
+        );
+    }
+    ret
+}
+
+/// Kernel thread id of the calling thread.
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+#[inline(always)]
+pub unsafe fn sys_gettid() -> usize {
+    let ret: usize;
+    // SAFETY: gettid takes no arguments and cannot fail.
+    unsafe {
+        core::arch::asm!(
+            "syscall",
+            inlateout("rax") 186_usize => ret,
+            lateout("rcx") _,
+            lateout("r11") _,
+            options(nostack),
+        );
+    }
+    ret
+}
+
+#[cfg(all(target_os = "linux", target_arch = "aarch64"))]
+#[inline(always)]
+pub unsafe fn sys_gettid() -> usize {
+    let ret: usize;
+    // SAFETY: gettid takes no arguments and cannot fail.
+    unsafe {
+        core::arch::asm!(
+            "svc #0",
+            in("x8") 178_usize,
+            lateout("x0") ret,
+            options(nostack),
+        );
+    }
+    ret
+}
+
+#[cfg(all(target_os = "linux", target_arch = "riscv64"))]
+#[inline(always)]
+pub unsafe fn sys_gettid() -> usize {
+    let ret: usize;
+    // SAFETY: gettid takes no arguments and cannot fail.
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            in("a7") 178_usize,
+            lateout("a0") ret,
+            options(nostack),
+        );
+    }
+    ret
+}
+
+/// Process id of the caller.
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+#[inline(always)]
+pub unsafe fn sys_getpid() -> usize {
+    let ret: usize;
+    // SAFETY: getpid takes no arguments and cannot fail.
+    unsafe {
+        core::arch::asm!(
+            "syscall",
+            inlateout("rax") 39_usize => ret,
+            lateout("rcx") _,
+            lateout("r11") _,
+            options(nostack),
+        );
+    }
+    ret
+}
+
+#[cfg(all(target_os = "linux", target_arch = "aarch64"))]
+#[inline(always)]
+pub unsafe fn sys_getpid() -> usize {
+    let ret: usize;
+    // SAFETY: getpid takes no arguments and cannot fail.
+    unsafe {
+        core::arch::asm!(
+            "svc #0",
+            in("x8") 172_usize,
+            lateout("x0") ret,
+            options(nostack),
+        );
+    }
+    ret
 }
+
+#[cfg(all(target_os = "linux", target_arch = "riscv64"))]
+#[inline(always)]
+pub unsafe fn sys_getpid() -> usize {
+    let ret: usize;
+    // SAFETY: getpid takes no arguments and cannot fail.
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            in("a7") 172_usize,
+            lateout("a0") ret,
+            options(nostack),
+        );
+    }
+    ret
+}
+
+/// Give up the CPU to another runnable thread.
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+#[inline(always)]
+pub unsafe fn sys_sched_yield() -> isize {
+    let ret: isize;
+    // SAFETY: sched_yield takes no arguments and always succeeds.
+    unsafe {
+        core::arch::asm!(
+            "syscall",
+            inlateout("rax") 24_isize => ret,
+            lateout("rcx") _,
+            lateout("r11") _,
+            options(nostack),
+        );
+    }
+    ret
+}
+
+#[cfg(all(target_os = "linux", target_arch = "aarch64"))]
+#[inline(always)]
+pub unsafe fn sys_sched_yield() -> isize {
+    let ret: isize;
+    // SAFETY: sched_yield takes no arguments and always succeeds.
+    unsafe {
+        core::arch::asm!(
+            "svc #0",
+            in("x8") 124_usize,
+            lateout("x0") ret,
+            options(nostack),
+        );
+    }
+    ret
+}
+
+#[cfg(all(target_os = "linux", target_arch = "riscv64"))]
+#[inline(always)]
+pub unsafe fn sys_sched_yield() -> isize {
+    let ret: isize;
+    // SAFETY: sched_yield takes no arguments and always succeeds.
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            in("a7") 124_usize,
+            lateout("a0") ret,
+            options(nostack),
+        );
+    }
+    ret
+}
+
+/// Kernel `struct timespec`.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct Timespec {
+    pub tv_sec: i64,
+    pub tv_nsec: i64,
+}
+
+#[cfg(not(any(target_os = "macos", target_os = "freebsd")))]
+pub const CLOCK_MONOTONIC: usize = 1;
+#[cfg(target_os = "macos")]
+pub const CLOCK_MONOTONIC: usize = 6;
+#[cfg(target_os = "freebsd")]
+pub const CLOCK_MONOTONIC: usize = 4;
+
+/// Read a clock. Returns 0 on success or a negative errno.
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+#[inline(always)]
+pub unsafe fn sys_clock_gettime(clock: usize, ts: *mut Timespec) -> isize {
+    let ret: isize;
+    // SAFETY: Caller guarantees ts points to a writable Timespec.
+    unsafe {
+        core::arch::asm!(
+            "syscall",
+            inlateout("rax") 228_isize => ret,
+            in("rdi") clock,
+            in("rsi") ts,
+            lateout("rcx") _,
+            lateout("r11") _,
+            options(nostack),
+        );
+    }
+    ret
+}
+
+#[cfg(all(target_os = "linux", target_arch = "aarch64"))]
+#[inline(always)]
+pub unsafe fn sys_clock_gettime(clock: usize, ts: *mut Timespec) -> isize {
+    let ret: isize;
+    // SAFETY: Caller guarantees ts points to a writable Timespec.
+    unsafe {
+        core::arch::asm!(
+            "svc #0",
+            in("x8") 113_usize,
+            inlateout("x0") clock as isize => ret,
+            in("x1") ts,
+            options(nostack),
+        );
+    }
+    ret
+}
+
+#[cfg(all(target_os = "linux", target_arch = "riscv64"))]
+#[inline(always)]
+pub unsafe fn sys_clock_gettime(clock: usize, ts: *mut Timespec) -> isize {
+    let ret: isize;
+    // SAFETY: Caller guarantees ts points to a writable Timespec.
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            in("a7") 113_usize,
+            inlateout("a0") clock as isize => ret,
+            in("a1") ts,
+            options(nostack),
+        );
+    }
+    ret
+}
+
+/// Terminate the calling process and all its threads.
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+#[inline(always)]
+pub unsafe fn sys_exit_group(code: i32) -> ! {
+    // SAFETY: This terminates the process. Caller is responsible for
+    // ensuring this is the intended behavior.
+    unsafe {
+        core::arch::asm!(
+            "syscall",
+            in("rax") 231_usize,
+            in("rdi") code as usize,
+            options(noreturn, nostack),
+        );
+    }
+}
+
+#[cfg(all(target_os = "linux", target_arch = "aarch64"))]
+#[inline(always)]
+pub unsafe fn sys_exit_group(code: i32) -> ! {
+    // SAFETY: This terminates the process. Caller is responsible for
+    // ensuring this is the intended behavior.
+    unsafe {
+        core::arch::asm!(
+            "svc #0",
+            in("x8") 94_usize,
+            in("x0") code as usize,
+            options(noreturn, nostack),
+        );
+    }
+}
+
+#[cfg(all(target_os = "linux", target_arch = "riscv64"))]
+#[inline(always)]
+pub unsafe fn sys_exit_group(code: i32) -> ! {
+    // SAFETY: This terminates the process. Caller is responsible for
+    // ensuring this is the intended behavior.
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            in("a7") 94_usize,
+            in("a0") code as usize,
+            options(noreturn, nostack),
+        );
+    }
+}
+
+pub const PROT_NONE: usize = 0x0;
+pub const PROT_READ: usize = 0x1;
+pub const PROT_WRITE: usize = 0x2;
+pub const MAP_PRIVATE: usize = 0x02;
+pub const MAP_ANONYMOUS: usize = 0x20;
+
+/// Map `len` bytes. Returns the mapping address, or a negative errno.
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+#[inline(always)]
+pub unsafe fn sys_mmap(
+    addr: usize,
+    len: usize,
+    prot: usize,
+    flags: usize,
+    fd: usize,
+    offset: usize,
+) -> isize {
+    let ret: isize;
+    // SAFETY: Caller guarantees the arguments describe a valid mapping.
+    unsafe {
+        core::arch::asm!(
+            "syscall",
+            inlateout("rax") 9_isize => ret,
+            in("rdi") addr,
+            in("rsi") len,
+            in("rdx") prot,
+            in("r10") flags,
+            in("r8") fd,
+            in("r9") offset,
+            lateout("rcx") _,
+            lateout("r11") _,
+            options(nostack),
+        );
+    }
+    ret
+}
+
+#[cfg(all(target_os = "linux", target_arch = "aarch64"))]
+#[inline(always)]
+pub unsafe fn sys_mmap(
+    addr: usize,
+    len: usize,
+    prot: usize,
+    flags: usize,
+    fd: usize,
+    offset: usize,
+) -> isize {
+    let ret: isize;
+    // SAFETY: Caller guarantees the arguments describe a valid mapping.
+    unsafe {
+        core::arch::asm!(
+            "svc #0",
+            in("x8") 222_usize,
+            inlateout("x0") addr as isize => ret,
+            in("x1") len,
+            in("x2") prot,
+            in("x3") flags,
+            in("x4") fd,
+            in("x5") offset,
+            options(nostack),
+        );
+    }
+    ret
+}
+
+#[cfg(all(target_os = "linux", target_arch = "riscv64"))]
+#[inline(always)]
+pub unsafe fn sys_mmap(
+    addr: usize,
+    len: usize,
+    prot: usize,
+    flags: usize,
+    fd: usize,
+    offset: usize,
+) -> isize {
+    let ret: isize;
+    // SAFETY: Caller guarantees the arguments describe a valid mapping.
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            in("a7") 222_usize,
+            inlateout("a0") addr as isize => ret,
+            in("a1") len,
+            in("a2") prot,
+            in("a3") flags,
+            in("a4") fd,
+            in("a5") offset,
+            options(nostack),
+        );
+    }
+    ret
+}
+
+/// Change the protection of the pages in `[addr, addr + len)`.
+/// Returns 0 on success, or a negative errno.
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+#[inline(always)]
+pub unsafe fn sys_mprotect(addr: usize, len: usize, prot: usize) -> isize {
+    let ret: isize;
+    // SAFETY: Caller guarantees the range belongs to one of its mappings.
+    unsafe {
+        core::arch::asm!(
+            "syscall",
+            inlateout("rax") 10_isize => ret,
+            in("rdi") addr,
+            in("rsi") len,
+            in("rdx") prot,
+            lateout("rcx") _,
+            lateout("r11") _,
+            options(nostack),
+        );
+    }
+    ret
+}
+
+#[cfg(all(target_os = "linux", target_arch = "aarch64"))]
+#[inline(always)]
+pub unsafe fn sys_mprotect(addr: usize, len: usize, prot: usize) -> isize {
+    let ret: isize;
+    // SAFETY: Caller guarantees the range belongs to one of its mappings.
+    unsafe {
+        core::arch::asm!(
+            "svc #0",
+            in("x8") 226_usize,
+            inlateout("x0") addr as isize => ret,
+            in("x1") len,
+            in("x2") prot,
+            options(nostack),
+        );
+    }
+    ret
+}
+
+#[cfg(all(target_os = "linux", target_arch = "riscv64"))]
+#[inline(always)]
+pub unsafe fn sys_mprotect(addr: usize, len: usize, prot: usize) -> isize {
+    let ret: isize;
+    // SAFETY: Caller guarantees the range belongs to one of its mappings.
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            in("a7") 226_usize,
+            inlateout("a0") addr as isize => ret,
+            in("a1") len,
+            in("a2") prot,
+            options(nostack),
+        );
+    }
+    ret
+}
+
+/// Unmap the pages in `[addr, addr + len)`.
+/// Returns 0 on success, or a negative errno.
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+#[inline(always)]
+pub unsafe fn sys_munmap(addr: usize, len: usize) -> isize {
+    let ret: isize;
+    // SAFETY: Caller guarantees nothing references the range any more.
+    unsafe {
+        core::arch::asm!(
+            "syscall",
+            inlateout("rax") 11_isize => ret,
+            in("rdi") addr,
+            in("rsi") len,
+            lateout("rcx") _,
+            lateout("r11") _,
+            options(nostack),
+        );
+    }
+    ret
+}
+
+#[cfg(all(target_os = "linux", target_arch = "aarch64"))]
+#[inline(always)]
+pub unsafe fn sys_munmap(addr: usize, len: usize) -> isize {
+    let ret: isize;
+    // SAFETY: Caller guarantees nothing references the range any more.
+    unsafe {
+        core::arch::asm!(
+            "svc #0",
+            in("x8") 215_usize,
+            inlateout("x0") addr as isize => ret,
+            in("x1") len,
+            options(nostack),
+        );
+    }
+    ret
+}
+
+#[cfg(all(target_os = "linux", target_arch = "riscv64"))]
+#[inline(always)]
+pub unsafe fn sys_munmap(addr: usize, len: usize) -> isize {
+    let ret: isize;
+    // SAFETY: Caller guarantees nothing references the range any more.
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            in("a7") 215_usize,
+            inlateout("a0") addr as isize => ret,
+            in("a1") len,
+            options(nostack),
+        );
+    }
+    ret
+}
+
+/// Kernel `struct sigaction` as taken by `rt_sigaction` on x86_64 and aarch64.
+#[cfg(not(target_arch = "riscv64"))]
+#[repr(C)]
+pub struct KernelSigaction {
+    pub handler: usize,
+    pub flags: u64,
+    pub restorer: usize,
+    pub mask: u64,
+}
+
+/// riscv64 has no `sa_restorer`; signals return through the vDSO. The field
+/// is kept after the ones the kernel reads so callers stay the same.
+#[cfg(target_arch = "riscv64")]
+#[repr(C)]
+pub struct KernelSigaction {
+    pub handler: usize,
+    pub flags: u64,
+    pub mask: u64,
+    pub restorer: usize,
+}
+
+pub const SIGSEGV: usize = 11;
+pub const SA_SIGINFO: u64 = 0x4;
+#[cfg(not(target_arch = "riscv64"))]
+pub const SA_RESTORER: u64 = 0x0400_0000;
+#[cfg(target_arch = "riscv64")]
+pub const SA_RESTORER: u64 = 0;
+pub const SA_RESETHAND: u64 = 0x8000_0000;
+
+/// Install a signal action. `old` may be null.
+/// Returns 0 on success, or a negative errno.
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+#[inline(always)]
+pub unsafe fn sys_rt_sigaction(
+    sig: usize,
+    act: *const KernelSigaction,
+    old: *mut KernelSigaction,
+) -> isize {
+    let ret: isize;
+    // SAFETY: Caller guarantees act is valid and old is null or writable.
+    unsafe {
+        core::arch::asm!(
+            "syscall",
+            inlateout("rax") 13_isize => ret,
+            in("rdi") sig,
+            in("rsi") act,
+            in("rdx") old,
+            in("r10") core::mem::size_of::<u64>(),
+            lateout("rcx") _,
+            lateout("r11") _,
+            options(nostack),
+        );
+    }
+    ret
+}
+
+#[cfg(all(target_os = "linux", target_arch = "aarch64"))]
+#[inline(always)]
+pub unsafe fn sys_rt_sigaction(
+    sig: usize,
+    act: *const KernelSigaction,
+    old: *mut KernelSigaction,
+) -> isize {
+    let ret: isize;
+    // SAFETY: Caller guarantees act is valid and old is null or writable.
+    unsafe {
+        core::arch::asm!(
+            "svc #0",
+            in("x8") 134_usize,
+            inlateout("x0") sig as isize => ret,
+            in("x1") act,
+            in("x2") old,
+            in("x3") core::mem::size_of::<u64>(),
+            options(nostack),
+        );
+    }
+    ret
+}
+
+#[cfg(all(target_os = "linux", target_arch = "riscv64"))]
+#[inline(always)]
+pub unsafe fn sys_rt_sigaction(
+    sig: usize,
+    act: *const KernelSigaction,
+    old: *mut KernelSigaction,
+) -> isize {
+    let ret: isize;
+    // SAFETY: Caller guarantees act is valid and old is null or writable.
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            in("a7") 134_usize,
+            inlateout("a0") sig as isize => ret,
+            in("a1") act,
+            in("a2") old,
+            in("a3") core::mem::size_of::<u64>(),
+            options(nostack),
+        );
+    }
+    ret
+}
+
+// Signal return trampoline for SA_RESTORER: the kernel refuses to deliver
+// a handled signal on x86_64 without one, and aarch64 only falls back to
+// the vDSO's. riscv64 never uses its copy.
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+core::arch::global_asm!(
+    ".pushsection .text.libcplusplus_sigreturn,\"ax\",@progbits",
+    ".globl libcplusplus_sigreturn",
+    ".hidden libcplusplus_sigreturn",
+    "libcplusplus_sigreturn:",
+    "mov rax, 15",
+    "syscall",
+    ".popsection",
+);
+
+#[cfg(all(target_os = "linux", target_arch = "aarch64"))]
+core::arch::global_asm!(
+    ".pushsection .text.libcplusplus_sigreturn,\"ax\",%progbits",
+    ".globl libcplusplus_sigreturn",
+    ".hidden libcplusplus_sigreturn",
+    "libcplusplus_sigreturn:",
+    "mov x8, #139",
+    "svc #0",
+    ".popsection",
+);
+
+#[cfg(all(target_os = "linux", target_arch = "riscv64"))]
+core::arch::global_asm!(
+    ".pushsection .text.libcplusplus_sigreturn,\"ax\",%progbits",
+    ".globl libcplusplus_sigreturn",
+    ".hidden libcplusplus_sigreturn",
+    "libcplusplus_sigreturn:",
+    "li a7, 139",
+    "ecall",
+    ".popsection",
+);
+
+#[cfg(all(
+    target_os = "linux",
+    any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    )
+))]
+unsafe extern "C" {
+    /// Calls `rt_sigreturn`; only ever entered by the kernel.
+    pub fn libcplusplus_sigreturn();
+}
+
+/// C library stand-ins for targets without raw syscall support above.
+///
+/// Failures are reported as `-1` rather than a negative errno. Facilities
+/// the sanitizer only needs on Linux (guard pages, the SIGSEGV handler)
+/// always fail here, as do `/proc` reads since the files do not exist.
+#[cfg(not(all(
+    target_os = "linux",
+    any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    )
+)))]
+mod fallback {
+    use super::{KernelSigaction, Timespec};
+
+    unsafe extern "C" {
+        fn write(fd: i32, buf: *const u8, len: usize) -> isize;
+        fn read(fd: i32, buf: *mut u8, len: usize) -> isize;
+        fn open(path: *const u8, flags: i32, ...) -> i32;
+        fn close(fd: i32) -> i32;
+        fn ioctl(fd: i32, request: core::ffi::c_ulong, ...) -> i32;
+        fn getentropy(buf: *mut u8, len: usize) -> i32;
+        fn pthread_self() -> usize;
+        fn getpid() -> i32;
+        fn sched_yield() -> i32;
+        fn clock_gettime(clock: i32, ts: *mut Timespec) -> i32;
+        fn _exit(code: i32) -> !;
+    }
+
+    #[inline(always)]
+    pub unsafe fn sys_write(fd: usize, buf: *const u8, len: usize) -> isize {
+        // SAFETY: Caller guarantees buf points to len readable bytes.
+        unsafe { write(fd as i32, buf, len) }
+    }
+
+    #[inline(always)]
+    pub unsafe fn sys_read(fd: usize, buf: *mut u8, len: usize) -> isize {
+        // SAFETY: Caller guarantees buf points to len writable bytes.
+        unsafe { read(fd as i32, buf, len) }
+    }
+
+    /// `flags` are passed through unchanged, so Linux flag values may mean
+    /// something else here.
+    #[inline(always)]
+    pub unsafe fn sys_open(path: *const u8, flags: usize, mode: usize) -> isize {
+        // SAFETY: Caller guarantees path is a valid NUL-terminated string.
+        unsafe { open(path, flags as i32, mode as u32) as isize }
+    }
+
+    #[inline(always)]
+    pub unsafe fn sys_close(fd: usize) -> isize {
+        // SAFETY: The caller must own fd.
+        unsafe { close(fd as i32) as isize }
+    }
+
+    #[inline(always)]
+    pub unsafe fn sys_ioctl(fd: usize, request: usize, arg: *mut u8) -> isize {
+        // SAFETY: Caller guarantees arg is valid for what request writes.
+        unsafe { ioctl(fd as i32, request as core::ffi::c_ulong, arg) as isize }
+    }
+
+    /// `getentropy` fills at most 256 bytes per call; `flags` are ignored.
+    #[inline(always)]
+    pub unsafe fn sys_getrandom(buf: *mut u8, len: usize, _flags: usize) -> isize {
+        let len = len.min(256);
+        // SAFETY: Caller guarantees buf points to len writable bytes.
+        if unsafe { getentropy(buf, len) } == 0 {
+            len as isize
+        } else {
+            -1
+        }
+    }
+
+    /// Not a kernel thread id, but non-zero and unique among live threads,
+    /// which is all callers rely on.
+    #[inline(always)]
+    pub unsafe fn sys_gettid() -> usize {
+        // SAFETY: pthread_self has no preconditions.
+        unsafe { pthread_self() }
+    }
+
+    #[inline(always)]
+    pub unsafe fn sys_getpid() -> usize {
+        // SAFETY: getpid has no preconditions.
+        unsafe { getpid() as usize }
+    }
+
+    #[inline(always)]
+    pub unsafe fn sys_sched_yield() -> isize {
+        // SAFETY: sched_yield has no preconditions.
+        unsafe { sched_yield() as isize }
+    }
+
+    #[inline(always)]
+    pub unsafe fn sys_clock_gettime(clock: usize, ts: *mut Timespec) -> isize {
+        // SAFETY: Caller guarantees ts points to a writable Timespec.
+        unsafe { clock_gettime(clock as i32, ts) as isize }
+    }
+
+    #[inline(always)]
+    pub unsafe fn sys_exit_group(code: i32) -> ! {
+        // SAFETY: This terminates the process.
+        unsafe { _exit(code) }
+    }
+
+    #[inline(always)]
+    pub unsafe fn sys_mmap(
+        _addr: usize,
+        _len: usize,
+        _prot: usize,
+        _flags: usize,
+        _fd: usize,
+        _offset: usize,
+    ) -> isize {
+        -1
+    }
+
+    #[inline(always)]
+    pub unsafe fn sys_mprotect(_addr: usize, _len: usize, _prot: usize) -> isize {
+        -1
+    }
+
+    #[inline(always)]
+    pub unsafe fn sys_munmap(_addr: usize, _len: usize) -> isize {
+        -1
+    }
+
+    #[inline(always)]
+    pub unsafe fn sys_rt_sigaction(
+        _sig: usize,
+        _act: *const KernelSigaction,
+        _old: *mut KernelSigaction,
+    ) -> isize {
+        -1
+    }
+
+    /// Never installed, since `sys_rt_sigaction` always fails.
+    pub extern "C" fn libcplusplus_sigreturn() {}
+}
+
+#[cfg(not(all(
+    target_os = "linux",
+    any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    )
+)))]
+pub use fallback::*;
@@ -0,0 +1,174 @@
+//! Observer hooks invoked on every sanitized allocation and free.
+//!
+//! Up to `MAX_HOOKS` independent tools (profilers, tracers) can register
+//! at once; hooks run in registration-slot order, outside all sanitizer
+//! locks. A hook that itself allocates does not recurse: nested events on
+//! the same thread are not reported.
+
+use crate::platform::cache::CacheAligned;
+use crate::sanitize::tracker::AllocKind;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+const MAX_HOOKS: usize = 8;
+
+/// Maximum number of threads that can be inside a hook simultaneously.
+/// Events from further threads are dropped rather than blocking.
+const MAX_ACTIVE: usize = 16;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum AllocEvent {
+    Alloc,
+    Free,
+}
+
+/// `fn(event, user_addr, user_size, kind)`.
+pub type AllocHook = fn(AllocEvent, usize, usize, AllocKind);
+
+/// Registered hooks stored as function-pointer addresses; 0 is empty.
+static HOOKS: [AtomicUsize; MAX_HOOKS] = [const { AtomicUsize::new(0) }; MAX_HOOKS];
+static HOOK_COUNT: CacheAligned<AtomicUsize> = CacheAligned::new(AtomicUsize::new(0));
+
+/// Thread ids currently running hooks; 0 is empty.
+static ACTIVE: [AtomicUsize; MAX_ACTIVE] = [const { AtomicUsize::new(0) }; MAX_ACTIVE];
+
+/// Register a hook. Returns `false` if all slots are taken.
+pub fn add(hook: AllocHook) -> bool {
+    for slot in &HOOKS {
+        if slot
+            .compare_exchange(0, hook as usize, Ordering::AcqRel, Ordering::Relaxed)
+            .is_ok()
+        {
+            HOOK_COUNT.fetch_add(1, Ordering::Release);
+            return true;
+        }
+    }
+    false
+}
+
+/// Unregister a previously added hook. Returns `false` if it was not registered.
+pub fn remove(hook: AllocHook) -> bool {
+    for slot in &HOOKS {
+        if slot
+            .compare_exchange(hook as usize, 0, Ordering::AcqRel, Ordering::Relaxed)
+            .is_ok()
+        {
+            HOOK_COUNT.fetch_sub(1, Ordering::Release);
+            return true;
+        }
+    }
+    false
+}
+
+/// Invoke every registered hook for one event.
+pub fn notify(event: AllocEvent, addr: usize, size: usize, kind: AllocKind) {
+    if HOOK_COUNT.load(Ordering::Acquire) == 0 {
+        return;
+    }
+
+    // SAFETY: gettid has no preconditions.
+    let tid = unsafe { crate::platform::syscall::sys_gettid() };
+    if ACTIVE.iter().any(|t| t.load(Ordering::Acquire) == tid) {
+        // Re-entered from inside one of our own hooks.
+        return;
+    }
+    let Some(claimed) = ACTIVE.iter().find(|t| {
+        t.compare_exchange(0, tid, Ordering::AcqRel, Ordering::Relaxed)
+            .is_ok()
+    }) else {
+        return;
+    };
+
+    for slot in &HOOKS {
+        let raw = slot.load(Ordering::Acquire);
+        if raw != 0 {
+            // SAFETY: Non-zero slots only ever hold values stored from an
+            // `AllocHook` in `add`.
+            let hook: AllocHook = unsafe { core::mem::transmute::<usize, AllocHook>(raw) };
+            hook(event, addr, size, kind);
+        }
+    }
+
+    claimed.store(0, Ordering::Release);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use core::alloc::Layout;
+
+    /// Size of the block each round allocates, and of the one `second`
+    /// allocates from inside the hook.
+    const WATCHED: usize = 333;
+    const NESTED: usize = 77;
+
+    /// Events each hook saw of `WATCHED`-sized blocks, as
+    /// `allocs << 32 | frees`, and nested events either saw at all.
+    static FIRST: AtomicUsize = AtomicUsize::new(0);
+    static SECOND: AtomicUsize = AtomicUsize::new(0);
+    static NESTED_SEEN: AtomicUsize = AtomicUsize::new(0);
+
+    fn count(seen: &AtomicUsize, event: AllocEvent, size: usize) {
+        match (event, size) {
+            (AllocEvent::Alloc, WATCHED) => seen.fetch_add(1 << 32, Ordering::Relaxed),
+            (AllocEvent::Free, WATCHED) => seen.fetch_add(1, Ordering::Relaxed),
+            (_, NESTED) => NESTED_SEEN.fetch_add(1, Ordering::Relaxed),
+            _ => 0,
+        };
+    }
+
+    fn first(event: AllocEvent, _addr: usize, size: usize, _kind: AllocKind) {
+        count(&FIRST, event, size);
+    }
+
+    /// Also allocates, which must not call the hooks again.
+    fn second(event: AllocEvent, _addr: usize, size: usize, _kind: AllocKind) {
+        count(&SECOND, event, size);
+        let layout = Layout::from_size_align(NESTED, 8).unwrap();
+        // SAFETY: Freed straight away.
+        unsafe {
+            let ptr = crate::sanitize::sanitized_alloc(layout);
+            crate::sanitize::sanitized_dealloc(ptr, layout);
+        }
+    }
+
+    /// Allocate and free one block, returning what each hook saw of it.
+    fn round() -> (usize, usize) {
+        FIRST.store(0, Ordering::Relaxed);
+        SECOND.store(0, Ordering::Relaxed);
+        let layout = Layout::from_size_align(WATCHED, 8).unwrap();
+        // SAFETY: Freed once, with its own layout.
+        unsafe {
+            let ptr = crate::sanitize::sanitized_alloc(layout);
+            crate::sanitize::sanitized_dealloc(ptr, layout);
+        }
+        (
+            FIRST.load(Ordering::Relaxed),
+            SECOND.load(Ordering::Relaxed),
+        )
+    }
+
+    #[test]
+    fn every_registered_hook_sees_each_event() {
+        const TEST: &str = "sanitize::alloc_hook::tests::every_registered_hook_sees_each_event";
+        if testing::in_child(TEST) {
+            const BOTH: usize = 1 << 32 | 1;
+            assert!(add(first));
+            assert!(add(second));
+            assert_eq!(round(), (BOTH, BOTH));
+            assert!(remove(first));
+            assert!(!remove(first));
+            assert_eq!(round(), (0, BOTH));
+            assert!(remove(second));
+            assert_eq!(round(), (0, 0));
+            assert_eq!(NESTED_SEEN.load(Ordering::Relaxed), 0);
+            for _ in 0..MAX_HOOKS {
+                assert!(add(first));
+            }
+            assert!(!add(second));
+            return;
+        }
+        let child = testing::spawn(TEST, &[]);
+        assert_eq!(child.code(), Some(0), "{}", child.stderr());
+    }
+}
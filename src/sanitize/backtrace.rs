@@ -0,0 +1,165 @@
+//! Allocation-site backtraces by walking the frame-pointer chain.
+//!
+//! Only meaningful when the program (and this crate) are built with frame
+//! pointers, e.g. `-fno-omit-frame-pointer` / `-C force-frame-pointers=yes`.
+//! Without the `backtrace` feature `Frames` and `Tid` are zero-sized and
+//! capturing is a no-op, so the tracker and quarantine pay nothing for it.
+
+/// Return addresses recorded per allocation.
+#[cfg(feature = "backtrace")]
+pub const DEPTH: usize = 8;
+
+/// Walk at most this far above the starting stack pointer. A frame pointer
+/// outside this window is taken to be a register reused for something else.
+#[cfg(feature = "backtrace")]
+const STACK_WINDOW: usize = 8 << 20;
+
+/// Kernel id of the thread that allocated or freed a block.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Tid {
+    #[cfg(feature = "backtrace")]
+    id: usize,
+}
+
+impl Tid {
+    pub const NONE: Self = Self {
+        #[cfg(feature = "backtrace")]
+        id: 0,
+    };
+
+    /// The calling thread. Safe to call from a signal handler.
+    pub fn current() -> Self {
+        Self {
+            // SAFETY: gettid has no preconditions.
+            #[cfg(feature = "backtrace")]
+            id: unsafe { crate::platform::syscall::sys_gettid() },
+        }
+    }
+
+    pub fn get(self) -> Option<usize> {
+        #[cfg(feature = "backtrace")]
+        let id = self.id;
+        #[cfg(not(feature = "backtrace"))]
+        let id = 0;
+        (id != 0).then_some(id)
+    }
+}
+
+/// Return addresses of an allocation site, innermost first, and the
+/// thread it ran on.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Frames {
+    #[cfg(feature = "backtrace")]
+    addrs: [usize; DEPTH],
+    pub tid: Tid,
+}
+
+impl Frames {
+    pub const EMPTY: Self = Self {
+        #[cfg(feature = "backtrace")]
+        addrs: [0; DEPTH],
+        tid: Tid::NONE,
+    };
+
+    /// Record the calling thread and the return addresses above the caller.
+    #[inline(never)]
+    pub fn capture() -> Self {
+        let mut frames = Self::EMPTY;
+        frames.tid = Tid::current();
+        #[cfg(all(feature = "backtrace", target_os = "linux", target_arch = "x86_64"))]
+        {
+            let mut fp: usize;
+            let sp: usize;
+            // SAFETY: Reads two registers, touches no memory.
+            unsafe {
+                core::arch::asm!(
+                    "mov {fp}, rbp",
+                    "mov {sp}, rsp",
+                    fp = out(reg) fp,
+                    sp = out(reg) sp,
+                    options(nomem, nostack, preserves_flags),
+                );
+            }
+            let mut i = 0;
+            while i < DEPTH && fp >= sp && fp - sp < STACK_WINDOW && fp.is_multiple_of(8) {
+                // SAFETY: fp lies on this thread's stack above sp, and with
+                // frame pointers it addresses a saved [next fp, return address] pair.
+                let (next, ret) = unsafe { (*(fp as *const usize), *((fp + 8) as *const usize)) };
+                if ret == 0 {
+                    break;
+                }
+                frames.addrs[i] = ret;
+                i += 1;
+                // Frames grow toward higher addresses as we unwind.
+                if next <= fp {
+                    break;
+                }
+                fp = next;
+            }
+        }
+        frames
+    }
+
+    /// Whether no return address was recorded.
+    pub fn is_empty(&self) -> bool {
+        self.iter().next().is_none()
+    }
+
+    /// FNV-1a over the return addresses. Equal for captures of the same
+    /// call stack, whichever thread made them.
+    pub fn site_hash(&self) -> u64 {
+        self.iter().fold(0xcbf2_9ce4_8422_2325, |h, addr| {
+            (h ^ addr as u64).wrapping_mul(0x0100_0000_01b3)
+        })
+    }
+
+    /// Whether both captures saw the same call stack. Threads may differ.
+    pub fn same_site(&self, other: &Self) -> bool {
+        self.iter().eq(other.iter())
+    }
+
+    /// Recorded return addresses, innermost first.
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        #[cfg(feature = "backtrace")]
+        let addrs: &[usize] = &self.addrs;
+        #[cfg(not(feature = "backtrace"))]
+        let addrs: &[usize] = &[];
+        addrs.iter().copied().take_while(|&a| a != 0)
+    }
+}
+
+/// The dynamic symbol containing return address `addr`, and the offset of
+/// `addr` into it.
+///
+/// Uses `dladdr`, so only exported symbols are found: link the program with
+/// `-rdynamic` for its own functions to show up. The name stays valid while
+/// its module is loaded. Takes the dynamic loader's lock, so not for use
+/// from a signal handler.
+pub fn symbol(addr: usize) -> Option<(&'static [u8], usize)> {
+    #[cfg(feature = "backtrace")]
+    {
+        use crate::platform::{DlInfo, dladdr};
+        let mut info = DlInfo {
+            fname: core::ptr::null(),
+            fbase: core::ptr::null_mut(),
+            sname: core::ptr::null(),
+            saddr: core::ptr::null_mut(),
+        };
+        // Look up the call instruction: after a call to a noreturn function
+        // the return address may already be past the caller's end.
+        // SAFETY: dladdr only reads the loader's tables.
+        if unsafe { dladdr(addr.wrapping_sub(1) as *const u8, &mut info) } == 0
+            || info.sname.is_null()
+        {
+            return None;
+        }
+        // SAFETY: sname is a NUL-terminated string in the module's string table.
+        let name = unsafe { core::ffi::CStr::from_ptr(info.sname.cast()) }.to_bytes();
+        Some((name, addr.wrapping_sub(info.saddr as usize)))
+    }
+    #[cfg(not(feature = "backtrace"))]
+    {
+        let _ = addr;
+        None
+    }
+}
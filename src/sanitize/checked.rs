@@ -0,0 +1,190 @@
+//! Iterator invalidation checks built on `Epoch`.
+//!
+//! A container bumps its epoch on every mutation that can move or drop
+//! elements; a `CheckedIter` captures the epoch when it is created and
+//! compares before each element. On a mismatch it reports and ends the
+//! iteration without touching the (possibly reallocated) storage. An
+//! iterator that already reached the end is not checked again.
+//!
+//! `EpochBuf` is the reference container: a growable buffer whose `push`
+//! takes `&self`, as a C++ container's would from any live reference, so
+//! an iterator can be left dangling the way `std::vector` iterators are.
+
+use crate::sanitize::epoch::Epoch;
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use core::marker::PhantomData;
+
+/// Iterator over `[ptr, end)` that is valid while `epoch` reads `captured`.
+/// Elements are yielded by value, so no reference into the storage
+/// outlives a check.
+pub struct CheckedIter<'a, T: Copy> {
+    ptr: *const T,
+    end: *const T,
+    epoch: &'a Epoch,
+    captured: u64,
+    /// Reported as the container address.
+    owner: usize,
+    _elements: PhantomData<&'a [T]>,
+}
+
+impl<'a, T: Copy> CheckedIter<'a, T> {
+    /// # Safety
+    /// `[ptr, ptr + len)` must stay valid for reads for as long as `epoch`
+    /// keeps its current value, and `epoch` must be bumped before the
+    /// elements are moved or freed.
+    pub unsafe fn new(ptr: *const T, len: usize, epoch: &'a Epoch, owner: usize) -> Self {
+        Self {
+            ptr,
+            // SAFETY: The caller guarantees the range is one allocation.
+            end: unsafe { ptr.add(len) },
+            epoch,
+            captured: epoch.get(),
+            owner,
+            _elements: PhantomData,
+        }
+    }
+}
+
+impl<T: Copy> Iterator for CheckedIter<'_, T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        if self.ptr == self.end {
+            return None;
+        }
+        let current = self.epoch.get();
+        if current != self.captured {
+            crate::sanitize::diagnostic::iterator_invalidated(self.owner, self.captured, current);
+            // Stop here, once, rather than read storage that may be gone.
+            self.ptr = self.end;
+            return None;
+        }
+        // SAFETY: The epoch is unchanged, so the range is still valid.
+        let value = unsafe { self.ptr.read() };
+        // SAFETY: ptr is below end.
+        self.ptr = unsafe { self.ptr.add(1) };
+        Some(value)
+    }
+}
+
+/// Growable buffer that invalidates its iterators on every push.
+///
+/// Not `Sync`: pushes through `&self` are only sound from one thread.
+pub struct EpochBuf<T: Copy> {
+    items: UnsafeCell<Vec<T>>,
+    epoch: Epoch,
+}
+
+impl<T: Copy> Default for EpochBuf<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Copy> EpochBuf<T> {
+    pub const fn new() -> Self {
+        Self {
+            items: UnsafeCell::new(Vec::new()),
+            epoch: Epoch::new(),
+        }
+    }
+
+    pub fn push(&self, value: T) {
+        // Bump first: the push may reallocate.
+        self.epoch.bump();
+        // SAFETY: No reference into `items` escapes this type, and it is
+        // !Sync, so nothing else is using the vector.
+        unsafe { (*self.items.get()).push(value) };
+    }
+
+    pub fn len(&self) -> usize {
+        // SAFETY: As in `push`.
+        unsafe { (*self.items.get()).len() }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn iter(&self) -> CheckedIter<'_, T> {
+        // SAFETY: As in `push`; only raw pointers leave here.
+        let items = unsafe { &*self.items.get() };
+        // SAFETY: The storage only moves or frees in `push` (after the
+        // bump) or on drop, which the borrow rules out.
+        unsafe {
+            CheckedIter::new(
+                items.as_ptr(),
+                items.len(),
+                &self.epoch,
+                self as *const Self as usize,
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::testing;
+
+    #[test]
+    fn unchanged_buffer_iterates_fully() {
+        let buf = EpochBuf::new();
+        for i in 0..10 {
+            buf.push(i);
+        }
+        assert_eq!(buf.iter().sum::<i32>(), 45);
+        assert_eq!(buf.iter().count(), buf.len());
+        // An exhausted iterator is not checked again.
+        let mut iter = buf.iter();
+        iter.by_ref().for_each(drop);
+        buf.push(10);
+        assert_eq!(iter.next(), None);
+    }
+
+    #[test]
+    fn push_during_iteration_is_reported_once() {
+        const TEST: &str = "sanitize::checked::tests::push_during_iteration_is_reported_once";
+        if testing::in_child(TEST) {
+            crate::sanitize::set_halt_on_error(false);
+            let buf = EpochBuf::new();
+            buf.push(1u64);
+            buf.push(2);
+            std::eprintln!("buf={:#018x}", &buf as *const _ as usize);
+            let mut iter = buf.iter();
+            assert_eq!(iter.next(), Some(1));
+            // Enough pushes that the storage moves under the iterator.
+            for i in 0..97 {
+                buf.push(i);
+            }
+            assert_eq!(iter.next(), None);
+            assert_eq!(iter.next(), None);
+            assert_eq!(crate::sanitize::diagnostic::error_count(), 1);
+            return;
+        }
+        let child = testing::spawn(TEST, &[]);
+        let stderr = child.stderr();
+        assert_eq!(child.code(), Some(0), "{stderr}");
+        let (first, report) = stderr.split_once('\n').unwrap();
+        let addr = first.strip_prefix("buf=").expect(first);
+        if cfg!(feature = "minimal-diagnostics") {
+            assert_eq!(report, std::format!("SAN 16 {addr}\n"));
+            return;
+        }
+        assert_eq!(
+            report.matches("ERROR: iterator invalidated").count(),
+            1,
+            "{stderr}"
+        );
+        assert!(
+            report.contains(&std::format!("container:       {addr}")),
+            "{stderr}"
+        );
+        // Created after the 2nd push, used after the 99th.
+        assert!(report.contains("iterator epoch:  2\n"), "{stderr}");
+        assert!(report.contains("container epoch: 99\n"), "{stderr}");
+    }
+}
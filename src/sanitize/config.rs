@@ -0,0 +1,317 @@
+//! Runtime tunables, read from the environment once.
+//!
+//! `LIBCPP_OPTIONS` takes colon-separated `key=value` entries, like
+//! `ASAN_OPTIONS`:
+//!
+//! ```text
+//! LIBCPP_OPTIONS=quarantine_size=1048576:redzone=32:halt_on_error=0:scribble=1:color=never
+//! ```
+//!
+//! The older single-purpose variables (`LIBCPP_REDZONE`,
+//! `LIBCPP_HALT_ON_ERROR`, `LIBCPP_SCRIBBLE`, `LIBCPP_COLOR`,
+//! `LIBCPP_FORMAT`, `LIBCPP_LOG`, `LIBCPP_TIMESTAMPS`, `LIBCPP_EXITCODE`)
+//! still work;
+//! an entry in `LIBCPP_OPTIONS` wins over them. If any entry is malformed
+//! the whole string is ignored with a one-line note. The `set_*` functions
+//! in `sanitize` override whatever was configured here.
+
+use crate::sanitize::procfs::parse_usize;
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+pub const OPTIONS_ENV: &[u8] = b"LIBCPP_OPTIONS";
+
+/// Settings taken from the environment. `None` leaves the built-in default.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct Config {
+    /// `quarantine_size`: bytes of freed user data held in quarantine.
+    pub quarantine_bytes: Option<usize>,
+    /// `redzone`: red zone size, subject to `redzone::valid_size`.
+    pub redzone: Option<usize>,
+    /// `halt_on_error`: abort on the first error, or report and carry on.
+    pub halt_on_error: Option<bool>,
+    /// `scribble`: fill new allocations with `redzone::SCRIBBLE_BYTE`.
+    pub scribble: Option<bool>,
+    /// `color`: `always` or `never`; `auto` (and unset) checks for a terminal.
+    pub color: Option<bool>,
+    /// `fixed_canary`: give every block `redzone::FIXED_CANARY` instead of a
+    /// random one, for reproducible memory dumps.
+    pub fixed_canary: Option<bool>,
+    /// `scale_redzone`: grow red zones with the block, see
+    /// `redzone::zone_size`.
+    pub scale_redzone: Option<bool>,
+    /// `format`: `json` for one-line JSON reports, or `text`.
+    pub json: Option<bool>,
+    /// `log`: file reports are appended to; empty for stderr.
+    pub log: Option<LogPath>,
+    /// `timestamps`: start report headers with the monotonic time.
+    pub timestamps: Option<bool>,
+    /// `exitcode`: status to exit with after a fatal report, 0 to abort.
+    pub exit_code: Option<u8>,
+}
+
+/// A `log` path, short enough to be NUL-terminated in a 256-byte buffer.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct LogPath {
+    bytes: [u8; LogPath::MAX],
+    len: usize,
+}
+
+impl LogPath {
+    pub const MAX: usize = 255;
+
+    fn new(path: &[u8]) -> Option<Self> {
+        let mut bytes = [0; Self::MAX];
+        bytes.get_mut(..path.len())?.copy_from_slice(path);
+        Some(Self {
+            bytes,
+            len: path.len(),
+        })
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+}
+
+impl Config {
+    pub const fn new() -> Self {
+        Self {
+            quarantine_bytes: None,
+            redzone: None,
+            halt_on_error: None,
+            scribble: None,
+            color: None,
+            fixed_canary: None,
+            scale_redzone: None,
+            json: None,
+            log: None,
+            timestamps: None,
+            exit_code: None,
+        }
+    }
+
+    /// Apply the entries of an options string. On a malformed entry (no
+    /// `=`, an unknown key or a bad value) nothing is applied and the entry
+    /// is returned. Empty entries are skipped.
+    pub fn parse<'a>(&mut self, options: &'a [u8]) -> Result<(), &'a [u8]> {
+        let mut parsed = *self;
+        for entry in options.split(|&b| b == b':') {
+            if entry.is_empty() {
+                continue;
+            }
+            let Some(eq) = entry.iter().position(|&b| b == b'=') else {
+                return Err(entry);
+            };
+            let (key, value) = (&entry[..eq], &entry[eq + 1..]);
+            match key {
+                b"quarantine_size" => {
+                    parsed.quarantine_bytes = Some(parse_usize(value).ok_or(entry)?)
+                }
+                b"redzone" => parsed.redzone = Some(parse_usize(value).ok_or(entry)?),
+                b"halt_on_error" => parsed.halt_on_error = Some(flag(value).ok_or(entry)?),
+                b"scribble" => parsed.scribble = Some(flag(value).ok_or(entry)?),
+                b"color" => parsed.color = color(value).ok_or(entry)?,
+                b"fixed_canary" => parsed.fixed_canary = Some(flag(value).ok_or(entry)?),
+                b"scale_redzone" => parsed.scale_redzone = Some(flag(value).ok_or(entry)?),
+                b"format" => parsed.json = Some(format(value).ok_or(entry)?),
+                b"log" => parsed.log = Some(LogPath::new(value).ok_or(entry)?),
+                b"timestamps" => parsed.timestamps = Some(flag(value).ok_or(entry)?),
+                b"exitcode" => parsed.exit_code = Some(exit_code(value).ok_or(entry)?),
+                _ => return Err(entry),
+            }
+        }
+        *self = parsed;
+        Ok(())
+    }
+
+    /// Read the single-purpose variables, then `LIBCPP_OPTIONS` on top.
+    fn load(note: bool) -> Self {
+        use crate::sanitize::procfs::{env_usize, env_var};
+
+        let mut config = Self {
+            redzone: env_usize(crate::sanitize::redzone::REDZONE_ENV),
+            halt_on_error: env_usize(b"LIBCPP_HALT_ON_ERROR").map(|v| v != 0),
+            scribble: env_usize(b"LIBCPP_SCRIBBLE").map(|v| v != 0),
+            timestamps: env_usize(b"LIBCPP_TIMESTAMPS").map(|v| v != 0),
+            // Out-of-range values have always meant "abort".
+            exit_code: env_usize(b"LIBCPP_EXITCODE").map(|v| u8::try_from(v).unwrap_or(0)),
+            ..Self::new()
+        };
+        let mut buf = [0u8; 8];
+        config.color = env_var(b"LIBCPP_COLOR", &mut buf).and_then(|n| color(&buf[..n]).flatten());
+        // Anything but `json` has always meant text.
+        config.json = env_var(b"LIBCPP_FORMAT", &mut buf).map(|n| &buf[..n] == b"json");
+        let mut path = [0u8; LogPath::MAX];
+        config.log = env_var(b"LIBCPP_LOG", &mut path).and_then(|n| LogPath::new(&path[..n]));
+
+        let mut buf = [0u8; 512];
+        if let Some(len) = env_var(OPTIONS_ENV, &mut buf)
+            && let Err(entry) = config.parse(&buf[..len])
+            && note
+        {
+            bad_entry(entry);
+        }
+        config
+    }
+}
+
+/// `LIBCPP_OPTIONS` state: 0 until read, 1 while one thread reads it, 2 once
+/// `CONFIG` holds the result.
+static STATE: AtomicU8 = AtomicU8::new(0);
+const LOADING: u8 = 1;
+const READY: u8 = 2;
+
+struct Shared(UnsafeCell<Config>);
+
+// SAFETY: The config is written once, before STATE becomes READY, and only
+// read after that.
+unsafe impl Sync for Shared {}
+
+static CONFIG: Shared = Shared(UnsafeCell::new(Config::new()));
+
+/// The configuration, read on first use. A thread that asks while another
+/// is still reading gets its own copy rather than waiting, so this is safe
+/// from a signal handler.
+pub fn get() -> Config {
+    match STATE.load(Ordering::Acquire) {
+        // SAFETY: CONFIG is no longer written once READY.
+        READY => unsafe { *CONFIG.0.get() },
+        LOADING => Config::load(false),
+        _ => {
+            if STATE
+                .compare_exchange(0, LOADING, Ordering::Acquire, Ordering::Acquire)
+                .is_err()
+            {
+                return get();
+            }
+            let config = Config::load(true);
+            // SAFETY: Only the thread that moved STATE to LOADING writes,
+            // and nobody reads until READY.
+            unsafe { *CONFIG.0.get() = config };
+            STATE.store(READY, Ordering::Release);
+            config
+        }
+    }
+}
+
+fn flag(value: &[u8]) -> Option<bool> {
+    match value {
+        b"1" | b"true" => Some(true),
+        b"0" | b"false" => Some(false),
+        _ => None,
+    }
+}
+
+/// `always`, `never` or `auto`, as `color` stores them.
+fn color(value: &[u8]) -> Option<Option<bool>> {
+    match value {
+        b"always" => Some(Some(true)),
+        b"never" => Some(Some(false)),
+        b"auto" => Some(None),
+        _ => None,
+    }
+}
+
+/// `json` or `text`, as `format` stores them.
+fn format(value: &[u8]) -> Option<bool> {
+    match value {
+        b"json" => Some(true),
+        b"text" => Some(false),
+        _ => None,
+    }
+}
+
+fn exit_code(value: &[u8]) -> Option<u8> {
+    parse_usize(value).and_then(|v| u8::try_from(v).ok())
+}
+
+/// `libcplusplus: ignoring LIBCPP_OPTIONS, bad entry 'x'`, in one write.
+fn bad_entry(entry: &[u8]) {
+    let mut line = crate::sanitize::diagnostic::Line::new();
+    line.push(b"libcplusplus: ignoring LIBCPP_OPTIONS, bad entry '");
+    line.push(entry);
+    line.push(b"'\n");
+    crate::sanitize::diagnostic::write_log(line.as_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::testing;
+
+    #[test]
+    fn output_keys_parse() {
+        let mut config = Config::new();
+        let options = b"format=json:log=/tmp/san.log:timestamps=1:exitcode=23";
+        assert_eq!(config.parse(options), Ok(()));
+        assert_eq!(config.json, Some(true));
+        assert_eq!(config.log.unwrap().as_bytes(), b"/tmp/san.log");
+        assert_eq!(config.timestamps, Some(true));
+        assert_eq!(config.exit_code, Some(23));
+
+        assert_eq!(config.parse(b"format=text:log=:exitcode=0"), Ok(()));
+        assert_eq!(config.json, Some(false));
+        assert_eq!(config.log.unwrap().as_bytes(), b"");
+        assert_eq!(config.exit_code, Some(0));
+    }
+
+    #[test]
+    fn malformed_output_entries_are_rejected_whole() {
+        let long = [b'a'; LogPath::MAX + 1];
+        let mut options = std::vec::Vec::from(&b"timestamps=1:log="[..]);
+        options.extend_from_slice(&long);
+        for (bad, entry) in [
+            (&b"format=xml"[..], &b"format=xml"[..]),
+            (b"exitcode=256", b"exitcode=256"),
+            (b"exitcode=-1", b"exitcode=-1"),
+            (b"timestamps=yes", b"timestamps=yes"),
+            (&options, &options[13..]),
+        ] {
+            let mut config = Config::new();
+            assert_eq!(config.parse(bad), Err(entry));
+            assert_eq!(config, Config::new());
+        }
+    }
+
+    #[test]
+    fn options_select_log_format_and_exit_code() {
+        const TEST: &str = "sanitize::config::tests::options_select_log_format_and_exit_code";
+        if testing::in_child(TEST) {
+            let layout = core::alloc::Layout::from_size_align(32, 8).unwrap();
+            // SAFETY: The second free is the reported error.
+            unsafe {
+                let ptr = crate::sanitize::sanitized_alloc(layout);
+                crate::sanitize::sanitized_dealloc(ptr, layout);
+                crate::sanitize::sanitized_dealloc(ptr, layout);
+            }
+            return;
+        }
+        let path =
+            std::env::temp_dir().join(std::format!("libcpp-options-{}.log", std::process::id()));
+        let options = std::format!("format=json:exitcode=23:log={}", path.display());
+        let child = testing::spawn(TEST, &[("LIBCPP_OPTIONS", &options)]);
+        let log = std::fs::read_to_string(&path).unwrap_or_default();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(child.code(), Some(23), "{}", child.stderr());
+        let expected = if cfg!(feature = "minimal-diagnostics") {
+            "SAN 1 "
+        } else {
+            "{\"error\":\"double-free\""
+        };
+        assert!(log.contains(expected), "{log}");
+        assert!(!child.stderr().contains(expected), "{}", child.stderr());
+
+        let child = testing::spawn(TEST, &[("LIBCPP_OPTIONS", "timestamps=1:exitcode=29")]);
+        let stderr = child.stderr();
+        assert_eq!(child.code(), Some(29), "{stderr}");
+        if !cfg!(feature = "minimal-diagnostics") {
+            assert!(
+                stderr.contains("\n[") && stderr.contains("] === libcplusplus"),
+                "{stderr}"
+            );
+        }
+    }
+}
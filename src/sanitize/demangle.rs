@@ -0,0 +1,722 @@
+//! Minimal Itanium C++ ABI demangler for diagnostics.
+//!
+//! Covers what shows up in allocation backtraces: nested and `std::`
+//! names, constructors and destructors, operator names, builtin and
+//! qualified types, template arguments and parameters, substitutions,
+//! ABI tags and clone suffixes. Function types, arrays, member pointers,
+//! local names, lambdas and expressions are not handled; `demangle`
+//! returns `None` for them and callers print the raw symbol.
+//!
+//! Output is written straight into the caller's buffer. Substitutions are
+//! remembered as ranges of that buffer, so nothing is allocated.
+
+/// Substitution candidates remembered per symbol.
+const MAX_SUBS: usize = 64;
+
+/// Template arguments remembered for `T_` references.
+const MAX_TEMPLATE_ARGS: usize = 16;
+
+/// Demangle `symbol` into `out`, returning the length written.
+///
+/// Returns `None` if `symbol` is not a mangled name, uses a construct this
+/// module does not handle, or does not fit in `out`.
+pub fn demangle(symbol: &[u8], out: &mut [u8]) -> Option<usize> {
+    let input = symbol.strip_prefix(b"_Z")?;
+    let mut d = Demangler {
+        input,
+        pos: 0,
+        out,
+        len: 0,
+        subs: [(0, 0); MAX_SUBS],
+        n_subs: 0,
+        targs: [(0, 0); MAX_TEMPLATE_ARGS],
+        n_targs: 0,
+        depth: 0,
+    };
+    d.mangled_name()?;
+    Some(d.len)
+}
+
+/// Qualifiers and shape of a function name, needed to finish the encoding.
+#[derive(Default)]
+struct NameInfo {
+    /// The last component has template arguments, so a return type follows.
+    template: bool,
+    /// Constructors, destructors and conversion operators have no return type.
+    no_return: bool,
+    cv_const: bool,
+    cv_volatile: bool,
+    cv_restrict: bool,
+    /// 1 for `&`, 2 for `&&`.
+    ref_qual: u8,
+}
+
+struct Demangler<'a> {
+    input: &'a [u8],
+    pos: usize,
+    out: &'a mut [u8],
+    len: usize,
+    /// Output ranges of the substitution candidates, in mangling order.
+    subs: [(usize, usize); MAX_SUBS],
+    n_subs: usize,
+    /// Output ranges of the template arguments `T_` refers to.
+    targs: [(usize, usize); MAX_TEMPLATE_ARGS],
+    n_targs: usize,
+    /// Template argument list nesting.
+    depth: usize,
+}
+
+impl Demangler<'_> {
+    fn peek(&self) -> Option<u8> {
+        self.input.get(self.pos).copied()
+    }
+
+    fn peek_next(&self) -> Option<u8> {
+        self.input.get(self.pos + 1).copied()
+    }
+
+    fn at_end(&self) -> bool {
+        matches!(self.peek(), None | Some(b'.'))
+    }
+
+    fn eat(&mut self, byte: u8) -> bool {
+        let hit = self.peek() == Some(byte);
+        if hit {
+            self.pos += 1;
+        }
+        hit
+    }
+
+    fn expect(&mut self, byte: u8) -> Option<()> {
+        self.eat(byte).then_some(())
+    }
+
+    fn push(&mut self, bytes: &[u8]) -> Option<()> {
+        let end = self.len.checked_add(bytes.len())?;
+        self.out.get_mut(self.len..end)?.copy_from_slice(bytes);
+        self.len = end;
+        Some(())
+    }
+
+    /// Append a copy of earlier output.
+    fn push_range(&mut self, (start, end): (usize, usize)) -> Option<()> {
+        let n = end - start;
+        if self.out.len() - self.len < n {
+            return None;
+        }
+        self.out.copy_within(start..end, self.len);
+        self.len += n;
+        Some(())
+    }
+
+    fn add_sub(&mut self, start: usize) -> Option<()> {
+        *self.subs.get_mut(self.n_subs)? = (start, self.len);
+        self.n_subs += 1;
+        Some(())
+    }
+
+    fn number(&mut self) -> Option<usize> {
+        let start = self.pos;
+        let mut value: usize = 0;
+        while let Some(digit @ b'0'..=b'9') = self.peek() {
+            value = value
+                .checked_mul(10)?
+                .checked_add((digit - b'0') as usize)?;
+            self.pos += 1;
+        }
+        (self.pos > start).then_some(value)
+    }
+
+    /// `_` is 0, `<base 36>_` is the number plus one.
+    fn seq_id(&mut self) -> Option<usize> {
+        if self.eat(b'_') {
+            return Some(0);
+        }
+        let mut value: usize = 0;
+        loop {
+            let digit = match self.peek()? {
+                b'_' => break,
+                c @ b'0'..=b'9' => c - b'0',
+                c @ b'A'..=b'Z' => c - b'A' + 10,
+                _ => return None,
+            };
+            value = value.checked_mul(36)?.checked_add(digit as usize)?;
+            self.pos += 1;
+        }
+        self.pos += 1;
+        value.checked_add(1)
+    }
+
+    fn mangled_name(&mut self) -> Option<()> {
+        match (self.peek()?, self.peek_next()) {
+            (b'T', Some(kind)) => {
+                let what: &[u8] = match kind {
+                    b'V' => b"vtable for ",
+                    b'T' => b"VTT for ",
+                    b'I' => b"typeinfo for ",
+                    b'S' => b"typeinfo name for ",
+                    _ => return None,
+                };
+                self.pos += 2;
+                self.push(what)?;
+                self.type_()?;
+            }
+            (b'G', Some(b'V')) => {
+                self.pos += 2;
+                self.push(b"guard variable for ")?;
+                self.name()?;
+            }
+            _ => self.encoding()?,
+        }
+        self.clone_suffix()
+    }
+
+    /// `.constprop.0`, `.cold` and friends added by the compiler.
+    fn clone_suffix(&mut self) -> Option<()> {
+        match self.peek() {
+            None => Some(()),
+            Some(b'.') => {
+                let rest = &self.input[self.pos..];
+                self.pos = self.input.len();
+                self.push(b" [clone ")?;
+                self.push(rest)?;
+                self.push(b"]")
+            }
+            Some(_) => None,
+        }
+    }
+
+    fn encoding(&mut self) -> Option<()> {
+        let name_start = self.len;
+        let info = self.name()?;
+        if self.at_end() {
+            return Some(());
+        }
+
+        if info.template && !info.no_return {
+            let ret = self.len;
+            self.type_()?;
+            self.push(b" ")?;
+            self.move_to_front(name_start, ret);
+        }
+
+        self.push(b"(")?;
+        if self.peek() == Some(b'v') && matches!(self.input.get(self.pos + 1), None | Some(b'.')) {
+            self.pos += 1;
+        } else {
+            let mut first = true;
+            while !self.at_end() {
+                if !first {
+                    self.push(b", ")?;
+                }
+                self.type_()?;
+                first = false;
+            }
+        }
+        self.push(b")")?;
+
+        if info.cv_const {
+            self.push(b" const")?;
+        }
+        if info.cv_volatile {
+            self.push(b" volatile")?;
+        }
+        if info.cv_restrict {
+            self.push(b" restrict")?;
+        }
+        match info.ref_qual {
+            1 => self.push(b" &"),
+            2 => self.push(b" &&"),
+            _ => Some(()),
+        }
+    }
+
+    /// Rotate `out[split..len]` (a return type) ahead of `out[start..split]`
+    /// (the name), keeping remembered ranges pointing at the same text.
+    fn move_to_front(&mut self, start: usize, split: usize) {
+        let moved = self.len - split;
+        self.out[start..self.len].rotate_right(moved);
+        let shift = |range: &mut (usize, usize)| {
+            if range.0 >= split {
+                range.0 -= split - start;
+                range.1 -= split - start;
+            } else if range.0 >= start {
+                range.0 += moved;
+                range.1 += moved;
+            }
+        };
+        self.subs[..self.n_subs].iter_mut().for_each(shift);
+        self.targs[..self.n_targs].iter_mut().for_each(shift);
+    }
+
+    fn name(&mut self) -> Option<NameInfo> {
+        let start = self.len;
+        match self.peek()? {
+            b'N' => self.nested_name(true),
+            b'Z' => None,
+            b'S' if self.peek_next() == Some(b't') => {
+                self.pos += 2;
+                self.push(b"std::")?;
+                self.unscoped_template(start)
+            }
+            b'S' => {
+                self.substitution(false)?;
+                // A substitution only names a function as a template.
+                self.peek().filter(|&c| c == b'I')?;
+                self.template_args(true)?;
+                Some(NameInfo {
+                    template: true,
+                    ..NameInfo::default()
+                })
+            }
+            _ => self.unscoped_template(start),
+        }
+    }
+
+    /// An unqualified name, optionally followed by template arguments.
+    fn unscoped_template(&mut self, start: usize) -> Option<NameInfo> {
+        let mut info = NameInfo {
+            no_return: self.unqualified_name()?,
+            ..NameInfo::default()
+        };
+        if self.peek() == Some(b'I') {
+            self.add_sub(start)?;
+            self.template_args(true)?;
+            info.template = true;
+        }
+        Some(info)
+    }
+
+    /// `N [qualifiers] <prefix components> E`. `record` is false in type
+    /// context, where template arguments are not the function's own.
+    fn nested_name(&mut self, record: bool) -> Option<NameInfo> {
+        self.expect(b'N')?;
+        let mut info = NameInfo {
+            cv_restrict: self.eat(b'r'),
+            cv_volatile: self.eat(b'V'),
+            cv_const: self.eat(b'K'),
+            ..NameInfo::default()
+        };
+        if self.eat(b'R') {
+            info.ref_qual = 1;
+        } else if self.eat(b'O') {
+            info.ref_qual = 2;
+        }
+
+        let start = self.len;
+        let mut first = true;
+        loop {
+            let c = self.peek()?;
+            let substitutable = match c {
+                b'E' => {
+                    self.pos += 1;
+                    break;
+                }
+                b'S' if self.peek_next() == Some(b't') => {
+                    self.pos += 2;
+                    self.push(b"std")?;
+                    false
+                }
+                b'S' => {
+                    self.substitution(true)?;
+                    false
+                }
+                b'I' => {
+                    self.template_args(record)?;
+                    info.template = true;
+                    true
+                }
+                b'C' | b'D' if !first => {
+                    self.ctor_dtor(start)?;
+                    info.template = false;
+                    info.no_return = true;
+                    true
+                }
+                _ => {
+                    if !first {
+                        self.push(b"::")?;
+                    }
+                    info.no_return = self.unqualified_name()?;
+                    info.template = false;
+                    true
+                }
+            };
+            first = false;
+            if substitutable && self.peek() != Some(b'E') {
+                self.add_sub(start)?;
+            }
+        }
+        Some(info)
+    }
+
+    /// Returns true for a conversion operator, which has no return type.
+    fn unqualified_name(&mut self) -> Option<bool> {
+        let mut conversion = false;
+        match self.peek()? {
+            b'0'..=b'9' => self.source_name()?,
+            b'L' => {
+                // Internal linkage; prints the same.
+                self.pos += 1;
+                self.source_name()?;
+            }
+            b'a'..=b'z' => conversion = self.operator_name()?,
+            _ => return None,
+        }
+        while self.eat(b'B') {
+            self.push(b"[abi:")?;
+            self.source_name()?;
+            self.push(b"]")?;
+        }
+        Some(conversion)
+    }
+
+    fn source_name(&mut self) -> Option<()> {
+        let n = self.number()?;
+        let end = self.pos.checked_add(n)?;
+        let ident = self.input.get(self.pos..end)?;
+        self.pos = end;
+        if ident.starts_with(b"_GLOBAL__N") {
+            self.push(b"(anonymous namespace)")
+        } else {
+            self.push(ident)
+        }
+    }
+
+    /// The constructor or destructor of the class named by the prefix
+    /// `out[start..len]`: its last component, without template arguments.
+    fn ctor_dtor(&mut self, start: usize) -> Option<()> {
+        let dtor = match (self.peek()?, self.peek_next()?) {
+            (b'C', b'1'..=b'5') => false,
+            (b'D', b'0' | b'1' | b'2' | b'4' | b'5') => true,
+            _ => return None,
+        };
+        self.pos += 2;
+
+        let prefix = &self.out[start..self.len];
+        let mut depth = 0usize;
+        let mut begin = 0;
+        let mut end = prefix.len();
+        for (i, &c) in prefix.iter().enumerate() {
+            match c {
+                b'<' if depth == 0 => {
+                    end = i;
+                    depth += 1;
+                }
+                b'<' => depth += 1,
+                b'>' => depth = depth.saturating_sub(1),
+                b'[' if depth == 0 => end = end.min(i),
+                b':' if depth == 0 && prefix.get(i + 1) == Some(&b':') => {
+                    begin = i + 2;
+                    end = prefix.len();
+                }
+                _ => {}
+            }
+        }
+        if begin >= end {
+            return None;
+        }
+
+        self.push(if dtor { b"::~" } else { b"::" })?;
+        self.push_range((start + begin, start + end))
+    }
+
+    /// Returns true for a conversion operator.
+    fn operator_name(&mut self) -> Option<bool> {
+        let code = [self.peek()?, self.peek_next()?];
+        self.pos += 2;
+        if code == *b"cv" {
+            self.push(b"operator ")?;
+            self.type_()?;
+            return Some(true);
+        }
+        if code == *b"li" {
+            self.push(b"operator\"\" ")?;
+            self.source_name()?;
+            return Some(false);
+        }
+        let symbol: &[u8] = match &code {
+            b"nw" => b" new",
+            b"na" => b" new[]",
+            b"dl" => b" delete",
+            b"da" => b" delete[]",
+            b"aw" => b" co_await",
+            b"ps" | b"pl" => b"+",
+            b"ng" | b"mi" => b"-",
+            b"ad" | b"an" => b"&",
+            b"de" | b"ml" => b"*",
+            b"co" => b"~",
+            b"dv" => b"/",
+            b"rm" => b"%",
+            b"or" => b"|",
+            b"eo" => b"^",
+            b"aS" => b"=",
+            b"pL" => b"+=",
+            b"mI" => b"-=",
+            b"mL" => b"*=",
+            b"dV" => b"/=",
+            b"rM" => b"%=",
+            b"aN" => b"&=",
+            b"oR" => b"|=",
+            b"eO" => b"^=",
+            b"ls" => b"<<",
+            b"rs" => b">>",
+            b"lS" => b"<<=",
+            b"rS" => b">>=",
+            b"eq" => b"==",
+            b"ne" => b"!=",
+            b"lt" => b"<",
+            b"gt" => b">",
+            b"le" => b"<=",
+            b"ge" => b">=",
+            b"ss" => b"<=>",
+            b"nt" => b"!",
+            b"aa" => b"&&",
+            b"oo" => b"||",
+            b"pp" => b"++",
+            b"mm" => b"--",
+            b"cm" => b",",
+            b"pm" => b"->*",
+            b"pt" => b"->",
+            b"cl" => b"()",
+            b"ix" => b"[]",
+            b"qu" => b"?",
+            _ => return None,
+        };
+        self.push(b"operator")?;
+        self.push(symbol)?;
+        Some(false)
+    }
+
+    /// `S_`, `S<seq-id>_` or one of the `std::` abbreviations. The long
+    /// forms of the stream and string abbreviations are used in a prefix,
+    /// so a constructor gets the real class name.
+    fn substitution(&mut self, prefix: bool) -> Option<()> {
+        self.expect(b'S')?;
+        let text: &[u8] = match self.peek()? {
+            b'a' => b"std::allocator",
+            b'b' => b"std::basic_string",
+            b's' if prefix => {
+                b"std::basic_string<char, std::char_traits<char>, std::allocator<char> >"
+            }
+            b's' => b"std::string",
+            b'i' if prefix => b"std::basic_istream<char, std::char_traits<char> >",
+            b'i' => b"std::istream",
+            b'o' if prefix => b"std::basic_ostream<char, std::char_traits<char> >",
+            b'o' => b"std::ostream",
+            b'd' if prefix => b"std::basic_iostream<char, std::char_traits<char> >",
+            b'd' => b"std::iostream",
+            _ => {
+                let id = self.seq_id()?;
+                let range = *self.subs[..self.n_subs].get(id)?;
+                return self.push_range(range);
+            }
+        };
+        self.pos += 1;
+        self.push(text)
+    }
+
+    /// `I <args> E`. `record` makes these the arguments `T_` refers to.
+    fn template_args(&mut self, record: bool) -> Option<()> {
+        self.expect(b'I')?;
+        self.push(b"<")?;
+        self.depth += 1;
+        let record = record && self.depth == 1;
+        let mut n = 0;
+        while !self.eat(b'E') {
+            if n > 0 {
+                self.push(b", ")?;
+            }
+            let start = self.len;
+            self.template_arg()?;
+            if record && n < MAX_TEMPLATE_ARGS {
+                self.targs[n] = (start, self.len);
+            }
+            n += 1;
+        }
+        self.depth -= 1;
+        if record {
+            self.n_targs = n.min(MAX_TEMPLATE_ARGS);
+        }
+        // Keep `> >` apart, as c++filt does.
+        if self.len > 0 && self.out[self.len - 1] == b'>' {
+            self.push(b" ")?;
+        }
+        self.push(b">")
+    }
+
+    fn template_arg(&mut self) -> Option<()> {
+        match self.peek()? {
+            b'L' => self.literal(),
+            b'J' => {
+                self.pos += 1;
+                let mut first = true;
+                while !self.eat(b'E') {
+                    if !first {
+                        self.push(b", ")?;
+                    }
+                    self.template_arg()?;
+                    first = false;
+                }
+                Some(())
+            }
+            b'X' => None,
+            _ => self.type_(),
+        }
+    }
+
+    /// `L <type> <value> E`, printed as `3`, `true` or `(type)3`.
+    fn literal(&mut self) -> Option<()> {
+        self.expect(b'L')?;
+        match self.peek()? {
+            b'_' => return None,
+            b'b' => {
+                self.pos += 1;
+                let value = self.number()?;
+                self.push(if value == 0 { b"false" } else { b"true" })?;
+                return self.expect(b'E');
+            }
+            b'i' => self.pos += 1,
+            _ => {
+                self.push(b"(")?;
+                self.type_()?;
+                self.push(b")")?;
+            }
+        }
+        if self.eat(b'n') {
+            self.push(b"-")?;
+        }
+        let start = self.pos;
+        while self.peek()? != b'E' {
+            self.pos += 1;
+        }
+        let value = &self.input[start..self.pos];
+        self.pos += 1;
+        self.push(value)
+    }
+
+    fn builtin(code: u8) -> Option<&'static [u8]> {
+        Some(match code {
+            b'v' => b"void",
+            b'w' => b"wchar_t",
+            b'b' => b"bool",
+            b'c' => b"char",
+            b'a' => b"signed char",
+            b'h' => b"unsigned char",
+            b's' => b"short",
+            b't' => b"unsigned short",
+            b'i' => b"int",
+            b'j' => b"unsigned int",
+            b'l' => b"long",
+            b'm' => b"unsigned long",
+            b'x' => b"long long",
+            b'y' => b"unsigned long long",
+            b'n' => b"__int128",
+            b'o' => b"unsigned __int128",
+            b'f' => b"float",
+            b'd' => b"double",
+            b'e' => b"long double",
+            b'g' => b"__float128",
+            b'z' => b"...",
+            _ => return None,
+        })
+    }
+
+    fn type_(&mut self) -> Option<()> {
+        let start = self.len;
+        let c = self.peek()?;
+        if let Some(name) = Self::builtin(c) {
+            self.pos += 1;
+            return self.push(name);
+        }
+        match c {
+            b'D' => {
+                let name: &[u8] = match self.peek_next()? {
+                    b'n' => b"decltype(nullptr)",
+                    b'i' => b"char32_t",
+                    b's' => b"char16_t",
+                    b'u' => b"char8_t",
+                    b'a' => b"auto",
+                    b'c' => b"decltype(auto)",
+                    b'p' => {
+                        // Pack expansion: the pack prints as its elements.
+                        self.pos += 2;
+                        self.type_()?;
+                        return self.add_sub(start);
+                    }
+                    _ => return None,
+                };
+                self.pos += 2;
+                return self.push(name);
+            }
+            b'r' | b'V' | b'K' => {
+                let restrict = self.eat(b'r');
+                let volatile = self.eat(b'V');
+                let konst = self.eat(b'K');
+                self.type_()?;
+                if konst {
+                    self.push(b" const")?;
+                }
+                if volatile {
+                    self.push(b" volatile")?;
+                }
+                if restrict {
+                    self.push(b" restrict")?;
+                }
+            }
+            b'P' => {
+                self.pos += 1;
+                self.type_()?;
+                self.push(b"*")?;
+            }
+            b'R' | b'O' => {
+                self.pos += 1;
+                self.type_()?;
+                // Reference collapsing, for references to template parameters.
+                if self.out[..self.len].ends_with(b"&&") && c == b'R' {
+                    self.len -= 1;
+                } else if !self.out[..self.len].ends_with(b"&") {
+                    self.push(if c == b'R' { b"&" } else { b"&&" })?;
+                }
+            }
+            b'N' => {
+                self.nested_name(false)?;
+            }
+            b'T' => {
+                self.pos += 1;
+                let n = self.seq_id()?;
+                let range = *self.targs[..self.n_targs].get(n)?;
+                self.push_range(range)?;
+                if self.peek() == Some(b'I') {
+                    self.add_sub(start)?;
+                    self.template_args(false)?;
+                }
+            }
+            b'S' if self.peek_next() == Some(b't') => {
+                self.pos += 2;
+                self.push(b"std::")?;
+                self.class_name(start)?;
+            }
+            b'S' => {
+                self.substitution(false)?;
+                if self.peek() != Some(b'I') {
+                    // Already a candidate; not added again.
+                    return Some(());
+                }
+                self.template_args(false)?;
+            }
+            b'0'..=b'9' => self.class_name(start)?,
+            _ => return None,
+        }
+        self.add_sub(start)
+    }
+
+    /// A class named by an unqualified name and optional template arguments.
+    fn class_name(&mut self, start: usize) -> Option<()> {
+        self.unqualified_name()?;
+        if self.peek() == Some(b'I') {
+            self.add_sub(start)?;
+            self.template_args(false)?;
+        }
+        Some(())
+    }
+}
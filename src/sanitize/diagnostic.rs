@@ -1,12 +1,508 @@
+use crate::sanitize::backtrace::{Frames, Tid};
+use crate::sanitize::error_hook::{self, ErrorInfo, ErrorKind};
 use crate::sanitize::tracker::AllocKind;
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
 
-const HEADER: &[u8] = b"\n\x1b[1;31m=== libcplusplus sanitizer ===\x1b[0m\n";
+/// Header colour of error reports.
+const ERROR: &[u8] = b"1;31";
+/// Header colour of leak reports.
+const NOTICE: &[u8] = b"1;33";
 
-pub fn write_stderr(msg: &[u8]) {
+/// Built with `minimal-diagnostics`: every reporter below emits a single
+/// `SAN <code> <hex address>` line instead of its prose, and report
+/// headers and footers are dropped. Detection and aborting are unchanged.
+const MINIMAL: bool = cfg!(feature = "minimal-diagnostics");
+
+/// Report format: 0 until configured, then `TEXT` or `JSON`.
+static FORMAT: AtomicU8 = AtomicU8::new(0);
+const TEXT: u8 = 1;
+const JSON: u8 = 2;
+
+/// Whether every reporter prints one JSON object per line, as selected by
+/// `format=json` (or `LIBCPP_FORMAT=json`) or `set_json`. Report framing
+/// is dropped so the log stays line-parseable. `minimal-diagnostics` wins.
+fn json() -> bool {
+    let mut format = FORMAT.load(Ordering::Relaxed);
+    if format == 0 {
+        format = if crate::sanitize::config::get().json == Some(true) {
+            JSON
+        } else {
+            TEXT
+        };
+        FORMAT.store(format, Ordering::Relaxed);
+    }
+    format == JSON
+}
+
+/// Select JSON reports regardless of `LIBCPP_FORMAT`.
+pub fn set_json(enabled: bool) {
+    FORMAT.store(if enabled { JSON } else { TEXT }, Ordering::Relaxed);
+}
+
+/// Halting: 0 until configured, then 1 to abort on the first error or 2 to
+/// report and carry on.
+static HALT: AtomicU8 = AtomicU8::new(0);
+
+/// Errors reported so far in recoverable mode.
+static ERRORS: AtomicUsize = AtomicUsize::new(0);
+
+/// Whether error reports abort the process. Unless `halt_on_error=0`,
+/// `LIBCPP_HALT_ON_ERROR=0` or `set_halt_on_error(false)` selected
+/// recoverable mode, they do.
+fn halt_on_error() -> bool {
+    let mut state = HALT.load(Ordering::Relaxed);
+    if state == 0 {
+        let recover = crate::sanitize::config::get().halt_on_error == Some(false);
+        state = if recover { 2 } else { 1 };
+        HALT.store(state, Ordering::Relaxed);
+    }
+    state == 1
+}
+
+/// Abort on the first error (the default), or count errors and continue.
+pub fn set_halt_on_error(enabled: bool) {
+    HALT.store(if enabled { 1 } else { 2 }, Ordering::Relaxed);
+}
+
+/// Errors reported and survived in recoverable mode.
+pub fn error_count() -> usize {
+    ERRORS.load(Ordering::Relaxed)
+}
+
+#[cfg(any(test, feature = "test-hooks"))]
+pub fn reset_error_count() {
+    ERRORS.store(0, Ordering::Relaxed);
+}
+
+/// Per-kind cap on printed reports set by `set_max_reports`; 0 is no cap.
+static MAX_REPORTS_OF: [AtomicUsize; ErrorKind::COUNT] =
+    [const { AtomicUsize::new(0) }; ErrorKind::COUNT];
+/// Errors of each kind seen while a cap was set.
+static REPORTED: [AtomicUsize; ErrorKind::COUNT] =
+    [const { AtomicUsize::new(0) }; ErrorKind::COUNT];
+
+/// Print at most `max` more reports of `kind` (0 for no limit), so a loop
+/// hitting the same bug in recoverable mode cannot flood the log. Later
+/// errors of that kind are still passed to the error hook and counted.
+pub fn set_max_reports(kind: ErrorKind, max: usize) {
+    REPORTED[kind as usize].store(0, Ordering::Relaxed);
+    MAX_REPORTS_OF[kind as usize].store(max, Ordering::Relaxed);
+}
+
+/// Pass the error to the hook, then decide whether to print it. Once its
+/// kind is over the `set_max_reports` cap the error is only counted, and
+/// the first one over leaves a note saying so.
+#[must_use]
+fn notify(info: &ErrorInfo) -> bool {
+    error_hook::notify(info);
+    let kind = info.kind as usize;
+    let max = MAX_REPORTS_OF[kind].load(Ordering::Relaxed);
+    if max == 0 {
+        return true;
+    }
+    let seen = REPORTED[kind].fetch_add(1, Ordering::Relaxed);
+    if seen < max {
+        return true;
+    }
+    if seen == max {
+        let mut line = Line::new();
+        line.push(b"libcplusplus: suppressing further ");
+        line.push(error_name(info.kind));
+        line.push(b" reports\n");
+        write_log(line.as_bytes());
+    }
+    survive();
+    false
+}
+
+/// The name `kind` goes by in JSON reports and notes.
+fn error_name(kind: ErrorKind) -> &'static [u8] {
+    match kind {
+        ErrorKind::DoubleFree => b"double-free",
+        ErrorKind::InvalidFree => b"invalid-free",
+        ErrorKind::InteriorFree => b"interior-free",
+        ErrorKind::MismatchedDealloc => b"mismatched-dealloc",
+        ErrorKind::ReallocOfCxx => b"realloc-of-cxx",
+        ErrorKind::SizeMismatch => b"size-mismatch",
+        ErrorKind::Overflow => b"overflow",
+        ErrorKind::WriteAfterFree => b"write-after-free",
+        ErrorKind::UseAfterFree => b"use-after-free",
+        ErrorKind::Segv => b"segv",
+        ErrorKind::IteratorInvalidated => b"iterator-invalidated",
+    }
+}
+
+/// Fatal exit status: `UNREAD` until configured, then 0 to abort or the
+/// status to exit with.
+static EXIT_CODE: AtomicUsize = AtomicUsize::new(UNREAD);
+const UNREAD: usize = usize::MAX;
+
+/// Stop the process after a fatal report. With `exitcode` (or
+/// `LIBCPP_EXITCODE`) set to 1..=255, or `set_exit_code`, it exits with that status, so harnesses can
+/// tell sanitizer failures from other crashes; otherwise it raises SIGABRT
+/// for debuggers. Signal-safe: nothing is flushed or run on the way out.
+fn die() -> ! {
+    flush_open();
+    let code = exit_code();
+    if code != 0 {
+        // SAFETY: exit_group has no preconditions.
+        unsafe { crate::platform::syscall::sys_exit_group(code as i32) }
+    }
+    // SAFETY: abort is provided by the C runtime.
+    unsafe { crate::platform::abort() }
+}
+
+/// Status to exit with after a fatal report, or 0 to abort.
+pub(crate) fn exit_code() -> u8 {
+    let mut code = EXIT_CODE.load(Ordering::Relaxed);
+    if code == UNREAD {
+        code = crate::sanitize::config::get()
+            .exit_code
+            .map_or(0, usize::from);
+        EXIT_CODE.store(code, Ordering::Relaxed);
+    }
+    code as u8
+}
+
+/// Exit with `code` instead of aborting after fatal reports, or abort again
+/// with `None`, regardless of `LIBCPP_EXITCODE`.
+pub fn set_exit_code(code: Option<u8>) {
+    let code = code.map_or(0, usize::from);
+    EXIT_CODE.store(code, Ordering::Relaxed);
+}
+
+/// Timestamps: 0 until configured, then 1 for off or 2 for on.
+static TIMESTAMPS: AtomicU8 = AtomicU8::new(0);
+
+/// Whether report headers start with a monotonic `[sec.nsec]` timestamp,
+/// as selected by `timestamps=1` (or a non-zero `LIBCPP_TIMESTAMPS`) or
+/// `set_timestamps`.
+fn timestamps() -> bool {
+    let mut state = TIMESTAMPS.load(Ordering::Relaxed);
+    if state == 0 {
+        let on = crate::sanitize::config::get().timestamps == Some(true);
+        state = if on { 2 } else { 1 };
+        TIMESTAMPS.store(state, Ordering::Relaxed);
+    }
+    state == 2
+}
+
+/// Turn header timestamps on or off regardless of `LIBCPP_TIMESTAMPS`.
+pub fn set_timestamps(enabled: bool) {
+    TIMESTAMPS.store(if enabled { 2 } else { 1 }, Ordering::Relaxed);
+}
+
+/// Colour: 0 until configured, then 1 for plain or 2 for coloured headers.
+static COLOR: AtomicU8 = AtomicU8::new(0);
+
+/// Whether report headers carry ANSI colour. `color=always` or `never`
+/// (or the same in `LIBCPP_COLOR`) decides; otherwise only when the log is
+/// a terminal. `set_color` overrides both.
+fn colored() -> bool {
+    let mut state = COLOR.load(Ordering::Relaxed);
+    if state == 0 {
+        let on = crate::sanitize::config::get()
+            .color
+            .unwrap_or_else(|| is_terminal(log_fd()));
+        state = if on { 2 } else { 1 };
+        COLOR.store(state, Ordering::Relaxed);
+    }
+    state == 2
+}
+
+fn is_terminal(fd: usize) -> bool {
+    // Room for any platform's struct termios.
+    let mut termios = [0u64; 16];
+    // SAFETY: termios is writable and larger than what TCGETS fills in.
+    let ret = unsafe {
+        crate::platform::syscall::sys_ioctl(
+            fd,
+            crate::platform::syscall::TCGETS,
+            termios.as_mut_ptr().cast(),
+        )
+    };
+    ret == 0
+}
+
+/// Turn header colour on or off regardless of `LIBCPP_COLOR`.
+pub fn set_color(enabled: bool) {
+    COLOR.store(if enabled { 2 } else { 1 }, Ordering::Relaxed);
+}
+
+/// Whether headers, totals and footers around reports are printed.
+fn framed() -> bool {
+    !MINIMAL && !json()
+}
+
+/// Numeric codes printed by `minimal-diagnostics` builds.
+///
+/// | code | meaning                                   | fatal |
+/// |------|-------------------------------------------|-------|
+/// | 1    | double-free                               | yes   |
+/// | 2    | invalid free                              | yes   |
+/// | 3    | mismatched deallocation                   | yes   |
+/// | 4    | buffer overflow (red zone corruption)     | yes   |
+/// | 5    | realloc of a C++ allocation               | yes   |
+/// | 6    | corrupt live block found by a heap sweep  | no    |
+/// | 7    | leak                                      | no    |
+/// | 8    | dangling pointer into freed memory        | no    |
+/// | 9    | block filled with the canary byte (hint)  | no    |
+/// | 10   | sized delete size mismatch                | yes   |
+/// | 11   | fault inside a freed block                | yes   |
+/// | 12   | fault outside any known block             | yes   |
+/// | 13   | write to a block while it was quarantined | yes   |
+/// | 14   | pure virtual function called              | yes   |
+/// | 15   | deleted virtual function called           | yes   |
+/// | 16   | iterator used after container mutation    | yes   |
+/// | 17   | block freed without being accessed        | no    |
+#[derive(Clone, Copy)]
+#[repr(u8)]
+enum Code {
+    DoubleFree = 1,
+    InvalidFree = 2,
+    MismatchedDealloc = 3,
+    Overflow = 4,
+    ReallocOfCxx = 5,
+    HeapCorruption = 6,
+    Leak = 7,
+    DanglingPointer = 8,
+    CanaryFillHint = 9,
+    SizeMismatch = 10,
+    UseAfterFree = 11,
+    Segv = 12,
+    WriteAfterFree = 13,
+    PureVirtual = 14,
+    DeletedVirtual = 15,
+    IteratorInvalidated = 16,
+    DeadAllocation = 17,
+}
+
+/// Write to the log (stderr unless `LIBCPP_LOG` is set), or append to the
+/// calling thread's open report.
+pub fn write_log(msg: &[u8]) {
+    if OPEN.load(Ordering::Acquire) != 0
+        && let Some(slot) = own_slot()
+    {
+        // SAFETY: Only the owning thread touches a claimed slot.
+        return unsafe { slot.push(msg) };
+    }
+    write_raw(msg);
+}
+
+fn write_raw(msg: &[u8]) {
+    // SAFETY: log_fd is stderr or a file opened for writing.
+    let ret = unsafe { crate::platform::syscall::sys_write(log_fd(), msg.as_ptr(), msg.len()) };
+    if ret < 0 && !WRITE_FAILED.swap(true, Ordering::Relaxed) {
+        write_failed(ret);
+    }
+}
+
+/// Where diagnostics go: `UNOPENED` until the log is configured, then the
+/// log file's descriptor, or 2 when it is unset or cannot be opened.
+static LOG_FD: AtomicUsize = AtomicUsize::new(UNOPENED);
+const UNOPENED: usize = usize::MAX;
+
+const O_WRONLY: usize = 0o1;
+const O_CREAT: usize = 0o100;
+const O_APPEND: usize = 0o2000;
+const O_CLOEXEC: usize = 0o2000000;
+
+/// Descriptor diagnostics are written to. `log=<path>` (or
+/// `LIBCPP_LOG=<path>`) appends them to that file, created if needed,
+/// which is opened once on first use.
+fn log_fd() -> usize {
+    let fd = LOG_FD.load(Ordering::Acquire);
+    if fd != UNOPENED {
+        return fd;
+    }
+    // One byte is kept for the terminating NUL.
+    let mut path = [0u8; crate::sanitize::config::LogPath::MAX + 1];
+    let log = crate::sanitize::config::get().log;
+    let fd = match log.as_ref().map(|p| p.as_bytes()) {
+        Some(log) if !log.is_empty() => {
+            path[..log.len()].copy_from_slice(log);
+            // SAFETY: path is NUL-terminated: the log path is shorter and
+            // path was zeroed.
+            let ret = unsafe {
+                crate::platform::syscall::sys_open(
+                    path.as_ptr(),
+                    O_WRONLY | O_CREAT | O_APPEND | O_CLOEXEC,
+                    0o644,
+                )
+            };
+            if ret < 0 {
+                log_open_failed(ret);
+                2
+            } else {
+                ret as usize
+            }
+        }
+        _ => 2,
+    };
+    match LOG_FD.compare_exchange(UNOPENED, fd, Ordering::AcqRel, Ordering::Acquire) {
+        Ok(_) => fd,
+        Err(current) => {
+            // Another thread opened the log first.
+            if fd != 2 {
+                // SAFETY: fd was opened above and is owned here.
+                unsafe { crate::platform::syscall::sys_close(fd) };
+            }
+            current
+        }
+    }
+}
+
+fn log_open_failed(ret: isize) {
+    let mut line = Line::new();
+    line.push(b"libcplusplus: cannot open LIBCPP_LOG, writing to stderr: ");
+    let mut buf = [0u8; 21];
+    line.push(format_idec(ret, &mut buf));
+    line.push(b"\n");
+    let msg = line.as_bytes();
     // SAFETY: sys_write to fd 2 (stderr) is always valid.
     unsafe { crate::platform::syscall::sys_write(2, msg.as_ptr(), msg.len()) };
 }
 
+/// Set once a diagnostic write has failed, so the failure is noted once.
+static WRITE_FAILED: AtomicBool = AtomicBool::new(false);
+
+/// `libcplusplus: diagnostic write failed: -<errno>`, in one write to
+/// stderr. It only gets through if the log is a file or the error was
+/// transient.
+fn write_failed(ret: isize) {
+    let mut line = Line::new();
+    line.push(b"libcplusplus: diagnostic write failed: ");
+    let mut buf = [0u8; 21];
+    line.push(format_idec(ret, &mut buf));
+    line.push(b"\n");
+    let msg = line.as_bytes();
+    // SAFETY: sys_write to fd 2 (stderr) is always valid.
+    unsafe { crate::platform::syscall::sys_write(2, msg.as_ptr(), msg.len()) };
+}
+
+/// Buffer a report is assembled in. Enough for any single error report
+/// (header, hex dumps and eight symbolized frames); longer output such as
+/// a leak report goes out in pieces of this size.
+const REPORT_BUF: usize = 8192;
+
+/// Threads that can have a report open at once. Reports from further
+/// threads are written line by line, as before buffering.
+pub(crate) const MAX_REPORTS: usize = 8;
+
+/// A report buffer, claimed by the thread id in `tid` (0 when free).
+struct Slot {
+    tid: AtomicUsize,
+    len: UnsafeCell<usize>,
+    buf: UnsafeCell<[u8; REPORT_BUF]>,
+}
+
+// SAFETY: `len` and `buf` are only accessed by the thread that claimed the
+// slot through `tid`.
+unsafe impl Sync for Slot {}
+
+impl Slot {
+    /// # Safety
+    /// The calling thread must own the slot.
+    unsafe fn push(&self, msg: &[u8]) {
+        // SAFETY: Forwarded from the caller.
+        let (len, buf) = unsafe { (&mut *self.len.get(), &mut *self.buf.get()) };
+        if *len + msg.len() > REPORT_BUF {
+            // SAFETY: Forwarded from the caller.
+            unsafe { self.flush() };
+            if msg.len() > REPORT_BUF {
+                return write_raw(msg);
+            }
+        }
+        buf[*len..*len + msg.len()].copy_from_slice(msg);
+        *len += msg.len();
+    }
+
+    /// # Safety
+    /// The calling thread must own the slot.
+    unsafe fn flush(&self) {
+        // SAFETY: Forwarded from the caller.
+        let (len, buf) = unsafe { (&mut *self.len.get(), &*self.buf.get()) };
+        write_raw(&buf[..*len]);
+        *len = 0;
+    }
+}
+
+static SLOTS: [Slot; MAX_REPORTS] = [const {
+    Slot {
+        tid: AtomicUsize::new(0),
+        len: UnsafeCell::new(0),
+        buf: UnsafeCell::new([0; REPORT_BUF]),
+    }
+}; MAX_REPORTS];
+
+/// Slots currently claimed, so `write_log` can skip the lookup.
+static OPEN: AtomicUsize = AtomicUsize::new(0);
+
+/// Reports open on any thread.
+#[cfg(test)]
+pub(crate) fn open_reports() -> usize {
+    OPEN.load(Ordering::Acquire)
+}
+
+fn own_slot() -> Option<&'static Slot> {
+    // SAFETY: gettid has no preconditions.
+    let tid = unsafe { crate::platform::syscall::sys_gettid() };
+    SLOTS.iter().find(|s| s.tid.load(Ordering::Acquire) == tid)
+}
+
+/// An open report: until it is dropped, everything the thread writes to
+/// the log is collected and then written with a single `write`, so reports
+/// from different threads never interleave. Opening one while the thread
+/// already has a report open (a SIGSEGV inside a report, a leak line inside
+/// the leak report) joins the outer report. Signal-safe.
+pub(crate) struct Writer {
+    slot: Option<&'static Slot>,
+}
+
+impl Writer {
+    pub(crate) fn open() -> Self {
+        if own_slot().is_some() {
+            return Self { slot: None };
+        }
+        // SAFETY: gettid has no preconditions.
+        let tid = unsafe { crate::platform::syscall::sys_gettid() };
+        let slot = SLOTS.iter().find(|s| {
+            s.tid
+                .compare_exchange(0, tid, Ordering::AcqRel, Ordering::Relaxed)
+                .is_ok()
+        });
+        if slot.is_some() {
+            OPEN.fetch_add(1, Ordering::AcqRel);
+        }
+        Self { slot }
+    }
+}
+
+impl Drop for Writer {
+    fn drop(&mut self) {
+        // In a fork child the slot was released by `forget_writers` and
+        // the thread has a new tid, so it is no longer this writer's.
+        if let Some(slot) = self.slot
+            && own_slot().is_some_and(|own| core::ptr::eq(own, slot))
+        {
+            // SAFETY: This writer claimed the slot.
+            unsafe { slot.flush() };
+            OPEN.fetch_sub(1, Ordering::AcqRel);
+            slot.tid.store(0, Ordering::Release);
+        }
+    }
+}
+
+/// Write out the calling thread's open report, if any, before the process
+/// ends without unwinding to its `Writer`.
+fn flush_open() {
+    if let Some(slot) = own_slot() {
+        // SAFETY: own_slot only returns the slot this thread claimed.
+        unsafe { slot.flush() };
+    }
+}
+
 /// Format a usize as a 16-digit zero-padded hex string with 0x prefix.
 /// Writes into the provided 18-byte buffer and returns a slice of it.
 pub fn format_hex(value: usize, buf: &mut [u8; 18]) -> &[u8] {
@@ -43,89 +539,1547 @@ pub fn format_dec(value: usize, buf: &mut [u8; 20]) -> &[u8] {
     &buf[i..]
 }
 
+/// Format an isize as a variable-length decimal string, with a leading
+/// `-` when negative. Writes right-aligned into the provided 21-byte
+/// buffer and returns the populated slice.
+pub fn format_idec(value: isize, buf: &mut [u8; 21]) -> &[u8] {
+    // unsigned_abs, unlike negation, is defined for isize::MIN.
+    let mut dec = [0u8; 20];
+    let digits = format_dec(value.unsigned_abs(), &mut dec);
+    let mut start = buf.len() - digits.len();
+    buf[start..].copy_from_slice(digits);
+    if value < 0 {
+        start -= 1;
+        buf[start] = b'-';
+    }
+    &buf[start..]
+}
+
+/// Format a timespec as `[sec.nsec]`, nanoseconds zero-padded to 9 digits.
+/// Writes into the provided 32-byte buffer and returns a slice of it.
+pub fn format_timespec(ts: crate::platform::syscall::Timespec, buf: &mut [u8; 32]) -> &[u8] {
+    let mut dec = [0u8; 20];
+    let sec = format_dec(ts.tv_sec as usize, &mut dec);
+    buf[0] = b'[';
+    buf[1..1 + sec.len()].copy_from_slice(sec);
+    let mut len = 1 + sec.len();
+    buf[len] = b'.';
+    let mut nsec = ts.tv_nsec as usize;
+    for i in (len + 1..len + 10).rev() {
+        buf[i] = b'0' + (nsec % 10) as u8;
+        nsec /= 10;
+    }
+    len += 10;
+    buf[len] = b']';
+    &buf[..len + 1]
+}
+
+/// Fixed line buffer so a record goes out in a single `write`. Input past
+/// the end of the buffer is dropped.
+pub(crate) struct Line {
+    buf: [u8; 512],
+    len: usize,
+}
+
+impl Line {
+    pub(crate) const fn new() -> Self {
+        Self {
+            buf: [0; 512],
+            len: 0,
+        }
+    }
+
+    pub(crate) fn push(&mut self, bytes: &[u8]) {
+        let n = bytes.len().min(self.buf.len() - self.len);
+        self.buf[self.len..self.len + n].copy_from_slice(&bytes[..n]);
+        self.len += n;
+    }
+
+    pub(crate) fn push_dec(&mut self, value: usize) {
+        let mut buf = [0u8; 20];
+        self.push(format_dec(value, &mut buf));
+    }
+
+    pub(crate) fn push_hex(&mut self, value: usize) {
+        let mut buf = [0u8; 18];
+        self.push(format_hex(value, &mut buf));
+    }
+
+    pub(crate) fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+/// Format `value` with `format_hex` and write it immediately.
+///
+/// Prefer this over holding a `format_hex` slice: a buffer reused for a
+/// second value before the first is written silently clobbers it.
+pub fn write_hex(value: usize) {
+    let mut buf = [0u8; 18];
+    write_log(format_hex(value, &mut buf));
+}
+
+/// Format `value` with `format_dec` and write it immediately.
+pub fn write_dec(value: usize) {
+    let mut buf = [0u8; 20];
+    write_log(format_dec(value, &mut buf));
+}
+
+/// Write `len` bytes at `ptr` as rows of address, hex and printable ASCII.
+/// Each row goes out in one write from a stack buffer, so this is safe to
+/// call from a signal handler.
+///
+/// # Safety
+/// `ptr..ptr + len` must be readable.
+pub unsafe fn hex_dump(ptr: *const u8, len: usize) {
+    const ROW: usize = 16;
+    const DIGITS: &[u8; 16] = b"0123456789abcdef";
+    let mut offset = 0;
+    while offset < len {
+        let n = (len - offset).min(ROW);
+        let mut bytes = [0u8; ROW];
+        // SAFETY: offset + n <= len, readable per the caller.
+        unsafe { core::ptr::copy_nonoverlapping(ptr.add(offset), bytes.as_mut_ptr(), n) };
+
+        let mut line = Line::new();
+        line.push(b"    ");
+        line.push_hex(ptr as usize + offset);
+        line.push(b": ");
+        for (i, &b) in bytes.iter().enumerate() {
+            if i < n {
+                line.push(&[DIGITS[(b >> 4) as usize], DIGITS[(b & 0xF) as usize], b' ']);
+            } else {
+                line.push(b"   ");
+            }
+        }
+        line.push(b" |");
+        for &b in &bytes[..n] {
+            line.push(&[if b.is_ascii_graphic() || b == b' ' {
+                b
+            } else {
+                b'.'
+            }]);
+        }
+        line.push(b"|\n");
+        write_log(line.as_bytes());
+        offset += n;
+    }
+}
+
 fn kind_name(kind: AllocKind) -> &'static [u8] {
     match kind {
         AllocKind::Rust => b"rust alloc",
         AllocKind::ScalarNew => b"operator new",
         AllocKind::ArrayNew => b"operator new[]",
+        AllocKind::Malloc => b"malloc",
+    }
+}
+
+/// The deallocation that pairs with `kind`.
+fn free_name(kind: AllocKind) -> &'static [u8] {
+    match kind {
+        AllocKind::Rust => b"rust dealloc",
+        AllocKind::ScalarNew => b"operator delete",
+        AllocKind::ArrayNew => b"operator delete[]",
+        AllocKind::Malloc => b"free",
+    }
+}
+
+/// Annotate a wild address with the registered region it falls into, if any.
+fn write_region(addr: usize) {
+    if let Some((name, offset)) = crate::sanitize::region::find(addr) {
+        write_log(b"  region:  ");
+        write_log(name.as_bytes());
+        write_log(b" +");
+        write_dec(offset);
+        write_log(b"\n");
+    }
+}
+
+/// Write `SAN <code> <hex>\n` with a single write.
+fn write_code(code: Code, addr: usize) {
+    let mut line = [0u8; 28];
+    line[..4].copy_from_slice(b"SAN ");
+    let mut dec = [0u8; 20];
+    let digits = format_dec(code as usize, &mut dec);
+    let mut len = 4 + digits.len();
+    line[4..len].copy_from_slice(digits);
+    line[len] = b' ';
+    len += 1;
+    let mut hex = [0u8; 18];
+    line[len..len + 18].copy_from_slice(format_hex(addr, &mut hex));
+    len += 18;
+    line[len] = b'\n';
+    write_log(&line[..len + 1]);
+}
+
+fn code_abort(code: Code, addr: usize) -> ! {
+    write_code(code, addr);
+    die()
+}
+
+fn code_error(code: Code, addr: usize) {
+    write_code(code, addr);
+    survive();
+}
+
+/// `  allocated by tid A, freed by tid B`, then `, <current> tid C` for the
+/// calling thread when `current` names its role. Threads that were not
+/// recorded are left out, so this prints nothing without the `backtrace`
+/// feature. Signal-safe.
+fn write_threads(allocated: Tid, freed: Tid, current: Option<&[u8]>) {
+    let current = current.map(|role| (role, Tid::current()));
+    let parts = [(&b"allocated by"[..], allocated), (b"freed by", freed)];
+    let mut line = Line::new();
+    for (role, tid) in parts.into_iter().chain(current) {
+        let Some(id) = tid.get() else {
+            continue;
+        };
+        line.push(if line.len == 0 { b"  " } else { b", " });
+        line.push(role);
+        line.push(b" tid ");
+        line.push_dec(id);
+    }
+    if line.len != 0 {
+        line.push(b"\n");
+        write_log(line.as_bytes());
+    }
+}
+
+/// Allocation-site return addresses, one per line, for addr2line.
+/// With `symbols`, each is followed by the (demangled) exported symbol it
+/// falls in; pass `false` from a signal handler. Prints nothing when no
+/// backtrace was captured.
+fn write_frames(frames: &Frames, symbols: bool) {
+    for (i, addr) in frames.iter().enumerate() {
+        if i == 0 {
+            write_log(b"  allocated at:\n");
+        }
+        write_log(b"    #");
+        write_dec(i);
+        write_log(b" ");
+        write_hex(addr);
+        if symbols {
+            write_symbol(addr);
+        }
+        write_log(b"\n");
+    }
+}
+
+/// ` in <symbol> +<offset>`, demangled when possible.
+fn write_symbol(addr: usize) {
+    let Some((name, offset)) = crate::sanitize::backtrace::symbol(addr) else {
+        return;
+    };
+    let mut buf = [0u8; 512];
+    write_log(b" in ");
+    match crate::sanitize::demangle::demangle(name, &mut buf) {
+        Some(len) => write_log(&buf[..len]),
+        None => write_log(name),
     }
+    write_log(b" +");
+    write_dec(offset);
+}
+
+/// Open a JSON report: `{"error":"<error>","addr":"0x..."`.
+fn json_report(error: &[u8], addr: usize) -> Line {
+    let mut line = Line::new();
+    line.push(b"{\"error\":\"");
+    line.push(error);
+    line.push(b"\",\"addr\":\"");
+    line.push_hex(addr);
+    line.push(b"\"");
+    line
+}
+
+/// Append `,"frames":[...]` when a backtrace was captured.
+fn json_frames(line: &mut Line, frames: &Frames) {
+    let mut open = false;
+    for addr in frames.iter() {
+        line.push(if open { b",\"" } else { b",\"frames\":[\"" });
+        line.push_hex(addr);
+        line.push(b"\"");
+        open = true;
+    }
+    if open {
+        line.push(b"]");
+    }
+}
+
+fn json_finish(mut line: Line) {
+    line.push(b"}\n");
+    write_log(line.as_bytes());
+}
+
+fn json_error(line: Line) {
+    json_finish(line);
+    survive();
 }
 
 fn report_abort() -> ! {
-    write_stderr(b"aborting.\n\n");
-    // SAFETY: abort is provided by the C runtime.
-    unsafe { crate::platform::abort() }
+    write_log(b"aborting.\n\n");
+    die()
+}
+
+fn report_error() {
+    if halt_on_error() {
+        report_abort();
+    }
+    write_log(b"continuing.\n\n");
+    survive();
+}
+
+/// Abort, or count the error in recoverable mode.
+fn survive() {
+    if halt_on_error() {
+        die();
+    }
+    ERRORS.fetch_add(1, Ordering::Relaxed);
 }
 
 // --- Error reporters ---
-// Each prints a diagnostic to stderr and aborts.
+// Each prints a diagnostic to the log and aborts, or returns in recoverable
+// mode. The SIGSEGV reporters always abort.
 
-pub fn double_free(addr: usize) -> ! {
-    write_stderr(HEADER);
-    write_stderr(b"ERROR: double-free\n");
-    write_stderr(b"  address: ");
-    let mut buf = [0u8; 18];
-    write_stderr(format_hex(addr, &mut buf));
-    write_stderr(b"\n  This address was already freed and is still in quarantine.\n");
-    report_abort();
+pub fn double_free(addr: usize) {
+    if !notify(&ErrorInfo::new(ErrorKind::DoubleFree, addr)) {
+        return;
+    }
+    if MINIMAL {
+        return code_error(Code::DoubleFree, addr);
+    }
+    if json() {
+        return json_error(json_report(b"double-free", addr));
+    }
+    let _report = Writer::open();
+    write_header(ERROR, b"");
+    write_log(b"ERROR: double-free\n");
+    write_log(b"  address: ");
+    write_hex(addr);
+    write_log(b"\n  This address was already freed and is still in quarantine.\n");
+    let (allocated, freed) = crate::sanitize::quarantine::find_containing(addr)
+        .map_or((Tid::NONE, Tid::NONE), |b| (b.frames.tid, b.freed_by));
+    write_threads(allocated, freed, Some(b"freed again by"));
+    report_error();
 }
 
-pub fn invalid_free(addr: usize) -> ! {
-    write_stderr(HEADER);
-    write_stderr(b"ERROR: invalid free\n");
-    write_stderr(b"  address: ");
-    let mut buf = [0u8; 18];
-    write_stderr(format_hex(addr, &mut buf));
-    write_stderr(b"\n  This address was not returned by any tracked allocation.\n");
-    report_abort();
+pub fn invalid_free(addr: usize) {
+    if !notify(&ErrorInfo::new(ErrorKind::InvalidFree, addr)) {
+        return;
+    }
+    if MINIMAL {
+        return code_error(Code::InvalidFree, addr);
+    }
+    if json() {
+        return json_error(json_report(b"invalid-free", addr));
+    }
+    let _report = Writer::open();
+    write_header(ERROR, b"");
+    write_log(b"ERROR: invalid free\n");
+    write_log(b"  address: ");
+    write_hex(addr);
+    write_log(b"\n  This address was not returned by any tracked allocation.\n");
+    write_region(addr);
+    report_error();
 }
 
-pub fn mismatched_dealloc(addr: usize, expected: AllocKind, actual: AllocKind) -> ! {
-    write_stderr(HEADER);
-    write_stderr(b"ERROR: mismatched deallocation\n");
-    write_stderr(b"  address:        ");
-    let mut buf = [0u8; 18];
-    write_stderr(format_hex(addr, &mut buf));
-    write_stderr(b"\n  allocated with: ");
-    write_stderr(kind_name(expected));
-    write_stderr(b"\n  freed with:     ");
-    write_stderr(kind_name(actual));
-    write_stderr(b"\n");
-    report_abort();
+pub fn interior_free(addr: usize, block: usize) {
+    let alloc = crate::sanitize::tracker::allocation(block);
+    if !notify(
+        &ErrorInfo::new(ErrorKind::InteriorFree, addr)
+            .with_block(block, alloc.map_or(0, |a| a.size)),
+    ) {
+        return;
+    }
+    if MINIMAL {
+        return code_error(Code::InvalidFree, addr);
+    }
+    if json() {
+        let mut line = json_report(b"interior-free", addr);
+        line.push(b",\"block\":\"");
+        line.push_hex(block);
+        line.push(b"\",\"offset\":");
+        line.push_dec(addr - block);
+        if let Some(alloc) = alloc {
+            line.push(b",\"size\":");
+            line.push_dec(alloc.size);
+            json_frames(&mut line, &alloc.frames);
+        }
+        return json_error(line);
+    }
+    let _report = Writer::open();
+    write_header(ERROR, b"");
+    write_log(b"ERROR: free of interior pointer\n");
+    write_log(b"  address: ");
+    write_hex(addr);
+    write_log(b"\n  -> ");
+    write_dec(addr - block);
+    write_log(b" bytes into block ");
+    write_hex(block);
+    if let Some(alloc) = alloc {
+        write_log(b" (");
+        write_dec(alloc.size);
+        write_log(b" bytes, ");
+        write_log(kind_name(alloc.kind));
+        write_log(b")\n");
+        write_frames(&alloc.frames, true);
+    } else {
+        write_log(b"\n");
+    }
+    report_error();
 }
 
-pub fn overflow_detected(
+pub fn mismatched_dealloc(addr: usize, expected: AllocKind, actual: AllocKind) {
+    if !notify(&ErrorInfo::new(ErrorKind::MismatchedDealloc, addr)) {
+        return;
+    }
+    if MINIMAL {
+        return code_error(Code::MismatchedDealloc, addr);
+    }
+    if json() {
+        let mut line = json_report(b"mismatched-dealloc", addr);
+        line.push(b",\"allocated\":\"");
+        line.push(kind_name(expected));
+        line.push(b"\",\"freed\":\"");
+        line.push(free_name(actual));
+        line.push(b"\"");
+        return json_error(line);
+    }
+    let _report = Writer::open();
+    write_header(ERROR, b"");
+    write_log(b"ERROR: mismatched deallocation\n");
+    write_log(b"  address:        ");
+    write_hex(addr);
+    write_log(b"\n  allocated with: ");
+    write_log(kind_name(expected));
+    write_log(b"\n  freed with:     ");
+    write_log(free_name(actual));
+    write_log(b"\n");
+    let allocated = crate::sanitize::tracker::allocation(addr).map_or(Tid::NONE, |a| a.frames.tid);
+    write_threads(allocated, Tid::NONE, Some(b"freed by"));
+    report_error();
+}
+
+pub fn realloc_of_cxx_allocation(addr: usize, kind: AllocKind) {
+    if !notify(&ErrorInfo::new(ErrorKind::ReallocOfCxx, addr)) {
+        return;
+    }
+    if MINIMAL {
+        return code_error(Code::ReallocOfCxx, addr);
+    }
+    if json() {
+        let mut line = json_report(b"realloc-of-cxx", addr);
+        line.push(b",\"allocated\":\"");
+        line.push(kind_name(kind));
+        line.push(b"\"");
+        return json_error(line);
+    }
+    let _report = Writer::open();
+    write_header(ERROR, b"");
+    write_log(b"ERROR: realloc of memory from a C++ allocation\n");
+    write_log(b"  address:        ");
+    write_hex(addr);
+    write_log(b"\n  allocated with: ");
+    write_log(kind_name(kind));
+    write_log(b"\n");
+    report_error();
+}
+
+pub fn size_mismatch(addr: usize, tracked: usize, claimed: usize) {
+    if !notify(&ErrorInfo {
+        claimed_size: claimed,
+        ..ErrorInfo::new(ErrorKind::SizeMismatch, addr).with_block(addr, tracked)
+    }) {
+        return;
+    }
+    if MINIMAL {
+        return code_error(Code::SizeMismatch, addr);
+    }
+    if json() {
+        let mut line = json_report(b"size-mismatch", addr);
+        line.push(b",\"size\":");
+        line.push_dec(tracked);
+        line.push(b",\"delete_size\":");
+        line.push_dec(claimed);
+        return json_error(line);
+    }
+    let _report = Writer::open();
+    write_header(ERROR, b"");
+    write_log(b"ERROR: sized delete size mismatch\n");
+    write_log(b"  address:      ");
+    write_hex(addr);
+    write_log(b"\n  allocated:    ");
+    write_dec(tracked);
+    write_log(b" bytes\n  delete size:  ");
+    write_dec(claimed);
+    write_log(b" bytes\n");
+    report_error();
+}
+
+/// Red zone bytes next to the user region shown in an overflow report.
+const DUMP_REDZONE: usize = 32;
+/// User bytes shown alongside them.
+const DUMP_USER: usize = 8;
+
+/// # Safety
+/// The user region at `addr` and both red zones must still be mapped.
+pub unsafe fn overflow_detected(
     addr: usize,
     size: usize,
     prefix_corrupt: bool,
     suffix_corrupt: bool,
-) -> ! {
-    write_stderr(HEADER);
-    write_stderr(b"ERROR: buffer overflow detected (red zone corruption)\n");
-    write_stderr(b"  address: ");
-    let mut hex_buf = [0u8; 18];
-    write_stderr(format_hex(addr, &mut hex_buf));
-    write_stderr(b"\n  size:    ");
-    let mut dec_buf = [0u8; 20];
-    write_stderr(format_dec(size, &mut dec_buf));
-    write_stderr(b" bytes\n");
+    overrun: usize,
+) {
+    if !notify(&ErrorInfo::new(ErrorKind::Overflow, addr).with_block(addr, size)) {
+        return;
+    }
+    if MINIMAL {
+        return code_error(Code::Overflow, addr);
+    }
+    if json() {
+        let mut line = json_report(b"overflow", addr);
+        line.push(b",\"size\":");
+        line.push_dec(size);
+        line.push(b",\"prefix\":");
+        line.push(if prefix_corrupt { b"true" } else { b"false" });
+        line.push(b",\"suffix\":");
+        line.push(if suffix_corrupt { b"true" } else { b"false" });
+        line.push(b",\"overrun\":");
+        line.push_dec(overrun);
+        if let Some(alloc) = crate::sanitize::tracker::allocation(addr) {
+            json_frames(&mut line, &alloc.frames);
+        }
+        return json_error(line);
+    }
+    let _report = Writer::open();
+    write_header(ERROR, b"");
+    write_log(b"ERROR: buffer overflow detected (red zone corruption)\n");
+    write_log(b"  address: ");
+    write_hex(addr);
+    write_log(b"\n  size:    ");
+    write_dec(size);
+    write_log(b" bytes\n");
+    let prefix = crate::sanitize::redzone::size().min(DUMP_REDZONE);
+    let suffix = crate::sanitize::redzone::zone_size(size).min(DUMP_REDZONE);
+    let user = size.min(DUMP_USER);
+    if prefix_corrupt {
+        write_log(b"  -> underflow: prefix red zone corrupted\n");
+        // SAFETY: The prefix red zone ends at addr (caller guarantees).
+        unsafe { hex_dump((addr - prefix) as *const u8, prefix + user) };
+    }
+    if suffix_corrupt {
+        write_log(b"  -> overflow of at least ");
+        write_dec(overrun);
+        write_log(b" bytes past the end (suffix red zone corrupted)\n");
+        // SAFETY: The suffix red zone starts at addr + size (caller guarantees).
+        unsafe { hex_dump((addr + size - user) as *const u8, user + suffix) };
+    }
+    if let Some(alloc) = crate::sanitize::tracker::allocation(addr) {
+        write_frames(&alloc.frames, true);
+    }
+    report_error();
+}
+
+pub fn write_after_free(
+    block: &crate::sanitize::quarantine::FreedBlock,
+    first_bad: Option<usize>,
+    prefix_corrupt: bool,
+    suffix_corrupt: bool,
+) {
+    if !notify(
+        &ErrorInfo::new(ErrorKind::WriteAfterFree, block.user_addr)
+            .with_block(block.user_addr, block.user_size),
+    ) {
+        return;
+    }
+    if MINIMAL {
+        return code_error(Code::WriteAfterFree, block.user_addr);
+    }
+    if json() {
+        let mut line = json_report(b"write-after-free", block.user_addr);
+        line.push(b",\"size\":");
+        line.push_dec(block.user_size);
+        line.push(b",\"kind\":\"");
+        line.push(kind_name(block.kind));
+        line.push(b"\"");
+        if let Some(offset) = first_bad {
+            line.push(b",\"offset\":");
+            line.push_dec(offset);
+        }
+        line.push(b",\"prefix\":");
+        line.push(if prefix_corrupt { b"true" } else { b"false" });
+        line.push(b",\"suffix\":");
+        line.push(if suffix_corrupt { b"true" } else { b"false" });
+        json_frames(&mut line, &block.frames);
+        return json_error(line);
+    }
+    let _report = Writer::open();
+    write_header(ERROR, b"");
+    write_log(b"ERROR: write-after-free (freed block modified in quarantine)\n");
+    write_log(b"  block:          ");
+    write_hex(block.user_addr);
+    write_log(b"\n  size:           ");
+    write_dec(block.user_size);
+    write_log(b" bytes\n  allocated with: ");
+    write_log(kind_name(block.kind));
+    write_log(b"\n");
+    if let Some(offset) = first_bad {
+        write_log(b"  -> first modified byte at ");
+        write_hex(block.user_addr + offset);
+        write_log(b" (");
+        write_dec(offset);
+        write_log(b" bytes into block)\n");
+    }
     if prefix_corrupt {
-        write_stderr(b"  -> underflow: prefix red zone corrupted\n");
+        write_log(b"  -> prefix red zone corrupted\n");
     }
     if suffix_corrupt {
-        write_stderr(b"  -> overflow: suffix red zone corrupted\n");
+        write_log(b"  -> suffix red zone corrupted\n");
+    }
+    write_threads(block.frames.tid, block.freed_by, None);
+    write_frames(&block.frames, true);
+    report_error();
+}
+
+/// An iterator over `container` was used after the container changed.
+pub fn iterator_invalidated(container: usize, captured: u64, current: u64) {
+    if !notify(&ErrorInfo::new(ErrorKind::IteratorInvalidated, container)) {
+        return;
+    }
+    if MINIMAL {
+        return code_error(Code::IteratorInvalidated, container);
+    }
+    if json() {
+        let mut line = json_report(b"iterator-invalidated", container);
+        line.push(b",\"iterator_epoch\":");
+        line.push_dec(captured as usize);
+        line.push(b",\"container_epoch\":");
+        line.push_dec(current as usize);
+        return json_error(line);
+    }
+    let _report = Writer::open();
+    write_header(ERROR, b"");
+    write_log(b"ERROR: iterator invalidated by mutation\n");
+    write_log(b"  container:       ");
+    write_hex(container);
+    write_log(b"\n  iterator epoch:  ");
+    write_dec(captured as usize);
+    write_log(b"\n  container epoch: ");
+    write_dec(current as usize);
+    write_log(b"\n");
+    report_error();
+}
+
+/// Called from the SIGSEGV handler: no locks, raw writes only.
+pub fn use_after_free(addr: usize, block: &crate::sanitize::quarantine::FreedBlock) -> ! {
+    error_hook::notify(
+        &ErrorInfo::new(ErrorKind::UseAfterFree, addr).with_block(block.user_addr, block.user_size),
+    );
+    if MINIMAL {
+        code_abort(Code::UseAfterFree, addr);
+    }
+    if json() {
+        let mut line = json_report(b"use-after-free", addr);
+        line.push(b",\"block\":\"");
+        line.push_hex(block.user_addr);
+        line.push(b"\",\"offset\":");
+        line.push_dec(addr - block.user_addr);
+        line.push(b",\"size\":");
+        line.push_dec(block.user_size);
+        line.push(b",\"kind\":\"");
+        line.push(kind_name(block.kind));
+        line.push(b"\"");
+        json_frames(&mut line, &block.frames);
+        json_finish(line);
+        die();
     }
+    let _report = Writer::open();
+    write_header(ERROR, b"");
+    write_log(b"ERROR: use-after-free (SIGSEGV)\n");
+    write_log(b"  address:        ");
+    write_hex(addr);
+    write_log(b" (");
+    write_dec(addr - block.user_addr);
+    write_log(b" bytes into block)\n  block:          ");
+    write_hex(block.user_addr);
+    write_log(b"\n  size:           ");
+    write_dec(block.user_size);
+    write_log(b" bytes\n  allocated with: ");
+    write_log(kind_name(block.kind));
+    write_log(b"\n");
+    write_threads(block.frames.tid, block.freed_by, Some(b"faulted in"));
+    write_frames(&block.frames, false);
     report_abort();
 }
 
-pub fn leak_detected(addr: usize, size: usize, kind: AllocKind) {
-    write_stderr(b"  LEAK: ");
-    let mut hex_buf = [0u8; 18];
-    write_stderr(format_hex(addr, &mut hex_buf));
-    write_stderr(b"  size=");
-    let mut dec_buf = [0u8; 20];
-    write_stderr(format_dec(size, &mut dec_buf));
-    write_stderr(b"  via=");
-    write_stderr(kind_name(kind));
-    write_stderr(b"\n");
+/// Called from the SIGSEGV handler: no locks, raw writes only.
+pub fn segv(addr: usize) -> ! {
+    error_hook::notify(&ErrorInfo::new(ErrorKind::Segv, addr));
+    if MINIMAL {
+        code_abort(Code::Segv, addr);
+    }
+    if json() {
+        json_finish(json_report(b"segv", addr));
+        die();
+    }
+    let _report = Writer::open();
+    write_header(ERROR, b"");
+    write_log(b"ERROR: SIGSEGV on unknown address\n");
+    write_log(b"  address: ");
+    write_hex(addr);
+    write_log(b"\n");
+    report_abort();
+}
+
+/// A virtual call that reached `__cxa_pure_virtual` (`deleted == false`)
+/// or `__cxa_deleted_virtual`.
+pub fn bad_virtual_call(deleted: bool) -> ! {
+    if MINIMAL {
+        let code = if deleted {
+            Code::DeletedVirtual
+        } else {
+            Code::PureVirtual
+        };
+        code_abort(code, 0);
+    }
+    if json() {
+        let error: &[u8] = if deleted {
+            b"deleted-virtual"
+        } else {
+            b"pure-virtual"
+        };
+        json_finish(json_report(error, 0));
+        die();
+    }
+    let _report = Writer::open();
+    write_header(ERROR, b"");
+    write_log(if deleted {
+        b"ERROR: deleted virtual function called\n"
+    } else {
+        b"ERROR: pure virtual function called\n"
+    });
+    report_abort();
+}
+
+/// Exit-time count of the errors survived in recoverable mode.
+pub fn error_summary(count: usize) {
+    if MINIMAL {
+        return;
+    }
+    if json() {
+        let mut line = Line::new();
+        line.push(b"{\"errors\":");
+        line.push_dec(count);
+        return json_finish(line);
+    }
+    let _report = Writer::open();
+    write_header(ERROR, b": summary");
+    write_log(b"  ");
+    write_dec(count);
+    write_log(if count == 1 {
+        b" error detected\n\n"
+    } else {
+        b" errors detected\n\n"
+    });
+}
+
+/// Paranoid-mode hint; not fatal.
+pub fn canary_fill_hint(addr: usize, size: usize) {
+    if MINIMAL {
+        return write_code(Code::CanaryFillHint, addr);
+    }
+    if json() {
+        let mut line = json_report(b"canary-fill-hint", addr);
+        line.push(b",\"size\":");
+        line.push_dec(size);
+        return json_finish(line);
+    }
+    let _report = Writer::open();
+    write_header(ERROR, b"");
+    write_log(b"HINT: block is filled with the red zone canary byte\n");
+    write_log(b"  address: ");
+    write_hex(addr);
+    write_log(b"\n  size:    ");
+    write_dec(size);
+    write_log(
+        b" bytes\n  A memset with the canary value that ran past the end would go undetected.\n",
+    );
+}
+
+/// Dead-allocation hint: a block freed without ever being read or written.
+/// Not fatal.
+pub fn dead_allocation(addr: usize, size: usize, kind: AllocKind, frames: &Frames) {
+    if MINIMAL {
+        return write_code(Code::DeadAllocation, addr);
+    }
+    if json() {
+        let mut line = json_report(b"dead-allocation", addr);
+        line.push(b",\"size\":");
+        line.push_dec(size);
+        line.push(b",\"kind\":\"");
+        line.push(kind_name(kind));
+        line.push(b"\"");
+        json_frames(&mut line, frames);
+        return json_finish(line);
+    }
+    let _report = Writer::open();
+    write_header(NOTICE, b"");
+    write_log(b"HINT: block freed without ever being accessed\n");
+    write_log(b"  address: ");
+    write_hex(addr);
+    write_log(b"\n  size:    ");
+    write_dec(size);
+    write_log(b" bytes via ");
+    write_log(kind_name(kind));
+    write_log(b"\n");
+    write_threads(frames.tid, Tid::NONE, None);
+    write_frames(frames, true);
+}
+
+/// One corrupt live block found by a heap sweep. Not fatal, so a sweep
+/// can report every corrupt block.
+pub fn heap_corruption(addr: usize, size: usize, prefix_corrupt: bool, suffix_corrupt: bool) {
+    if MINIMAL {
+        return write_code(Code::HeapCorruption, addr);
+    }
+    if json() {
+        let mut line = json_report(b"heap-corruption", addr);
+        line.push(b",\"size\":");
+        line.push_dec(size);
+        line.push(b",\"prefix\":");
+        line.push(if prefix_corrupt { b"true" } else { b"false" });
+        line.push(b",\"suffix\":");
+        line.push(if suffix_corrupt { b"true" } else { b"false" });
+        return json_finish(line);
+    }
+    write_log(b"  CORRUPT: ");
+    write_hex(addr);
+    write_log(b"  size=");
+    write_dec(size);
+    if prefix_corrupt {
+        write_log(b"  prefix");
+    }
+    if suffix_corrupt {
+        write_log(b"  suffix");
+    }
+    write_log(b" red zone\n");
+}
+
+/// Not fatal: a dangling pointer itself is not yet a memory error.
+pub fn dangling_pointer(
+    addr: usize,
+    block: &crate::sanitize::quarantine::FreedBlock,
+    header_intact: bool,
+) {
+    if MINIMAL {
+        return write_code(Code::DanglingPointer, addr);
+    }
+    if json() {
+        let mut line = json_report(b"dangling-pointer", addr);
+        line.push(b",\"block\":\"");
+        line.push_hex(block.user_addr);
+        line.push(b"\",\"offset\":");
+        line.push_dec(addr - block.user_addr);
+        line.push(b",\"size\":");
+        line.push_dec(block.user_size);
+        line.push(b",\"kind\":\"");
+        line.push(kind_name(block.kind));
+        line.push(b"\",\"generation\":");
+        line.push_dec(block.generation as usize);
+        line.push(b",\"header_intact\":");
+        line.push(if header_intact { b"true" } else { b"false" });
+        json_frames(&mut line, &block.frames);
+        return json_finish(line);
+    }
+    let _report = Writer::open();
+    write_header(ERROR, b"");
+    write_log(b"WARNING: dangling pointer into freed memory\n");
+    write_log(b"  pointer:        ");
+    write_hex(addr);
+    write_log(b" (");
+    write_dec(addr - block.user_addr);
+    write_log(b" bytes into block)\n  block:          ");
+    write_hex(block.user_addr);
+    write_log(b"\n  size:           ");
+    write_dec(block.user_size);
+    write_log(b" bytes\n  allocated with: ");
+    write_log(kind_name(block.kind));
+    write_log(b"\n  freed in generation ");
+    write_dec(block.generation as usize);
+    if header_intact {
+        write_log(b"\n");
+    } else {
+        write_log(b" (tripwire header overwritten since)\n");
+    }
+    write_frames(&block.frames, true);
+}
+
+pub fn leak_detected(addr: usize, size: usize, kind: AllocKind, frames: &Frames) {
+    if MINIMAL {
+        return write_code(Code::Leak, addr);
+    }
+    if json() {
+        let mut line = json_report(b"leak", addr);
+        line.push(b",\"size\":");
+        line.push_dec(size);
+        line.push(b",\"kind\":\"");
+        line.push(kind_name(kind));
+        line.push(b"\"");
+        json_frames(&mut line, frames);
+        return json_finish(line);
+    }
+    write_log(b"  LEAK: ");
+    write_hex(addr);
+    write_log(b"  size=");
+    write_dec(size);
+    write_log(b"  via=");
+    write_log(kind_name(kind));
+    write_log(b"\n");
+    write_threads(frames.tid, Tid::NONE, None);
+    write_frames(frames, true);
+}
+
+/// `count` leaks of `bytes` in total from one allocation site, `first`
+/// being one of them.
+pub fn leak_site(first: usize, kind: AllocKind, count: usize, bytes: usize, frames: &Frames) {
+    if MINIMAL {
+        return write_code(Code::Leak, first);
+    }
+    if json() {
+        let mut line = json_report(b"leak", first);
+        line.push(b",\"kind\":\"");
+        line.push(kind_name(kind));
+        line.push(b"\",\"count\":");
+        line.push_dec(count);
+        line.push(b",\"bytes\":");
+        line.push_dec(bytes);
+        json_frames(&mut line, frames);
+        return json_finish(line);
+    }
+    write_log(b"  LEAK: ");
+    write_dec(count);
+    write_log(b" blocks  bytes=");
+    write_dec(bytes);
+    write_log(b"  via=");
+    write_log(kind_name(kind));
+    write_log(b"  first=");
+    write_hex(first);
+    write_log(b"\n");
+    write_frames(frames, true);
+}
+
+/// Process-wide memory context for the leak report footer.
+pub fn process_memory(status: crate::sanitize::procfs::MemStatus) {
+    if !framed() {
+        return;
+    }
+    write_log(b"  process: VmRSS=");
+    write_dec(status.rss_kb);
+    write_log(b" kB  VmHWM=");
+    write_dec(status.hwm_kb);
+    write_log(b" kB\n");
+}
+
+/// `value` right-aligned in a field of `width` columns.
+fn write_dec_padded(value: usize, width: usize) {
+    let mut buf = [0u8; 20];
+    let digits = format_dec(value, &mut buf);
+    for _ in digits.len()..width {
+        write_log(b" ");
+    }
+    write_log(digits);
+}
+
+fn stat_line(label: &[u8], value: usize) {
+    write_log(label);
+    write_dec_padded(value, 10);
+    write_log(b"\n");
+}
+
+/// Allocation counters in glibc's `malloc_stats` layout. Printed in full
+/// even with `minimal-diagnostics`, since it is only ever asked for.
+pub fn malloc_stats(
+    stats: &crate::sanitize::tracker::Stats,
+    quarantine: &crate::sanitize::quarantine::Stats,
+) {
+    let _report = Writer::open();
+    stat_line(b"live allocations = ", stats.live_count);
+    stat_line(b"live bytes       = ", stats.live_bytes);
+    stat_line(b"peak bytes       = ", stats.peak_bytes);
+    stat_line(b"total allocs     = ", stats.total_allocs as usize);
+    stat_line(b"total frees      = ", stats.total_frees as usize);
+    write_log(b"by kind:                live      total\n");
+    for kind in AllocKind::ALL {
+        let name = kind_name(kind);
+        let counts = stats.by_kind[kind as usize];
+        write_log(b"  ");
+        write_log(name);
+        for _ in name.len()..16 {
+            write_log(b" ");
+        }
+        write_dec_padded(counts.live_count, 10);
+        write_log(b" ");
+        write_dec_padded(counts.total_allocs as usize, 10);
+        write_log(b"\n");
+    }
+    write_log(b"quarantine:              now       peak\n");
+    for (name, now, peak) in [
+        (
+            b"blocks".as_slice(),
+            quarantine.entries,
+            quarantine.peak_entries,
+        ),
+        (b"bytes", quarantine.bytes, quarantine.peak_bytes),
+    ] {
+        write_log(b"  ");
+        write_log(name);
+        for _ in name.len()..16 {
+            write_log(b" ");
+        }
+        write_dec_padded(now, 10);
+        write_log(b" ");
+        write_dec_padded(peak, 10);
+        write_log(b"\n");
+    }
+}
+
+/// One leak as a single-line JSON object (JSON Lines). `age` is the number
+/// of allocations made after this one; `tag` is `null` for untagged blocks.
+pub fn leak_json_line(
+    addr: usize,
+    size: usize,
+    kind: AllocKind,
+    tag: Option<&str>,
+    seq: u64,
+    age: u64,
+) {
+    if MINIMAL {
+        return write_code(Code::Leak, addr);
+    }
+    write_log(b"{\"addr\":\"");
+    write_hex(addr);
+    write_log(b"\",\"size\":");
+    write_dec(size);
+    write_log(b",\"kind\":\"");
+    write_log(kind_name(kind));
+    write_log(b"\",\"tag\":");
+    match tag {
+        Some(tag) => write_json_string(tag.as_bytes()),
+        None => write_log(b"null"),
+    }
+    write_log(b",\"seq\":");
+    write_dec(seq as usize);
+    write_log(b",\"age\":");
+    write_dec(age as usize);
+    write_log(b"}\n");
+}
+
+/// `s` as a quoted JSON string, escaping quotes, backslashes and control
+/// characters.
+fn write_json_string(s: &[u8]) {
+    const HEX: &[u8; 16] = b"0123456789abcdef";
+    write_log(b"\"");
+    let mut start = 0;
+    for (i, &b) in s.iter().enumerate() {
+        if b != b'"' && b != b'\\' && b >= 0x20 {
+            continue;
+        }
+        write_log(&s[start..i]);
+        match b {
+            b'"' => write_log(b"\\\""),
+            b'\\' => write_log(b"\\\\"),
+            _ => write_log(&[
+                b'\\',
+                b'u',
+                b'0',
+                b'0',
+                HEX[usize::from(b >> 4)],
+                HEX[usize::from(b & 0xf)],
+            ]),
+        }
+        start = i + 1;
+    }
+    write_log(&s[start..]);
+    write_log(b"\"");
+}
+
+/// Cached process id; 0 until first read and again in a forked child.
+static PID: AtomicUsize = AtomicUsize::new(0);
+
+pub(crate) fn pid() -> usize {
+    let mut pid = PID.load(Ordering::Relaxed);
+    if pid == 0 {
+        // SAFETY: getpid has no preconditions.
+        pid = unsafe { crate::platform::syscall::sys_getpid() };
+        PID.store(pid, Ordering::Relaxed);
+    }
+    pid
+}
+
+/// Forget the cached PID, so a `fork` child's reports carry its own.
+pub fn forget_pid() {
+    PID.store(0, Ordering::Relaxed);
+}
+
+/// Release every report slot, for the child of `fork`: the threads that
+/// held them are gone, and the forking thread has a new tid. Their
+/// buffered output is dropped; the parent still writes it.
+///
+/// # Safety
+/// No other thread may be running, as in a `fork` child.
+pub unsafe fn forget_writers() {
+    for slot in &SLOTS {
+        // SAFETY: With a single thread no slot is being written.
+        unsafe { *slot.len.get() = 0 };
+        slot.tid.store(0, Ordering::Release);
+    }
+    OPEN.store(0, Ordering::Release);
+}
+
+/// Write `=== libcplusplus sanitizer [pid N]<title> ===` after a blank line,
+/// in `color` when colour is on and prefixed with a timestamp when enabled.
+fn write_header(color: &[u8], title: &[u8]) {
+    let mut line = Line::new();
+    line.push(b"\n");
+    if timestamps() {
+        let mut ts = crate::platform::syscall::Timespec::default();
+        // SAFETY: ts is a valid, writable Timespec.
+        unsafe {
+            crate::platform::syscall::sys_clock_gettime(
+                crate::platform::syscall::CLOCK_MONOTONIC,
+                &mut ts,
+            )
+        };
+        let mut buf = [0u8; 32];
+        line.push(format_timespec(ts, &mut buf));
+        line.push(b" ");
+    }
+    let color = colored().then_some(color);
+    if let Some(color) = color {
+        line.push(b"\x1b[");
+        line.push(color);
+        line.push(b"m");
+    }
+    line.push(b"=== libcplusplus sanitizer [pid ");
+    line.push_dec(pid());
+    line.push(b"]");
+    line.push(title);
+    line.push(b" ===");
+    if color.is_some() {
+        line.push(b"\x1b[0m");
+    }
+    line.push(b"\n");
+    write_log(line.as_bytes());
+}
+
+// --- Report framing ---
+// Headers and totals around the per-block lines above. Dropped entirely
+// in minimal builds.
+
+pub fn heap_verification_header() {
+    if framed() {
+        write_header(ERROR, b": heap verification");
+    }
+}
+
+pub fn leak_report_header() {
+    if framed() {
+        write_header(NOTICE, b": leak report");
+    }
+}
+
+pub fn scope_leak_header() {
+    if framed() {
+        write_header(NOTICE, b": scope leak report");
+    }
+}
+
+/// `  <label>: <count>` followed by `trailer`.
+pub fn report_total(label: &[u8], count: usize, trailer: &[u8]) {
+    if framed() {
+        write_log(b"  ");
+        write_log(label);
+        write_log(b": ");
+        write_dec(count);
+        write_log(trailer);
+    }
+}
+
+/// `  by kind: rust alloc: 3, operator new: 1, ...` for every family, in
+/// one write.
+pub fn report_kinds(counts: &[usize; AllocKind::COUNT]) {
+    if framed() {
+        let mut line = Line::new();
+        line.push(b"  by kind: ");
+        for (i, kind) in AllocKind::ALL.into_iter().enumerate() {
+            if i != 0 {
+                line.push(b", ");
+            }
+            line.push(kind_name(kind));
+            line.push(b": ");
+            line.push_dec(counts[kind as usize]);
+        }
+        line.push(b"\n");
+        write_log(line.as_bytes());
+    }
+}
+
+pub fn report_end() {
+    if framed() {
+        write_log(b"\n");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::testing;
+    use core::alloc::Layout;
+
+    /// Call the reporter that prints code `code`.
+    #[cfg(feature = "minimal-diagnostics")]
+    fn report_code(code: u8, block: &crate::sanitize::quarantine::FreedBlock) {
+        let addr = block.user_addr;
+        let frames = &Frames::EMPTY;
+        match code {
+            1 => double_free(addr),
+            2 => invalid_free(addr),
+            3 => mismatched_dealloc(addr, AllocKind::ArrayNew, AllocKind::ScalarNew),
+            // SAFETY: `block` describes a live allocation, red zones and all.
+            4 => unsafe { overflow_detected(addr, block.user_size, false, true, 1) },
+            5 => realloc_of_cxx_allocation(addr, AllocKind::ScalarNew),
+            6 => heap_corruption(addr, block.user_size, true, false),
+            7 => leak_detected(addr, block.user_size, block.kind, frames),
+            8 => dangling_pointer(addr, block, true),
+            9 => canary_fill_hint(addr, block.user_size),
+            10 => size_mismatch(addr, block.user_size, 2 * block.user_size),
+            11 => use_after_free(addr, block),
+            12 => segv(addr),
+            13 => write_after_free(block, Some(addr), false, false),
+            14 => bad_virtual_call(false),
+            15 => bad_virtual_call(true),
+            16 => iterator_invalidated(addr, 1, 2),
+            17 => dead_allocation(addr, block.user_size, block.kind, frames),
+            _ => unreachable!("no code {code}"),
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "minimal-diagnostics")]
+    fn minimal_reports_are_one_code_line_and_keep_their_outcome() {
+        const TEST: &str =
+            "sanitize::diagnostic::tests::minimal_reports_are_one_code_line_and_keep_their_outcome";
+        if testing::in_child(TEST) {
+            let code: u8 = std::env::var("REPORT_CODE").unwrap().parse().unwrap();
+            let layout = Layout::from_size_align(32, 8).unwrap();
+            // SAFETY: Leaked; only its address and red zones are read.
+            let ptr = unsafe { crate::sanitize::sanitized_alloc(layout) };
+            std::eprintln!("addr={:#018x}", ptr as usize);
+            let block = crate::sanitize::quarantine::FreedBlock {
+                user_addr: ptr as usize,
+                base_addr: ptr as usize,
+                user_size: 32,
+                align: 8,
+                kind: AllocKind::Rust,
+                generation: 1,
+                frames: Frames::EMPTY,
+                freed_by: Tid::NONE,
+            };
+            report_code(code, &block);
+            return;
+        }
+        // The fatal column of the table on `Code`.
+        let fatal = [1, 2, 3, 4, 5, 10, 11, 12, 13, 14, 15, 16];
+        for code in 1..=17u8 {
+            let child = testing::spawn(
+                TEST,
+                &[
+                    ("REPORT_CODE", &std::format!("{code}")),
+                    ("LIBCPP_EXITCODE", "23"),
+                ],
+            );
+            let stderr = child.stderr();
+            let (first, report) = stderr.split_once('\n').unwrap();
+            let addr = first.strip_prefix("addr=").expect(first);
+            // Virtual calls have no address to report.
+            let addr = if matches!(code, 14 | 15) {
+                "0x0000000000000000"
+            } else {
+                addr
+            };
+            let line = std::format!("SAN {code} {addr}\n");
+            assert!(report.starts_with(&line), "code {code}: {stderr}");
+            if fatal.contains(&code) {
+                assert_eq!(child.code(), Some(23), "code {code}: {stderr}");
+                assert_eq!(report, line, "code {code}");
+            } else {
+                assert_eq!(child.code(), Some(0), "code {code}: {stderr}");
+            }
+        }
+    }
+
+    /// Parse one flat JSON object whose values are strings, numbers,
+    /// booleans, `null` or arrays of strings, returning each key with its
+    /// raw value text. Panics on anything else.
+    fn json_object(line: &str) -> std::vec::Vec<(&str, &str)> {
+        fn string(s: &str) -> usize {
+            let bytes = s.as_bytes();
+            assert_eq!(bytes[0], b'"', "{s}");
+            let mut i = 1;
+            while bytes[i] != b'"' {
+                i += if bytes[i] == b'\\' { 2 } else { 1 };
+            }
+            i + 1
+        }
+        fn value(s: &str) -> usize {
+            match s.as_bytes()[0] {
+                b'"' => string(s),
+                b'[' => {
+                    let mut i = 1;
+                    while s.as_bytes()[i] != b']' {
+                        i += string(&s[i..]);
+                        if s.as_bytes()[i] == b',' {
+                            i += 1;
+                        }
+                    }
+                    i + 1
+                }
+                _ => {
+                    let end = s.find([',', '}']).unwrap_or(s.len());
+                    let word = &s[..end];
+                    assert!(
+                        ["true", "false", "null"].contains(&word) || word.parse::<u64>().is_ok(),
+                        "bad value {word}"
+                    );
+                    end
+                }
+            }
+        }
+        let mut rest = line.strip_prefix('{').expect(line);
+        let mut members = std::vec::Vec::new();
+        loop {
+            let key = string(rest);
+            let (name, after) = rest.split_at(key);
+            let after = after.strip_prefix(':').expect(line);
+            let len = value(after);
+            members.push((&name[1..name.len() - 1], &after[..len]));
+            match after.as_bytes()[len] {
+                b',' => rest = &after[len + 1..],
+                b'}' => {
+                    assert_eq!(len + 1, after.len(), "trailing text in {line}");
+                    return members;
+                }
+                _ => panic!("bad separator in {line}"),
+            }
+        }
+    }
+
+    #[test]
+    #[cfg_attr(
+        feature = "minimal-diagnostics",
+        ignore = "minimal reports are not JSON"
+    )]
+    fn json_reports_are_one_object_per_line() {
+        const TEST: &str = "sanitize::diagnostic::tests::json_reports_are_one_object_per_line";
+        static WILD: [u64; 4] = [0; 4];
+        if testing::in_child(TEST) {
+            use crate::sanitize::{alloc_inner, dealloc_inner, dealloc_sized_inner, realloc_inner};
+            set_halt_on_error(false);
+            let layout = Layout::from_size_align(48, 8).unwrap();
+            // SAFETY: Each bad free is reported and survived without the
+            // block being touched; the overflow stays in the red zone.
+            unsafe {
+                let freed = alloc_inner(layout, AllocKind::Malloc);
+                dealloc_inner(freed, AllocKind::Malloc);
+                dealloc_inner(freed, AllocKind::Malloc);
+                let wild = WILD.as_ptr().add(1).cast_mut().cast::<u8>();
+                dealloc_inner(wild, AllocKind::Malloc);
+                let array = alloc_inner(layout, AllocKind::ArrayNew);
+                dealloc_inner(array, AllocKind::ScalarNew);
+                let smashed = alloc_inner(layout, AllocKind::Malloc);
+                smashed.add(48).write(!smashed.add(48).read());
+                dealloc_inner(smashed, AllocKind::Malloc);
+                let cxx = alloc_inner(layout, AllocKind::ScalarNew);
+                assert!(realloc_inner(cxx, layout, 96, AllocKind::Malloc).is_null());
+                dealloc_sized_inner(cxx, AllocKind::ScalarNew, 24);
+                let leaked = alloc_inner(layout, AllocKind::Malloc);
+                std::eprintln!(
+                    "addrs {:#018x} {:#018x} {:#018x} {:#018x} {:#018x} {:#018x}",
+                    freed as usize,
+                    wild as usize,
+                    array as usize,
+                    smashed as usize,
+                    cxx as usize,
+                    leaked as usize
+                );
+            }
+            crate::sanitize::tracker::report_leaks();
+            return;
+        }
+        let child = testing::spawn(TEST, &[("LIBCPP_FORMAT", "json")]);
+        let stderr = child.stderr();
+        assert_eq!(child.code(), Some(0), "{stderr}");
+        let (errors, rest) = stderr.split_once("addrs ").expect(&stderr);
+        let (addrs, leaks) = rest.split_once('\n').unwrap();
+        let addrs: std::vec::Vec<&str> = addrs.split(' ').collect();
+
+        let errors: std::vec::Vec<_> = errors.lines().map(json_object).collect();
+        fn field<'a>(members: &[(&str, &'a str)], key: &str) -> Option<&'a str> {
+            let (_, value) = members.iter().find(|(k, _)| *k == key)?;
+            Some(value.trim_matches('"'))
+        }
+        let expected = [
+            ("double-free", addrs[0]),
+            ("invalid-free", addrs[1]),
+            ("mismatched-dealloc", addrs[2]),
+            ("overflow", addrs[3]),
+            ("realloc-of-cxx", addrs[4]),
+            ("size-mismatch", addrs[4]),
+        ];
+        assert_eq!(errors.len(), expected.len(), "{stderr}");
+        for (members, (error, addr)) in errors.iter().zip(&expected) {
+            assert_eq!(
+                &members[0],
+                &("error", &*std::format!("\"{error}\"")),
+                "{stderr}"
+            );
+            assert_eq!(field(members, "addr"), Some(*addr), "{stderr}");
+        }
+        assert_eq!(field(&errors[2], "allocated"), Some("operator new[]"));
+        assert_eq!(field(&errors[3], "size"), Some("48"));
+        assert_eq!(field(&errors[3], "suffix"), Some("true"));
+        assert_eq!(field(&errors[4], "allocated"), Some("operator new"));
+        assert_eq!(field(&errors[5], "size"), Some("48"));
+        assert_eq!(field(&errors[5], "delete_size"), Some("24"));
+
+        let leaks: std::vec::Vec<_> = leaks.lines().map(json_object).collect();
+        let ours = leaks
+            .iter()
+            .find(|m| field(m, "addr") == Some(addrs[5]))
+            .expect(&stderr);
+        assert_eq!(field(ours, "error"), Some("leak"));
+        assert_eq!(field(ours, "size"), Some("48"));
+        assert_eq!(field(ours, "kind"), Some("malloc"));
+    }
+
+    #[test]
+    fn log_file_gets_the_reports_instead_of_stderr() {
+        const TEST: &str =
+            "sanitize::diagnostic::tests::log_file_gets_the_reports_instead_of_stderr";
+        if testing::in_child(TEST) {
+            set_halt_on_error(false);
+            let layout = Layout::from_size_align(16, 8).unwrap();
+            // SAFETY: The second free is the error reported on purpose.
+            unsafe {
+                let ptr = crate::sanitize::sanitized_alloc(layout);
+                std::eprintln!("freed={:#018x}", ptr as usize);
+                crate::sanitize::sanitized_dealloc(ptr, layout);
+                crate::sanitize::sanitized_dealloc(ptr, layout);
+            }
+            return;
+        }
+        let expected = |stderr: &str| {
+            let (first, _) = stderr.split_once('\n').unwrap();
+            let addr = first.strip_prefix("freed=").expect(first);
+            if cfg!(feature = "minimal-diagnostics") {
+                std::format!("SAN 1 {addr}\n")
+            } else {
+                std::format!("ERROR: double-free\n  address: {addr}\n")
+            }
+        };
+
+        let path = std::env::temp_dir().join(std::format!("libcpp-log-{}.txt", std::process::id()));
+        // Reports are appended to what is already there.
+        std::fs::write(&path, "earlier\n").unwrap();
+        let child = testing::spawn(TEST, &[("LIBCPP_LOG", path.to_str().unwrap())]);
+        let stderr = child.stderr();
+        let log = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(child.code(), Some(0), "{stderr}");
+        assert_eq!(stderr.lines().count(), 1, "{stderr}");
+        assert!(log.starts_with("earlier\n"), "{log}");
+        assert!(log.contains(&expected(&stderr)), "{log}");
+
+        let child = testing::spawn(TEST, &[("LIBCPP_LOG", "/nonexistent-libcpp-dir/log")]);
+        let stderr = child.stderr();
+        assert_eq!(child.code(), Some(0), "{stderr}");
+        assert!(
+            stderr.contains("libcplusplus: cannot open LIBCPP_LOG, writing to stderr: -2\n"),
+            "{stderr}"
+        );
+        assert!(stderr.contains(&expected(&stderr)), "{stderr}");
+    }
+
+    #[test]
+    fn direct_writes_keep_every_value() {
+        const TEST: &str = "sanitize::diagnostic::tests::direct_writes_keep_every_value";
+        if testing::in_child(TEST) {
+            let _report = Writer::open();
+            write_log(b"values: ");
+            write_hex(0xdead_beef);
+            write_log(b" ");
+            write_hex(0x1000);
+            write_log(b" ");
+            write_dec(12345);
+            write_log(b" ");
+            write_dec(0);
+            write_log(b"\n");
+            return;
+        }
+        let child = testing::spawn(TEST, &[]);
+        let stderr = child.stderr();
+        assert_eq!(child.code(), Some(0), "{stderr}");
+        let expected = "values: 0x00000000deadbeef 0x0000000000001000 12345 0\n";
+        assert!(stderr.contains(expected), "{stderr}");
+    }
+
+    #[test]
+    fn format_idec_handles_the_extremes() {
+        let mut buf = [0u8; 21];
+        for value in [isize::MIN, -1, 0, isize::MAX] {
+            let expected = std::format!("{value}");
+            assert_eq!(format_idec(value, &mut buf), expected.as_bytes());
+        }
+    }
+
+    #[test]
+    fn max_reports_caps_a_looping_overflow() {
+        const TEST: &str = "sanitize::diagnostic::tests::max_reports_caps_a_looping_overflow";
+        if testing::in_child(TEST) {
+            set_halt_on_error(false);
+            set_max_reports(ErrorKind::Overflow, 3);
+            let layout = Layout::from_size_align(32, 8).unwrap();
+            for _ in 0..10 {
+                // SAFETY: The block is freed below; the write lands in its
+                // suffix red zone, which is part of the same allocation.
+                // Flipping the byte changes it whatever the canary is.
+                unsafe {
+                    let ptr = crate::sanitize::sanitized_alloc(layout);
+                    let tail = ptr.add(32);
+                    tail.write(!tail.read());
+                    crate::sanitize::sanitized_dealloc(ptr, layout);
+                }
+            }
+            std::eprintln!("errors={}", error_count());
+            return;
+        }
+        let child = testing::spawn(TEST, &[]);
+        let stderr = child.stderr();
+        assert_eq!(child.code(), Some(0), "{stderr}");
+        let report = if MINIMAL {
+            "SAN 4 "
+        } else {
+            "buffer overflow detected"
+        };
+        assert_eq!(stderr.matches(report).count(), 3, "{stderr}");
+        let note = "libcplusplus: suppressing further overflow reports\n";
+        assert_eq!(stderr.matches(note).count(), 1, "{stderr}");
+        assert!(
+            stderr.find(note) > stderr.rfind(report),
+            "note comes after the last report: {stderr}"
+        );
+        assert!(stderr.contains("errors=10\n"), "{stderr}");
+    }
 }
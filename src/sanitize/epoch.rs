@@ -1,11 +1,25 @@
-use core::sync::atomic::{AtomicU64, Ordering};
+use core::sync::atomic::Ordering;
+
+#[cfg(target_has_atomic = "64")]
+use core::sync::atomic::AtomicU64 as Counter;
+
+#[cfg(not(target_has_atomic = "64"))]
+use Locked as Counter;
+
+#[cfg(any(test, not(target_has_atomic = "64")))]
+use crate::platform::spinlock::SpinLock;
 
 /// Generation counter for detecting iterator invalidation.
 ///
 /// Containers increment their epoch on every mutating operation.
 /// Iterators capture the current epoch on creation and compare
 /// on dereference — a mismatch means the iterator is invalidated.
-pub struct Epoch(AtomicU64);
+///
+/// The counter is a native `AtomicU64` where the target has 64-bit
+/// atomics. Elsewhere (e.g. some 32-bit targets) a plain `u64` behind a
+/// spin lock is used instead, so a reader can never observe half of an
+/// increment.
+pub struct Epoch(Counter);
 
 impl Default for Epoch {
     fn default() -> Self {
@@ -15,7 +29,7 @@ impl Default for Epoch {
 
 impl Epoch {
     pub const fn new() -> Self {
-        Self(AtomicU64::new(0))
+        Self(Counter::new(0))
     }
 
     /// Read the current generation.
@@ -28,3 +42,77 @@ impl Epoch {
         self.0.fetch_add(1, Ordering::AcqRel)
     }
 }
+
+/// The `AtomicU64` operations `Epoch` uses, as a `u64` behind a spin lock.
+/// Built in tests too, so the fallback is exercised on 64-bit hosts.
+#[cfg(any(test, not(target_has_atomic = "64")))]
+struct Locked(SpinLock<u64>);
+
+#[cfg(any(test, not(target_has_atomic = "64")))]
+impl Locked {
+    const fn new(value: u64) -> Self {
+        Self(SpinLock::new(value))
+    }
+
+    /// The lock orders every access, so `_order` is not needed.
+    fn load(&self, _order: Ordering) -> u64 {
+        *self.0.lock()
+    }
+
+    fn fetch_add(&self, n: u64, _order: Ordering) -> u64 {
+        let mut guard = self.0.lock();
+        let prev = *guard;
+        *guard = prev.wrapping_add(n);
+        prev
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+
+    /// Bump from just below 2^32 to just above it while other threads read.
+    /// A torn read would combine the halves of two different values, e.g.
+    /// the high half of 2^32 with the low half of 2^32 - 1.
+    fn check_untorn(read: impl Fn() -> u64 + Sync, bump: impl Fn() -> u64 + Sync) {
+        const START: u64 = (1 << 32) - 5_000;
+        const END: u64 = (1 << 32) + 5_000;
+        std::thread::scope(|s| {
+            let readers: std::vec::Vec<_> = (0..3)
+                .map(|_| {
+                    s.spawn(|| {
+                        let mut last = START;
+                        while last < END {
+                            let now = read();
+                            assert!((last..=END).contains(&now), "{last:#x} then {now:#x}");
+                            last = now;
+                        }
+                    })
+                })
+                .collect();
+            for expected in START..END {
+                assert_eq!(bump(), expected);
+            }
+            for reader in readers {
+                reader.join().unwrap();
+            }
+        });
+    }
+
+    #[test]
+    fn native_epoch_reads_are_never_torn() {
+        let epoch = Epoch(Counter::new((1 << 32) - 5_000));
+        check_untorn(|| epoch.get(), || epoch.bump());
+    }
+
+    #[test]
+    fn locked_fallback_reads_are_never_torn() {
+        let counter = Locked::new((1 << 32) - 5_000);
+        check_untorn(
+            || counter.load(Ordering::Acquire),
+            || counter.fetch_add(1, Ordering::AcqRel),
+        );
+    }
+}
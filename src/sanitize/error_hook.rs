@@ -0,0 +1,123 @@
+//! Embedder callback for sanitizer errors.
+//!
+//! One hook, stored as a function-pointer address, sees every error before
+//! the reporter prints it and decides whether to abort. It can run on the
+//! free path with sanitizer locks held (write-after-free is found while the
+//! quarantine evicts) and from the SIGSEGV handler, so it must not allocate,
+//! free or block. An error raised from inside the hook on the same thread
+//! is reported but not passed to the hook again.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Maximum number of threads that can be inside the hook simultaneously.
+/// Errors from further threads skip the hook rather than blocking.
+const MAX_ACTIVE: usize = 16;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ErrorKind {
+    DoubleFree,
+    InvalidFree,
+    /// Free of a pointer into the middle of a live block.
+    InteriorFree,
+    MismatchedDealloc,
+    /// `realloc` of an operator new block.
+    ReallocOfCxx,
+    /// Sized delete with a size other than the allocated one.
+    SizeMismatch,
+    /// Red zone corruption found on free.
+    Overflow,
+    /// A quarantined block was written to.
+    WriteAfterFree,
+    /// A fault inside a quarantined block.
+    UseAfterFree,
+    /// A fault outside any known block.
+    Segv,
+    /// A checked iterator outlived a mutation of its container.
+    IteratorInvalidated,
+}
+
+impl ErrorKind {
+    /// Number of kinds, for tables indexed by `kind as usize`.
+    pub(crate) const COUNT: usize = Self::IteratorInvalidated as usize + 1;
+}
+
+/// What the hook learns about an error. Fields that do not apply are 0.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct ErrorInfo {
+    pub kind: ErrorKind,
+    /// The address passed to the failing call, or the faulting address.
+    pub addr: usize,
+    /// User address of the block involved, if known.
+    pub block: usize,
+    /// Allocated size of that block, if known.
+    pub size: usize,
+    /// Size the caller claimed (sized delete).
+    pub claimed_size: usize,
+}
+
+impl ErrorInfo {
+    pub const fn new(kind: ErrorKind, addr: usize) -> Self {
+        Self {
+            kind,
+            addr,
+            block: 0,
+            size: 0,
+            claimed_size: 0,
+        }
+    }
+
+    /// Attach the block `addr` refers to.
+    pub const fn with_block(self, block: usize, size: usize) -> Self {
+        Self {
+            block,
+            size,
+            ..self
+        }
+    }
+}
+
+pub type ErrorHook = fn(&ErrorInfo);
+
+/// The registered hook's address; 0 when none.
+static HOOK: AtomicUsize = AtomicUsize::new(0);
+
+/// Thread ids currently running the hook; 0 is empty.
+static ACTIVE: [AtomicUsize; MAX_ACTIVE] = [const { AtomicUsize::new(0) }; MAX_ACTIVE];
+
+/// Install `hook`, replacing any previous one.
+pub fn set(hook: ErrorHook) {
+    HOOK.store(hook as usize, Ordering::Release);
+}
+
+/// Remove the hook.
+pub fn clear() {
+    HOOK.store(0, Ordering::Release);
+}
+
+/// Pass an error to the hook, if one is installed.
+pub fn notify(info: &ErrorInfo) {
+    let raw = HOOK.load(Ordering::Acquire);
+    if raw == 0 {
+        return;
+    }
+
+    // SAFETY: gettid has no preconditions.
+    let tid = unsafe { crate::platform::syscall::sys_gettid() };
+    if ACTIVE.iter().any(|t| t.load(Ordering::Acquire) == tid) {
+        // The hook itself hit an error.
+        return;
+    }
+    let Some(claimed) = ACTIVE.iter().find(|t| {
+        t.compare_exchange(0, tid, Ordering::AcqRel, Ordering::Relaxed)
+            .is_ok()
+    }) else {
+        return;
+    };
+
+    // SAFETY: HOOK only ever holds 0 or an address stored from an
+    // `ErrorHook` in `set`.
+    let hook: ErrorHook = unsafe { core::mem::transmute::<usize, ErrorHook>(raw) };
+    hook(info);
+
+    claimed.store(0, Ordering::Release);
+}
@@ -0,0 +1,241 @@
+//! Leak report at process exit.
+//!
+//! A constructor in `.init_array` registers `report_at_exit` with this
+//! crate's `__cxa_atexit` before `main`. Exit handlers run in reverse order
+//! of registration, and C++ static destructors are registered as each object
+//! is constructed, i.e. later, so they run (and free) before the report.
+//! `.fini_array` functions of modules finalized after this one still run
+//! afterwards.
+//!
+//! The quarantine is drained first, so every freed block gets its final
+//! write-after-free check and the report starts from a clean heap.
+//!
+//! In recoverable mode the handler also prints how many errors were
+//! survived. If there were any it ends the process on the spot, with the
+//! `LIBCPP_EXITCODE` status or else 1, as exit handlers cannot change the
+//! status: stdio is flushed, but
+//! handlers registered before it and the remaining `.fini_array` functions
+//! are skipped.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+static REPORT_ON_EXIT: AtomicBool = AtomicBool::new(true);
+
+unsafe extern "C" {
+    fn fflush(stream: *mut u8) -> i32;
+}
+
+/// Turn the automatic exit-time leak report on or off (on by default).
+pub fn set_report_on_exit(enabled: bool) {
+    REPORT_ON_EXIT.store(enabled, Ordering::Relaxed);
+}
+
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+extern "C" fn report_at_exit(_arg: *mut u8) {
+    crate::sanitize::quarantine::drain();
+    if REPORT_ON_EXIT.load(Ordering::Relaxed) {
+        crate::sanitize::tracker::report_leaks();
+    }
+    let errors = crate::sanitize::diagnostic::error_count();
+    if errors != 0 {
+        crate::sanitize::diagnostic::error_summary(errors);
+        // SAFETY: Flushing all streams and exiting is what exit() would do
+        // next; only the exit status differs.
+        unsafe {
+            fflush(core::ptr::null_mut());
+            let code = crate::sanitize::diagnostic::exit_code().max(1);
+            crate::platform::syscall::sys_exit_group(code as i32);
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod register {
+    unsafe extern "C" {
+        pub(super) static __dso_handle: u8;
+    }
+
+    pub(super) extern "C" fn register() {
+        // __dso_handle identifies this module, so the handler runs early if
+        // it is unloaded.
+        crate::abi::atexit::__cxa_atexit(
+            super::report_at_exit,
+            core::ptr::null_mut(),
+            &raw const __dso_handle,
+        );
+    }
+
+    // Priority 101 is the earliest available to user code: registering
+    // ahead of other constructors puts the report after their exit handlers.
+    // Not in the test harness, whose own exit must not print a report; the
+    // tests call `register` themselves.
+    #[cfg(not(test))]
+    #[used]
+    #[unsafe(link_section = ".init_array.00101")]
+    static REGISTER: extern "C" fn() = register;
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use crate::sanitize::{sanitized_alloc, sanitized_dealloc};
+    use crate::testing;
+    use core::alloc::Layout;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Block freed by an exit handler registered after the report's.
+    static LATE: AtomicUsize = AtomicUsize::new(0);
+
+    extern "C" fn late_destructor(_arg: *mut u8) {
+        let layout = Layout::from_size_align(555, 8).unwrap();
+        // SAFETY: LATE holds a live block of that layout.
+        unsafe { sanitized_dealloc(LATE.load(Ordering::Relaxed) as *mut u8, layout) };
+    }
+
+    #[test]
+    fn leak_reported_at_exit() {
+        const TEST: &str = "sanitize::exit::tests::leak_reported_at_exit";
+        if testing::in_child(TEST) {
+            // SAFETY: Plain allocations; the first one is leaked on purpose.
+            let (leaked, late) = unsafe {
+                (
+                    sanitized_alloc(Layout::from_size_align(777, 8).unwrap()),
+                    sanitized_alloc(Layout::from_size_align(555, 8).unwrap()),
+                )
+            };
+            LATE.store(late as usize, Ordering::Relaxed);
+            std::eprintln!(
+                "leaked={:#018x} late={:#018x}",
+                leaked as usize,
+                late as usize
+            );
+            super::register::register();
+            // Like a C++ static constructed in main, freeing at exit.
+            crate::abi::atexit::__cxa_atexit(
+                late_destructor,
+                core::ptr::null_mut(),
+                &raw const super::register::__dso_handle,
+            );
+            std::process::exit(0);
+        }
+        let child = testing::spawn(TEST, &[]);
+        let stderr = child.stderr();
+        assert_eq!(child.code(), Some(0), "{stderr}");
+        let (first, report) = stderr.split_once('\n').unwrap();
+        let (leaked, late) = first.split_once(' ').unwrap();
+        let (leaked, late) = (&leaked["leaked=".len()..], &late["late=".len()..]);
+        let line = if cfg!(feature = "minimal-diagnostics") {
+            std::format!("SAN 7 {leaked}")
+        } else {
+            std::format!("LEAK: {leaked}  size=777")
+        };
+        assert!(report.contains(&line), "{stderr}");
+        assert!(!report.contains(late), "{stderr}");
+    }
+
+    #[test]
+    fn verify_on_exit_reports_corruption_before_leaks() {
+        const TEST: &str = "sanitize::exit::tests::verify_on_exit_reports_corruption_before_leaks";
+        if testing::in_child(TEST) {
+            crate::sanitize::tracker::set_verify_on_exit(true);
+            let layout = Layout::from_size_align(40, 8).unwrap();
+            // SAFETY: Both leaked on purpose; the overflow stays inside
+            // the second block's suffix red zone.
+            let (clean, smashed) = unsafe {
+                let clean = sanitized_alloc(layout);
+                let smashed = sanitized_alloc(layout);
+                let tail = smashed.add(40);
+                tail.write(!tail.read());
+                (clean, smashed)
+            };
+            std::eprintln!(
+                "clean={:#018x} smashed={:#018x}",
+                clean as usize,
+                smashed as usize
+            );
+            super::register::register();
+            std::process::exit(0);
+        }
+        let child = testing::spawn(TEST, &[]);
+        let stderr = child.stderr();
+        assert_eq!(child.code(), Some(0), "{stderr}");
+        let (first, report) = stderr.split_once('\n').unwrap();
+        let (clean, smashed) = first.split_once(' ').unwrap();
+        let (clean, smashed) = (&clean["clean=".len()..], &smashed["smashed=".len()..]);
+        let (corrupt, leak) = if cfg!(feature = "minimal-diagnostics") {
+            (
+                std::format!("SAN 6 {smashed}"),
+                std::format!("SAN 7 {clean}"),
+            )
+        } else {
+            (
+                std::format!("CORRUPT: {smashed}  size=40  suffix"),
+                std::format!("LEAK: {clean}  size=40"),
+            )
+        };
+        let corrupt = report.find(&corrupt).expect(&stderr);
+        let leak = report.find(&leak).expect(&stderr);
+        assert!(corrupt < leak, "{stderr}");
+    }
+
+    /// Unlike the tests above, the child returns from the test instead of
+    /// calling `exit`, so the report runs from the harness's own teardown.
+    #[test]
+    fn leak_and_survived_error_fail_a_normal_return() {
+        const TEST: &str = "sanitize::exit::tests::leak_and_survived_error_fail_a_normal_return";
+        if testing::in_child(TEST) {
+            crate::sanitize::set_halt_on_error(false);
+            let layout = Layout::from_size_align(64, 8).unwrap();
+            // SAFETY: `leaked` is leaked on purpose; the second free of
+            // `freed` is the error survived on purpose.
+            let leaked = unsafe {
+                let freed = sanitized_alloc(layout);
+                sanitized_dealloc(freed, layout);
+                sanitized_dealloc(freed, layout);
+                sanitized_alloc(Layout::from_size_align(333, 8).unwrap())
+            };
+            std::eprintln!("leaked={:#018x}", leaked as usize);
+            super::register::register();
+            return;
+        }
+        let child = testing::spawn(TEST, &[("LIBCPP_EXITCODE", "23")]);
+        let stderr = child.stderr();
+        assert_eq!(child.code(), Some(23), "{stderr}");
+        let leaked = stderr
+            .lines()
+            .find_map(|l| l.strip_prefix("leaked="))
+            .expect(&stderr);
+        let report = &stderr[stderr.find(leaked).unwrap() + leaked.len()..];
+        if cfg!(feature = "minimal-diagnostics") {
+            assert!(report.contains(&std::format!("SAN 7 {leaked}")), "{stderr}");
+            return;
+        }
+        let leak = report
+            .find(&std::format!("LEAK: {leaked}  size=333"))
+            .expect(&stderr);
+        let summary = report.find("1 error detected").expect(&stderr);
+        assert!(leak < summary, "{stderr}");
+    }
+
+    #[test]
+    fn survived_errors_exit_with_the_configured_code() {
+        const TEST: &str = "sanitize::exit::tests::survived_errors_exit_with_the_configured_code";
+        if testing::in_child(TEST) {
+            crate::sanitize::set_halt_on_error(false);
+            let layout = Layout::from_size_align(64, 8).unwrap();
+            // SAFETY: The second free is the error survived on purpose.
+            unsafe {
+                let ptr = sanitized_alloc(layout);
+                sanitized_dealloc(ptr, layout);
+                sanitized_dealloc(ptr, layout);
+            }
+            super::register::register();
+            std::process::exit(0);
+        }
+        let child = testing::spawn(TEST, &[("LIBCPP_EXITCODE", "23")]);
+        assert_eq!(child.code(), Some(23), "{}", child.stderr());
+        let child = testing::spawn(TEST, &[]);
+        assert_eq!(child.code(), Some(1), "{}", child.stderr());
+    }
+}
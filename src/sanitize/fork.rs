@@ -0,0 +1,125 @@
+//! Recovery in the child of `fork`.
+//!
+//! `fork` copies only the calling thread. A lock some other thread held at
+//! that moment stays taken in the child, with nobody left to release it,
+//! and the child's first allocation spins forever. A `pthread_atfork`
+//! child handler, registered from `.init_array`, therefore frees every
+//! lock the sanitizer takes: tracker shards and thread caches, quarantine,
+//! region table, the `__cxa_atexit` table and, with `mmap-backend`, the
+//! heap. It also drops the cached PID so the child's reports name it, and
+//! releases the report buffers of the threads that did not survive.
+//!
+//! This is best-effort. Whatever the lost thread was updating stays half
+//! done, so the child may later see a wrong count or a stray tracker
+//! entry. POSIX only allows async-signal-safe calls in the child of a
+//! multithreaded program until it calls `exec`, and allocating there is a
+//! gamble with any allocator; this only keeps the gamble from being a
+//! certain hang.
+
+#[cfg(target_os = "linux")]
+mod register {
+    unsafe extern "C" {
+        fn pthread_atfork(
+            prepare: Option<extern "C" fn()>,
+            parent: Option<extern "C" fn()>,
+            child: Option<extern "C" fn()>,
+        ) -> i32;
+    }
+
+    pub(super) extern "C" fn child() {
+        // SAFETY: The child has a single thread, so nothing else can be
+        // inside these locks.
+        unsafe {
+            crate::sanitize::tracker::force_unlock();
+            crate::sanitize::quarantine::force_unlock();
+            crate::sanitize::region::force_unlock();
+            crate::abi::atexit::force_unlock();
+            #[cfg(feature = "mmap-backend")]
+            crate::platform::force_unlock_heap();
+            crate::sanitize::diagnostic::forget_writers();
+        }
+        crate::sanitize::diagnostic::forget_pid();
+    }
+
+    pub(super) extern "C" fn register() {
+        // SAFETY: Registers a handler that only touches atomics.
+        unsafe { pthread_atfork(None, None, Some(child)) };
+    }
+
+    // Not in the test harness; the tests call `register` themselves.
+    #[cfg(not(test))]
+    #[used]
+    #[unsafe(link_section = ".init_array")]
+    static REGISTER: extern "C" fn() = register;
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    extern crate std;
+
+    use crate::sanitize::diagnostic::{self, MAX_REPORTS, Writer};
+    use crate::testing;
+    use std::sync::{Arc, Barrier};
+
+    unsafe extern "C" {
+        fn fork() -> i32;
+        fn waitpid(pid: i32, status: *mut i32, options: i32) -> i32;
+    }
+
+    #[test]
+    fn fork_child_gets_the_report_slots_back() {
+        const TEST: &str = "sanitize::fork::tests::fork_child_gets_the_report_slots_back";
+        if testing::in_child(TEST) {
+            super::register::register();
+            // Every slot is held by a thread that a fork leaves behind.
+            let claimed = Arc::new(Barrier::new(MAX_REPORTS + 1));
+            let forked = Arc::new(Barrier::new(MAX_REPORTS + 1));
+            let threads: std::vec::Vec<_> = (0..MAX_REPORTS)
+                .map(|_| {
+                    let (claimed, forked) = (claimed.clone(), forked.clone());
+                    std::thread::spawn(move || {
+                        let _report = Writer::open();
+                        diagnostic::write_log(b"parent report\n");
+                        claimed.wait();
+                        forked.wait();
+                    })
+                })
+                .collect();
+            claimed.wait();
+            assert_eq!(diagnostic::open_reports(), MAX_REPORTS);
+
+            // SAFETY: The child only touches atomics and exits directly.
+            let pid = unsafe { fork() };
+            if pid == 0 {
+                let released = diagnostic::open_reports() == 0;
+                let report = Writer::open();
+                let reopened = diagnostic::open_reports() == 1;
+                diagnostic::write_log(b"child report\n");
+                drop(report);
+                let ok = released && reopened && diagnostic::open_reports() == 0;
+                // SAFETY: Leaves the forked child without running the
+                // harness, whose other threads did not survive the fork.
+                unsafe { crate::platform::syscall::sys_exit_group(if ok { 0 } else { 1 }) }
+            }
+            let mut status = 0;
+            // SAFETY: pid is the child just forked.
+            assert_eq!(unsafe { waitpid(pid, &mut status, 0) }, pid);
+            forked.wait();
+            for thread in threads {
+                thread.join().unwrap();
+            }
+            assert_eq!(status, 0, "fork child status");
+            assert_eq!(diagnostic::open_reports(), 0);
+            return;
+        }
+        let child = testing::spawn(TEST, &[]);
+        let stderr = child.stderr();
+        assert_eq!(child.code(), Some(0), "{stderr}");
+        assert_eq!(stderr.matches("child report").count(), 1, "{stderr}");
+        assert_eq!(
+            stderr.matches("parent report").count(),
+            MAX_REPORTS,
+            "{stderr}"
+        );
+    }
+}
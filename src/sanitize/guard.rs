@@ -0,0 +1,264 @@
+//! Guard-page backing for sanitized allocations.
+//!
+//! Each block gets its own anonymous mapping with a `PROT_NONE` page right
+//! after the suffix red zone, so an overflow past the canaries faults on
+//! the spot. On free the whole mapping is made inaccessible, turning
+//! use-after-free into a fault for as long as the block is quarantined.
+//!
+//! ```text
+//! base                                  user          guard
+//! | slack | header | prefix red zone | user data | suffix | PROT_NONE page |
+//! ```
+//!
+//! The mapping length is a pure function of the user size and alignment,
+//! so the tracker and quarantine only need to keep those and the base.
+//!
+//! With the dead-allocation check on, a new block is also made
+//! inaccessible once set up. Its first access faults; the SIGSEGV handler
+//! opens the block back up, marks its header touched and lets the access
+//! retry. A block still marked hidden when freed was never used.
+
+use crate::platform::syscall::{
+    MAP_ANONYMOUS, MAP_PRIVATE, PROT_NONE, PROT_READ, PROT_WRITE, sys_mmap, sys_mprotect,
+    sys_munmap,
+};
+use crate::sanitize::{redzone, segv, tracker};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+pub const PAGE_SIZE: usize = 4096;
+
+/// Bytes before the guard page: red zones, user data, and enough slack to
+/// align the user pointer downwards from the guard.
+fn data_len(user_size: usize, align: usize) -> Option<usize> {
+    redzone::prefix_size(align)
+        .checked_add(user_size)?
+        .checked_add(redzone::zone_size(user_size))?
+        .checked_add(align - 1)?
+        .checked_next_multiple_of(PAGE_SIZE)
+}
+
+/// Total mapping length for a block, guard page included.
+pub fn map_len(user_size: usize, align: usize) -> Option<usize> {
+    data_len(user_size, align)?.checked_add(PAGE_SIZE)
+}
+
+/// Map a block of `user_size` bytes aligned to `align`.
+/// Returns the mapping base and the user pointer.
+///
+/// # Safety
+/// The mapping must only be released through `unmap` with the same size
+/// and alignment.
+pub unsafe fn map(user_size: usize, align: usize) -> Option<(*mut u8, *mut u8)> {
+    let data = data_len(user_size, align)?;
+    let len = data.checked_add(PAGE_SIZE)?;
+    // SAFETY: A fresh anonymous mapping aliases nothing.
+    let base = unsafe {
+        sys_mmap(
+            0,
+            len,
+            PROT_READ | PROT_WRITE,
+            MAP_PRIVATE | MAP_ANONYMOUS,
+            usize::MAX,
+            0,
+        )
+    };
+    if base < 0 {
+        return None;
+    }
+    let base = base as usize;
+    let guard = base + data;
+
+    // SAFETY: The guard page is the last page of the mapping just created.
+    if unsafe { sys_mprotect(guard, PAGE_SIZE, PROT_NONE) } != 0 {
+        // SAFETY: Nothing references the mapping yet.
+        unsafe { sys_munmap(base, len) };
+        return None;
+    }
+
+    // End the suffix red zone as close to the guard as alignment allows.
+    let user = (guard - redzone::zone_size(user_size) - user_size) & !(align - 1);
+    Some((base as *mut u8, user as *mut u8))
+}
+
+/// Make a freed block's whole mapping inaccessible.
+///
+/// # Safety
+/// `base` must come from `map(user_size, align)` and not be unmapped.
+pub unsafe fn protect(base: usize, user_size: usize, align: usize) {
+    if let Some(len) = map_len(user_size, align) {
+        // SAFETY: Forwarded from the caller.
+        unsafe { sys_mprotect(base, len, PROT_NONE) };
+    }
+}
+
+/// Release a block's mapping.
+///
+/// # Safety
+/// `base` must come from `map(user_size, align)` and nothing may
+/// reference the block any more.
+pub unsafe fn unmap(base: usize, user_size: usize, align: usize) {
+    if let Some(len) = map_len(user_size, align) {
+        // SAFETY: Forwarded from the caller.
+        unsafe { sys_munmap(base, len) };
+    }
+}
+
+/// `Header::access` of a block no one has accessed since `hide`.
+const HIDDEN: u8 = 1;
+/// `Header::access` of a hidden block after its first access.
+const TOUCHED: u8 = 2;
+
+/// Whether new blocks are hidden.
+static DEAD_CHECK: AtomicBool = AtomicBool::new(false);
+/// Whether any block was ever hidden; stays set so blocks hidden before
+/// the check was turned off are still revealed.
+static HIDDEN_ANY: AtomicBool = AtomicBool::new(false);
+
+/// Turn the dead-allocation check on or off. Turning it on installs the
+/// SIGSEGV handler; returns `false` if that failed.
+pub fn set_dead_check(enabled: bool) -> bool {
+    if enabled {
+        if !segv::install() {
+            return false;
+        }
+        HIDDEN_ANY.store(true, Ordering::Relaxed);
+    }
+    DEAD_CHECK.store(enabled, Ordering::Relaxed);
+    true
+}
+
+/// Make the data pages of a block accessible again.
+///
+/// # Safety
+/// `base` must come from `map(user_size, align)` and not be unmapped.
+unsafe fn reveal(base: usize, user_size: usize, align: usize) {
+    if let Some(len) = data_len(user_size, align) {
+        // SAFETY: Forwarded from the caller; the guard page is left alone.
+        unsafe { sys_mprotect(base, len, PROT_READ | PROT_WRITE) };
+    }
+}
+
+/// Hide a freshly set up block until its first access, if the
+/// dead-allocation check is on.
+///
+/// # Safety
+/// `base` and `user_ptr` must come from `map(user_size, align)`, with the
+/// header already written.
+pub unsafe fn hide(base: usize, user_ptr: *mut u8, user_size: usize, align: usize) {
+    if !DEAD_CHECK.load(Ordering::Relaxed) {
+        return;
+    }
+    // SAFETY: The header was written and the block is still accessible.
+    unsafe {
+        let mut header = redzone::read_header(user_ptr);
+        header.access = HIDDEN;
+        redzone::write_header(user_ptr, header);
+        protect(base, user_size, align);
+    }
+}
+
+/// Open up the hidden live block whose data pages contain `addr`, from the
+/// SIGSEGV handler. Returns `false` if there is none, in which case the
+/// fault is a real one.
+pub fn touch(addr: usize) -> bool {
+    if !HIDDEN_ANY.load(Ordering::Relaxed) {
+        return false;
+    }
+    let Some((user_addr, block)) = tracker::try_find(|base, size, align| {
+        data_len(size, align).is_some_and(|len| addr >= base && addr - base < len)
+    }) else {
+        return false;
+    };
+    // SAFETY: The block is live, so its mapping is still in place.
+    unsafe {
+        reveal(block.base, block.size, block.align);
+        let ptr = user_addr as *mut u8;
+        let mut header = redzone::read_header(ptr);
+        if header.access == HIDDEN {
+            header.access = TOUCHED;
+            redzone::write_header(ptr, header);
+        }
+    }
+    // The handler resets on delivery; it is needed for the next block.
+    segv::install()
+}
+
+/// Make a block being freed accessible again. Returns whether it was
+/// still hidden, i.e. never accessed.
+///
+/// # Safety
+/// Same as `hide`, and the block must no longer be tracked.
+pub unsafe fn reveal_for_free(
+    base: usize,
+    user_ptr: *mut u8,
+    user_size: usize,
+    align: usize,
+) -> bool {
+    if !HIDDEN_ANY.load(Ordering::Relaxed) {
+        return false;
+    }
+    // SAFETY: Forwarded from the caller.
+    unsafe {
+        reveal(base, user_size, align);
+        let mut header = redzone::read_header(user_ptr);
+        let dead = header.access == HIDDEN;
+        header.access = 0;
+        redzone::write_header(user_ptr, header);
+        dead
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use crate::sanitize::{AllocKind, alloc_inner, dealloc_inner, set_dead_alloc_check};
+    use crate::testing;
+    use core::alloc::Layout;
+    use std::eprintln;
+
+    #[test]
+    fn block_freed_untouched_is_reported_dead() {
+        const TEST: &str = "sanitize::guard::tests::block_freed_untouched_is_reported_dead";
+        if testing::in_child(TEST) {
+            assert!(set_dead_alloc_check(true));
+            let layout = Layout::from_size_align(64, 16).unwrap();
+            // SAFETY: Each block is freed once, with the kind it came from.
+            unsafe {
+                let dead = alloc_inner(layout, AllocKind::ScalarNew);
+                let written = alloc_inner(layout, AllocKind::ScalarNew);
+                let read = alloc_inner(layout, AllocKind::ScalarNew);
+                written.add(10).write(7);
+                assert_eq!(written.add(10).read(), 7);
+                let _ = core::ptr::read_volatile(read);
+                eprintln!("dead={dead:p} written={written:p} read={read:p}");
+                dealloc_inner(dead, AllocKind::ScalarNew);
+                dealloc_inner(written, AllocKind::ScalarNew);
+                dealloc_inner(read, AllocKind::ScalarNew);
+            }
+            assert!(set_dead_alloc_check(false));
+            return;
+        }
+        let child = testing::spawn(TEST, &[]);
+        let stderr = child.stderr();
+        assert_eq!(child.code(), Some(0), "{stderr}");
+        let addr = |name: &str| {
+            let field = stderr
+                .split_whitespace()
+                .find_map(|w| w.strip_prefix(name))
+                .expect("child printed its addresses");
+            let value = usize::from_str_radix(field.trim_start_matches("0x"), 16).unwrap();
+            std::format!("{value:#018x}")
+        };
+        let expected = if cfg!(feature = "minimal-diagnostics") {
+            "SAN 17 "
+        } else {
+            "HINT: block freed without ever being accessed"
+        };
+        assert_eq!(stderr.matches(expected).count(), 1, "{stderr}");
+        assert!(stderr.contains(&addr("dead=")), "{stderr}");
+        let reported = stderr.split_once(expected).unwrap().1;
+        assert!(!reported.contains(&addr("written=")), "{stderr}");
+        assert!(!reported.contains(&addr("read=")), "{stderr}");
+    }
+}
@@ -0,0 +1,262 @@
+//! Fault-injection primitives for exercising sanitizer error paths.
+//!
+//! Only compiled with the `test-hooks` feature and in this crate's own
+//! tests. Every function here deliberately breaks a sanitizer invariant so
+//! that tests can drive the detection paths (overflow, corrupted metadata,
+//! post-eviction reuse, re-entrant frees) without ad hoc pointer hacks.
+//! Never enable this feature in production.
+
+use crate::sanitize::alloc_hook::{self, AllocEvent};
+#[cfg(not(feature = "guard-pages"))]
+use crate::sanitize::redzone;
+use crate::sanitize::tracker::AllocKind;
+use crate::sanitize::{quarantine, tracker};
+#[cfg(not(feature = "guard-pages"))]
+use core::alloc::Layout;
+use core::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+
+/// Overwrite bytes relative to an allocation, e.g. to corrupt a red zone.
+///
+/// `offset` is relative to `ptr` and may be negative to reach the prefix
+/// red zone.
+///
+/// # Safety
+/// `ptr.offset(offset)` through `ptr.offset(offset + bytes.len())` must lie
+/// within a block returned by `sanitized_alloc`, red zones included.
+pub unsafe fn corrupt_bytes(ptr: *mut u8, offset: isize, bytes: &[u8]) {
+    // SAFETY: Caller guarantees the target range is inside the malloc'd block.
+    unsafe {
+        let dst = ptr.offset(offset);
+        core::ptr::copy_nonoverlapping(bytes.as_ptr(), dst, bytes.len());
+    }
+}
+
+/// Rewrite the size the tracker recorded for a live allocation.
+///
+/// Returns `false` if `addr` is not tracked. The block's header then
+/// disagrees with the tracker, so the next free reports the prefix as
+/// corrupted, simulating metadata corruption.
+pub fn corrupt_tracked_size(addr: usize, size: usize) -> bool {
+    tracker::update_size(addr, size)
+}
+
+/// Force the oldest quarantined block out of quarantine and free it.
+///
+/// Returns `false` if the quarantine was empty. After this, a second free
+/// of the evicted address is reported as an invalid free rather than a
+/// double-free.
+pub fn force_evict() -> bool {
+    match quarantine::evict_oldest() {
+        Some(block) => {
+            // SAFETY: The block left quarantine, so nothing else owns it.
+            unsafe { crate::sanitize::retire(block) };
+            true
+        }
+        None => false,
+    }
+}
+
+/// List the quarantined block at `addr` in the tracker again, as if its
+/// entry had survived the free, so the next free of it gets past the
+/// tracker and quarantine checks to the block itself. Returns `false` if
+/// `addr` is not quarantined.
+pub fn retrack(addr: usize) -> bool {
+    let Some(block) = quarantine::find_containing(addr) else {
+        return false;
+    };
+    let Ok(layout) = core::alloc::Layout::from_size_align(block.user_size, block.align) else {
+        return false;
+    };
+    tracker::insert(block.user_addr, block.base_addr, layout, block.kind);
+    true
+}
+
+/// Most blocks `alloc_at` can have outstanding at once.
+#[cfg(not(feature = "guard-pages"))]
+const MAX_PLACED: usize = 16;
+
+/// Bases of the blocks `alloc_at` built in caller memory; 0 is empty.
+#[cfg(not(feature = "guard-pages"))]
+static PLACED: [AtomicUsize; MAX_PLACED] = [const { AtomicUsize::new(0) }; MAX_PLACED];
+
+/// Build a sanitized block for `layout` at the start of `buf`, so its
+/// address is known in advance: the user pointer is
+/// `buf + redzone::prefix_size(layout.align())`. The block is tracked,
+/// checked and quarantined like any other; once it leaves quarantine the
+/// memory is simply no longer used. Returns null if `buf` is too short or
+/// misaligned, or `MAX_PLACED` placed blocks are still outstanding. Not
+/// available with `guard-pages`, which needs a mapping per block.
+///
+/// # Safety
+/// `buf` must be valid for `len` bytes and untouched by anything else
+/// until the block has left quarantine.
+#[cfg(not(feature = "guard-pages"))]
+pub unsafe fn alloc_at(buf: *mut u8, len: usize, layout: Layout, kind: AllocKind) -> *mut u8 {
+    let align = layout.align();
+    if !(buf as usize).is_multiple_of(align) || len < redzone::total_size(layout.size(), align) {
+        return core::ptr::null_mut();
+    }
+    let placed = PLACED.iter().any(|slot| {
+        slot.compare_exchange(0, buf as usize, Ordering::AcqRel, Ordering::Relaxed)
+            .is_ok()
+    });
+    if !placed {
+        return core::ptr::null_mut();
+    }
+    // SAFETY: buf holds the whole block, laid out as `reserve` would, and
+    // `release` recognizes it through PLACED.
+    unsafe { crate::sanitize::install(buf, buf.add(redzone::prefix_size(align)), layout, kind) }
+}
+
+/// Called by `release`: whether `base` was placed by `alloc_at`, in which
+/// case it is forgotten instead of being freed.
+#[cfg(not(feature = "guard-pages"))]
+pub(crate) fn release_placed(base: usize) -> bool {
+    PLACED.iter().any(|slot| {
+        slot.compare_exchange(base, 0, Ordering::AcqRel, Ordering::Relaxed)
+            .is_ok()
+    })
+}
+
+/// Block `inject_free` armed; 0 when none.
+static INJECTED: AtomicUsize = AtomicUsize::new(0);
+/// `AllocKind` of the injected block.
+static INJECTED_KIND: AtomicU8 = AtomicU8::new(0);
+
+/// Free `ptr` as `kind` from inside the allocation hook of the next
+/// sanitized allocation, as a hook that calls back into the allocator
+/// would. The hook removes itself once it has fired. Returns `false` if a
+/// free is already pending or every hook slot is taken.
+///
+/// # Safety
+/// `ptr` must be a live block allocated as `kind`, and nothing else may
+/// free it.
+pub unsafe fn inject_free(ptr: *mut u8, kind: AllocKind) -> bool {
+    if ptr.is_null()
+        || INJECTED
+            .compare_exchange(0, ptr as usize, Ordering::AcqRel, Ordering::Relaxed)
+            .is_err()
+    {
+        return false;
+    }
+    INJECTED_KIND.store(kind as u8, Ordering::Relaxed);
+    if !alloc_hook::add(inject_hook) {
+        INJECTED.store(0, Ordering::Release);
+        return false;
+    }
+    true
+}
+
+fn inject_hook(event: AllocEvent, _addr: usize, _size: usize, _kind: AllocKind) {
+    if event != AllocEvent::Alloc {
+        return;
+    }
+    let ptr = INJECTED.swap(0, Ordering::AcqRel);
+    if ptr == 0 {
+        return;
+    }
+    alloc_hook::remove(inject_hook);
+    let kind = AllocKind::ALL[INJECTED_KIND.load(Ordering::Relaxed) as usize];
+    // SAFETY: inject_free's caller handed over a live block of this kind
+    // and gave up freeing it.
+    unsafe { crate::sanitize::dealloc_inner(ptr as *mut u8, kind) };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sanitize::{DeallocError, try_dealloc};
+    use crate::testing;
+    use core::alloc::Layout;
+
+    const LAYOUT: Layout = match Layout::from_size_align(32, 16) {
+        Ok(layout) => layout,
+        Err(_) => panic!(),
+    };
+
+    fn alloc() -> *mut u8 {
+        // SAFETY: Every block is freed or deliberately left behind by the
+        // test, which runs in a child.
+        unsafe { crate::sanitize::alloc_inner(LAYOUT, AllocKind::Malloc) }
+    }
+
+    #[cfg(not(feature = "guard-pages"))]
+    fn placed_block_has_the_chosen_address() {
+        static mut BUF: [u128; 16] = [0; 16];
+
+        let buf = (&raw mut BUF).cast::<u8>();
+        // SAFETY: BUF is only used by this test, which runs once per child.
+        let ptr = unsafe { alloc_at(buf, 256, LAYOUT, AllocKind::Malloc) };
+        assert_eq!(ptr as usize, buf as usize + redzone::prefix_size(16));
+        assert_eq!(tracker::lookup(ptr as usize), Some((32, AllocKind::Malloc)));
+        // SAFETY: ptr is a live block of LAYOUT.
+        assert_eq!(unsafe { try_dealloc(ptr, AllocKind::Malloc) }, Ok(()));
+        assert!(quarantine::contains(ptr as usize));
+        while quarantine::contains(ptr as usize) {
+            assert!(force_evict());
+        }
+        // Released without being passed to free: the slot is empty again.
+        assert!(PLACED.iter().all(|slot| slot.load(Ordering::Relaxed) == 0));
+    }
+
+    fn injected_free_runs_inside_the_next_allocation() {
+        let victim = alloc();
+        // SAFETY: victim is live and not freed anywhere else.
+        assert!(unsafe { inject_free(victim, AllocKind::Malloc) });
+        assert!(tracker::lookup(victim as usize).is_some());
+        let trigger = alloc();
+        assert!(tracker::lookup(victim as usize).is_none());
+        assert!(quarantine::contains(victim as usize));
+        // The hook removed itself, so this allocation frees nothing.
+        let other = alloc();
+        assert!(tracker::lookup(trigger as usize).is_some());
+        // SAFETY: Both blocks are live.
+        unsafe {
+            assert_eq!(try_dealloc(trigger, AllocKind::Malloc), Ok(()));
+            assert_eq!(try_dealloc(other, AllocKind::Malloc), Ok(()));
+        }
+    }
+
+    fn corruption_is_detected() {
+        let ptr = alloc();
+        // SAFETY: The byte just past the user region is in the suffix red
+        // zone; flipped, it differs from the canary whatever that is.
+        unsafe { corrupt_bytes(ptr, 32, &[!ptr.add(32).read()]) };
+        // SAFETY: try_dealloc leaves a corrupt block allocated.
+        let err = unsafe { try_dealloc(ptr, AllocKind::Malloc) };
+        assert!(
+            matches!(
+                err,
+                Err(DeallocError::RedzoneCorrupt {
+                    suffix: true,
+                    prefix: false,
+                    ..
+                })
+            ),
+            "{err:?}"
+        );
+
+        let ptr = alloc();
+        assert!(corrupt_tracked_size(ptr as usize, 16));
+        // SAFETY: As above.
+        let err = unsafe { try_dealloc(ptr, AllocKind::Malloc) };
+        assert!(
+            matches!(err, Err(DeallocError::RedzoneCorrupt { prefix: true, .. })),
+            "{err:?}"
+        );
+    }
+
+    #[test]
+    fn hooks_inject_and_the_sanitizer_observes() {
+        const TEST: &str = "sanitize::hooks::tests::hooks_inject_and_the_sanitizer_observes";
+        if testing::in_child(TEST) {
+            #[cfg(not(feature = "guard-pages"))]
+            placed_block_has_the_chosen_address();
+            injected_free_runs_inside_the_next_allocation();
+            corruption_is_detected();
+            return;
+        }
+        let child = testing::spawn(TEST, &[]);
+        assert_eq!(child.code(), Some(0), "{}", child.stderr());
+    }
+}
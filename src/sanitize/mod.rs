@@ -1,38 +1,224 @@
+pub mod alloc_hook;
+pub mod backtrace;
+pub mod checked;
+pub mod config;
+pub mod demangle;
 pub mod diagnostic;
 pub mod epoch;
+pub mod error_hook;
+pub mod exit;
+pub mod fork;
+#[cfg(feature = "guard-pages")]
+pub mod guard;
+#[cfg(any(test, feature = "test-hooks"))]
+pub mod hooks;
+pub mod procfs;
 pub mod quarantine;
 pub mod redzone;
-pub mod spinlock;
+pub mod region;
+pub mod segv;
+pub mod trace;
 pub mod tracker;
 
+use alloc_hook::{AllocEvent, AllocHook};
 use core::alloc::Layout;
+use core::sync::atomic::{AtomicBool, Ordering};
 use tracker::AllocKind;
 
+static PARANOID: AtomicBool = AtomicBool::new(false);
+
+/// Enable extra heuristic checks on free that print hints, not errors.
+pub fn set_paranoid(enabled: bool) {
+    PARANOID.store(enabled, Ordering::Relaxed);
+}
+
 /// Sanitized allocation: adds redzones, tracks the allocation.
 ///
 /// # Safety
 /// Must be called from a GlobalAlloc implementation. The returned pointer
-/// is offset by REDZONE_SIZE from the real malloc'd base.
+/// is offset by `redzone::prefix_size(layout.align())` from the real
+/// malloc'd base, and is aligned to `layout.align()`.
 pub unsafe fn sanitized_alloc(layout: Layout) -> *mut u8 {
+    unsafe { alloc_inner(layout, AllocKind::Rust) }
+}
+
+/// Allocation logic shared between the global allocator and the operator
+/// new exports. The block is tracked as `kind`, which the matching
+/// deallocation must pass to `dealloc_inner`. With scribbling on, the user
+/// region is filled with `redzone::SCRIBBLE_BYTE`.
+///
+/// # Safety
+/// The returned block must only be released through `dealloc_inner`.
+pub unsafe fn alloc_inner(layout: Layout, kind: AllocKind) -> *mut u8 {
+    let fill = redzone::scribble().then_some(redzone::SCRIBBLE_BYTE);
+    // SAFETY: Forwarded from the caller.
+    unsafe { alloc_filled(layout, kind, fill) }
+}
+
+/// `alloc_inner` with the user region set to `fill`, or left as the
+/// platform allocator returned it with `None`.
+///
+/// # Safety
+/// Same as `alloc_inner`.
+unsafe fn alloc_filled(layout: Layout, kind: AllocKind, fill: Option<u8>) -> *mut u8 {
     let user_size = layout.size();
-    let total = redzone::total_size(user_size);
+    let align = layout.align();
 
-    // SAFETY: malloc is provided by the C runtime.
-    let base = unsafe { crate::platform::malloc(total) };
-    if base.is_null() {
+    // SAFETY: Released through dealloc_inner, which ends in `release`.
+    let Some((base, user_ptr)) = (unsafe { reserve(user_size, align) }) else {
         return core::ptr::null_mut();
+    };
+    // SAFETY: reserve laid the block out around user_ptr.
+    unsafe { install(base, user_ptr, layout, kind) };
+    if let Some(byte) = fill {
+        // SAFETY: user_ptr points to user_size writable user bytes.
+        unsafe { core::ptr::write_bytes(user_ptr, byte, user_size) };
     }
+    #[cfg(feature = "guard-pages")]
+    // SAFETY: The block was just mapped and set up for this layout.
+    unsafe {
+        guard::hide(base as usize, user_ptr, user_size, align)
+    };
+    user_ptr
+}
 
-    // SAFETY: base points to `total` bytes of writable memory.
-    unsafe { redzone::fill_canaries(base, user_size) };
-
-    let user_ptr = unsafe { base.add(redzone::REDZONE_SIZE) };
+/// Write the header and red zones of a block laid out by `reserve`, and
+/// start tracking it. Returns `user_ptr`.
+///
+/// # Safety
+/// `base` and `user_ptr` must be laid out as `reserve` returns them for
+/// `layout`, and `base` must be accepted by `release`.
+pub(crate) unsafe fn install(
+    base: *mut u8,
+    user_ptr: *mut u8,
+    layout: Layout,
+    kind: AllocKind,
+) -> *mut u8 {
+    let user_size = layout.size();
+    let canary = redzone::next_canary();
+    // SAFETY: reserve leaves room for the header and the prefix canaries,
+    // the `redzone::size()` bytes before user_ptr.
+    unsafe {
+        redzone::write_header(user_ptr, redzone::Header::new(user_size, kind, canary));
+        redzone::fill_canaries(user_ptr.sub(redzone::size()), user_size, canary);
+    }
 
-    tracker::insert(user_ptr as usize, user_size, AllocKind::Rust);
+    tracker::insert(user_ptr as usize, base as usize, layout, kind);
+    alloc_hook::notify(AllocEvent::Alloc, user_ptr as usize, user_size, kind);
 
     user_ptr
 }
 
+/// Get memory for a block with red zones. Returns the base to release and
+/// the user pointer, which has at least `redzone::HEADER_SIZE +
+/// redzone::size()` bytes before it and `user_size +
+/// redzone::zone_size(user_size)` after it inside the block.
+///
+/// # Safety
+/// The base must only be passed to `release` with the same size and align.
+#[cfg(not(feature = "guard-pages"))]
+unsafe fn reserve(user_size: usize, align: usize) -> Option<(*mut u8, *mut u8)> {
+    let total = redzone::total_size(user_size, align);
+    let base = if align > crate::platform::MIN_ALIGN {
+        // aligned_alloc wants a size that is a multiple of the alignment.
+        let padded = total.checked_next_multiple_of(align)?;
+        // SAFETY: aligned_alloc is provided by the C runtime; align is a
+        // power of two and padded a multiple of it.
+        unsafe { crate::platform::aligned_alloc(align, padded) }
+    } else {
+        // SAFETY: malloc is provided by the C runtime.
+        unsafe { crate::platform::malloc(total) }
+    };
+    if base.is_null() {
+        return None;
+    }
+    // SAFETY: prefix + user_size + zone == total, within the block.
+    let user_ptr = unsafe { base.add(redzone::prefix_size(align)) };
+    Some((base, user_ptr))
+}
+
+/// Guard-page variant: one mapping per block, see `guard`.
+///
+/// # Safety
+/// The base must only be passed to `release` with the same size and align.
+#[cfg(feature = "guard-pages")]
+unsafe fn reserve(user_size: usize, align: usize) -> Option<(*mut u8, *mut u8)> {
+    // SAFETY: Forwarded from the caller.
+    unsafe { guard::map(user_size, align) }
+}
+
+/// Release a block the quarantine let go of, first checking that nothing
+/// wrote to it while it was held: the red zones must be intact and the
+/// user region still poisoned. Guarded blocks cannot have been written.
+///
+/// # Safety
+/// `block` must have just left the quarantine.
+pub(crate) unsafe fn retire(block: quarantine::FreedBlock) {
+    #[cfg(not(feature = "guard-pages"))]
+    {
+        let user = block.user_addr as *const u8;
+        // SAFETY: A quarantined block keeps its red zones and user region
+        // until it is released below.
+        let ((prefix, suffix), first_bad) = unsafe {
+            (
+                redzone::freed_block_status(user, block.user_size),
+                redzone::first_unpoisoned(user, block.user_size, block.generation),
+            )
+        };
+        if prefix || suffix || first_bad.is_some() {
+            diagnostic::write_after_free(&block, first_bad, prefix, suffix);
+        }
+    }
+    // SAFETY: Forwarded from the caller.
+    unsafe { release(block.base_addr, block.user_size, block.align) };
+}
+
+/// Return a block's memory once the quarantine lets go of it.
+///
+/// # Safety
+/// `base` must come from `reserve(user_size, align)` and nothing may
+/// reference the block any more.
+unsafe fn release(
+    base: usize,
+    #[allow(unused_variables)] user_size: usize,
+    #[allow(unused_variables)] align: usize,
+) {
+    #[cfg(all(any(test, feature = "test-hooks"), not(feature = "guard-pages")))]
+    if hooks::release_placed(base) {
+        return;
+    }
+    #[cfg(feature = "guard-pages")]
+    // SAFETY: Forwarded from the caller.
+    unsafe {
+        guard::unmap(base, user_size, align)
+    };
+    #[cfg(not(feature = "guard-pages"))]
+    // SAFETY: base was returned by malloc or aligned_alloc in reserve.
+    unsafe {
+        crate::platform::free(base as *mut u8)
+    };
+}
+
+/// `sanitized_alloc` with the user region zeroed. The red zones are
+/// already canaried, so only the user bytes are written, and never
+/// scribbled first.
+///
+/// # Safety
+/// Same as `sanitized_alloc`.
+pub unsafe fn sanitized_alloc_zeroed(layout: Layout) -> *mut u8 {
+    // SAFETY: Forwarded from the caller.
+    unsafe { alloc_zeroed_inner(layout, AllocKind::Rust) }
+}
+
+/// `alloc_inner`, but the user region is zeroed instead of scribbled.
+///
+/// # Safety
+/// Same as `alloc_inner`.
+pub unsafe fn alloc_zeroed_inner(layout: Layout, kind: AllocKind) -> *mut u8 {
+    // SAFETY: Forwarded from the caller.
+    unsafe { alloc_filled(layout, kind, Some(0)) }
+}
+
 /// Sanitized deallocation: checks for errors, quarantines the block.
 ///
 /// # Safety
@@ -41,65 +227,351 @@ pub unsafe fn sanitized_dealloc(ptr: *mut u8, _layout: Layout) {
     unsafe { dealloc_inner(ptr, AllocKind::Rust) };
 }
 
-/// Deallocation logic shared between the global allocator and future
+/// A deallocation error detected by `try_dealloc`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DeallocError {
+    /// The address was already freed and is still in quarantine.
+    DoubleFree { addr: usize },
+    /// The address was never returned by a tracked allocation.
+    InvalidFree { addr: usize },
+    /// The address points `offset` bytes into the live block at `block`.
+    InteriorFree {
+        addr: usize,
+        block: usize,
+        offset: usize,
+    },
+    /// Freed with a different allocator family than it was allocated with.
+    MismatchedKind {
+        addr: usize,
+        allocated: AllocKind,
+        freed: AllocKind,
+    },
+    /// A red zone around the block was overwritten.
+    RedzoneCorrupt {
+        addr: usize,
+        size: usize,
+        prefix: bool,
+        suffix: bool,
+        /// Bytes past the end the suffix was overwritten up to, at least.
+        overrun: usize,
+    },
+    /// A sized delete passed a size other than the one allocated.
+    SizeMismatch {
+        addr: usize,
+        tracked: usize,
+        claimed: usize,
+    },
+}
+
+/// Deallocation logic shared between the global allocator and the
 /// operator delete exports. `expected_kind` is checked against the
-/// tracked allocation kind.
+/// tracked allocation kind. Any error is reported and aborts; in
+/// recoverable mode the block is left allocated instead.
 /// # Safety
 /// `ptr` must be null or a pointer previously returned by `sanitized_alloc`.
 pub unsafe fn dealloc_inner(ptr: *mut u8, expected_kind: AllocKind) {
+    unsafe { dealloc_sized_inner(ptr, expected_kind, 0) };
+}
+
+/// `dealloc_inner` for sized deletes: `claimed_size` is cross-checked
+/// against the tracked size. A claimed size of 0 skips the check, since
+/// some toolchains pass 0 when the size is unknown.
+/// # Safety
+/// `ptr` must be null or a pointer previously returned by `sanitized_alloc`.
+pub unsafe fn dealloc_sized_inner(ptr: *mut u8, expected_kind: AllocKind, claimed_size: usize) {
+    match unsafe { try_dealloc_sized(ptr, expected_kind, claimed_size) } {
+        Ok(()) => {}
+        Err(DeallocError::DoubleFree { addr }) => diagnostic::double_free(addr),
+        Err(DeallocError::InvalidFree { addr }) => diagnostic::invalid_free(addr),
+        Err(DeallocError::InteriorFree { addr, block, .. }) => {
+            diagnostic::interior_free(addr, block)
+        }
+        Err(DeallocError::MismatchedKind {
+            addr,
+            allocated,
+            freed,
+        }) => diagnostic::mismatched_dealloc(addr, allocated, freed),
+        Err(DeallocError::RedzoneCorrupt {
+            addr,
+            size,
+            prefix,
+            suffix,
+            overrun,
+        }) => {
+            // SAFETY: try_dealloc_sized leaves a corrupt block allocated.
+            unsafe { diagnostic::overflow_detected(addr, size, prefix, suffix, overrun) }
+        }
+        Err(DeallocError::SizeMismatch {
+            addr,
+            tracked,
+            claimed,
+        }) => diagnostic::size_mismatch(addr, tracked, claimed),
+    }
+}
+
+/// Non-aborting deallocation: runs the same checks as `dealloc_inner` but
+/// returns the first error instead of reporting it.
+///
+/// On error the block is left exactly as it was (still tracked, not
+/// poisoned), so the caller decides what to do with it.
+///
+/// # Safety
+/// `ptr` must be null or a pointer previously returned by `sanitized_alloc`.
+pub unsafe fn try_dealloc(ptr: *mut u8, expected_kind: AllocKind) -> Result<(), DeallocError> {
+    unsafe { try_dealloc_sized(ptr, expected_kind, 0) }
+}
+
+/// Non-aborting form of `dealloc_sized_inner`.
+///
+/// # Safety
+/// `ptr` must be null or a pointer previously returned by `sanitized_alloc`.
+pub unsafe fn try_dealloc_sized(
+    ptr: *mut u8,
+    expected_kind: AllocKind,
+    claimed_size: usize,
+) -> Result<(), DeallocError> {
     if ptr.is_null() {
-        return;
+        return Ok(());
     }
 
     let user_addr = ptr as usize;
 
-    match tracker::remove(user_addr) {
-        Some((tracked_size, tracked_kind)) => {
-            // Check alloc/dealloc kind matches (new vs new[], etc.).
-            if !kind_compatible(tracked_kind, expected_kind) {
-                diagnostic::mismatched_dealloc(user_addr, tracked_kind, expected_kind);
-            }
+    // The tracker decides whether the block is live. Taking the entry out
+    // straight away keeps the common case to one locked operation; it is
+    // put back if a check fails.
+    let Some(alloc) = tracker::take(user_addr) else {
+        return Err(untracked_error(user_addr));
+    };
+    // A block hidden by the dead-allocation check must be readable again
+    // before its header is.
+    #[cfg(feature = "guard-pages")]
+    // SAFETY: ptr is a live guarded block mapped at alloc.base.
+    let dead = unsafe { guard::reveal_for_free(alloc.base, ptr, alloc.size, alloc.align) };
+    // SAFETY: ptr is a live block, so its header and red zones are in place.
+    if let Err(err) = unsafe { check_free(ptr, &alloc, expected_kind, claimed_size) } {
+        tracker::restore(user_addr, &alloc);
+        return Err(err);
+    }
+    let tracker::Allocation {
+        base: base_addr,
+        size: tracked_size,
+        align,
+        kind: tracked_kind,
+        frames,
+        ..
+    } = alloc;
+    alloc_hook::notify(AllocEvent::Free, user_addr, tracked_size, tracked_kind);
 
-            // Check redzones for overflow/underflow.
-            let base = unsafe { ptr.sub(redzone::REDZONE_SIZE) };
-            // SAFETY: base is the original malloc'd pointer.
-            unsafe { redzone::check_canaries(base, tracked_size, user_addr) };
+    #[cfg(feature = "guard-pages")]
+    if dead {
+        diagnostic::dead_allocation(user_addr, tracked_size, tracked_kind, &frames);
+    }
 
-            // Poison user data to catch use-after-free reads.
-            // SAFETY: ptr points to tracked_size bytes of allocated memory.
-            unsafe { redzone::poison(ptr, tracked_size) };
+    // SAFETY: ptr points to tracked_size bytes of allocated memory, after
+    // its header.
+    if PARANOID.load(Ordering::Relaxed)
+        && unsafe {
+            redzone::looks_canary_filled(ptr, tracked_size, redzone::read_header(ptr).canary)
+        }
+    {
+        diagnostic::canary_fill_hint(user_addr, tracked_size);
+    }
 
-            // Quarantine instead of immediately freeing.
-            let evicted = quarantine::push(user_addr, base as usize, tracked_size);
+    // Poison user data to catch use-after-free reads.
+    // SAFETY: ptr points to tracked_size bytes of allocated memory, after
+    // its header.
+    unsafe {
+        redzone::poison(ptr, tracked_size);
+        redzone::invalidate_header(ptr);
+    }
 
-            // If the quarantine evicted an old entry, actually free it now.
-            if let Some(base_addr) = evicted {
-                // SAFETY: base_addr was previously returned by malloc.
-                unsafe { crate::platform::free(base_addr as *mut u8) };
-            }
-        }
-        None => {
-            if quarantine::contains(user_addr) {
-                diagnostic::double_free(user_addr);
+    // Stamp the tripwire so stale readers can recognize a freed block.
+    let generation = quarantine::next_generation();
+    // SAFETY: ptr points to tracked_size bytes of allocated memory.
+    unsafe { redzone::set_tripwire(ptr, tracked_size, generation) };
+
+    // Any later access faults instead of reading the poison.
+    #[cfg(feature = "guard-pages")]
+    // SAFETY: base_addr was mapped by reserve for this size and align.
+    unsafe {
+        guard::protect(base_addr, tracked_size, align)
+    };
+
+    // Quarantine instead of immediately freeing; whatever the quarantine
+    // lets go of to make room is actually released.
+    let block = quarantine::FreedBlock {
+        user_addr,
+        base_addr,
+        user_size: tracked_size,
+        align,
+        kind: tracked_kind,
+        generation,
+        frames,
+        freed_by: backtrace::Tid::current(),
+    };
+    // SAFETY: Only blocks leaving the quarantine are passed to the callback.
+    quarantine::push(block, |evicted| unsafe { retire(evicted) });
+
+    Ok(())
+}
+
+/// The checks a block must pass before `try_dealloc_sized` lets go of it.
+///
+/// # Safety
+/// `ptr` must be the user region of `alloc`, still allocated.
+unsafe fn check_free(
+    ptr: *mut u8,
+    alloc: &tracker::Allocation,
+    expected_kind: AllocKind,
+    claimed_size: usize,
+) -> Result<(), DeallocError> {
+    let addr = ptr as usize;
+
+    // A block the tracker still lists but that was already freed: it was
+    // re-entered into the table somehow, so quarantine never saw this free.
+    // SAFETY: Forwarded from the caller.
+    if unsafe { redzone::read_header(ptr) }.is_freed() {
+        return Err(DeallocError::DoubleFree { addr });
+    }
+
+    // Check alloc/dealloc kind matches (new vs new[], etc.).
+    if !kind_compatible(alloc.kind, expected_kind) {
+        return Err(DeallocError::MismatchedKind {
+            addr,
+            allocated: alloc.kind,
+            freed: expected_kind,
+        });
+    }
+
+    if claimed_size != 0 && claimed_size != alloc.size {
+        return Err(DeallocError::SizeMismatch {
+            addr,
+            tracked: alloc.size,
+            claimed: claimed_size,
+        });
+    }
+
+    // Check the header and redzones for overflow/underflow.
+    // SAFETY: Forwarded from the caller.
+    let (prefix, suffix) = unsafe { redzone::block_status(ptr, alloc.size, alloc.kind) };
+    if prefix || suffix {
+        return Err(DeallocError::RedzoneCorrupt {
+            addr,
+            size: alloc.size,
+            prefix,
+            suffix,
+            overrun: if suffix {
+                // SAFETY: Forwarded from the caller.
+                unsafe { redzone::block_overrun(ptr, alloc.size) }
             } else {
-                diagnostic::invalid_free(user_addr);
-            }
+                0
+            },
+        });
+    }
+    Ok(())
+}
+
+fn untracked_error(addr: usize) -> DeallocError {
+    if quarantine::contains(addr) {
+        DeallocError::DoubleFree { addr }
+    } else if let Some((block, _)) = tracker::find_containing(addr) {
+        DeallocError::InteriorFree {
+            addr,
+            block,
+            offset: addr - block,
         }
+    } else {
+        DeallocError::InvalidFree { addr }
     }
 }
 
 /// Sanitized reallocation: alloc + copy + dealloc.
 ///
-/// Cannot use platform realloc directly because of redzone layout.
+/// Cannot use platform realloc directly because of redzone layout. A
+/// shrinking realloc keeps the block in place instead: the suffix red zone
+/// moves down to the new end and the cut-off tail is poisoned. Hooks see
+/// the old size freed and the new size allocated at the same address.
+/// With `guard-pages` the mapping is sized for the old block, so shrinking
+/// still moves it. A grown block comes from `alloc_inner`, so with
+/// scribbling on the bytes past the old size read as `SCRIBBLE_BYTE`.
 ///
 /// # Safety
 /// `ptr` must have been returned by `sanitized_alloc`. `new_size` must be > 0.
 pub unsafe fn sanitized_realloc(ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+    // SAFETY: Forwarded from the caller.
+    unsafe { realloc_inner(ptr, layout, new_size, AllocKind::Rust) }
+}
+
+/// Reallocation logic shared between the global allocator and the C
+/// `realloc` export. A moved block is allocated and freed as `kind`; a
+/// block from another family is reported as a mismatched deallocation.
+///
+/// # Safety
+/// `ptr` must have been returned by `alloc_inner` for `kind`, with
+/// `layout`.
+pub unsafe fn realloc_inner(
+    ptr: *mut u8,
+    layout: Layout,
+    new_size: usize,
+    kind: AllocKind,
+) -> *mut u8 {
+    let tracked = tracker::lookup(ptr as usize);
+
+    // C++ has no realloc for operator new/new[] blocks. In recoverable
+    // mode the block is left alone and the realloc fails.
+    if let Some((_, kind)) = tracked
+        && matches!(kind, AllocKind::ScalarNew | AllocKind::ArrayNew)
+    {
+        diagnostic::realloc_of_cxx_allocation(ptr as usize, kind);
+        return core::ptr::null_mut();
+    }
+
+    // The in-place paths below never reach dealloc_inner, so check the
+    // family here. In recoverable mode the realloc fails the same way.
+    if let Some((_, tracked_kind)) = tracked
+        && !kind_compatible(tracked_kind, kind)
+    {
+        diagnostic::mismatched_dealloc(ptr as usize, tracked_kind, kind);
+        return core::ptr::null_mut();
+    }
+
+    // Nothing changes: keep the block in place, but still verify it.
+    if new_size == layout.size()
+        && let Some((tracked_size, kind)) = tracked
+    {
+        // SAFETY: ptr is tracked, so its header and red zones are in place.
+        unsafe { redzone::check_canaries(ptr, tracked_size, kind) };
+        return ptr;
+    }
+
+    if new_size < layout.size()
+        && !cfg!(feature = "guard-pages")
+        && let Some((tracked_size, kind)) = tracked
+        && new_size < tracked_size
+    {
+        // SAFETY: ptr is tracked, so its header and red zones are in place,
+        // and new_size < tracked_size.
+        unsafe {
+            redzone::check_canaries(ptr, tracked_size, kind);
+            redzone::shrink(ptr, tracked_size, new_size);
+        }
+        tracker::update_size(ptr as usize, new_size);
+        alloc_hook::notify(AllocEvent::Free, ptr as usize, tracked_size, kind);
+        alloc_hook::notify(AllocEvent::Alloc, ptr as usize, new_size, kind);
+        return ptr;
+    }
+
+    // The new block keeps the old alignment; sanitized_alloc widens the
+    // prefix to honour it.
     let new_layout = unsafe { Layout::from_size_align_unchecked(new_size, layout.align()) };
-    let new_ptr = unsafe { sanitized_alloc(new_layout) };
+    let new_ptr = unsafe { alloc_inner(new_layout, kind) };
     if new_ptr.is_null() {
         return new_ptr;
     }
+    debug_assert!((new_ptr as usize).is_multiple_of(layout.align()));
+    if let Some(tag) = tracker::allocation(ptr as usize).and_then(|a| a.tag) {
+        tracker::set_tag(new_ptr as usize, tag);
+    }
 
     let copy_size = if layout.size() < new_size {
         layout.size()
@@ -107,22 +579,883 @@ pub unsafe fn sanitized_realloc(ptr: *mut u8, layout: Layout, new_size: usize) -
         new_size
     };
     // SAFETY: Both pointers are valid for copy_size bytes, non-overlapping
-    // (sanitized_alloc returned a fresh allocation).
+    // (alloc_inner returned a fresh allocation).
     unsafe { core::ptr::copy_nonoverlapping(ptr, new_ptr, copy_size) };
 
     // Free old block through sanitized path.
-    unsafe { sanitized_dealloc(ptr, layout) };
+    unsafe { dealloc_inner(ptr, kind) };
 
     new_ptr
 }
 
-/// Rust alloc is compatible with itself. Future operator new/delete
-/// will enforce scalar vs array matching.
+/// Identify `ptr` as a freed, still-quarantined block by its tripwire header.
+///
+/// Returns the block's free generation when the header carries the
+/// tripwire and the generation matches the quarantine record, i.e. `ptr`
+/// is a dangling pointer to memory freed in that generation.
+///
+/// With `guard-pages` the freed block is inaccessible, so the header is
+/// not read and the quarantine record alone decides.
+///
+/// # Safety
+/// `ptr` must point to at least `redzone::TRIPWIRE_SIZE` readable bytes.
+pub unsafe fn freed_generation(ptr: *const u8) -> Option<u64> {
+    if cfg!(feature = "guard-pages") {
+        return quarantine::generation(ptr as usize);
+    }
+    // SAFETY: Caller guarantees TRIPWIRE_SIZE readable bytes.
+    let stamped = unsafe { redzone::read_tripwire(ptr) }?;
+    match quarantine::generation(ptr as usize) {
+        Some(generation) if generation == stamped => Some(generation),
+        _ => None,
+    }
+}
+
+/// Report `ptr` as dangling if it points into a quarantined block, printing
+/// the original allocation it referenced. Returns `true` if it did.
+///
+/// The report also says whether the block's tripwire header still carries
+/// the generation it was freed with; a mismatch means something wrote to
+/// the block after it was freed.
+pub fn report_dangling(ptr: *const u8) -> bool {
+    let Some(block) = quarantine::find_containing(ptr as usize) else {
+        return false;
+    };
+    // A guarded block cannot be written after free, and cannot be read.
+    let header_intact = block.user_size >= redzone::TRIPWIRE_SIZE
+        && (cfg!(feature = "guard-pages")
+            // SAFETY: The block is still quarantined, so its user region (at
+            // least TRIPWIRE_SIZE bytes here) remains mapped.
+            || unsafe { redzone::read_tripwire(block.user_addr as *const u8) }
+                == Some(block.generation));
+    diagnostic::dangling_pointer(ptr as usize, &block, header_intact);
+    true
+}
+
+/// Whether a described block is still allocated.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Status {
+    Live,
+    /// Freed and still held in quarantine.
+    Quarantined {
+        generation: u64,
+    },
+}
+
+/// Everything the sanitizer knows about one allocation.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Description {
+    /// The malloc'd base the user region was carved from.
+    pub base: usize,
+    pub user_size: usize,
+    /// Requested alignment. Only known while the block is live.
+    pub align: Option<usize>,
+    pub kind: AllocKind,
+    /// Allocation sequence number. Only known while the block is live.
+    pub seq: Option<u64>,
+    /// Label from `tag_allocation`. Only known while the block is live.
+    pub tag: Option<&'static str>,
+    /// Allocation site; empty unless built with `backtrace`.
+    pub frames: backtrace::Frames,
+    pub status: Status,
+}
+
+/// Look up `ptr` in the tracker and then the quarantine.
+///
+/// `ptr` must be the exact pointer an allocation returned; interior
+/// pointers and untracked addresses give `None`.
+pub fn describe(ptr: *const u8) -> Option<Description> {
+    let addr = ptr as usize;
+    if let Some(alloc) = tracker::allocation(addr) {
+        return Some(Description {
+            base: alloc.base,
+            user_size: alloc.size,
+            align: Some(alloc.align),
+            kind: alloc.kind,
+            seq: Some(alloc.seq),
+            tag: alloc.tag,
+            frames: alloc.frames,
+            status: Status::Live,
+        });
+    }
+    let block = quarantine::find_containing(addr).filter(|b| b.user_addr == addr)?;
+    Some(Description {
+        base: block.base_addr,
+        user_size: block.user_size,
+        align: None,
+        kind: block.kind,
+        seq: None,
+        tag: None,
+        frames: block.frames,
+        status: Status::Quarantined {
+            generation: block.generation,
+        },
+    })
+}
+
+/// Label the live allocation at `ptr`, e.g. with the subsystem that owns
+/// it. The tag is shown by `describe` and in JSONL leak reports, and
+/// follows the block through a realloc that moves it. Returns `false` if
+/// `ptr` is not a live allocation.
+pub fn tag_allocation(ptr: *const u8, tag: &'static str) -> bool {
+    tracker::set_tag(ptr as usize, tag)
+}
+
+/// Live and cumulative allocation counts, e.g. to assert a long-running
+/// test does not grow.
+pub fn stats() -> tracker::Stats {
+    tracker::stats()
+}
+
+/// How full the quarantine is and has been, e.g. to check whether
+/// `set_quarantine_budget` is too small for a workload.
+pub fn quarantine_stats() -> quarantine::Stats {
+    quarantine::stats()
+}
+
+/// Number of live allocations, without printing anything. Cheap enough to
+/// call at every test teardown and compare with a baseline taken at setup.
+pub fn live_count() -> usize {
+    tracker::live_count()
+}
+
+/// Live user bytes, the byte counterpart of `live_count`.
+pub fn live_bytes() -> usize {
+    tracker::live_bytes()
+}
+
+/// Return the sanitizer to its startup state between test cases: the
+/// tracker forgets every live block and zeroes its statistics, quarantined
+/// blocks are freed without being checked and its high-water marks
+/// cleared, and the recoverable-mode error
+/// count restarts at zero. Settings (policy, budgets, hooks) are kept.
+///
+/// # Safety
+/// No other thread may allocate or free while this runs, and no block
+/// allocated before the call may be freed after it: the tracker no longer
+/// knows it.
+#[cfg(any(test, feature = "test-hooks"))]
+pub unsafe fn reset() {
+    tracker::reset();
+    quarantine::clear(|block| {
+        // SAFETY: The block left quarantine; the caller guarantees nothing
+        // else is using the allocator.
+        unsafe { release(block.base_addr, block.user_size, block.align) }
+    });
+    diagnostic::reset_error_count();
+}
+
+/// Leave leaks allocated through the code in `range` (return addresses,
+/// e.g. a one-time init function's body) out of the exit-time leak report.
+/// Needs `backtrace`. Returns `false` if the suppression table is full.
+pub fn suppress_leaks_from(range: core::ops::Range<usize>) -> bool {
+    tracker::suppress_leaks_from(range)
+}
+
+/// Start attributing new allocations to a leak scope.
+pub fn enter_scope() {
+    tracker::enter_scope();
+}
+
+/// End the current leak scope, reporting allocations from it that are
+/// still live. Returns how many were reported.
+pub fn leave_scope_and_report() -> usize {
+    tracker::leave_scope_and_report()
+}
+
+/// Register an allocation observer. Several hooks can be installed at once;
+/// each sees every allocation and free. Returns `false` if all slots are used.
+pub fn add_alloc_hook(hook: AllocHook) -> bool {
+    alloc_hook::add(hook)
+}
+
+/// Unregister an allocation observer added with `add_alloc_hook`.
+pub fn remove_alloc_hook(hook: AllocHook) -> bool {
+    alloc_hook::remove(hook)
+}
+
+/// Name a memory region (e.g. a custom arena) so invalid-free reports for
+/// pointers inside it say which region they fell into.
+pub fn register_region(start: usize, len: usize, name: &'static str) -> bool {
+    region::register(start, len, name)
+}
+
+/// Report segmentation faults, naming the freed block when the faulting
+/// address is inside one still in quarantine. Returns `false` if the
+/// handler could not be installed.
+pub fn install_segv_handler() -> bool {
+    segv::install()
+}
+
+/// Hide each new block until its first access and report blocks freed
+/// without one. Installs the SIGSEGV handler; returns `false` if that
+/// failed. Any access counts, including the sanitizer's own checks.
+#[cfg(feature = "guard-pages")]
+pub fn set_dead_alloc_check(enabled: bool) -> bool {
+    guard::set_dead_check(enabled)
+}
+
+/// Print the main error reports as single-line JSON objects, overriding
+/// `LIBCPP_FORMAT`.
+pub fn set_json_diagnostics(enabled: bool) {
+    diagnostic::set_json(enabled);
+}
+
+/// Start report headers with a monotonic timestamp, overriding
+/// `LIBCPP_TIMESTAMPS`.
+pub fn set_diagnostic_timestamps(enabled: bool) {
+    diagnostic::set_timestamps(enabled);
+}
+
+/// Colour report headers, or leave them plain, overriding `LIBCPP_COLOR`
+/// and the terminal check.
+pub fn set_diagnostic_color(enabled: bool) {
+    diagnostic::set_color(enabled);
+}
+
+/// Call `hook` with the details of every error before it is reported.
+/// The hook must not allocate or free; see `error_hook`.
+pub fn set_error_hook(hook: error_hook::ErrorHook) {
+    error_hook::set(hook);
+}
+
+/// Remove the hook installed by `set_error_hook`.
+pub fn clear_error_hook() {
+    error_hook::clear();
+}
+
+/// Report errors and keep running instead of aborting, overriding
+/// `LIBCPP_HALT_ON_ERROR`. A process that survived errors exits with the
+/// `LIBCPP_EXITCODE` status, or 1, after printing how many there were.
+pub fn set_halt_on_error(enabled: bool) {
+    diagnostic::set_halt_on_error(enabled);
+}
+
+/// Print at most `max` reports of `kind` from now on (0 lifts the cap),
+/// then one "suppressing further" note. Errors past the cap are still
+/// counted and passed to the error hook.
+pub fn set_max_reports(kind: error_hook::ErrorKind, max: usize) {
+    diagnostic::set_max_reports(kind, max);
+}
+
+/// End the process with exit status `code` after a fatal report instead of
+/// raising SIGABRT, overriding `LIBCPP_EXITCODE`. `None` restores the abort.
+pub fn set_error_exit_code(code: Option<u8>) {
+    diagnostic::set_exit_code(code);
+}
+
+/// Set the red zone size, overriding `LIBCPP_REDZONE`. Only possible before
+/// the first sanitized allocation; returns `false` afterwards or if `size`
+/// is not a multiple of 16.
+pub fn set_redzone_size(size: usize) -> bool {
+    redzone::set_size(size)
+}
+
+/// Fill every new allocation except zeroed ones with `0xCD`, overriding
+/// `LIBCPP_SCRIBBLE`. Reads of uninitialized memory then show a
+/// recognizable pattern instead of whatever the block held before.
+pub fn set_scribble(enabled: bool) {
+    redzone::set_scribble(enabled);
+}
+
+/// Give every new block the fixed `0xAB` canary instead of a random one,
+/// overriding `fixed_canary` in `LIBCPP_OPTIONS`. Blocks already allocated
+/// keep their canary.
+pub fn set_fixed_canary(enabled: bool) {
+    redzone::set_fixed_canary(enabled);
+}
+
+/// Release every block held in quarantine after checking it for writes
+/// after free. Returns how many were released. Runs at exit on its own.
+pub fn drain_quarantine() -> usize {
+    quarantine::drain()
+}
+
+/// Cap the total bytes of freed blocks held in quarantine (16 MiB by default).
+pub fn set_quarantine_budget(bytes: usize) {
+    quarantine::set_byte_budget(bytes);
+}
+
+/// Select how the quarantine chooses which freed block to release once full.
+pub fn set_quarantine_policy(policy: quarantine::Policy) {
+    quarantine::set_policy(policy);
+}
+
+/// Each allocation family is only compatible with itself: Rust alloc with
+/// Rust dealloc, operator new with operator delete, new[] with delete[],
+/// malloc with free.
 fn kind_compatible(tracked: AllocKind, freed: AllocKind) -> bool {
     matches!(
         (tracked, freed),
         (AllocKind::Rust, AllocKind::Rust)
             | (AllocKind::ScalarNew, AllocKind::ScalarNew)
             | (AllocKind::ArrayNew, AllocKind::ArrayNew)
+            | (AllocKind::Malloc, AllocKind::Malloc)
     )
 }
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::testing;
+
+    #[test]
+    fn try_dealloc_returns_each_error_and_leaves_the_block() {
+        const TEST: &str = "sanitize::tests::try_dealloc_returns_each_error_and_leaves_the_block";
+        if testing::in_child(TEST) {
+            let layout = Layout::from_size_align(32, 8).unwrap();
+            // SAFETY: Every failed free leaves its block allocated; each
+            // block is freed for real once at the end, the red zone write
+            // stays inside the block's own suffix.
+            unsafe {
+                let ptr = alloc_inner(layout, AllocKind::ScalarNew);
+                let addr = ptr as usize;
+                assert_eq!(
+                    try_dealloc(ptr, AllocKind::ArrayNew),
+                    Err(DeallocError::MismatchedKind {
+                        addr,
+                        allocated: AllocKind::ScalarNew,
+                        freed: AllocKind::ArrayNew,
+                    })
+                );
+                assert_eq!(
+                    try_dealloc_sized(ptr, AllocKind::ScalarNew, 31),
+                    Err(DeallocError::SizeMismatch {
+                        addr,
+                        tracked: 32,
+                        claimed: 31,
+                    })
+                );
+                assert_eq!(
+                    try_dealloc(ptr.add(8), AllocKind::ScalarNew),
+                    Err(DeallocError::InteriorFree {
+                        addr: addr + 8,
+                        block: addr,
+                        offset: 8,
+                    })
+                );
+                let stack = 0u64;
+                let wild = &raw const stack as *mut u8;
+                assert_eq!(
+                    try_dealloc(wild, AllocKind::ScalarNew),
+                    Err(DeallocError::InvalidFree {
+                        addr: wild as usize
+                    })
+                );
+
+                let tail = ptr.add(32);
+                tail.write(!tail.read());
+                assert_eq!(
+                    try_dealloc(ptr, AllocKind::ScalarNew),
+                    Err(DeallocError::RedzoneCorrupt {
+                        addr,
+                        size: 32,
+                        prefix: false,
+                        suffix: true,
+                        overrun: 1,
+                    })
+                );
+                tail.write(!tail.read());
+                assert_eq!(tracker::lookup(addr), Some((32, AllocKind::ScalarNew)));
+
+                assert_eq!(try_dealloc(ptr, AllocKind::ScalarNew), Ok(()));
+                assert_eq!(
+                    try_dealloc(ptr, AllocKind::ScalarNew),
+                    Err(DeallocError::DoubleFree { addr })
+                );
+                assert_eq!(try_dealloc(core::ptr::null_mut(), AllocKind::Rust), Ok(()));
+            }
+            return;
+        }
+        let child = testing::spawn(TEST, &[]);
+        let stderr = child.stderr();
+        assert_eq!(child.code(), Some(0), "{stderr}");
+        assert!(stderr.is_empty(), "nothing is reported: {stderr}");
+    }
+
+    #[test]
+    fn realloc_of_an_array_new_block_is_reported() {
+        const TEST: &str = "sanitize::tests::realloc_of_an_array_new_block_is_reported";
+        if testing::in_child(TEST) {
+            set_halt_on_error(false);
+            let layout = Layout::from_size_align(24, 8).unwrap();
+            // SAFETY: The realloc is refused, so the block is freed once,
+            // with the delete[] it came from.
+            unsafe {
+                let ptr = alloc_inner(layout, AllocKind::ArrayNew);
+                std::eprintln!("array={:#018x}", ptr as usize);
+                assert!(realloc_inner(ptr, layout, 48, AllocKind::Malloc).is_null());
+                assert_eq!(
+                    tracker::lookup(ptr as usize),
+                    Some((24, AllocKind::ArrayNew))
+                );
+                assert_eq!(diagnostic::error_count(), 1);
+                dealloc_inner(ptr, AllocKind::ArrayNew);
+            }
+            return;
+        }
+        let child = testing::spawn(TEST, &[]);
+        let stderr = child.stderr();
+        assert_eq!(child.code(), Some(0), "{stderr}");
+        let (first, report) = stderr.split_once('\n').unwrap();
+        let addr = first.strip_prefix("array=").expect(first);
+        if cfg!(feature = "minimal-diagnostics") {
+            assert!(report.contains(&std::format!("SAN 5 {addr}")), "{stderr}");
+            return;
+        }
+        assert!(
+            report.contains("ERROR: realloc of memory from a C++ allocation"),
+            "{stderr}"
+        );
+        assert!(
+            report.contains(&std::format!("address:        {addr}")),
+            "{stderr}"
+        );
+        assert!(
+            report.contains("allocated with: operator new[]"),
+            "{stderr}"
+        );
+    }
+
+    #[test]
+    fn realloc_in_place_with_the_wrong_family_is_reported() {
+        const TEST: &str = "sanitize::tests::realloc_in_place_with_the_wrong_family_is_reported";
+        if testing::in_child(TEST) {
+            set_halt_on_error(false);
+            let layout = Layout::from_size_align(64, 8).unwrap();
+            // SAFETY: Both reallocs are refused, so the block is freed once,
+            // with the free it came from.
+            unsafe {
+                let ptr = alloc_inner(layout, AllocKind::Malloc);
+                std::eprintln!("block={:#018x}", ptr as usize);
+                assert!(realloc_inner(ptr, layout, 64, AllocKind::Rust).is_null());
+                assert!(realloc_inner(ptr, layout, 16, AllocKind::Rust).is_null());
+                assert_eq!(tracker::lookup(ptr as usize), Some((64, AllocKind::Malloc)));
+                assert_eq!(diagnostic::error_count(), 2);
+                dealloc_inner(ptr, AllocKind::Malloc);
+            }
+            return;
+        }
+        let child = testing::spawn(TEST, &[]);
+        let stderr = child.stderr();
+        assert_eq!(child.code(), Some(0), "{stderr}");
+        let (first, report) = stderr.split_once('\n').unwrap();
+        let addr = first.strip_prefix("block=").expect(first);
+        if cfg!(feature = "minimal-diagnostics") {
+            let line = std::format!("SAN 3 {addr}\n");
+            assert_eq!(report.matches(&line).count(), 2, "{stderr}");
+            return;
+        }
+        assert_eq!(
+            report.matches("ERROR: mismatched deallocation").count(),
+            2,
+            "{stderr}"
+        );
+        assert!(
+            report.contains(&std::format!("address:        {addr}")),
+            "{stderr}"
+        );
+        assert!(report.contains("allocated with: malloc"), "{stderr}");
+        assert!(report.contains("freed with:     rust dealloc"), "{stderr}");
+    }
+
+    #[test]
+    fn grown_realloc_tail_is_scribbled() {
+        const TEST: &str = "sanitize::tests::grown_realloc_tail_is_scribbled";
+        if testing::in_child(TEST) {
+            set_scribble(true);
+            let layout = Layout::from_size_align(16, 8).unwrap();
+            // SAFETY: Each block is written within its size and freed once.
+            unsafe {
+                let ptr = sanitized_alloc(layout);
+                core::ptr::write_bytes(ptr, 0x11, 16);
+                let grown = sanitized_realloc(ptr, layout, 100);
+                let bytes = core::slice::from_raw_parts(grown, 100);
+                assert!(bytes[..16].iter().all(|&b| b == 0x11));
+                assert!(bytes[16..].iter().all(|&b| b == redzone::SCRIBBLE_BYTE));
+                sanitized_dealloc(grown, Layout::from_size_align(100, 8).unwrap());
+            }
+            return;
+        }
+        let child = testing::spawn(TEST, &[]);
+        assert_eq!(child.code(), Some(0), "{}", child.stderr());
+    }
+
+    #[test]
+    fn equal_size_realloc_keeps_the_block_and_checks_it() {
+        const TEST: &str = "sanitize::tests::equal_size_realloc_keeps_the_block_and_checks_it";
+        if testing::in_child(TEST) {
+            let layout = Layout::from_size_align(48, 8).unwrap();
+            // SAFETY: The suffix write lands in the block's own red zone;
+            // the second realloc reports it and exits.
+            unsafe {
+                let ptr = sanitized_alloc(layout);
+                for i in 0..48 {
+                    ptr.add(i).write(i as u8);
+                }
+                let same = sanitized_realloc(ptr, layout, 48);
+                assert_eq!(same, ptr);
+                assert!((0..48).all(|i| same.add(i).read() == i as u8));
+                assert_eq!(tracker::lookup(ptr as usize), Some((48, AllocKind::Rust)));
+                std::eprintln!("kept in place");
+                let tail = ptr.add(48);
+                tail.write(!tail.read());
+                sanitized_realloc(ptr, layout, 48);
+            }
+            unreachable!();
+        }
+        let child = testing::spawn(TEST, &[("LIBCPP_EXITCODE", "23")]);
+        let stderr = child.stderr();
+        assert_eq!(child.code(), Some(23), "{stderr}");
+        let report = stderr.split_once("kept in place\n").expect(&stderr).1;
+        let overflow = if cfg!(feature = "minimal-diagnostics") {
+            "SAN 4 "
+        } else {
+            "buffer overflow"
+        };
+        assert!(report.contains(overflow), "{stderr}");
+    }
+
+    #[test]
+    fn dangling_pointer_report_names_the_original_allocation() {
+        const TEST: &str = "sanitize::tests::dangling_pointer_report_names_the_original_allocation";
+        if testing::in_child(TEST) {
+            let layout = Layout::from_size_align(72, 8).unwrap();
+            // SAFETY: The freed block stays quarantined and is never
+            // dereferenced here; `live` is freed once.
+            unsafe {
+                let live = alloc_inner(layout, AllocKind::ArrayNew);
+                let freed = alloc_inner(layout, AllocKind::ArrayNew);
+                dealloc_inner(freed, AllocKind::ArrayNew);
+                assert!(!report_dangling(live.add(8)));
+                std::eprintln!("freed={:#018x}", freed as usize);
+                assert!(report_dangling(freed.add(8)));
+                dealloc_inner(live, AllocKind::ArrayNew);
+            }
+            return;
+        }
+        let child = testing::spawn(TEST, &[]);
+        let stderr = child.stderr();
+        assert_eq!(child.code(), Some(0), "{stderr}");
+        let (first, report) = stderr.split_once('\n').unwrap();
+        let block = first.strip_prefix("freed=").expect(first);
+        let pointer = std::format!(
+            "{:#018x}",
+            usize::from_str_radix(&block[2..], 16).unwrap() + 8
+        );
+        if cfg!(feature = "minimal-diagnostics") {
+            assert!(
+                report.contains(&std::format!("SAN 8 {pointer}")),
+                "{stderr}"
+            );
+            return;
+        }
+        for line in [
+            std::format!("pointer:        {pointer} (8 bytes into block)\n"),
+            std::format!("block:          {block}\n"),
+            "size:           72 bytes\n".into(),
+            "allocated with: operator new[]\n".into(),
+        ] {
+            assert!(report.contains(&line), "{line:?} in {stderr}");
+        }
+        assert!(report.contains("freed in generation "), "{stderr}");
+        assert!(!report.contains("overwritten"), "{stderr}");
+    }
+
+    #[test]
+    fn describe_reports_a_block_through_its_life() {
+        const TEST: &str = "sanitize::tests::describe_reports_a_block_through_its_life";
+        if testing::in_child(TEST) {
+            let layout = Layout::from_size_align(40, 64).unwrap();
+            // SAFETY: Each block is freed once; the freed one stays mapped
+            // while quarantined.
+            unsafe {
+                let before = sanitized_alloc(Layout::from_size_align(8, 8).unwrap());
+                let ptr = alloc_inner(layout, AllocKind::ArrayNew);
+                assert!(tag_allocation(ptr, "frame \"cache\""));
+                let live = describe(ptr).unwrap();
+                assert_eq!(live.user_size, 40);
+                assert_eq!(live.align, Some(64));
+                assert_eq!(live.kind, AllocKind::ArrayNew);
+                assert_eq!(live.tag, Some("frame \"cache\""));
+                assert_eq!(live.status, Status::Live);
+                assert!(live.base < ptr as usize);
+                let earlier = describe(before).unwrap();
+                assert!(earlier.seq.unwrap() < live.seq.unwrap());
+                assert_eq!(earlier.tag, None);
+                assert_eq!(
+                    live.frames.iter().next().is_some(),
+                    cfg!(feature = "backtrace")
+                );
+
+                // Interior pointers and unknown addresses are not described.
+                assert_eq!(describe(ptr.add(8)), None);
+                assert_eq!(describe(core::ptr::dangling()), None);
+
+                dealloc_inner(ptr, AllocKind::ArrayNew);
+                let freed = describe(ptr).unwrap();
+                let generation = quarantine::generation(ptr as usize).unwrap();
+                assert_eq!(freed.status, Status::Quarantined { generation });
+                assert_eq!((freed.base, freed.user_size), (live.base, 40));
+                assert_eq!(freed.kind, AllocKind::ArrayNew);
+                assert_eq!((freed.align, freed.seq, freed.tag), (None, None, None));
+                assert!(!tag_allocation(ptr, "late"));
+
+                quarantine::drain();
+                assert_eq!(describe(ptr), None);
+                sanitized_dealloc(before, Layout::from_size_align(8, 8).unwrap());
+            }
+            return;
+        }
+        let child = testing::spawn(TEST, &[]);
+        assert_eq!(child.code(), Some(0), "{}", child.stderr());
+    }
+
+    #[test]
+    fn realloc_that_moves_a_block_keeps_its_tag() {
+        let layout = Layout::from_size_align(16, 8).unwrap();
+        // SAFETY: The block is freed once, at its final size.
+        unsafe {
+            let ptr = sanitized_alloc(layout);
+            assert!(tag_allocation(ptr, "parser"));
+            let grown = sanitized_realloc(ptr, layout, 4096);
+            assert_eq!(describe(grown).unwrap().tag, Some("parser"));
+            sanitized_dealloc(grown, Layout::from_size_align(4096, 8).unwrap());
+        }
+    }
+
+    #[test]
+    #[cfg_attr(
+        feature = "guard-pages",
+        ignore = "freed blocks are inaccessible, so the write faults"
+    )]
+    fn drain_releases_every_block_and_checks_each() {
+        const TEST: &str = "sanitize::tests::drain_releases_every_block_and_checks_each";
+        if testing::in_child(TEST) {
+            set_halt_on_error(false);
+            let layout = Layout::from_size_align(64, 8).unwrap();
+            let before = quarantine::stats().entries;
+            let mut freed = [core::ptr::null_mut(); 10];
+            // SAFETY: Each block is freed once; the write into block 3
+            // lands in its user region while it is still quarantined.
+            unsafe {
+                for slot in &mut freed {
+                    *slot = sanitized_alloc(layout);
+                }
+                for &ptr in &freed {
+                    sanitized_dealloc(ptr, layout);
+                }
+                freed[3].add(40).write(0x5a);
+            }
+            std::eprintln!("smashed={:#018x}", freed[3] as usize);
+            assert_eq!(diagnostic::error_count(), 0);
+            assert_eq!(drain_quarantine(), before + 10);
+            assert_eq!(diagnostic::error_count(), 1);
+            let stats = quarantine::stats();
+            assert_eq!((stats.entries, stats.bytes), (0, 0));
+            assert!(freed.iter().all(|&p| !quarantine::contains(p as usize)));
+            assert_eq!(drain_quarantine(), 0);
+            return;
+        }
+        let child = testing::spawn(TEST, &[]);
+        let stderr = child.stderr();
+        assert_eq!(child.code(), Some(0), "{stderr}");
+        let (first, report) = stderr.split_once('\n').unwrap();
+        let addr = first.strip_prefix("smashed=").expect(first);
+        if cfg!(feature = "minimal-diagnostics") {
+            assert_eq!(report, std::format!("SAN 13 {addr}\n"));
+            return;
+        }
+        assert_eq!(
+            report.matches("ERROR: write-after-free").count(),
+            1,
+            "{stderr}"
+        );
+        assert!(
+            report.contains(&std::format!("block:          {addr}")),
+            "{stderr}"
+        );
+        assert!(report.contains("(40 bytes into block)"), "{stderr}");
+    }
+
+    #[test]
+    fn quarantine_peak_is_printed_and_cleared_by_reset() {
+        const TEST: &str = "sanitize::tests::quarantine_peak_is_printed_and_cleared_by_reset";
+        if testing::in_child(TEST) {
+            let layout = Layout::from_size_align(50, 8).unwrap();
+            let before = quarantine_stats();
+            // SAFETY: Each block is freed once.
+            unsafe {
+                let blocks = [(); 6].map(|_| sanitized_alloc(layout));
+                for ptr in blocks {
+                    sanitized_dealloc(ptr, layout);
+                }
+            }
+            drain_quarantine();
+            let stats = quarantine_stats();
+            assert_eq!((stats.entries, stats.bytes), (0, 0));
+            assert!(stats.peak_entries >= before.entries + 6);
+            assert!(stats.peak_bytes >= before.bytes + 300);
+            diagnostic::malloc_stats(&tracker::stats(), &stats);
+            std::eprintln!("peak={} {}", stats.peak_entries, stats.peak_bytes);
+            // With `c-alloc` the harness frees its own earlier blocks after
+            // this returns, which a reset would make untracked.
+            if !cfg!(feature = "c-alloc") {
+                // SAFETY: Nothing allocated before the reset is freed after it.
+                unsafe { reset() };
+                let cleared = quarantine_stats();
+                assert_eq!((cleared.peak_entries, cleared.peak_bytes), (0, 0));
+            }
+            return;
+        }
+        let child = testing::spawn(TEST, &[]);
+        let stderr = child.stderr();
+        assert_eq!(child.code(), Some(0), "{stderr}");
+        let (count, bytes) = stderr
+            .lines()
+            .find_map(|l| l.strip_prefix("peak="))
+            .and_then(|l| l.split_once(' '))
+            .expect(&stderr);
+        let blocks = std::format!("  blocks                   0 {count:>10}\n");
+        let bytes = std::format!("  bytes                    0 {bytes:>10}\n");
+        assert!(stderr.contains(&blocks), "{stderr}");
+        assert!(stderr.contains(&bytes), "{stderr}");
+    }
+
+    #[test]
+    fn freed_block_header_identifies_its_free() {
+        const TEST: &str = "sanitize::tests::freed_block_header_identifies_its_free";
+        if testing::in_child(TEST) {
+            let layout = Layout::from_size_align(64, 8).unwrap();
+            // SAFETY: Freed blocks stay mapped while quarantined; the
+            // guarded ones are not read, see freed_generation.
+            unsafe {
+                let live = sanitized_alloc(layout);
+                assert_eq!(freed_generation(live), None);
+                let first = sanitized_alloc(layout);
+                let second = sanitized_alloc(layout);
+                sanitized_dealloc(first, layout);
+                sanitized_dealloc(second, layout);
+                let (a, b) = (freed_generation(first), freed_generation(second));
+                assert!(a.is_some() && b > a, "{a:?} {b:?}");
+                assert_eq!(a, quarantine::generation(first as usize));
+                if !cfg!(feature = "guard-pages") {
+                    assert_eq!(redzone::read_tripwire(first), a);
+                    // A stale stamp no longer matches the quarantine record.
+                    redzone::set_tripwire(first, 64, a.unwrap() + 100);
+                    assert_eq!(freed_generation(first), None);
+                    redzone::set_tripwire(first, 64, a.unwrap());
+                }
+                sanitized_dealloc(live, layout);
+            }
+            return;
+        }
+        let child = testing::spawn(TEST, &[]);
+        assert_eq!(child.code(), Some(0), "{}", child.stderr());
+    }
+
+    #[test]
+    fn over_aligned_blocks_keep_their_layout_through_free() {
+        const TEST: &str = "sanitize::tests::over_aligned_blocks_keep_their_layout_through_free";
+        if testing::in_child(TEST) {
+            for align in [32, 64, 4096] {
+                let layout = Layout::from_size_align(40, align).unwrap();
+                // SAFETY: Freed once below, with its own layout.
+                unsafe {
+                    let ptr = sanitized_alloc(layout);
+                    assert!(!ptr.is_null());
+                    assert!(ptr.addr().is_multiple_of(align), "align {align}: {ptr:p}");
+                    let header = redzone::read_header(ptr);
+                    assert_eq!(header.size, 40);
+                    let zones = ptr.sub(redzone::size());
+                    assert_eq!(
+                        redzone::canary_status(zones, 40, header.canary),
+                        (false, false)
+                    );
+                    let alloc = tracker::allocation(ptr as usize).unwrap();
+                    assert_eq!(alloc.align, align);
+                    #[cfg(not(feature = "guard-pages"))]
+                    assert_eq!(alloc.base, ptr as usize - redzone::prefix_size(align));
+                    assert_eq!(try_dealloc(ptr, AllocKind::Rust), Ok(()));
+                }
+            }
+            // Hands every base back to the platform allocator, which
+            // aborts on one it did not return.
+            drain_quarantine();
+            return;
+        }
+        let child = testing::spawn(TEST, &[]);
+        let stderr = child.stderr();
+        assert_eq!(child.code(), Some(0), "{stderr}");
+        let overflow = if cfg!(feature = "minimal-diagnostics") {
+            "SAN 4 "
+        } else {
+            "buffer overflow"
+        };
+        assert!(!stderr.contains(overflow), "{stderr}");
+    }
+
+    #[test]
+    fn realloc_keeps_over_aligned_blocks_aligned() {
+        for align in [64, 4096] {
+            let layout = Layout::from_size_align(100, align).unwrap();
+            // SAFETY: Each step reads and writes within the current size,
+            // and the block is freed once at its final size.
+            unsafe {
+                let ptr = sanitized_alloc(layout);
+                for i in 0..100 {
+                    ptr.add(i).write(i as u8);
+                }
+                let grown = sanitized_realloc(ptr, layout, 5000);
+                assert!(
+                    grown.addr().is_multiple_of(align),
+                    "align {align}: {grown:p}"
+                );
+                assert!((0..100).all(|i| grown.add(i).read() == i as u8));
+                assert_eq!(tracker::allocation(grown as usize).unwrap().align, align);
+
+                let grown_layout = Layout::from_size_align(5000, align).unwrap();
+                let shrunk = sanitized_realloc(grown, grown_layout, 30);
+                assert!(
+                    shrunk.addr().is_multiple_of(align),
+                    "align {align}: {shrunk:p}"
+                );
+                assert!((0..30).all(|i| shrunk.add(i).read() == i as u8));
+                sanitized_dealloc(shrunk, Layout::from_size_align(30, align).unwrap());
+            }
+        }
+    }
+
+    /// Guarded blocks are inaccessible once freed, so a second free faults
+    /// before it can read the header.
+    #[test]
+    #[cfg_attr(feature = "guard-pages", ignore = "freed blocks are inaccessible")]
+    fn second_free_past_the_tracker_hits_the_freed_header() {
+        const TEST: &str = "sanitize::tests::second_free_past_the_tracker_hits_the_freed_header";
+        if testing::in_child(TEST) {
+            let layout = Layout::from_size_align(48, 16).unwrap();
+            // SAFETY: Freed below, then freed again on purpose.
+            unsafe {
+                let ptr = alloc_inner(layout, AllocKind::ScalarNew);
+                dealloc_inner(ptr, AllocKind::ScalarNew);
+                assert!(hooks::retrack(ptr as usize));
+                assert_eq!(
+                    try_dealloc(ptr, AllocKind::ScalarNew),
+                    Err(DeallocError::DoubleFree { addr: ptr as usize })
+                );
+                dealloc_inner(ptr, AllocKind::ScalarNew);
+            }
+            return;
+        }
+        let child = testing::spawn(TEST, &[("LIBCPP_EXITCODE", "23")]);
+        assert_eq!(child.code(), Some(23), "{}", child.stderr());
+        let expected = if cfg!(feature = "minimal-diagnostics") {
+            "SAN 1 "
+        } else {
+            "ERROR: double-free"
+        };
+        assert!(child.stderr().contains(expected), "{}", child.stderr());
+    }
+}
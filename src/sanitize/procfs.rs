@@ -0,0 +1,247 @@
+//! Allocation-free readers for `/proc/self` process information.
+
+use crate::platform::syscall::{sys_close, sys_open, sys_read};
+
+const O_RDONLY: usize = 0;
+const O_CLOEXEC: usize = 0o2000000;
+const EINTR: isize = 4;
+
+/// Resident-set figures from `/proc/self/status`, in kB.
+#[derive(Clone, Copy, Default)]
+pub struct MemStatus {
+    /// Current resident set size (`VmRSS`).
+    pub rss_kb: usize,
+    /// Peak resident set size (`VmHWM`).
+    pub hwm_kb: usize,
+}
+
+/// Read a whole file into `buf`, looping over short reads and retrying on
+/// `EINTR` until EOF or the buffer is full. Returns the bytes read.
+fn read_file(path: &core::ffi::CStr, buf: &mut [u8]) -> Option<usize> {
+    // SAFETY: path is a NUL-terminated C string.
+    let fd = unsafe { sys_open(path.as_ptr().cast(), O_RDONLY | O_CLOEXEC, 0) };
+    if fd < 0 {
+        return None;
+    }
+    let mut filled = 0;
+    while filled < buf.len() {
+        // SAFETY: buf[filled..] is valid for buf.len() - filled writable bytes.
+        let n = unsafe {
+            sys_read(
+                fd as usize,
+                buf.as_mut_ptr().add(filled),
+                buf.len() - filled,
+            )
+        };
+        match n {
+            0 => break,
+            n if n > 0 => filled += n as usize,
+            n if n == -EINTR => continue,
+            _ => {
+                filled = 0;
+                break;
+            }
+        }
+    }
+    // SAFETY: fd was opened above and is owned here.
+    unsafe { sys_close(fd as usize) };
+    if filled == 0 { None } else { Some(filled) }
+}
+
+/// Read `VmRSS` and `VmHWM` from `/proc/self/status`.
+/// Returns `None` if `/proc` is unavailable or the fields are missing.
+pub fn memory_status() -> Option<MemStatus> {
+    let mut buf = [0u8; 4096];
+    let n = read_file(c"/proc/self/status", &mut buf)?;
+
+    let mut status = MemStatus::default();
+    let mut found = 0;
+    for line in buf[..n].split(|&b| b == b'\n') {
+        if let Some(v) = field(line, b"VmRSS:") {
+            status.rss_kb = v;
+            found += 1;
+        } else if let Some(v) = field(line, b"VmHWM:") {
+            status.hwm_kb = v;
+            found += 1;
+        }
+    }
+    if found == 2 { Some(status) } else { None }
+}
+
+/// Look up `name` in `/proc/self/environ` and copy its value into `out`.
+/// Returns the value's length, or `None` if the variable is unset or its
+/// value does not fit.
+///
+/// The file is streamed in small chunks, so the environment may be of any
+/// size; this can run before the C runtime has set up `environ`.
+pub fn env_var(name: &[u8], out: &mut [u8]) -> Option<usize> {
+    // SAFETY: The path is a NUL-terminated C string.
+    let fd = unsafe {
+        sys_open(
+            c"/proc/self/environ".as_ptr().cast(),
+            O_RDONLY | O_CLOEXEC,
+            0,
+        )
+    };
+    if fd < 0 {
+        return None;
+    }
+
+    // Position within the current `KEY=value` entry, or None once it can
+    // no longer match `name`; then the value length copied so far.
+    let mut matched = Some(0usize);
+    let mut value: Option<usize> = None;
+    let mut found = None;
+    let mut buf = [0u8; 256];
+    'read: loop {
+        // SAFETY: buf is valid for buf.len() writable bytes.
+        let n = unsafe { sys_read(fd as usize, buf.as_mut_ptr(), buf.len()) };
+        let n = match n {
+            n if n > 0 => n as usize,
+            n if n == -EINTR => continue,
+            _ => break,
+        };
+        for &b in &buf[..n] {
+            if b == 0 {
+                if value.is_some() {
+                    found = value;
+                    break 'read;
+                }
+                matched = Some(0);
+                continue;
+            }
+            match (matched, value) {
+                (Some(_), Some(len)) if len < out.len() => {
+                    out[len] = b;
+                    value = Some(len + 1);
+                }
+                (Some(i), None) if i == name.len() && b == b'=' => value = Some(0),
+                (Some(i), None) if i < name.len() && b == name[i] => matched = Some(i + 1),
+                _ => {
+                    matched = None;
+                    value = None;
+                }
+            }
+        }
+    }
+    // SAFETY: fd was opened above and is owned here.
+    unsafe { sys_close(fd as usize) };
+    found
+}
+
+/// `env_var` parsed as a decimal number. Returns `None` if the variable is
+/// unset or not a number.
+pub fn env_usize(name: &[u8]) -> Option<usize> {
+    let mut buf = [0u8; 20];
+    let len = env_var(name, &mut buf)?;
+    parse_usize(&buf[..len])
+}
+
+/// A non-empty string of decimal digits as a number, if it fits.
+pub fn parse_usize(digits: &[u8]) -> Option<usize> {
+    if digits.is_empty() {
+        return None;
+    }
+    digits.iter().try_fold(0usize, |v, &b| {
+        if !b.is_ascii_digit() {
+            return None;
+        }
+        v.checked_mul(10)?.checked_add((b - b'0') as usize)
+    })
+}
+
+/// Parse the decimal value of a `Name:   1234 kB` line if it starts with `name`.
+fn field(line: &[u8], name: &[u8]) -> Option<usize> {
+    let rest = line.strip_prefix(name)?;
+    let mut value = 0usize;
+    let mut digits = 0;
+    for &b in rest.iter().skip_while(|b| **b == b' ' || **b == b'\t') {
+        if !b.is_ascii_digit() {
+            break;
+        }
+        value = value.wrapping_mul(10).wrapping_add((b - b'0') as usize);
+        digits += 1;
+    }
+    if digits > 0 { Some(value) } else { None }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::testing;
+
+    /// A maps-style line for mapping `i`.
+    fn maps_line(i: usize) -> std::string::String {
+        std::format!(
+            "{:012x}-{:012x} r-xp 00000000 08:01 {i:<8} /usr/lib/libsynthetic{i}.so\n",
+            0x7f00_0000_0000 + i * 0x10000,
+            0x7f00_0000_8000 + i * 0x10000
+        )
+    }
+
+    #[test]
+    fn read_file_keeps_reading_past_short_reads() {
+        use std::io::Write;
+        use std::os::fd::AsRawFd;
+
+        const LINES: usize = 600;
+        let (reader, mut writer) = std::io::pipe().unwrap();
+        // A pipe hands out whatever has been written so far, so writing in
+        // small pieces makes every read a short one.
+        let feeder = std::thread::spawn(move || {
+            for i in 0..LINES {
+                writer.write_all(maps_line(i).as_bytes()).unwrap();
+                if i % 50 == 0 {
+                    std::thread::sleep(std::time::Duration::from_millis(1));
+                }
+            }
+        });
+        let path = std::format!("/proc/self/fd/{}\0", reader.as_raw_fd());
+        let path = core::ffi::CStr::from_bytes_with_nul(path.as_bytes()).unwrap();
+        let mut buf = std::vec![0u8; 128 << 10];
+        let n = read_file(path, &mut buf).unwrap();
+        feeder.join().unwrap();
+
+        let expected: std::string::String = (0..LINES).map(maps_line).collect();
+        assert!(expected.len() > 16 << 10);
+        assert_eq!(n, expected.len());
+        let lines: std::vec::Vec<&[u8]> = buf[..n].split(|&b| b == b'\n').collect();
+        assert_eq!(lines.len(), LINES + 1);
+        for (i, line) in lines[..LINES].iter().enumerate() {
+            assert!(line.ends_with(std::format!("libsynthetic{i}.so").as_bytes()));
+        }
+    }
+
+    #[test]
+    #[cfg_attr(
+        feature = "minimal-diagnostics",
+        ignore = "minimal reports have no footer"
+    )]
+    fn leak_report_footer_shows_the_resident_set() {
+        const TEST: &str = "sanitize::procfs::tests::leak_report_footer_shows_the_resident_set";
+        if testing::in_child(TEST) {
+            let status = memory_status().expect("/proc/self/status is readable");
+            assert!(status.rss_kb > 0 && status.hwm_kb >= status.rss_kb);
+            let layout = core::alloc::Layout::from_size_align(64, 8).unwrap();
+            // SAFETY: Leaked on purpose.
+            unsafe { crate::sanitize::sanitized_alloc(layout) };
+            crate::sanitize::tracker::report_leaks();
+            return;
+        }
+        let child = testing::spawn(TEST, &[]);
+        let stderr = child.stderr();
+        assert_eq!(child.code(), Some(0), "{stderr}");
+        let line = stderr
+            .lines()
+            .find_map(|l| l.strip_prefix("  process: VmRSS="))
+            .expect(&stderr);
+        let (rss, hwm) = line.split_once(" kB  VmHWM=").expect(line);
+        assert!(rss.parse::<usize>().unwrap() > 0, "{line}");
+        assert!(
+            hwm.strip_suffix(" kB").unwrap().parse::<usize>().is_ok(),
+            "{line}"
+        );
+    }
+}
@@ -1,13 +1,70 @@
-use crate::sanitize::spinlock::SpinLock;
+use crate::platform::cache::CacheAligned;
+use crate::platform::spinlock::SpinLock;
+use crate::sanitize::backtrace::{Frames, Tid};
+use crate::sanitize::tracker::AllocKind;
+use core::sync::atomic::{AtomicU64, Ordering};
 
-const CAPACITY: usize = 256;
+/// Ring size until `resize` is called.
+const DEFAULT_CAPACITY: usize = 256;
+
+/// Total user bytes held unless `quarantine_size` or `set_byte_budget`
+/// says otherwise.
+pub const DEFAULT_BYTE_BUDGET: usize = 16 << 20;
+
+/// How a victim is chosen once the quarantine is full.
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Policy {
+    /// Evict the oldest freed block (plain ring buffer).
+    Fifo = 0,
+    /// Evict the block least recently hit by a double-free check.
+    Lru = 1,
+    /// Evict a pseudo-randomly chosen block, making address reuse
+    /// order unpredictable.
+    Random = 2,
+}
+
+/// Quarantine occupancy, now and at its fullest.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct Stats {
+    /// Blocks held now.
+    pub entries: usize,
+    /// User bytes held now.
+    pub bytes: usize,
+    /// Most blocks held at once since startup (or `sanitize::reset`).
+    pub peak_entries: usize,
+    /// Most user bytes held at once. Close to the byte budget means blocks
+    /// are being evicted early, and dangling pointers to them go unnoticed.
+    pub peak_bytes: usize,
+}
+
+/// What the quarantine remembers about a freed block's original allocation.
+#[derive(Clone, Copy)]
+pub struct FreedBlock {
+    pub user_addr: usize,
+    pub base_addr: usize,
+    pub user_size: usize,
+    pub align: usize,
+    pub kind: AllocKind,
+    pub generation: u64,
+    pub frames: Frames,
+    /// Thread that freed the block.
+    pub freed_by: Tid,
+}
 
 #[derive(Clone, Copy)]
 struct Entry {
     user_addr: usize,
     base_addr: usize,
-    #[allow(dead_code)]
     user_size: usize,
+    align: usize,
+    kind: AllocKind,
+    /// Logical time of the last push or `contains` hit, for LRU eviction.
+    stamp: u64,
+    /// Free generation stamped into the block's tripwire header.
+    generation: u64,
+    frames: Frames,
+    freed_by: Tid,
 }
 
 impl Entry {
@@ -15,63 +72,587 @@ impl Entry {
         user_addr: 0,
         base_addr: 0,
         user_size: 0,
+        align: 1,
+        kind: AllocKind::Rust,
+        stamp: 0,
+        generation: 0,
+        frames: Frames::EMPTY,
+        freed_by: Tid::NONE,
     };
+
+    fn new(block: FreedBlock, stamp: u64) -> Self {
+        Self {
+            user_addr: block.user_addr,
+            base_addr: block.base_addr,
+            user_size: block.user_size,
+            align: block.align,
+            kind: block.kind,
+            stamp,
+            generation: block.generation,
+            frames: block.frames,
+            freed_by: block.freed_by,
+        }
+    }
+
+    fn block(&self) -> FreedBlock {
+        FreedBlock {
+            user_addr: self.user_addr,
+            base_addr: self.base_addr,
+            user_size: self.user_size,
+            align: self.align,
+            kind: self.kind,
+            generation: self.generation,
+            frames: self.frames,
+            freed_by: self.freed_by,
+        }
+    }
 }
 
 struct QuarantineInner {
-    ring: [Entry; CAPACITY],
+    inline: [Entry; DEFAULT_CAPACITY],
+    /// malloc'd ring installed by `resize`; null while `inline` is in use.
+    heap: *mut Entry,
+    capacity: usize,
     pos: usize,
     len: usize,
+    /// Sum of `user_size` over live entries.
+    bytes: usize,
+    /// High-water marks of `len` and `bytes`.
+    peak_len: usize,
+    peak_bytes: usize,
+    /// Byte budget; `None` until the first push reads the configuration
+    /// or `set_byte_budget` is called.
+    budget: Option<usize>,
+    policy: Policy,
+    clock: u64,
+    rng: u64,
 }
 
+// SAFETY: `heap` is owned by the quarantine and only touched under its lock.
+unsafe impl Send for QuarantineInner {}
+
 impl QuarantineInner {
     const fn new() -> Self {
         Self {
-            ring: [Entry::EMPTY; CAPACITY],
+            inline: [Entry::EMPTY; DEFAULT_CAPACITY],
+            heap: core::ptr::null_mut(),
+            capacity: DEFAULT_CAPACITY,
             pos: 0,
             len: 0,
+            bytes: 0,
+            peak_len: 0,
+            peak_bytes: 0,
+            budget: None,
+            policy: Policy::Fifo,
+            clock: 0,
+            rng: 0x9E3779B97F4A7C15,
         }
     }
 
-    /// Push a freed block into quarantine.
-    /// Returns the evicted entry's base_addr if the ring was full.
-    fn push(&mut self, user_addr: usize, base_addr: usize, user_size: usize) -> Option<usize> {
-        let evicted = if self.len == CAPACITY {
-            Some(self.ring[self.pos].base_addr)
+    fn ring(&self) -> &[Entry] {
+        if self.heap.is_null() {
+            &self.inline
         } else {
+            // SAFETY: heap holds `capacity` initialized entries (see resize).
+            unsafe { core::slice::from_raw_parts(self.heap, self.capacity) }
+        }
+    }
+
+    fn ring_mut(&mut self) -> &mut [Entry] {
+        if self.heap.is_null() {
+            &mut self.inline
+        } else {
+            // SAFETY: heap holds `capacity` initialized entries (see resize).
+            unsafe { core::slice::from_raw_parts_mut(self.heap, self.capacity) }
+        }
+    }
+
+    /// Live entries, oldest first.
+    fn live(&self) -> impl Iterator<Item = &Entry> {
+        let start = self.oldest();
+        let ring = self.ring();
+        (0..self.len).map(move |i| &ring[(start + i) % ring.len()])
+    }
+
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    /// xorshift64 — cheap, lock-protected, good enough to scatter evictions.
+    fn next_random(&mut self) -> u64 {
+        let mut x = self.rng;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.rng = x;
+        x
+    }
+
+    /// Pick the slot to overwrite when the ring is full.
+    fn victim(&mut self, user_addr: usize) -> usize {
+        match self.policy {
+            Policy::Fifo => self.pos,
+            Policy::Lru => {
+                let ring = self.ring();
+                let mut idx = 0;
+                for i in 1..ring.len() {
+                    if ring[i].stamp < ring[idx].stamp {
+                        idx = i;
+                    }
+                }
+                idx
+            }
+            Policy::Random => {
+                // Mix in the freed address so the sequence also depends on ASLR.
+                self.rng ^= user_addr as u64;
+                (self.next_random() % self.capacity as u64) as usize
+            }
+        }
+    }
+
+    /// Push a freed block into quarantine, handing every block that has
+    /// to leave to `evict`. The oldest entries go first until the new
+    /// block fits the byte budget; a block larger than the whole budget is
+    /// evicted straight away. A full ring then loses one entry chosen by
+    /// the policy; the entries after it close the gap, so the ring stays
+    /// oldest first for `evict_oldest`, `drain` and `resize`.
+    fn push(&mut self, block: FreedBlock, mut evict: impl FnMut(FreedBlock)) {
+        let budget = *self.budget.get_or_insert_with(|| {
+            crate::sanitize::config::get()
+                .quarantine_bytes
+                .unwrap_or(DEFAULT_BYTE_BUDGET)
+        });
+        if block.user_size > budget {
+            evict(block);
+            return;
+        }
+        while self.bytes + block.user_size > budget
+            && let Some(oldest) = self.evict_oldest()
+        {
+            evict(oldest);
+        }
+
+        let stamp = self.tick();
+        let entry = Entry::new(block, stamp);
+        self.bytes += block.user_size;
+
+        if self.len < self.capacity {
+            let pos = self.pos;
+            self.ring_mut()[pos] = entry;
+            self.pos = (pos + 1) % self.capacity;
             self.len += 1;
-            None
-        };
+            self.note_peak();
+            return;
+        }
 
-        self.ring[self.pos] = Entry {
-            user_addr,
-            base_addr,
-            user_size,
-        };
-        self.pos = (self.pos + 1) % CAPACITY;
+        let idx = self.victim(block.user_addr);
+        let evicted = self.ring()[idx].block();
+        self.bytes -= evicted.user_size;
+        let (pos, capacity) = (self.pos, self.capacity);
+        if idx == pos {
+            // The oldest entry: the newest takes its slot.
+            self.ring_mut()[idx] = entry;
+            self.pos = (idx + 1) % capacity;
+        } else {
+            let ring = self.ring_mut();
+            let mut at = idx;
+            while (at + 1) % capacity != pos {
+                let next = (at + 1) % capacity;
+                ring[at] = ring[next];
+                at = next;
+            }
+            ring[at] = entry;
+        }
+        self.note_peak();
+        evict(evicted);
+    }
+
+    fn note_peak(&mut self) {
+        self.peak_len = self.peak_len.max(self.len);
+        self.peak_bytes = self.peak_bytes.max(self.bytes);
+    }
+
+    fn stats(&self) -> Stats {
+        Stats {
+            entries: self.len,
+            bytes: self.bytes,
+            peak_entries: self.peak_len,
+            peak_bytes: self.peak_bytes,
+        }
+    }
 
-        evicted
+    /// Index of the oldest live entry.
+    fn oldest(&self) -> usize {
+        (self.pos + self.capacity - self.len) % self.capacity
     }
 
     /// Check if an address was recently freed (linear scan).
-    fn contains(&self, user_addr: usize) -> bool {
+    /// A hit refreshes the entry's LRU stamp.
+    fn contains(&mut self, user_addr: usize) -> bool {
+        let start = self.oldest();
         for i in 0..self.len {
-            if self.ring[i].user_addr == user_addr {
+            let idx = (start + i) % self.capacity;
+            if self.ring()[idx].user_addr == user_addr {
+                let stamp = self.tick();
+                self.ring_mut()[idx].stamp = stamp;
                 return true;
             }
         }
         false
     }
+
+    /// Generation of a quarantined block, if `user_addr` is still held.
+    fn generation(&self, user_addr: usize) -> Option<u64> {
+        self.live()
+            .find(|e| e.user_addr == user_addr)
+            .map(|e| e.generation)
+    }
+
+    /// Find the quarantined block whose user region contains `addr`.
+    fn find_containing(&self, addr: usize) -> Option<FreedBlock> {
+        self.live()
+            .find(|e| addr >= e.user_addr && addr - e.user_addr < e.user_size.max(1))
+            .map(Entry::block)
+    }
+
+    /// Remove the oldest entry, returning it.
+    fn evict_oldest(&mut self) -> Option<FreedBlock> {
+        if self.len == 0 {
+            return None;
+        }
+        let idx = self.oldest();
+        let evicted = self.ring()[idx].block();
+        self.ring_mut()[idx] = Entry::EMPTY;
+        self.len -= 1;
+        self.bytes -= evicted.user_size;
+        Some(evicted)
+    }
+
+    /// Move the ring into a freshly malloc'd buffer of `new_capacity` slots,
+    /// preserving order. When shrinking below the current length, the oldest
+    /// entries are handed to `evict`. Returns `false` if malloc fails.
+    fn resize(&mut self, new_capacity: usize, mut evict: impl FnMut(&Entry)) -> bool {
+        let Some(bytes) = new_capacity.checked_mul(core::mem::size_of::<Entry>()) else {
+            return false;
+        };
+        // SAFETY: malloc is provided by the C runtime.
+        let new = unsafe { crate::platform::malloc(bytes) } as *mut Entry;
+        if new.is_null() {
+            return false;
+        }
+
+        let dropped = self.len.saturating_sub(new_capacity);
+        let mut dropped_bytes = 0;
+        let mut kept = 0;
+        for (i, entry) in self.live().enumerate() {
+            if i < dropped {
+                dropped_bytes += entry.user_size;
+                evict(entry);
+            } else {
+                // SAFETY: kept < new_capacity, within the new buffer.
+                unsafe { new.add(kept).write(*entry) };
+                kept += 1;
+            }
+        }
+        for i in kept..new_capacity {
+            // SAFETY: i < new_capacity, within the new buffer.
+            unsafe { new.add(i).write(Entry::EMPTY) };
+        }
+
+        if !self.heap.is_null() {
+            // SAFETY: heap was malloc'd by a previous resize.
+            unsafe { crate::platform::free(self.heap as *mut u8) };
+        }
+        self.heap = new;
+        self.capacity = new_capacity;
+        self.len = kept;
+        self.pos = kept % new_capacity;
+        self.bytes -= dropped_bytes;
+        true
+    }
 }
 
-static QUARANTINE: SpinLock<QuarantineInner> = SpinLock::new(QuarantineInner::new());
+static QUARANTINE: CacheAligned<SpinLock<QuarantineInner>> =
+    CacheAligned::new(SpinLock::new(QuarantineInner::new()));
+
+static GENERATION: CacheAligned<AtomicU64> = CacheAligned::new(AtomicU64::new(0));
 
-/// Quarantine a freed block. Returns the evicted base address to actually free, if any.
-pub fn push(user_addr: usize, base_addr: usize, user_size: usize) -> Option<usize> {
-    QUARANTINE.lock().push(user_addr, base_addr, user_size)
+/// Allocate a fresh free generation for a block about to be quarantined.
+pub fn next_generation() -> u64 {
+    GENERATION.fetch_add(1, Ordering::Relaxed) + 1
+}
+
+/// Quarantine a freed block. `evict` is called, under the quarantine
+/// lock, for each block that leaves to make room and must be released.
+pub fn push(block: FreedBlock, evict: impl FnMut(FreedBlock)) {
+    QUARANTINE.lock().push(block, evict)
+}
+
+/// Free the quarantine lock, e.g. in a `fork` child.
+///
+/// # Safety
+/// No other thread may be using the quarantine.
+pub unsafe fn force_unlock() {
+    // SAFETY: Forwarded from the caller.
+    unsafe { QUARANTINE.force_unlock() };
+}
+
+/// Current and peak occupancy, for tuning the byte budget and capacity.
+pub fn stats() -> Stats {
+    QUARANTINE.lock().stats()
+}
+
+/// Free generation recorded for a block still in quarantine.
+pub fn generation(user_addr: usize) -> Option<u64> {
+    QUARANTINE.lock().generation(user_addr)
 }
 
 /// Check if an address was recently freed (is still in quarantine).
 pub fn contains(user_addr: usize) -> bool {
     QUARANTINE.lock().contains(user_addr)
 }
+
+/// Original allocation of the quarantined block `addr` points into, if any.
+pub fn find_containing(addr: usize) -> Option<FreedBlock> {
+    QUARANTINE.lock().find_containing(addr)
+}
+
+/// `find_containing` that gives up instead of waiting for the lock.
+/// Safe to call from a signal handler.
+pub fn try_find_containing(addr: usize) -> Option<FreedBlock> {
+    QUARANTINE.try_lock()?.find_containing(addr)
+}
+
+/// Select the eviction policy used once the quarantine is full.
+pub fn set_policy(policy: Policy) {
+    QUARANTINE.lock().policy = policy;
+}
+
+/// Cap the total user bytes held in quarantine. A lower budget takes
+/// effect on the next push.
+pub fn set_byte_budget(bytes: usize) {
+    QUARANTINE.lock().budget = Some(bytes);
+}
+
+/// Change the number of quarantine slots at runtime.
+///
+/// Existing entries are migrated in order. If the new capacity is smaller
+/// than the current length, the oldest blocks are checked for writes after
+/// free and released. Returns `false` (leaving the quarantine unchanged) if
+/// `new_capacity` is zero or the new ring cannot be allocated.
+pub fn resize(new_capacity: usize) -> bool {
+    if new_capacity == 0 {
+        return false;
+    }
+    // SAFETY: The dropped entries leave the quarantine.
+    QUARANTINE.lock().resize(new_capacity, |e| unsafe {
+        crate::sanitize::retire(e.block())
+    })
+}
+
+/// Release every quarantined block, oldest first, checking each for writes
+/// after free as eviction would. Returns how many were released.
+pub fn drain() -> usize {
+    let mut quarantine = QUARANTINE.lock();
+    let mut drained = 0;
+    while let Some(block) = quarantine.evict_oldest() {
+        // SAFETY: The block left quarantine, so nothing else owns it.
+        unsafe { crate::sanitize::retire(block) };
+        drained += 1;
+    }
+    drained
+}
+
+/// Evict the oldest quarantined block. Returns it to actually release.
+#[cfg(any(test, feature = "test-hooks"))]
+pub fn evict_oldest() -> Option<FreedBlock> {
+    QUARANTINE.lock().evict_oldest()
+}
+
+/// Remove every quarantined block, oldest first, handing each to
+/// `release` under the lock, and zero the high-water marks. Capacity,
+/// budget and policy are kept.
+#[cfg(any(test, feature = "test-hooks"))]
+pub fn clear(mut release: impl FnMut(FreedBlock)) {
+    let mut quarantine = QUARANTINE.lock();
+    while let Some(block) = quarantine.evict_oldest() {
+        release(block);
+    }
+    quarantine.pos = 0;
+    quarantine.clock = 0;
+    quarantine.peak_len = 0;
+    quarantine.peak_bytes = 0;
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use std::vec::Vec;
+
+    fn block(i: usize) -> FreedBlock {
+        FreedBlock {
+            user_addr: 0x1000 + i * 64,
+            base_addr: 0x1000 + i * 64,
+            user_size: 16,
+            align: 8,
+            kind: AllocKind::Malloc,
+            generation: i as u64,
+            frames: Frames::EMPTY,
+            freed_by: Tid::NONE,
+        }
+    }
+
+    /// A full quarantine under `policy`, holding blocks `0..DEFAULT_CAPACITY`.
+    fn full(policy: Policy) -> QuarantineInner {
+        let mut q = QuarantineInner::new();
+        q.budget = Some(usize::MAX);
+        q.policy = policy;
+        for i in 0..DEFAULT_CAPACITY {
+            q.push(block(i), |_| panic!("evicted before full"));
+        }
+        q
+    }
+
+    /// Push one more block, returning the index of the one it evicted.
+    fn push_one(q: &mut QuarantineInner) -> usize {
+        let mut evicted = None;
+        q.push(block(DEFAULT_CAPACITY), |b| evicted = Some(b.generation));
+        evicted.expect("full ring evicts") as usize
+    }
+
+    fn order(q: &QuarantineInner) -> Vec<usize> {
+        q.live().map(|e| e.generation as usize).collect()
+    }
+
+    /// The ring still holds `0..=DEFAULT_CAPACITY` less `gone`, oldest first.
+    fn assert_aged(q: &mut QuarantineInner, gone: usize) {
+        let expected: Vec<usize> = (0..=DEFAULT_CAPACITY).filter(|&i| i != gone).collect();
+        assert_eq!(order(q), expected);
+        let first = expected[0];
+        assert_eq!(q.evict_oldest().map(|b| b.generation as usize), Some(first));
+    }
+
+    #[test]
+    fn byte_budget_evicts_the_oldest_until_a_block_fits() {
+        let mut q = QuarantineInner::new();
+        q.budget = Some(1000);
+        let mut evicted = Vec::new();
+        for i in 0..20 {
+            let user_size = if i % 3 == 0 { 300 } else { 100 };
+            q.push(
+                FreedBlock {
+                    user_size,
+                    ..block(i)
+                },
+                |b| evicted.push(b.generation as usize),
+            );
+            assert!(q.bytes <= 1000, "{} bytes after block {i}", q.bytes);
+            let held: usize = q.live().map(|e| e.user_size).sum();
+            assert_eq!(held, q.bytes);
+            // Whatever left went oldest first, and everything else stayed.
+            let mut all = evicted.clone();
+            all.extend(order(&q));
+            assert_eq!(all, (0..=i).collect::<Vec<_>>());
+        }
+        assert!(q.stats().peak_bytes <= 1000);
+
+        // A block over the whole budget is handed straight back.
+        let before = order(&q);
+        let mut returned = Vec::new();
+        q.push(
+            FreedBlock {
+                user_size: 1001,
+                ..block(20)
+            },
+            |b| returned.push(b.generation as usize),
+        );
+        assert_eq!(returned, [20]);
+        assert_eq!(order(&q), before);
+    }
+
+    #[test]
+    fn peaks_only_rise_and_stay_within_the_budget() {
+        let mut q = QuarantineInner::new();
+        q.budget = Some(1000);
+        let mut last = q.stats();
+        for i in 0..30 {
+            let user_size = 64 + i;
+            q.push(
+                FreedBlock {
+                    user_size,
+                    ..block(i)
+                },
+                |_| {},
+            );
+            if i % 7 == 6 {
+                // Releasing blocks lowers the counts, never the peaks.
+                q.evict_oldest();
+                q.evict_oldest();
+            }
+            let now = q.stats();
+            assert!(now.peak_entries >= last.peak_entries, "block {i}");
+            assert!(now.peak_bytes >= last.peak_bytes, "block {i}");
+            assert!(now.peak_entries >= now.entries && now.peak_bytes >= now.bytes);
+            assert!(now.peak_bytes <= 1000, "block {i}: {}", now.peak_bytes);
+            last = now;
+        }
+        assert!(last.peak_entries > last.entries);
+    }
+
+    #[test]
+    fn resize_keeps_order_and_evicts_the_oldest_on_shrink() {
+        let mut q = QuarantineInner::new();
+        q.budget = Some(usize::MAX);
+        for i in 0..10 {
+            q.push(block(i), |_| panic!("room for all"));
+        }
+        assert!(q.resize(20, |_| panic!("growing evicts nothing")));
+        assert_eq!(order(&q), (0..10).collect::<Vec<_>>());
+        assert_eq!(q.bytes, 10 * 16);
+
+        let mut evicted = Vec::new();
+        assert!(q.resize(4, |e| evicted.push(e.generation as usize)));
+        assert_eq!(evicted, (0..6).collect::<Vec<_>>());
+        assert_eq!(order(&q), [6, 7, 8, 9]);
+        assert_eq!(q.bytes, 4 * 16);
+
+        // The smaller ring wraps as usual.
+        for i in 10..13 {
+            q.push(block(i), |b| evicted.push(b.generation as usize));
+        }
+        assert_eq!(evicted[6..], [6, 7, 8]);
+        assert_eq!(order(&q), [9, 10, 11, 12]);
+        assert!(q.contains(block(12).user_addr));
+        assert!(!q.contains(block(8).user_addr));
+    }
+
+    #[test]
+    fn fifo_evicts_the_first_pushed() {
+        let mut q = full(Policy::Fifo);
+        assert_eq!(push_one(&mut q), 0);
+        assert_aged(&mut q, 0);
+    }
+
+    #[test]
+    fn lru_evicts_the_block_no_check_hit() {
+        let mut q = full(Policy::Lru);
+        for i in (0..DEFAULT_CAPACITY).filter(|&i| i != 5) {
+            assert!(q.contains(block(i).user_addr));
+        }
+        assert_eq!(push_one(&mut q), 5);
+        assert_aged(&mut q, 5);
+    }
+
+    #[test]
+    fn random_is_repeatable_for_a_seed_and_address() {
+        let mut a = full(Policy::Random);
+        let mut b = full(Policy::Random);
+        let gone = push_one(&mut a);
+        assert_eq!(push_one(&mut b), gone);
+        assert_eq!(order(&a), order(&b));
+        assert_aged(&mut a, gone);
+    }
+}
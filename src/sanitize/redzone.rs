@@ -1,6 +1,99 @@
-/// Size of each red zone (prefix and suffix), in bytes.
+use crate::sanitize::tracker::AllocKind;
+use core::sync::atomic::{AtomicU8, AtomicU64, AtomicUsize, Ordering};
+
+/// Default size of each red zone (prefix and suffix), in bytes.
 /// 16 bytes aligns with malloc's max_align_t guarantee on x86_64.
-pub const REDZONE_SIZE: usize = 16;
+pub const DEFAULT_REDZONE_SIZE: usize = 16;
+
+/// Largest accepted red zone.
+pub const MAX_REDZONE_SIZE: usize = 4096;
+
+/// Environment variable read for the red zone size at first allocation,
+/// unless `LIBCPP_OPTIONS` has a `redzone` entry.
+pub const REDZONE_ENV: &[u8] = b"LIBCPP_REDZONE";
+
+/// Red zone size in use; 0 until the first allocation fixes it.
+static REDZONE_SIZE: AtomicUsize = AtomicUsize::new(0);
+
+/// Whether `size` can be used as the red zone size: a non-zero multiple
+/// of `max_align_t`, at most `MAX_REDZONE_SIZE`.
+pub fn valid_size(size: usize) -> bool {
+    size != 0 && size <= MAX_REDZONE_SIZE && size.is_multiple_of(crate::platform::MIN_ALIGN)
+}
+
+/// Size of the prefix red zone, and of the suffix red zone of a small
+/// block, in bytes; see `zone_size`.
+///
+/// Fixed by the first call: `set_size` if it came earlier, otherwise the
+/// configured size (`redzone` or `LIBCPP_REDZONE`) when it is valid,
+/// otherwise the default.
+/// Blocks already carrying red zones are never measured with another size.
+pub fn size() -> usize {
+    let size = REDZONE_SIZE.load(Ordering::Relaxed);
+    if size != 0 {
+        return size;
+    }
+    let wanted = crate::sanitize::config::get()
+        .redzone
+        .filter(|&s| valid_size(s))
+        .unwrap_or(DEFAULT_REDZONE_SIZE);
+    match REDZONE_SIZE.compare_exchange(0, wanted, Ordering::Relaxed, Ordering::Relaxed) {
+        Ok(_) => wanted,
+        Err(current) => current,
+    }
+}
+
+/// Largest red zone the size scaling grows to.
+pub const MAX_SCALED_REDZONE: usize = 256;
+
+/// User bytes per red zone byte once a block is large enough to scale.
+const SCALE_RATIO: usize = 8192;
+
+/// Size scaling: 0 until configured, then 1 for off or 2 for on.
+static SCALED: AtomicU8 = AtomicU8::new(0);
+
+/// Whether suffix red zones grow with the block, as they do unless
+/// `scale_redzone=0` is configured. Read once, since blocks are measured
+/// with it for as long as they live.
+fn scaled() -> bool {
+    let mut state = SCALED.load(Ordering::Relaxed);
+    if state == 0 {
+        let off = crate::sanitize::config::get().scale_redzone == Some(false);
+        state = if off { 1 } else { 2 };
+        SCALED.store(state, Ordering::Relaxed);
+    }
+    state == 2
+}
+
+/// Size of the suffix red zone of a block of `user_size` bytes. The prefix
+/// red zone is always `size()`, so the header in front of it sits at a
+/// fixed distance from the user pointer.
+///
+/// About one byte per 8 KiB of block, rounded up to a power of two, but
+/// no less than `size()` and no more than `MAX_SCALED_REDZONE` (reached at
+/// 2 MiB), so a strided overrun past a large array still lands in a red
+/// zone. Blocks up to 128 KiB keep the default 16 bytes, and the growth
+/// never costs more than 1/4096 of the block. A configured size above the
+/// cap is used for every block.
+pub fn zone_size(user_size: usize) -> usize {
+    let base = size();
+    if !scaled() {
+        return base;
+    }
+    let scaled = (user_size / SCALE_RATIO)
+        .next_power_of_two()
+        .min(MAX_SCALED_REDZONE);
+    base.max(scaled)
+}
+
+/// Choose the red zone size before anything has been allocated.
+/// Returns `false` if `size` is invalid or the size is already fixed.
+pub fn set_size(size: usize) -> bool {
+    valid_size(size)
+        && REDZONE_SIZE
+            .compare_exchange(0, size, Ordering::Relaxed, Ordering::Relaxed)
+            .is_ok()
+}
 
 /// Byte pattern written into red zones.
 pub const CANARY_BYTE: u8 = 0xAB;
@@ -8,65 +101,407 @@ pub const CANARY_BYTE: u8 = 0xAB;
 /// Byte pattern written over freed user data.
 pub const POISON_BYTE: u8 = 0xFE;
 
-/// Total allocation size including both redzones.
-pub const fn total_size(user_size: usize) -> usize {
-    REDZONE_SIZE + user_size + REDZONE_SIZE
+/// Byte pattern written over fresh user data when scribbling is on.
+pub const SCRIBBLE_BYTE: u8 = 0xCD;
+
+/// Scribbling: 0 until configured, then 1 for off or 2 for on.
+static SCRIBBLE: AtomicU8 = AtomicU8::new(0);
+
+/// Whether new allocations are filled with `SCRIBBLE_BYTE`, as selected by
+/// `scribble=1`, a non-zero `LIBCPP_SCRIBBLE` or `set_scribble`. Off by
+/// default.
+pub fn scribble() -> bool {
+    let mut state = SCRIBBLE.load(Ordering::Relaxed);
+    if state == 0 {
+        let on = crate::sanitize::config::get().scribble == Some(true);
+        state = if on { 2 } else { 1 };
+        SCRIBBLE.store(state, Ordering::Relaxed);
+    }
+    state == 2
+}
+
+/// Turn scribbling on or off regardless of `LIBCPP_SCRIBBLE`.
+pub fn set_scribble(enabled: bool) {
+    SCRIBBLE.store(if enabled { 2 } else { 1 }, Ordering::Relaxed);
+}
+
+/// Marker written over the first word of a freed block that is large
+/// enough to hold it, followed by the quarantine generation.
+pub const TRIPWIRE: u64 = 0xF4EE_D0FF_F4EE_D0FF;
+
+/// Bytes at the start of a freed block taken by the tripwire and generation.
+pub const TRIPWIRE_SIZE: usize = 16;
+
+/// Per-block metadata stored just before the prefix red zone, so the red
+/// zones can be checked without asking the tracker.
+#[repr(C)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Header {
+    pub size: usize,
+    /// Canary the block's red zones were filled with.
+    pub canary: u64,
+    /// `AllocKind` discriminant; raw, since an underflow may have hit it.
+    kind: u8,
+    /// First-access state for the dead-allocation check; see `guard`.
+    pub access: u8,
+}
+
+/// Bytes taken by the header.
+pub const HEADER_SIZE: usize = core::mem::size_of::<Header>();
+
+/// `kind` byte of a freed block's header.
+const FREED: u8 = 0xFF;
+
+impl Header {
+    pub fn new(size: usize, kind: AllocKind, canary: u64) -> Self {
+        Self {
+            size,
+            canary,
+            kind: kind as u8,
+            access: 0,
+        }
+    }
+
+    /// The recorded kind, or `None` if the byte was overwritten with
+    /// something that is not one.
+    pub fn kind(&self) -> Option<AllocKind> {
+        AllocKind::ALL.get(self.kind as usize).copied()
+    }
+
+    /// Whether `invalidate_header` marked the block freed.
+    pub fn is_freed(&self) -> bool {
+        self.kind == FREED
+    }
+}
+
+/// Store the header of the block whose user region starts at `user_ptr`.
+///
+/// # Safety
+/// `user_ptr` must have at least `size() + HEADER_SIZE` writable bytes of
+/// prefix before it.
+pub unsafe fn write_header(user_ptr: *mut u8, header: Header) {
+    // SAFETY: Forwarded from the caller.
+    unsafe { (user_ptr.sub(size() + HEADER_SIZE) as *mut Header).write_unaligned(header) };
 }
 
-/// Fill prefix and suffix redzones with canary bytes.
+/// Read the header written by `write_header`.
 ///
-/// `base` points to the start of the malloc'd block.
-/// The user region starts at `base + REDZONE_SIZE`.
+/// # Safety
+/// `user_ptr` must have at least `size() + HEADER_SIZE` readable bytes of
+/// prefix before it.
+pub unsafe fn read_header(user_ptr: *const u8) -> Header {
+    // SAFETY: Forwarded from the caller; every bit pattern is a valid Header.
+    unsafe { (user_ptr.sub(size() + HEADER_SIZE) as *const Header).read_unaligned() }
+}
+
+/// Mark the header of a block being freed, so that another free reaching
+/// it is caught even if the tracker was fooled into thinking it live. The
+/// size and canary stay, for the write-after-free check on release.
 ///
 /// # Safety
-/// `base` must point to at least `total_size(user_size)` writable bytes.
-pub unsafe fn fill_canaries(base: *mut u8, user_size: usize) {
-    // Prefix redzone.
-    // SAFETY: base is valid for REDZONE_SIZE bytes (caller guarantees total_size).
-    unsafe { core::ptr::write_bytes(base, CANARY_BYTE, REDZONE_SIZE) };
+/// Same as `write_header`.
+pub unsafe fn invalidate_header(user_ptr: *mut u8) {
+    // SAFETY: Forwarded from the caller.
+    unsafe {
+        let header = read_header(user_ptr);
+        write_header(
+            user_ptr,
+            Header {
+                kind: FREED,
+                ..header
+            },
+        );
+    }
+}
+
+/// Distance from the platform block to the user region.
+///
+/// At least `HEADER_SIZE + size()`, widened to a multiple of `align` so an
+/// `align`-aligned block yields an `align`-aligned user pointer. The
+/// canaries always occupy the last `size()` bytes of the prefix, with the
+/// header right before them; anything before is slack.
+pub fn prefix_size(align: usize) -> usize {
+    (HEADER_SIZE + size()).next_multiple_of(align)
+}
+
+/// Total allocation size including the header, both redzones and
+/// alignment slack.
+pub fn total_size(user_size: usize, align: usize) -> usize {
+    prefix_size(align) + user_size + zone_size(user_size)
+}
+
+/// Canary every block gets in fixed mode: `CANARY_BYTE` repeated.
+pub const FIXED_CANARY: u64 = u64::from_ne_bytes([CANARY_BYTE; 8]);
+
+/// Fixed canaries: 0 until configured, then 1 for off or 2 for on.
+static FIXED: AtomicU8 = AtomicU8::new(0);
+
+/// Seed for per-block canaries; 0 until the first random canary is drawn.
+static SEED: AtomicU64 = AtomicU64::new(0);
 
-    // Suffix redzone.
-    // SAFETY: base + REDZONE_SIZE + user_size is still within the allocation.
-    let suffix = unsafe { base.add(REDZONE_SIZE + user_size) };
-    unsafe { core::ptr::write_bytes(suffix, CANARY_BYTE, REDZONE_SIZE) };
+/// Random canaries handed out so far.
+static DRAWN: AtomicU64 = AtomicU64::new(0);
+
+/// Whether every block gets `FIXED_CANARY`, as selected by
+/// `fixed_canary=1`, a non-zero `LIBCPP_FIXED_CANARY` or `set_fixed_canary`.
+/// Off by default: each block gets its own random canary, so an overflow
+/// that happens to write the canary pattern is still caught.
+pub fn fixed_canary() -> bool {
+    let mut state = FIXED.load(Ordering::Relaxed);
+    if state == 0 {
+        let on = crate::sanitize::config::get().fixed_canary == Some(true);
+        state = if on { 2 } else { 1 };
+        FIXED.store(state, Ordering::Relaxed);
+    }
+    state == 2
+}
+
+/// Switch between fixed and random canaries. Blocks keep the canary they
+/// were allocated with, so this is safe at any time.
+pub fn set_fixed_canary(enabled: bool) {
+    FIXED.store(if enabled { 2 } else { 1 }, Ordering::Relaxed);
+}
+
+/// Canary for a new block: `FIXED_CANARY` in fixed mode, otherwise the
+/// next value of a sequence seeded once from `getrandom`.
+pub fn next_canary() -> u64 {
+    if fixed_canary() {
+        return FIXED_CANARY;
+    }
+    let n = DRAWN.fetch_add(1, Ordering::Relaxed);
+    mix(seed().wrapping_add(n.wrapping_mul(0x9E37_79B9_7F4A_7C15)))
+}
+
+/// Seeded on first use rather than from a constructor, so a block that
+/// another module's constructor allocates before any of ours run still
+/// gets a random canary.
+fn seed() -> u64 {
+    let seed = SEED.load(Ordering::Relaxed);
+    if seed != 0 {
+        return seed;
+    }
+    let mut bytes = [0u8; 8];
+    // SAFETY: bytes is 8 writable bytes.
+    let got = unsafe {
+        crate::platform::syscall::sys_getrandom(
+            bytes.as_mut_ptr(),
+            bytes.len(),
+            crate::platform::syscall::GRND_NONBLOCK,
+        )
+    };
+    let mut fresh = u64::from_ne_bytes(bytes);
+    if got != bytes.len() as isize {
+        // No entropy yet: the clock and this stack address still differ
+        // from run to run.
+        use crate::platform::syscall::{CLOCK_MONOTONIC, Timespec, sys_clock_gettime};
+        let mut ts = Timespec::default();
+        // SAFETY: ts is a valid, writable Timespec.
+        unsafe { sys_clock_gettime(CLOCK_MONOTONIC, &mut ts) };
+        let stack = &raw const ts as usize as u64;
+        fresh = mix((ts.tv_sec as u64) ^ ((ts.tv_nsec as u64) << 32) ^ stack);
+    }
+    // 0 means unseeded.
+    let fresh = fresh | 1;
+    match SEED.compare_exchange(0, fresh, Ordering::Relaxed, Ordering::Relaxed) {
+        Ok(_) => fresh,
+        Err(current) => current,
+    }
+}
+
+/// splitmix64's finalizer.
+fn mix(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    x ^ (x >> 31)
+}
+
+/// Byte `i` of a red zone carrying `canary`.
+fn canary_byte(canary: u64, i: usize) -> u8 {
+    canary.to_ne_bytes()[i % 8]
 }
 
-/// Check that redzones are intact.
+/// Fill prefix and suffix redzones with `canary`, repeated.
 ///
-/// On corruption, calls `diagnostic::overflow_detected` which aborts.
+/// `base` points to the prefix canaries, `size()` bytes before the user
+/// region; the suffix canaries are `zone_size(user_size)` bytes after it.
 ///
 /// # Safety
-/// `base` must point to the start of the malloc'd block with valid redzones.
-pub unsafe fn check_canaries(base: *mut u8, user_size: usize, user_addr: usize) {
-    let mut prefix_corrupt = false;
-    let mut suffix_corrupt = false;
-
-    // Check prefix.
-    for i in 0..REDZONE_SIZE {
-        // SAFETY: i < REDZONE_SIZE, within the allocation.
-        if unsafe { *base.add(i) } != CANARY_BYTE {
-            prefix_corrupt = true;
-            break;
-        }
+/// `base` must point to at least `size() + user_size + zone_size(user_size)`
+/// writable bytes.
+pub unsafe fn fill_canaries(base: *mut u8, user_size: usize, canary: u64) {
+    let prefix = size();
+    for i in 0..prefix {
+        // SAFETY: i < prefix, within the allocation.
+        unsafe { *base.add(i) = canary_byte(canary, i) };
     }
+    // SAFETY: base + prefix + user_size is still within the allocation.
+    let suffix = unsafe { base.add(prefix + user_size) };
+    for i in 0..zone_size(user_size) {
+        // SAFETY: The suffix zone is within total_size.
+        unsafe { *suffix.add(i) = canary_byte(canary, i) };
+    }
+}
 
-    // Check suffix.
-    // SAFETY: suffix starts at base + REDZONE_SIZE + user_size, within the allocation.
-    let suffix = unsafe { base.add(REDZONE_SIZE + user_size) };
-    for i in 0..REDZONE_SIZE {
-        if unsafe { *suffix.add(i) } != CANARY_BYTE {
-            suffix_corrupt = true;
-            break;
+/// Cut a live block down to `new_size` bytes without moving it.
+///
+/// The header records the new size and a fresh suffix red zone starts at
+/// `new_size`. The rest of the old tail, up to the end of the old suffix
+/// zone, is filled with `POISON_BYTE`, so a read past the new end finds a
+/// recognisable pattern instead of old data.
+///
+/// # Safety
+/// `user_ptr` must be the user region of a live block of `old_size` bytes,
+/// and `new_size <= old_size`.
+pub unsafe fn shrink(user_ptr: *mut u8, old_size: usize, new_size: usize) {
+    // SAFETY: Forwarded from the caller.
+    let mut header = unsafe { read_header(user_ptr) };
+    header.size = new_size;
+    let zone = zone_size(new_size);
+    // SAFETY: zone_size never decreases with the block size, so the new
+    // suffix zone and the poisoned tail end within the old suffix zone.
+    unsafe {
+        write_header(user_ptr, header);
+        let suffix = user_ptr.add(new_size);
+        for i in 0..zone {
+            *suffix.add(i) = canary_byte(header.canary, i);
         }
+        let tail = old_size + zone_size(old_size) - new_size - zone;
+        core::ptr::write_bytes(suffix.add(zone), POISON_BYTE, tail);
     }
+}
+
+/// Check a live block's header and red zones.
+///
+/// On corruption, calls `diagnostic::overflow_detected`, which dumps the
+/// corrupted red zone and aborts unless the sanitizer is recoverable.
+///
+/// # Safety
+/// `user_ptr` must be the user region of a live block of `user_size` bytes.
+pub unsafe fn check_canaries(user_ptr: *mut u8, user_size: usize, kind: AllocKind) {
+    // SAFETY: Forwarded from the caller.
+    let (prefix_corrupt, suffix_corrupt) = unsafe { block_status(user_ptr, user_size, kind) };
 
     if prefix_corrupt || suffix_corrupt {
-        crate::sanitize::diagnostic::overflow_detected(
-            user_addr,
-            user_size,
-            prefix_corrupt,
-            suffix_corrupt,
-        );
+        let overrun = if suffix_corrupt {
+            // SAFETY: Forwarded from the caller.
+            unsafe { block_overrun(user_ptr, user_size) }
+        } else {
+            0
+        };
+        // SAFETY: The block is still allocated.
+        unsafe {
+            crate::sanitize::diagnostic::overflow_detected(
+                user_ptr as usize,
+                user_size,
+                prefix_corrupt,
+                suffix_corrupt,
+                overrun,
+            )
+        };
+    }
+}
+
+/// Inspect a block's header and both red zones without reporting.
+/// A header that no longer records `user_size` and `kind` counts as
+/// prefix corruption, since only a write below the prefix red zone reaches
+/// it; the suffix is then left unchecked, as the canary is lost.
+/// Returns `(prefix_corrupt, suffix_corrupt)`.
+///
+/// # Safety
+/// `user_ptr` must be the user region of a block of `user_size` bytes
+/// whose header and red zones are still mapped.
+pub unsafe fn block_status(user_ptr: *const u8, user_size: usize, kind: AllocKind) -> (bool, bool) {
+    // SAFETY: Forwarded from the caller.
+    unsafe { status_with_kind(user_ptr, user_size, kind as u8) }
+}
+
+/// `block_status` for a quarantined block, whose header must be marked
+/// freed by `invalidate_header`.
+///
+/// # Safety
+/// Same as `block_status`.
+pub unsafe fn freed_block_status(user_ptr: *const u8, user_size: usize) -> (bool, bool) {
+    // SAFETY: Forwarded from the caller.
+    unsafe { status_with_kind(user_ptr, user_size, FREED) }
+}
+
+/// # Safety
+/// Same as `block_status`.
+unsafe fn status_with_kind(user_ptr: *const u8, user_size: usize, kind: u8) -> (bool, bool) {
+    // SAFETY: Forwarded from the caller.
+    let header = unsafe { read_header(user_ptr) };
+    if header.size != user_size || header.kind != kind {
+        return (true, false);
+    }
+    // SAFETY: The prefix canaries are the `size()` bytes before user_ptr.
+    unsafe { canary_status(user_ptr.sub(size()), user_size, header.canary) }
+}
+
+/// `overrun` for the block at `user_ptr`, using the canary in its header.
+///
+/// # Safety
+/// Same as `block_status`.
+pub unsafe fn block_overrun(user_ptr: *const u8, user_size: usize) -> usize {
+    // SAFETY: Forwarded from the caller.
+    unsafe {
+        let canary = read_header(user_ptr).canary;
+        overrun(user_ptr.sub(size()), user_size, canary)
+    }
+}
+
+/// How far past the end of the user region the suffix red zone was
+/// overwritten: one past the offset of its last byte that no longer holds
+/// `canary`, or 0 if it is intact. Writes that went beyond the red zone
+/// cannot be seen, so this is a lower bound.
+///
+/// # Safety
+/// `base` must point to the prefix canaries of a block with valid redzones.
+pub unsafe fn overrun(base: *const u8, user_size: usize, canary: u64) -> usize {
+    // SAFETY: suffix starts at base + size() + user_size, within the allocation.
+    let suffix = unsafe { base.add(size() + user_size) };
+    // SAFETY: i < zone_size(user_size), within the allocation.
+    (0..zone_size(user_size))
+        .rposition(|i| unsafe { *suffix.add(i) } != canary_byte(canary, i))
+        .map_or(0, |last| last + 1)
+}
+
+/// Inspect both redzones against `canary` without reporting.
+/// Returns `(prefix_corrupt, suffix_corrupt)`.
+///
+/// # Safety
+/// `base` must point to the prefix canaries of a block with valid redzones.
+pub unsafe fn canary_status(base: *const u8, user_size: usize, canary: u64) -> (bool, bool) {
+    let prefix = size();
+
+    // SAFETY: i < prefix, within the allocation.
+    let prefix_corrupt = (0..prefix).any(|i| unsafe { *base.add(i) } != canary_byte(canary, i));
+
+    // SAFETY: suffix starts at base + prefix + user_size, within the allocation.
+    let suffix = unsafe { base.add(prefix + user_size) };
+    // SAFETY: i < zone_size(user_size), within the allocation.
+    let suffix_corrupt =
+        (0..zone_size(user_size)).any(|i| unsafe { *suffix.add(i) } != canary_byte(canary, i));
+
+    (prefix_corrupt, suffix_corrupt)
+}
+
+/// Heuristic: both boundary bytes of the user region hold the canary
+/// pattern, laid out from the start of the region as the red zones are, as
+/// left by a fill with the canary (a memset with a one-byte canary such as
+/// `FIXED_CANARY`, or a copy of the pattern). Such a fill that also ran
+/// past the end would rewrite the suffix with the very bytes it is checked
+/// against, hiding the overflow.
+///
+/// # Safety
+/// `user_ptr` must point to at least `user_size` readable bytes.
+pub unsafe fn looks_canary_filled(user_ptr: *const u8, user_size: usize, canary: u64) -> bool {
+    if user_size == 0 {
+        return false;
+    }
+    let last = user_size - 1;
+    // SAFETY: Both offsets are < user_size.
+    unsafe {
+        *user_ptr == canary_byte(canary, 0) && *user_ptr.add(last) == canary_byte(canary, last)
     }
 }
 
@@ -78,3 +513,189 @@ pub unsafe fn poison(user_ptr: *mut u8, user_size: usize) {
     // SAFETY: user_ptr is valid for user_size bytes (the original allocation).
     unsafe { core::ptr::write_bytes(user_ptr, POISON_BYTE, user_size) };
 }
+
+/// Stamp a freed (already poisoned) block with the tripwire and its
+/// quarantine generation. Blocks too small for the header stay plain poison.
+///
+/// # Safety
+/// `user_ptr` must point to at least `user_size` writable bytes.
+pub unsafe fn set_tripwire(user_ptr: *mut u8, user_size: usize, generation: u64) {
+    if user_size < TRIPWIRE_SIZE {
+        return;
+    }
+    // SAFETY: user_ptr is valid for TRIPWIRE_SIZE bytes (checked above).
+    unsafe {
+        (user_ptr as *mut u64).write_unaligned(TRIPWIRE);
+        (user_ptr.add(8) as *mut u64).write_unaligned(generation);
+    }
+}
+
+/// Offset of the first byte of a freed block that no longer holds what
+/// `poison` and `set_tripwire` left there, or `None` if it is untouched.
+///
+/// # Safety
+/// `user_ptr` must point to at least `user_size` readable bytes.
+pub unsafe fn first_unpoisoned(
+    user_ptr: *const u8,
+    user_size: usize,
+    generation: u64,
+) -> Option<usize> {
+    let mut start = 0;
+    if user_size >= TRIPWIRE_SIZE {
+        let mut header = [0u8; TRIPWIRE_SIZE];
+        header[..8].copy_from_slice(&TRIPWIRE.to_ne_bytes());
+        header[8..].copy_from_slice(&generation.to_ne_bytes());
+        for (i, &expected) in header.iter().enumerate() {
+            // SAFETY: i < TRIPWIRE_SIZE <= user_size.
+            if unsafe { *user_ptr.add(i) } != expected {
+                return Some(i);
+            }
+        }
+        start = TRIPWIRE_SIZE;
+    }
+    // SAFETY: i < user_size.
+    (start..user_size).find(|&i| unsafe { *user_ptr.add(i) } != POISON_BYTE)
+}
+
+/// Read the generation stamp from a block carrying the tripwire.
+///
+/// # Safety
+/// `user_ptr` must point to at least `TRIPWIRE_SIZE` readable bytes.
+pub unsafe fn read_tripwire(user_ptr: *const u8) -> Option<u64> {
+    // SAFETY: Caller guarantees TRIPWIRE_SIZE readable bytes.
+    let (marker, generation) = unsafe {
+        (
+            (user_ptr as *const u64).read_unaligned(),
+            (user_ptr.add(8) as *const u64).read_unaligned(),
+        )
+    };
+    if marker == TRIPWIRE {
+        Some(generation)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::testing;
+    use core::sync::atomic::AtomicUsize;
+
+    const EARLY_TEST: &str =
+        "sanitize::redzone::tests::allocation_before_constructors_sets_itself_up";
+
+    /// Block `early` allocated, and the seed it saw beforehand.
+    static EARLY: AtomicUsize = AtomicUsize::new(0);
+    static SEED_BEFORE: AtomicU64 = AtomicU64::new(u64::MAX);
+
+    /// Allocates as a C++ static constructor of another module might,
+    /// before anything of the sanitizer has run.
+    extern "C" fn early() {
+        if !testing::in_child(EARLY_TEST) {
+            return;
+        }
+        SEED_BEFORE.store(SEED.load(Ordering::Relaxed), Ordering::Relaxed);
+        let layout = core::alloc::Layout::from_size_align(48, 8).unwrap();
+        // SAFETY: Freed by the test.
+        let ptr = unsafe { crate::sanitize::sanitized_alloc(layout) };
+        EARLY.store(ptr as usize, Ordering::Relaxed);
+    }
+
+    // Ahead of every constructor this crate registers.
+    #[used]
+    #[unsafe(link_section = ".init_array.00100")]
+    static EARLY_CTOR: extern "C" fn() = early;
+
+    #[test]
+    fn allocation_before_constructors_sets_itself_up() {
+        if testing::in_child(EARLY_TEST) {
+            let ptr = EARLY.load(Ordering::Relaxed) as *mut u8;
+            assert!(!ptr.is_null());
+            // With `preload` the C runtime's own startup mallocs get here
+            // first and may already have seeded it.
+            if !cfg!(feature = "preload") {
+                assert_eq!(SEED_BEFORE.load(Ordering::Relaxed), 0);
+            }
+            assert_ne!(SEED.load(Ordering::Relaxed), 0);
+            // SAFETY: ptr is a live 48-byte block from the constructor.
+            unsafe {
+                // The config was read for the very first allocation.
+                let user = core::slice::from_raw_parts(ptr, 48);
+                assert!(user.iter().all(|&b| b == SCRIBBLE_BYTE));
+                assert_ne!(read_header(ptr).canary, FIXED_CANARY);
+                let layout = core::alloc::Layout::from_size_align(48, 8).unwrap();
+                crate::sanitize::sanitized_dealloc(ptr, layout);
+            }
+            return;
+        }
+        let child = testing::spawn(EARLY_TEST, &[("LIBCPP_OPTIONS", "scribble=1")]);
+        let stderr = child.stderr();
+        assert_eq!(child.code(), Some(0), "{stderr}");
+        let overflow = if cfg!(feature = "minimal-diagnostics") {
+            "SAN 4 "
+        } else {
+            "buffer overflow"
+        };
+        assert!(!stderr.contains(overflow), "{stderr}");
+    }
+
+    #[test]
+    fn canary_fill_is_recognised_for_any_canary() {
+        let canary = u64::from_ne_bytes([1, 2, 3, 4, 5, 6, 7, 8]);
+        let mut block = [0u8; 21];
+        for (i, b) in block.iter_mut().enumerate() {
+            *b = canary_byte(canary, i);
+        }
+        // SAFETY: block is 21 readable bytes.
+        unsafe {
+            assert!(looks_canary_filled(block.as_ptr(), 21, canary));
+            // A memset with the low byte does not reproduce this canary.
+            block.fill(canary as u8);
+            assert!(!looks_canary_filled(block.as_ptr(), 21, canary));
+            block.fill(CANARY_BYTE);
+            assert!(looks_canary_filled(block.as_ptr(), 21, FIXED_CANARY));
+            assert!(!looks_canary_filled(block.as_ptr(), 0, FIXED_CANARY));
+        }
+    }
+
+    #[test]
+    fn memset_with_the_canary_past_the_end_is_caught_or_hinted() {
+        const TEST: &str =
+            "sanitize::redzone::tests::memset_with_the_canary_past_the_end_is_caught_or_hinted";
+        if testing::in_child(TEST) {
+            crate::sanitize::set_paranoid(true);
+            crate::sanitize::set_halt_on_error(false);
+            let layout = core::alloc::Layout::from_size_align(32, 8).unwrap();
+            for fixed in [false, true] {
+                crate::sanitize::set_fixed_canary(fixed);
+                // SAFETY: The memset runs 8 bytes into the suffix red zone
+                // on purpose; the block is freed once.
+                unsafe {
+                    let ptr = crate::sanitize::sanitized_alloc(layout);
+                    let byte = read_header(ptr).canary as u8;
+                    core::ptr::write_bytes(ptr, byte, 40);
+                    std::eprintln!("fixed={fixed}");
+                    crate::sanitize::sanitized_dealloc(ptr, layout);
+                }
+            }
+            return;
+        }
+        let child = testing::spawn(TEST, &[]);
+        let stderr = child.stderr();
+        let (random, fixed) = stderr.split_once("fixed=true").expect("both runs");
+        let (overflow, hint) = if cfg!(feature = "minimal-diagnostics") {
+            ("SAN 4 ", "SAN 9 ")
+        } else {
+            (
+                "buffer overflow",
+                "HINT: block is filled with the red zone canary byte",
+            )
+        };
+        assert!(random.contains(overflow), "{stderr}");
+        assert!(!fixed.contains(overflow), "{stderr}");
+        assert!(fixed.contains(hint), "{stderr}");
+    }
+}
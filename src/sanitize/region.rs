@@ -0,0 +1,142 @@
+use crate::platform::spinlock::SpinLock;
+
+const CAPACITY: usize = 32;
+
+#[derive(Clone, Copy)]
+struct Region {
+    start: usize,
+    len: usize,
+    name: &'static str,
+}
+
+impl Region {
+    const EMPTY: Self = Self {
+        start: 0,
+        len: 0,
+        name: "",
+    };
+
+    fn contains(&self, addr: usize) -> bool {
+        self.len != 0 && addr >= self.start && addr - self.start < self.len
+    }
+}
+
+struct RegionTable {
+    regions: [Region; CAPACITY],
+}
+
+impl RegionTable {
+    const fn new() -> Self {
+        Self {
+            regions: [Region::EMPTY; CAPACITY],
+        }
+    }
+
+    fn register(&mut self, start: usize, len: usize, name: &'static str) -> bool {
+        for slot in &mut self.regions {
+            if slot.len == 0 {
+                *slot = Region { start, len, name };
+                return true;
+            }
+        }
+        false
+    }
+
+    fn unregister(&mut self, start: usize) -> bool {
+        for slot in &mut self.regions {
+            if slot.len != 0 && slot.start == start {
+                *slot = Region::EMPTY;
+                return true;
+            }
+        }
+        false
+    }
+
+    fn find(&self, addr: usize) -> Option<(&'static str, usize)> {
+        self.regions
+            .iter()
+            .find(|r| r.contains(addr))
+            .map(|r| (r.name, addr - r.start))
+    }
+}
+
+static REGIONS: SpinLock<RegionTable> = SpinLock::new(RegionTable::new());
+
+/// Free the region table lock, e.g. in a `fork` child.
+///
+/// # Safety
+/// No other thread may be using the table.
+pub unsafe fn force_unlock() {
+    // SAFETY: Forwarded from the caller.
+    unsafe { REGIONS.force_unlock() };
+}
+
+/// Register `[start, start + len)` under `name` so diagnostics about
+/// pointers into it (e.g. from a custom sub-allocator) name the region.
+/// Returns `false` if the table is full or `len` is zero.
+pub fn register(start: usize, len: usize, name: &'static str) -> bool {
+    len != 0 && REGIONS.lock().register(start, len, name)
+}
+
+/// Forget the region previously registered at `start`.
+pub fn unregister(start: usize) -> bool {
+    REGIONS.lock().unregister(start)
+}
+
+/// Name of the registered region containing `addr` and the offset into it.
+pub fn find(addr: usize) -> Option<(&'static str, usize)> {
+    REGIONS.lock().find(addr)
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use crate::testing;
+
+    /// Stand-in for a custom sub-allocator's arena.
+    static ARENA: [u8; 256] = [0; 256];
+
+    #[test]
+    #[cfg_attr(
+        feature = "minimal-diagnostics",
+        ignore = "minimal reports carry no region"
+    )]
+    fn invalid_free_inside_a_region_names_it() {
+        const TEST: &str = "sanitize::region::tests::invalid_free_inside_a_region_names_it";
+        if testing::in_child(TEST) {
+            let start = ARENA.as_ptr() as usize;
+            assert!(crate::sanitize::register_region(
+                start,
+                ARENA.len(),
+                "frame-arena"
+            ));
+            // SAFETY: Reported as an invalid free, which aborts before
+            // anything is released.
+            unsafe {
+                crate::sanitize::dealloc_inner(
+                    ARENA.as_ptr().add(40).cast_mut(),
+                    crate::sanitize::tracker::AllocKind::Rust,
+                )
+            };
+            unreachable!();
+        }
+        let child = testing::spawn(TEST, &[("LIBCPP_EXITCODE", "23")]);
+        let stderr = child.stderr();
+        assert_eq!(child.code(), Some(23), "{stderr}");
+        assert!(stderr.contains("ERROR: invalid free"), "{stderr}");
+        assert!(stderr.contains("  region:  frame-arena +40\n"), "{stderr}");
+    }
+
+    #[test]
+    fn unregistered_region_is_forgotten() {
+        static OTHER: [u8; 64] = [0; 64];
+        let start = OTHER.as_ptr() as usize;
+        assert!(super::register(start, OTHER.len(), "other"));
+        assert_eq!(super::find(start + 63), Some(("other", 63)));
+        assert_eq!(super::find(start + 64), None);
+        assert!(super::unregister(start));
+        assert_eq!(super::find(start + 63), None);
+        assert!(!super::register(start, 0, "empty"));
+    }
+}
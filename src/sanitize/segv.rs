@@ -0,0 +1,46 @@
+//! Optional SIGSEGV handler that explains faults on freed memory.
+//!
+//! Runs in signal context: it only uses the raw syscall writer and never
+//! spins on a lock, since the faulting thread may hold it.
+
+use crate::platform::syscall::{
+    KernelSigaction, SA_RESETHAND, SA_RESTORER, SA_SIGINFO, SIGSEGV, libcplusplus_sigreturn,
+    sys_rt_sigaction,
+};
+use crate::sanitize::{diagnostic, quarantine};
+
+/// Leading fields of the kernel `siginfo_t` for SIGSEGV on 64-bit Linux.
+#[repr(C)]
+struct SigInfo {
+    signo: i32,
+    errno: i32,
+    code: i32,
+    _pad: i32,
+    addr: usize,
+}
+
+extern "C" fn on_segv(_sig: i32, info: *const SigInfo, _ctx: *mut u8) {
+    // SAFETY: The kernel passes a valid siginfo_t with SA_SIGINFO.
+    let addr = unsafe { (*info).addr };
+    #[cfg(feature = "guard-pages")]
+    if crate::sanitize::guard::touch(addr) {
+        return;
+    }
+    match quarantine::try_find_containing(addr) {
+        Some(block) => diagnostic::use_after_free(addr, &block),
+        None => diagnostic::segv(addr),
+    }
+}
+
+/// Install the handler. The disposition resets to default on delivery, so
+/// a fault inside the report itself kills the process normally.
+pub fn install() -> bool {
+    let act = KernelSigaction {
+        handler: on_segv as *const () as usize,
+        flags: SA_SIGINFO | SA_RESTORER | SA_RESETHAND,
+        restorer: libcplusplus_sigreturn as *const () as usize,
+        mask: 0,
+    };
+    // SAFETY: act is a valid sigaction; the old action is not requested.
+    unsafe { sys_rt_sigaction(SIGSEGV, &act, core::ptr::null_mut()) == 0 }
+}
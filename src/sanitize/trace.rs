@@ -0,0 +1,207 @@
+//! Allocation timeline in Chrome Trace Event Format.
+//!
+//! Once started, every allocation and free is written to the trace fd as
+//! one JSON event per line, in the JSON Array Format that chrome://tracing
+//! and Perfetto accept without a closing bracket. Allocation lifetimes are
+//! not nested per thread, so they are emitted as async `b`/`e` event pairs
+//! keyed by address rather than `B`/`E` duration events.
+//!
+//! Each event goes out in a single `write` as it happens. Nothing is
+//! buffered, so an aborting report or exit has nothing left to flush: the
+//! trace ends with the last operation that completed before the failure.
+
+use crate::platform::syscall::{CLOCK_MONOTONIC, Timespec, sys_clock_gettime, sys_write};
+use crate::sanitize::alloc_hook::{self, AllocEvent};
+use crate::sanitize::diagnostic::{self, Line};
+use crate::sanitize::tracker::AllocKind;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Trace destination; `usize::MAX` while tracing is off.
+static TRACE_FD: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+fn write_fd(fd: usize, bytes: &[u8]) {
+    // SAFETY: bytes is a valid readable slice.
+    unsafe { sys_write(fd, bytes.as_ptr(), bytes.len()) };
+}
+
+/// Monotonic time in microseconds, the unit Trace Event `ts` expects.
+fn now_us() -> usize {
+    let mut ts = Timespec::default();
+    // SAFETY: ts is a valid, writable Timespec.
+    unsafe { sys_clock_gettime(CLOCK_MONOTONIC, &mut ts) };
+    (ts.tv_sec as usize) * 1_000_000 + (ts.tv_nsec as usize) / 1_000
+}
+
+fn kind_label(kind: AllocKind) -> &'static [u8] {
+    match kind {
+        AllocKind::Rust => b"rust",
+        AllocKind::ScalarNew => b"new",
+        AllocKind::ArrayNew => b"new[]",
+        AllocKind::Malloc => b"malloc",
+    }
+}
+
+fn on_event(event: AllocEvent, addr: usize, size: usize, kind: AllocKind) {
+    let fd = TRACE_FD.load(Ordering::Acquire);
+    if fd == usize::MAX {
+        return;
+    }
+    // SAFETY: gettid has no preconditions.
+    let tid = unsafe { crate::platform::syscall::sys_gettid() };
+
+    let mut line = Line::new();
+    line.push(b"{\"name\":\"");
+    line.push(kind_label(kind));
+    line.push(b"\",\"cat\":\"heap\",\"ph\":\"");
+    line.push(match event {
+        AllocEvent::Alloc => b"b",
+        AllocEvent::Free => b"e",
+    });
+    line.push(b"\",\"id\":\"");
+    line.push_hex(addr);
+    line.push(b"\",\"ts\":");
+    line.push_dec(now_us());
+    line.push(b",\"pid\":");
+    line.push_dec(diagnostic::pid());
+    line.push(b",\"tid\":");
+    line.push_dec(tid);
+    line.push(b",\"args\":{\"size\":");
+    line.push_dec(size);
+    line.push(b"}},\n");
+    write_fd(fd, line.as_bytes());
+}
+
+/// Start writing allocation events to `fd`. Returns `false` if tracing is
+/// already active or no alloc hook slot is free.
+pub fn start(fd: usize) -> bool {
+    if TRACE_FD
+        .compare_exchange(usize::MAX, fd, Ordering::AcqRel, Ordering::Relaxed)
+        .is_err()
+    {
+        return false;
+    }
+    write_fd(fd, b"[\n");
+    if !alloc_hook::add(on_event) {
+        TRACE_FD.store(usize::MAX, Ordering::Release);
+        return false;
+    }
+    true
+}
+
+/// Stop tracing. The fd is left open for the caller to close.
+pub fn stop() {
+    alloc_hook::remove(on_event);
+    TRACE_FD.store(usize::MAX, Ordering::Release);
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::sanitize::{alloc_inner, dealloc_inner};
+    use core::alloc::Layout;
+    use std::os::fd::AsRawFd;
+    use std::vec::Vec;
+
+    /// The value of `"key":` in a flat event line, up to the next `,` or `}`.
+    fn field<'a>(event: &'a str, key: &str) -> &'a str {
+        let start = event.find(&std::format!("\"{key}\":")).expect(key) + key.len() + 3;
+        let rest = &event[start..];
+        &rest[..rest.find([',', '}']).unwrap()]
+    }
+
+    #[test]
+    fn abort_keeps_every_event_before_the_failure() {
+        const TEST: &str = "sanitize::trace::tests::abort_keeps_every_event_before_the_failure";
+        if crate::testing::in_child(TEST) {
+            let path = std::env::var("TRACE_OUT").unwrap();
+            let file = std::fs::File::create(path).unwrap();
+            assert!(start(file.as_raw_fd() as usize));
+            let layout = Layout::from_size_align(24, 8).unwrap();
+            // SAFETY: The second free is the error under test; it aborts
+            // before anything could reuse the block.
+            unsafe {
+                let ptr = alloc_inner(layout, AllocKind::ScalarNew);
+                std::eprintln!("traced={ptr:p}");
+                dealloc_inner(ptr, AllocKind::ScalarNew);
+                dealloc_inner(ptr, AllocKind::ScalarNew);
+            }
+            unreachable!();
+        }
+        let path = std::env::temp_dir().join(std::format!(
+            "libcpp-trace-abort-{}.json",
+            std::process::id()
+        ));
+        let child = crate::testing::spawn(TEST, &[("TRACE_OUT", path.to_str().unwrap())]);
+        let stderr = child.stderr();
+        let trace = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_ne!(child.code(), Some(0), "{stderr}");
+        let traced = stderr
+            .lines()
+            .find_map(|l| l.strip_prefix("traced=0x"))
+            .expect("child printed its pointer");
+        let id = std::format!("\"0x{:016x}\"", usize::from_str_radix(traced, 16).unwrap());
+        let phases: Vec<&str> = trace
+            .lines()
+            .skip(1)
+            .filter(|l| field(l, "id") == id)
+            .map(|l| field(l, "ph"))
+            .collect();
+        assert_eq!(phases, ["\"b\"", "\"e\""], "{trace}");
+        // The free that completed is the last event in the file.
+        assert_eq!(field(trace.lines().last().unwrap(), "id"), id, "{trace}");
+    }
+
+    #[test]
+    fn events_are_trace_format_objects_with_the_real_pid() {
+        const TEST: &str =
+            "sanitize::trace::tests::events_are_trace_format_objects_with_the_real_pid";
+        if crate::testing::in_child(TEST) {
+            let path = std::env::var("TRACE_OUT").unwrap();
+            let file = std::fs::File::create(path).unwrap();
+            assert!(start(file.as_raw_fd() as usize));
+            assert!(!start(file.as_raw_fd() as usize));
+            let layout = Layout::from_size_align(40, 8).unwrap();
+            // SAFETY: Freed once, with the kind it came from.
+            unsafe {
+                let ptr = alloc_inner(layout, AllocKind::ArrayNew);
+                dealloc_inner(ptr, AllocKind::ArrayNew);
+                std::eprintln!("traced={ptr:p} pid={}", std::process::id());
+            }
+            stop();
+            return;
+        }
+        let path =
+            std::env::temp_dir().join(std::format!("libcpp-trace-{}.json", std::process::id()));
+        let child = crate::testing::spawn(TEST, &[("TRACE_OUT", path.to_str().unwrap())]);
+        let stderr = child.stderr();
+        assert_eq!(child.code(), Some(0), "{stderr}");
+        let trace = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let mut lines = trace.lines();
+        assert_eq!(lines.next(), Some("["));
+        let (traced, pid) = stderr
+            .lines()
+            .find_map(|l| l.strip_prefix("traced=0x")?.split_once(" pid="))
+            .expect("child printed its pointer and pid");
+        let id = std::format!("\"0x{:016x}\"", usize::from_str_radix(traced, 16).unwrap());
+        let events: Vec<&str> = lines.filter(|l| field(l, "id") == id).collect();
+        assert_eq!(events.len(), 2, "{trace}");
+        for (event, ph) in events.iter().zip(["\"b\"", "\"e\""]) {
+            assert!(event.starts_with('{') && event.ends_with("}},"), "{event}");
+            assert_eq!(field(event, "name"), "\"new[]\"");
+            assert_eq!(field(event, "cat"), "\"heap\"");
+            assert_eq!(field(event, "ph"), ph);
+            assert!(field(event, "ts").parse::<u64>().is_ok(), "{event}");
+            assert!(field(event, "tid").parse::<u64>().unwrap() > 0, "{event}");
+            assert_eq!(field(event, "size"), "40");
+            assert_eq!(field(event, "pid"), pid);
+            let opening = event.matches('{').count();
+            assert_eq!(opening, event.matches('}').count(), "{event}");
+            assert_eq!(event.matches('"').count() % 2, 0, "{event}");
+        }
+    }
+}
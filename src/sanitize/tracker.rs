@@ -1,13 +1,57 @@
-use crate::sanitize::spinlock::SpinLock;
+use crate::platform::cache::CacheAligned;
+use crate::platform::spinlock::{RwSpinLock, SpinLock};
+use crate::sanitize::backtrace::Frames;
+use crate::sanitize::epoch::Epoch;
+use core::alloc::Layout;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+#[cfg(feature = "thread-cache")]
+mod thread_cache;
 
 const CAPACITY: usize = 16384;
 
-#[derive(Clone, Copy, PartialEq, Eq)]
+/// Independently locked slices of the table. An address always lives in
+/// the shard picked by the top bits of its hash, so threads touching
+/// different addresses rarely contend.
+const SHARDS: usize = 16;
+
+const SHARD_CAPACITY: usize = CAPACITY / SHARDS;
+
+/// Right shift that maps the 64-bit Fibonacci product to a shard.
+const SHARD_SHIFT: u32 = usize::BITS - SHARDS.trailing_zeros();
+
+/// Right shift that maps the product, with the shard bits shifted out,
+/// into `0..SHARD_CAPACITY`. Derived from the table size so the hash
+/// covers the full index range.
+const HASH_SHIFT: u32 = usize::BITS - SHARD_CAPACITY.trailing_zeros();
+
+const _: () = assert!(CAPACITY.is_power_of_two() && SHARDS.is_power_of_two());
+const _: () = assert!(SHARDS > 1 && SHARD_CAPACITY > 1);
+
+/// Number of power-of-two size buckets in the allocation histogram.
+/// Bucket `i` counts sizes in `[2^(i-1), 2^i)`; bucket 0 is zero-sized
+/// requests and the last bucket absorbs everything larger.
+pub const HISTOGRAM_BUCKETS: usize = 40;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 #[repr(u8)]
 pub enum AllocKind {
     Rust = 0,
     ScalarNew = 1,
     ArrayNew = 2,
+    /// C `malloc` family (`posix_memalign`, `memalign`, ...).
+    Malloc = 3,
+}
+
+impl AllocKind {
+    pub const COUNT: usize = 4;
+    /// Every kind, in discriminant order.
+    pub const ALL: [AllocKind; Self::COUNT] = [
+        AllocKind::Rust,
+        AllocKind::ScalarNew,
+        AllocKind::ArrayNew,
+        AllocKind::Malloc,
+    ];
 }
 
 #[derive(Clone, Copy, PartialEq, Eq)]
@@ -21,133 +65,1480 @@ enum SlotState {
 #[derive(Clone, Copy)]
 struct Entry {
     addr: usize,
+    /// Block returned by the platform allocator; differs from
+    /// `addr - redzone::HEADER_SIZE - redzone::size()` when the prefix was
+    /// widened for alignment or the block is guarded.
+    base: usize,
     size: usize,
+    /// Alignment the block was requested with.
+    align: usize,
     state: SlotState,
     kind: AllocKind,
+    /// Allocation sequence number, 1-based, in tracker insertion order.
+    seq: u64,
+    /// Scope epoch current when the block was allocated.
+    scope: u64,
+    frames: Frames,
+    /// Label set with `set_tag`.
+    tag: Option<&'static str>,
 }
 
 impl Entry {
     const EMPTY: Self = Self {
         addr: 0,
+        base: 0,
         size: 0,
+        align: 0,
         state: SlotState::Empty,
         kind: AllocKind::Rust,
+        seq: 0,
+        scope: 0,
+        frames: Frames::EMPTY,
+        tag: None,
     };
 }
 
+/// A live allocation as recorded by the tracker.
+#[derive(Clone, Copy)]
+pub struct Allocation {
+    pub base: usize,
+    pub size: usize,
+    pub align: usize,
+    pub kind: AllocKind,
+    /// Allocation sequence number, 1-based.
+    pub seq: u64,
+    /// Scope epoch current when the block was allocated.
+    pub scope: u64,
+    pub frames: Frames,
+    pub tag: Option<&'static str>,
+    /// Shard the entry was in, so `restore` can undo the removal there.
+    /// With `thread-cache`, `SHARDS + n` for an entry taken from cache
+    /// slot `n` before it reached a shard.
+    shard: usize,
+}
+
+impl Allocation {
+    fn of(e: &Entry) -> Self {
+        Self {
+            shard: home(e.addr),
+            base: e.base,
+            size: e.size,
+            align: e.align,
+            kind: e.kind,
+            seq: e.seq,
+            scope: e.scope,
+            frames: e.frames,
+            tag: e.tag,
+        }
+    }
+}
+
+/// Live allocation count at its high-water mark, with the size
+/// distribution of the blocks that were live at that moment.
+#[derive(Clone, Copy)]
+pub struct Peak {
+    pub count: usize,
+    pub histogram: [u64; HISTOGRAM_BUCKETS],
+}
+
+/// Allocation counters summed over all shards.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct Stats {
+    /// Allocations currently live.
+    pub live_count: usize,
+    /// User bytes currently live.
+    pub live_bytes: usize,
+    /// Allocations tracked since startup.
+    pub total_allocs: u64,
+    /// Tracked allocations freed since startup.
+    pub total_frees: u64,
+    /// Highest `live_bytes` seen so far.
+    pub peak_bytes: usize,
+    /// The same counts split by allocation family, indexed by `AllocKind`.
+    pub by_kind: [KindStats; AllocKind::COUNT],
+}
+
+/// Per-family part of `Stats`.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct KindStats {
+    pub live_count: usize,
+    pub total_allocs: u64,
+}
+
+/// One shard of the table.
 struct TrackerInner {
-    entries: [Entry; CAPACITY],
+    entries: [Entry; SHARD_CAPACITY],
     count: usize,
+    /// User bytes of the occupied entries.
+    bytes: usize,
+    allocs: u64,
+    frees: u64,
+    by_kind: [KindStats; AllocKind::COUNT],
+    histogram: [u64; HISTOGRAM_BUCKETS],
 }
 
 impl TrackerInner {
     const fn new() -> Self {
         Self {
-            entries: [Entry::EMPTY; CAPACITY],
+            entries: [Entry::EMPTY; SHARD_CAPACITY],
             count: 0,
+            bytes: 0,
+            allocs: 0,
+            frees: 0,
+            by_kind: [KindStats {
+                live_count: 0,
+                total_allocs: 0,
+            }; AllocKind::COUNT],
+            histogram: [0; HISTOGRAM_BUCKETS],
+        }
+    }
+
+    /// Forget every entry and counter.
+    #[cfg(any(test, feature = "test-hooks"))]
+    fn clear(&mut self) {
+        self.entries.fill(Entry::EMPTY);
+        self.count = 0;
+        self.bytes = 0;
+        self.allocs = 0;
+        self.frees = 0;
+        self.by_kind = [KindStats::default(); AllocKind::COUNT];
+        self.histogram = [0; HISTOGRAM_BUCKETS];
+    }
+
+    fn bucket(size: usize) -> usize {
+        let bits = (usize::BITS - size.leading_zeros()) as usize;
+        if bits < HISTOGRAM_BUCKETS {
+            bits
+        } else {
+            HISTOGRAM_BUCKETS - 1
         }
     }
 
-    /// Fibonacci hashing — good distribution for pointer addresses.
-    fn hash(addr: usize) -> usize {
-        addr.wrapping_mul(0x9E3779B97F4A7C15) >> (usize::BITS - 14)
+    /// Home slot of `addr` within its shard.
+    fn slot(addr: usize) -> usize {
+        (hash(addr) << SHARDS.trailing_zeros()) >> HASH_SHIFT
     }
 
-    fn insert(&mut self, addr: usize, size: usize, kind: AllocKind) {
-        let mut idx = Self::hash(addr) % CAPACITY;
-        for _ in 0..CAPACITY {
+    /// Store an `Occupied` entry. Returns `false` if the shard is full and
+    /// the block went untracked.
+    fn insert(&mut self, entry: Entry) -> bool {
+        let mut idx = Self::slot(entry.addr) % SHARD_CAPACITY;
+        for _ in 0..SHARD_CAPACITY {
             match self.entries[idx].state {
                 SlotState::Empty | SlotState::Tombstone => {
-                    self.entries[idx] = Entry {
-                        addr,
-                        size,
-                        state: SlotState::Occupied,
-                        kind,
-                    };
+                    self.histogram[Self::bucket(entry.size)] += 1;
                     self.count += 1;
-                    return;
+                    self.bytes += entry.size;
+                    self.allocs += 1;
+                    let kind = &mut self.by_kind[entry.kind as usize];
+                    kind.live_count += 1;
+                    kind.total_allocs += 1;
+                    self.entries[idx] = entry;
+                    return true;
                 }
                 SlotState::Occupied => {
-                    idx = (idx + 1) % CAPACITY;
+                    idx = (idx + 1) % SHARD_CAPACITY;
                 }
             }
         }
-        // Table full — silently drop. Sanitizer degrades but doesn't crash.
+        false
+    }
+
+    /// `insert` an entry `remove` just took out without counting it as
+    /// a new allocation. The removal itself is undone by `unfree` on the
+    /// shard it came from.
+    fn restore(&mut self, entry: Entry) -> bool {
+        let (size, kind) = (entry.size, entry.kind);
+        if !self.insert(entry) {
+            return false;
+        }
+        self.allocs -= 1;
+        self.by_kind[kind as usize].total_allocs -= 1;
+        self.histogram[Self::bucket(size)] -= 1;
+        true
     }
 
-    fn remove(&mut self, addr: usize) -> Option<(usize, AllocKind)> {
-        let mut idx = Self::hash(addr) % CAPACITY;
-        for _ in 0..CAPACITY {
+    fn unfree(&mut self) {
+        self.frees -= 1;
+    }
+
+    fn remove(&mut self, addr: usize) -> Option<Entry> {
+        if self.count == 0 {
+            return None;
+        }
+        let mut idx = Self::slot(addr) % SHARD_CAPACITY;
+        for _ in 0..SHARD_CAPACITY {
             match self.entries[idx].state {
                 SlotState::Occupied if self.entries[idx].addr == addr => {
-                    let size = self.entries[idx].size;
-                    let kind = self.entries[idx].kind;
+                    let entry = self.entries[idx];
+                    let (size, kind) = (entry.size, entry.kind);
                     self.entries[idx].state = SlotState::Tombstone;
-                    self.count -= 1;
-                    return Some((size, kind));
+                    // Occupied slots are always counted, but never let a
+                    // bookkeeping slip wrap the count and flood the leak report.
+                    debug_assert!(self.count > 0, "tracker count underflow");
+                    self.count = self.count.saturating_sub(1);
+                    self.bytes = self.bytes.saturating_sub(size);
+                    self.frees += 1;
+                    let by_kind = &mut self.by_kind[kind as usize];
+                    by_kind.live_count = by_kind.live_count.saturating_sub(1);
+                    return Some(entry);
+                }
+                SlotState::Empty => return None,
+                _ => idx = (idx + 1) % SHARD_CAPACITY,
+            }
+        }
+        None
+    }
+
+    fn find(&self, addr: usize) -> Option<&Entry> {
+        let mut idx = Self::slot(addr) % SHARD_CAPACITY;
+        for _ in 0..SHARD_CAPACITY {
+            match self.entries[idx].state {
+                SlotState::Occupied if self.entries[idx].addr == addr => {
+                    return Some(&self.entries[idx]);
                 }
                 SlotState::Empty => return None,
-                _ => idx = (idx + 1) % CAPACITY,
+                _ => idx = (idx + 1) % SHARD_CAPACITY,
+            }
+        }
+        None
+    }
+
+    fn set_tag(&mut self, addr: usize, tag: &'static str) -> Option<()> {
+        let mut idx = Self::slot(addr) % SHARD_CAPACITY;
+        for _ in 0..SHARD_CAPACITY {
+            match self.entries[idx].state {
+                SlotState::Occupied if self.entries[idx].addr == addr => {
+                    self.entries[idx].tag = Some(tag);
+                    return Some(());
+                }
+                SlotState::Empty => return None,
+                _ => idx = (idx + 1) % SHARD_CAPACITY,
             }
         }
         None
     }
 
     fn lookup(&self, addr: usize) -> Option<(usize, AllocKind)> {
-        let mut idx = Self::hash(addr) % CAPACITY;
-        for _ in 0..CAPACITY {
+        self.find(addr).map(|e| (e.size, e.kind))
+    }
+
+    /// Overwrite the recorded size of a live allocation, returning the old one.
+    fn update_size(&mut self, addr: usize, size: usize) -> Option<usize> {
+        let mut idx = Self::slot(addr) % SHARD_CAPACITY;
+        for _ in 0..SHARD_CAPACITY {
             match self.entries[idx].state {
                 SlotState::Occupied if self.entries[idx].addr == addr => {
-                    return Some((self.entries[idx].size, self.entries[idx].kind));
+                    let old = core::mem::replace(&mut self.entries[idx].size, size);
+                    self.bytes = self.bytes - old + size;
+                    return Some(old);
                 }
                 SlotState::Empty => return None,
-                _ => idx = (idx + 1) % CAPACITY,
+                _ => idx = (idx + 1) % SHARD_CAPACITY,
             }
         }
         None
     }
 
-    /// Walk all live allocations, calling `f` for each. Used for leak reporting.
-    fn for_each_live(&self, mut f: impl FnMut(usize, usize, AllocKind)) {
+    /// Walk all live allocations in this shard, calling `f` for each.
+    fn for_each_live(&self, mut f: impl FnMut(&Entry)) {
         for entry in &self.entries {
             if entry.state == SlotState::Occupied {
-                f(entry.addr, entry.size, entry.kind);
+                f(entry);
+            }
+        }
+    }
+}
+
+/// Fibonacci hashing — good distribution for pointer addresses.
+fn hash(addr: usize) -> usize {
+    addr.wrapping_mul(0x9E3779B97F4A7C15)
+}
+
+/// Lookups and walks take a shard's lock shared; only inserts, removes and
+/// resets take it exclusively.
+static TRACKER: [CacheAligned<RwSpinLock<TrackerInner>>; SHARDS] =
+    [const { CacheAligned::new(RwSpinLock::new(TrackerInner::new())) }; SHARDS];
+
+/// Shard `addr` is stored in unless that shard was full.
+fn home(addr: usize) -> usize {
+    hash(addr) >> SHARD_SHIFT
+}
+
+/// Set once an entry went to a shard other than its home, after which
+/// lookups that miss at home try the other shards too.
+static SPILLED: AtomicBool = AtomicBool::new(false);
+
+/// Set once an entry fit in no shard; the note is printed once.
+static FULL: AtomicBool = AtomicBool::new(false);
+
+/// Store `entry` with `put` in the shard at `home`, or the next one that
+/// has room. Returns the shard it went to.
+fn place(
+    entry: Entry,
+    home: usize,
+    put: impl Fn(&mut TrackerInner, Entry) -> bool,
+) -> Option<usize> {
+    let placed = (0..SHARDS)
+        .map(|i| (home + i) % SHARDS)
+        .find(|&idx| put(&mut TRACKER[idx].write(), entry));
+    match placed {
+        Some(idx) if idx != home => SPILLED.store(true, Ordering::Relaxed),
+        Some(_) => {}
+        None => {
+            if !FULL.swap(true, Ordering::Relaxed) {
+                crate::sanitize::diagnostic::write_log(
+                    b"libcplusplus: allocation tracker full; new blocks go untracked\n",
+                );
+            }
+        }
+    }
+    placed
+}
+
+/// Run `f` on the shard holding `addr` under its read lock: the home
+/// shard, then the others once entries have spilled.
+fn read_entry<T>(addr: usize, f: impl Fn(&TrackerInner) -> Option<T>) -> Option<T> {
+    let home = home(addr);
+    if let Some(found) = f(&TRACKER[home].read()) {
+        return Some(found);
+    }
+    if !SPILLED.load(Ordering::Relaxed) {
+        return None;
+    }
+    (1..SHARDS).find_map(|i| f(&TRACKER[(home + i) % SHARDS].read()))
+}
+
+/// `read_entry` under the write lock, also returning the shard index.
+fn write_entry<T>(
+    addr: usize,
+    mut f: impl FnMut(&mut TrackerInner) -> Option<T>,
+) -> Option<(usize, T)> {
+    let home = home(addr);
+    let rounds = if SPILLED.load(Ordering::Relaxed) {
+        SHARDS
+    } else {
+        1
+    };
+    (0..rounds)
+        .map(|i| (home + i) % SHARDS)
+        .find_map(|idx| f(&mut TRACKER[idx].write()).map(|t| (idx, t)))
+}
+
+/// Move the entries thread caches still hold into the shards, so a walk
+/// over the shards sees every live block.
+fn flush_caches() {
+    #[cfg(feature = "thread-cache")]
+    thread_cache::flush_all();
+}
+
+/// `f`, run again after flushing the thread caches if it found nothing
+/// while they held entries.
+fn or_flushed<T>(mut f: impl FnMut() -> Option<T>) -> Option<T> {
+    let found = f();
+    #[cfg(feature = "thread-cache")]
+    if found.is_none() && thread_cache::flush_all() {
+        return f();
+    }
+    found
+}
+
+/// Walk all live allocations, calling `f` for each. Used for leak reporting.
+///
+/// Shards are locked one at a time, so the walk is not an atomic snapshot
+/// of the whole heap while other threads keep allocating.
+fn for_each_live(mut f: impl FnMut(&Entry)) {
+    flush_caches();
+    for shard in &TRACKER {
+        shard.read().for_each_live(&mut f);
+    }
+}
+
+/// Check the redzones of every live allocation, printing each corrupt
+/// block without aborting. Returns the number of corrupt blocks.
+fn verify_live() -> usize {
+    let mut corrupt = 0;
+    for_each_live(|e| {
+        // SAFETY: Live entries always point at a malloc'd block laid out
+        // by sanitized_alloc.
+        let (prefix, suffix) =
+            unsafe { crate::sanitize::redzone::block_status(e.addr as *const u8, e.size, e.kind) };
+        if prefix || suffix {
+            if corrupt == 0 {
+                crate::sanitize::diagnostic::heap_verification_header();
             }
+            crate::sanitize::diagnostic::heap_corruption(e.addr, e.size, prefix, suffix);
+            corrupt += 1;
         }
+    });
+    corrupt
+}
+
+/// Allocation sequence counter shared by all shards.
+static NEXT_SEQ: CacheAligned<AtomicU64> = CacheAligned::new(AtomicU64::new(0));
+
+/// Live block count and size distribution across all shards.
+static LIVE: CacheAligned<AtomicUsize> = CacheAligned::new(AtomicUsize::new(0));
+static LIVE_HISTOGRAM: [AtomicU64; HISTOGRAM_BUCKETS] =
+    [const { AtomicU64::new(0) }; HISTOGRAM_BUCKETS];
+
+/// Live user bytes across all shards and their high-water mark.
+static LIVE_BYTES: CacheAligned<AtomicUsize> = CacheAligned::new(AtomicUsize::new(0));
+static PEAK_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+/// `PEAK.count`, readable without the lock so only new peaks take it.
+static PEAK_COUNT: CacheAligned<AtomicUsize> = CacheAligned::new(AtomicUsize::new(0));
+static PEAK: CacheAligned<SpinLock<Peak>> = CacheAligned::new(SpinLock::new(Peak {
+    count: 0,
+    histogram: [0; HISTOGRAM_BUCKETS],
+}));
+
+fn live_added(size: usize) {
+    let bytes = LIVE_BYTES.fetch_add(size, Ordering::Relaxed) + size;
+    PEAK_BYTES.fetch_max(bytes, Ordering::Relaxed);
+    LIVE_HISTOGRAM[TrackerInner::bucket(size)].fetch_add(1, Ordering::Relaxed);
+    let live = LIVE.fetch_add(1, Ordering::Relaxed) + 1;
+    if live <= PEAK_COUNT.load(Ordering::Relaxed) {
+        return;
+    }
+    let mut peak = PEAK.lock();
+    if live > peak.count {
+        // Other threads may move the histogram while it is copied; the
+        // snapshot is exact when allocation is single-threaded.
+        peak.count = live;
+        for (dst, src) in peak.histogram.iter_mut().zip(&LIVE_HISTOGRAM) {
+            *dst = src.load(Ordering::Relaxed);
+        }
+        PEAK_COUNT.store(live, Ordering::Relaxed);
+    }
+}
+
+fn live_removed(size: usize) {
+    LIVE_BYTES.fetch_sub(size, Ordering::Relaxed);
+    LIVE_HISTOGRAM[TrackerInner::bucket(size)].fetch_sub(1, Ordering::Relaxed);
+    LIVE.fetch_sub(1, Ordering::Relaxed);
+}
+
+/// Current scope epoch. Odd values are open scopes, even values are
+/// "outside any scope".
+static SCOPE: CacheAligned<Epoch> = CacheAligned::new(Epoch::new());
+
+static LEAK_JSONL: AtomicBool = AtomicBool::new(false);
+
+static VERIFY_ON_EXIT: AtomicBool = AtomicBool::new(false);
+
+/// Sweep every live allocation's redzones before the leak report, so
+/// corruption is reported first and leaked blocks with smashed metadata
+/// are flagged rather than silently listed.
+pub fn set_verify_on_exit(enabled: bool) {
+    VERIFY_ON_EXIT.store(enabled, Ordering::Relaxed);
+}
+
+/// Check the redzones of every live allocation without aborting.
+/// Returns the number of corrupt blocks found (each is printed).
+pub fn verify_heap() -> usize {
+    let _report = crate::sanitize::diagnostic::Writer::open();
+    let corrupt = verify_live();
+    if corrupt != 0 {
+        crate::sanitize::diagnostic::report_total(b"corrupt blocks", corrupt, b"\n");
+    }
+    corrupt
+}
+
+/// Most call-site ranges `suppress_leaks_from` holds.
+const LEAK_SUPPRESSIONS: usize = 16;
+
+/// Code ranges, as `[start, end)`, whose allocations `report_leaks` skips.
+#[derive(Clone, Copy)]
+struct Suppressions {
+    ranges: [(usize, usize); LEAK_SUPPRESSIONS],
+    len: usize,
+}
+
+impl Suppressions {
+    /// Whether any return address in `frames` falls in a suppressed range.
+    fn matches(&self, frames: &Frames) -> bool {
+        let ranges = &self.ranges[..self.len];
+        frames.iter().any(|addr| {
+            ranges
+                .iter()
+                .any(|&(start, end)| addr >= start && addr < end)
+        })
     }
 }
 
-static TRACKER: SpinLock<TrackerInner> = SpinLock::new(TrackerInner::new());
+static SUPPRESSED: SpinLock<Suppressions> = SpinLock::new(Suppressions {
+    ranges: [(0, 0); LEAK_SUPPRESSIONS],
+    len: 0,
+});
 
-pub fn insert(addr: usize, size: usize, kind: AllocKind) {
-    TRACKER.lock().insert(addr, size, kind);
+/// Leave blocks whose allocation stack has a return address in `range`
+/// (say, the code of a one-time init function) out of the leak report.
+/// Needs `backtrace`: without it no stack matches. Returns `false` once
+/// `LEAK_SUPPRESSIONS` ranges are registered.
+pub fn suppress_leaks_from(range: core::ops::Range<usize>) -> bool {
+    let mut suppressed = SUPPRESSED.lock();
+    let len = suppressed.len;
+    if len == LEAK_SUPPRESSIONS {
+        return false;
+    }
+    suppressed.ranges[len] = (range.start, range.end);
+    suppressed.len = len + 1;
+    true
+}
+
+/// Emit leaks as one JSON object per line instead of the decorated text
+/// report, so large leak sets can be stream-processed.
+pub fn set_leak_jsonl(enabled: bool) {
+    LEAK_JSONL.store(enabled, Ordering::Relaxed);
+}
+
+/// Track a new allocation at user address `addr`, carved out of the
+/// platform block at `base`.
+pub fn insert(addr: usize, base: usize, layout: Layout, kind: AllocKind) {
+    let entry = Entry {
+        addr,
+        base,
+        size: layout.size(),
+        align: layout.align(),
+        state: SlotState::Occupied,
+        kind,
+        seq: NEXT_SEQ.fetch_add(1, Ordering::Relaxed) + 1,
+        scope: SCOPE.get(),
+        frames: Frames::capture(),
+        tag: None,
+    };
+    #[cfg(feature = "thread-cache")]
+    if thread_cache::push(entry) {
+        live_added(layout.size());
+        return;
+    }
+    if place(entry, home(addr), TrackerInner::insert).is_some() {
+        live_added(layout.size());
+    }
 }
 
 pub fn remove(addr: usize) -> Option<(usize, AllocKind)> {
-    TRACKER.lock().remove(addr)
+    take(addr).map(|a| (a.size, a.kind))
+}
+
+/// `remove`, returning the whole record so it can be handed to `restore`.
+pub fn take(addr: usize) -> Option<Allocation> {
+    #[cfg(feature = "thread-cache")]
+    if let Some(alloc) = thread_cache::take(addr) {
+        live_removed(alloc.size);
+        return Some(alloc);
+    }
+    let (shard, removed) = or_flushed(|| write_entry(addr, |inner| inner.remove(addr)))?;
+    live_removed(removed.size);
+    Some(Allocation {
+        shard,
+        ..Allocation::of(&removed)
+    })
+}
+
+/// Track a block `take` just removed again, with its original sequence
+/// number, scope and frames, as though it had never been removed.
+pub fn restore(addr: usize, alloc: &Allocation) {
+    let entry = Entry {
+        addr,
+        base: alloc.base,
+        size: alloc.size,
+        align: alloc.align,
+        state: SlotState::Occupied,
+        kind: alloc.kind,
+        seq: alloc.seq,
+        scope: alloc.scope,
+        frames: alloc.frames,
+        tag: alloc.tag,
+    };
+    // The cache counted the block as freed; count it as allocated afresh.
+    #[cfg(feature = "thread-cache")]
+    if let Some(slot) = alloc.shard.checked_sub(SHARDS) {
+        thread_cache::unsettle(slot, &entry);
+        if place(entry, home(addr), TrackerInner::insert).is_some() {
+            live_added(alloc.size);
+        }
+        return;
+    }
+    if place(entry, alloc.shard, TrackerInner::restore).is_some() {
+        TRACKER[alloc.shard].write().unfree();
+        live_added(alloc.size);
+    }
 }
 
 pub fn lookup(addr: usize) -> Option<(usize, AllocKind)> {
-    TRACKER.lock().lookup(addr)
+    #[cfg(feature = "thread-cache")]
+    if let Some(found) = thread_cache::with_own(addr, |e| (e.size, e.kind)) {
+        return Some(found);
+    }
+    or_flushed(|| read_entry(addr, |inner| inner.lookup(addr)))
+}
+
+/// Full tracker record of a live allocation.
+pub fn allocation(addr: usize) -> Option<Allocation> {
+    #[cfg(feature = "thread-cache")]
+    if let Some(found) = thread_cache::with_own(addr, |e| Allocation::of(e)) {
+        return Some(found);
+    }
+    or_flushed(|| read_entry(addr, |inner| inner.find(addr).map(Allocation::of)))
+}
+
+/// The live allocation whose user region contains `addr`, with its user
+/// address. Scans every shard, so only for error paths.
+pub fn find_containing(addr: usize) -> Option<(usize, Allocation)> {
+    let mut found = None;
+    for_each_live(|e| {
+        if found.is_none() && addr >= e.addr && addr - e.addr < e.size {
+            found = Some((e.addr, Allocation::of(e)));
+        }
+    });
+    found
+}
+
+/// `find_containing` that skips any shard a writer holds instead of
+/// waiting for it, so a block inserted or removed concurrently may be
+/// missed.
+pub fn try_find_containing(addr: usize) -> Option<(usize, Allocation)> {
+    try_find_entry(|e| addr >= e.addr && addr - e.addr < e.size)
+}
+
+/// The first live allocation whose base, size and alignment satisfy
+/// `pred`, with the same locking as `try_find_containing`.
+#[cfg(feature = "guard-pages")]
+pub fn try_find(pred: impl Fn(usize, usize, usize) -> bool) -> Option<(usize, Allocation)> {
+    try_find_entry(|e| pred(e.base, e.size, e.align))
+}
+
+fn try_find_entry(pred: impl Fn(&Entry) -> bool) -> Option<(usize, Allocation)> {
+    let found = TRACKER.iter().find_map(|shard| {
+        let inner = shard.try_read()?;
+        let mut found = None;
+        inner.for_each_live(|e| {
+            if found.is_none() && pred(e) {
+                found = Some((e.addr, Allocation::of(e)));
+            }
+        });
+        found
+    });
+    #[cfg(feature = "thread-cache")]
+    if found.is_none() {
+        return thread_cache::try_find(pred);
+    }
+    found
+}
+
+/// Record a new size for the live allocation at `addr`, moving it between
+/// histogram buckets. Returns `false` if `addr` is not tracked.
+pub fn update_size(addr: usize, size: usize) -> bool {
+    #[cfg(feature = "thread-cache")]
+    let cached = thread_cache::update_size(addr, size);
+    #[cfg(not(feature = "thread-cache"))]
+    let cached = None;
+    let Some(old) = cached.or_else(|| {
+        or_flushed(|| write_entry(addr, |inner| inner.update_size(addr, size))).map(|(_, old)| old)
+    }) else {
+        return false;
+    };
+    LIVE_HISTOGRAM[TrackerInner::bucket(old)].fetch_sub(1, Ordering::Relaxed);
+    LIVE_HISTOGRAM[TrackerInner::bucket(size)].fetch_add(1, Ordering::Relaxed);
+    LIVE_BYTES.fetch_sub(old, Ordering::Relaxed);
+    LIVE_BYTES.fetch_add(size, Ordering::Relaxed);
+    true
+}
+
+/// Label the live allocation at `addr`, replacing any earlier tag.
+/// Returns `false` if `addr` is not tracked.
+pub fn set_tag(addr: usize, tag: &'static str) -> bool {
+    #[cfg(feature = "thread-cache")]
+    if thread_cache::with_own(addr, |e| e.tag = Some(tag)).is_some() {
+        return true;
+    }
+    or_flushed(|| write_entry(addr, |inner| inner.set_tag(addr, tag))).is_some()
+}
+
+/// Empty every shard and zero the live, peak and cumulative counters.
+/// Blocks still allocated are forgotten, not freed.
+#[cfg(any(test, feature = "test-hooks"))]
+pub fn reset() {
+    #[cfg(feature = "thread-cache")]
+    thread_cache::clear();
+    for shard in &TRACKER {
+        shard.write().clear();
+    }
+    LIVE.store(0, Ordering::Relaxed);
+    LIVE_BYTES.store(0, Ordering::Relaxed);
+    PEAK_BYTES.store(0, Ordering::Relaxed);
+    for bucket in &LIVE_HISTOGRAM {
+        bucket.store(0, Ordering::Relaxed);
+    }
+    let mut peak = PEAK.lock();
+    peak.count = 0;
+    peak.histogram = [0; HISTOGRAM_BUCKETS];
+    PEAK_COUNT.store(0, Ordering::Relaxed);
+    SPILLED.store(false, Ordering::Relaxed);
+    FULL.store(false, Ordering::Relaxed);
+}
+
+/// Copy of the allocation size histogram accumulated so far.
+pub fn histogram() -> [u64; HISTOGRAM_BUCKETS] {
+    flush_caches();
+    let mut total = [0; HISTOGRAM_BUCKETS];
+    for shard in &TRACKER {
+        let guard = shard.read();
+        for (t, h) in total.iter_mut().zip(&guard.histogram) {
+            *t += h;
+        }
+    }
+    total
+}
+
+/// Read and reset the allocation size histogram, one shard at a time
+/// under its lock, so every allocation is counted in exactly one snapshot.
+pub fn take_histogram() -> [u64; HISTOGRAM_BUCKETS] {
+    flush_caches();
+    let mut total = [0; HISTOGRAM_BUCKETS];
+    for shard in &TRACKER {
+        let taken = core::mem::replace(&mut shard.write().histogram, [0; HISTOGRAM_BUCKETS]);
+        for (t, h) in total.iter_mut().zip(&taken) {
+            *t += h;
+        }
+    }
+    total
+}
+
+/// Current and cumulative allocation counters. Each shard is read under
+/// its lock, so its figures agree with each other; shards are visited in
+/// turn, as in `histogram`.
+pub fn stats() -> Stats {
+    flush_caches();
+    let mut stats = Stats::default();
+    for shard in &TRACKER {
+        let guard = shard.read();
+        stats.live_count += guard.count;
+        stats.live_bytes += guard.bytes;
+        stats.total_allocs += guard.allocs;
+        stats.total_frees += guard.frees;
+        for (total, shard) in stats.by_kind.iter_mut().zip(&guard.by_kind) {
+            total.live_count += shard.live_count;
+            total.total_allocs += shard.total_allocs;
+        }
+    }
+    stats.peak_bytes = PEAK_BYTES.load(Ordering::Relaxed).max(stats.live_bytes);
+    stats
+}
+
+/// Free every shard lock and the peak lock, e.g. in a `fork` child.
+///
+/// # Safety
+/// No other thread may be using the tracker.
+pub unsafe fn force_unlock() {
+    for shard in &TRACKER {
+        // SAFETY: Forwarded from the caller.
+        unsafe { shard.force_unlock() };
+    }
+    // SAFETY: Forwarded from the caller.
+    #[cfg(feature = "thread-cache")]
+    unsafe {
+        thread_cache::force_unlock()
+    };
+    // SAFETY: Forwarded from the caller.
+    unsafe { PEAK.force_unlock() };
+}
+
+/// Number of live allocations, from the running counter. Unlike `stats`
+/// this takes no lock.
+pub fn live_count() -> usize {
+    LIVE.load(Ordering::Relaxed)
+}
+
+/// Live user bytes, from the running counter.
+pub fn live_bytes() -> usize {
+    LIVE_BYTES.load(Ordering::Relaxed)
+}
+
+/// Highest number of simultaneously live allocations seen so far, and the
+/// live size histogram captured when that peak was reached.
+pub fn peak() -> Peak {
+    *PEAK.lock()
 }
 
 /// Report all live (unfreed) allocations. Called at program exit for leak detection.
 pub fn report_leaks() {
-    let guard = TRACKER.lock();
-    if guard.count == 0 {
+    let _report = crate::sanitize::diagnostic::Writer::open();
+    if VERIFY_ON_EXIT.load(Ordering::Relaxed) {
+        verify_heap();
+    }
+    let mut count = LIVE.load(Ordering::Relaxed);
+    // Copied out so the lock is not held while reporting.
+    let suppressed = *SUPPRESSED.lock();
+    let leaked = |e: &Entry| !suppressed.matches(&e.frames);
+    if suppressed.len != 0 {
+        count = 0;
+        for_each_live(|e| count += usize::from(leaked(e)));
+    }
+    if count == 0 {
         return;
     }
-    crate::sanitize::diagnostic::write_stderr(
-        b"\n\x1b[1;33m=== libcplusplus sanitizer: leak report ===\x1b[0m\n",
-    );
-    guard.for_each_live(|addr, size, kind| {
-        crate::sanitize::diagnostic::leak_detected(addr, size, kind);
+    if LEAK_JSONL.load(Ordering::Relaxed) {
+        let now = NEXT_SEQ.load(Ordering::Relaxed);
+        for_each_live(|e| {
+            if leaked(e) {
+                crate::sanitize::diagnostic::leak_json_line(
+                    e.addr,
+                    e.size,
+                    e.kind,
+                    e.tag,
+                    e.seq,
+                    now - e.seq,
+                );
+            }
+        });
+        return;
+    }
+    crate::sanitize::diagnostic::leak_report_header();
+    let mut by_kind = [0; AllocKind::COUNT];
+    let mut sites = [LeakSite::EMPTY; LEAK_SITES];
+    let mut used = 0;
+    for_each_live(|e| {
+        if !leaked(e) {
+            return;
+        }
+        by_kind[e.kind as usize] += 1;
+        if !e.frames.is_empty() && LeakSite::add(&mut sites, &mut used, e) {
+            return;
+        }
+        crate::sanitize::diagnostic::leak_detected(e.addr, e.size, e.kind, &e.frames);
     });
-    let mut dec_buf = [0u8; 20];
-    crate::sanitize::diagnostic::write_stderr(b"  total leaks: ");
-    crate::sanitize::diagnostic::write_stderr(crate::sanitize::diagnostic::format_dec(
-        guard.count,
-        &mut dec_buf,
-    ));
-    crate::sanitize::diagnostic::write_stderr(b"\n\n");
+    let sites = &mut sites[..used];
+    sites.sort_unstable_by_key(|s| core::cmp::Reverse(s.bytes));
+    for site in sites.iter() {
+        if site.count == 1 {
+            crate::sanitize::diagnostic::leak_detected(
+                site.first,
+                site.bytes,
+                site.kind,
+                &site.frames,
+            );
+        } else {
+            crate::sanitize::diagnostic::leak_site(
+                site.first,
+                site.kind,
+                site.count,
+                site.bytes,
+                &site.frames,
+            );
+        }
+    }
+    crate::sanitize::diagnostic::report_total(b"total leaks", count, b"\n");
+    crate::sanitize::diagnostic::report_kinds(&by_kind);
+    if let Some(status) = crate::sanitize::procfs::memory_status() {
+        crate::sanitize::diagnostic::process_memory(status);
+    }
+    crate::sanitize::diagnostic::report_end();
+}
+
+/// Most distinct allocation sites one leak report groups; leaks from
+/// further sites are listed one by one.
+const LEAK_SITES: usize = 128;
+
+/// Leaks from one allocation site (same call stack and family).
+#[derive(Clone, Copy)]
+struct LeakSite {
+    hash: u64,
+    kind: AllocKind,
+    /// One of the leaked blocks, shown as an example.
+    first: usize,
+    count: usize,
+    bytes: usize,
+    frames: Frames,
+}
+
+impl LeakSite {
+    const EMPTY: Self = Self {
+        hash: 0,
+        kind: AllocKind::Rust,
+        first: 0,
+        count: 0,
+        bytes: 0,
+        frames: Frames::EMPTY,
+    };
+
+    /// Count `e` under its site in `sites[..*used]`, adding the site if
+    /// there is room. Returns `false` when it had to be left out.
+    fn add(sites: &mut [Self], used: &mut usize, e: &Entry) -> bool {
+        let hash = e.frames.site_hash();
+        let found = sites[..*used]
+            .iter()
+            .position(|s| s.hash == hash && s.kind == e.kind && s.frames.same_site(&e.frames));
+        let i = match found {
+            Some(i) => i,
+            None if *used < sites.len() => {
+                sites[*used] = Self {
+                    hash,
+                    kind: e.kind,
+                    first: e.addr,
+                    frames: e.frames,
+                    ..Self::EMPTY
+                };
+                *used += 1;
+                *used - 1
+            }
+            None => return false,
+        };
+        sites[i].count += 1;
+        sites[i].bytes += e.size;
+        true
+    }
+}
+
+/// Open a leak scope: allocations made from now on are tagged with it.
+/// Scopes do not nest; entering while a scope is open is a no-op.
+pub fn enter_scope() {
+    if SCOPE.get().is_multiple_of(2) {
+        SCOPE.bump();
+    }
+}
+
+/// Close the current scope and report every allocation made inside it
+/// that is still live. Returns the number of scope leaks.
+pub fn leave_scope_and_report() -> usize {
+    let scope = SCOPE.get();
+    if scope.is_multiple_of(2) {
+        return 0;
+    }
+    SCOPE.bump();
+
+    let mut leaks = 0;
+    let _report = crate::sanitize::diagnostic::Writer::open();
+    for_each_live(|e| {
+        if e.scope == scope {
+            if leaks == 0 {
+                crate::sanitize::diagnostic::scope_leak_header();
+            }
+            crate::sanitize::diagnostic::leak_detected(e.addr, e.size, e.kind, &e.frames);
+            leaks += 1;
+        }
+    });
+    if leaks != 0 {
+        crate::sanitize::diagnostic::report_total(b"scope leaks", leaks, b"\n\n");
+    }
+    leaks
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use std::vec;
+
+    /// Fill the table to half its capacity with addresses `stride` apart,
+    /// as a run of same-sized mallocs would produce, and check where they
+    /// land.
+    fn check_distribution(stride: usize) {
+        let mut occupied = vec![[false; SHARD_CAPACITY]; SHARDS];
+        let mut homes = vec![false; SHARD_CAPACITY];
+        let mut per_shard = [0usize; SHARDS];
+        let mut longest = 0;
+        for i in 0..CAPACITY / 2 {
+            let addr = 0x7f3a_2c00_0000 + i * stride;
+            let shard = hash(addr) >> SHARD_SHIFT;
+            let home = TrackerInner::slot(addr);
+            assert!(home < SHARD_CAPACITY, "stride {stride}: slot {home}");
+            homes[home] = true;
+            per_shard[shard] += 1;
+            let (mut idx, mut probes) = (home, 0);
+            while occupied[shard][idx] {
+                idx = (idx + 1) % SHARD_CAPACITY;
+                probes += 1;
+            }
+            occupied[shard][idx] = true;
+            longest = longest.max(probes);
+        }
+        assert!(
+            homes.iter().all(|&hit| hit),
+            "stride {stride}: unused home slots"
+        );
+        let mean = CAPACITY / 2 / SHARDS;
+        assert!(
+            per_shard.iter().all(|&n| n > mean / 2 && n < mean * 2),
+            "stride {stride}: shards {per_shard:?}"
+        );
+        assert!(longest < 32, "stride {stride}: probe length {longest}");
+    }
+
+    /// Leak a block allocated from a frame of its own, returning its address
+    /// and this function's entry point.
+    #[cfg(feature = "backtrace")]
+    #[inline(never)]
+    fn leak_from_init() -> (usize, usize) {
+        let layout = Layout::from_size_align(40, 8).unwrap();
+        // SAFETY: Leaked on purpose.
+        let ptr = unsafe { crate::sanitize::sanitized_alloc(layout) };
+        (ptr as usize, leak_from_init as *const () as usize)
+    }
+
+    #[cfg(feature = "backtrace")]
+    #[inline(never)]
+    fn leak_from_elsewhere() -> usize {
+        let layout = Layout::from_size_align(24, 8).unwrap();
+        // SAFETY: Leaked on purpose.
+        unsafe { crate::sanitize::sanitized_alloc(layout) as usize }
+    }
+
+    #[cfg(feature = "backtrace")]
+    #[test]
+    fn suppressed_call_site_is_left_out_of_the_leak_report() {
+        const TEST: &str =
+            "sanitize::tracker::tests::suppressed_call_site_is_left_out_of_the_leak_report";
+        if crate::testing::in_child(TEST) {
+            let (quiet, entry) = leak_from_init();
+            let loud = leak_from_elsewhere();
+            // From the function's entry to the return address inside it:
+            // code of no other function can fall in between.
+            let frames = allocation(quiet).unwrap().frames;
+            let ret = frames.iter().filter(|&a| a > entry).min().unwrap();
+            assert!(suppress_leaks_from(entry..ret + 1));
+            std::eprintln!("quiet={quiet:#018x} loud={loud:#018x}");
+            report_leaks();
+            return;
+        }
+        let child = crate::testing::spawn(TEST, &[]);
+        let stderr = child.stderr();
+        assert_eq!(child.code(), Some(0), "{stderr}");
+        let (quiet, loud) = stderr
+            .lines()
+            .find_map(|l| l.strip_prefix("quiet="))
+            .and_then(|l| l.split_once(" loud="))
+            .expect("child printed its addresses");
+        let report = &stderr[stderr.find(" loud=").unwrap()..];
+        assert!(!report.contains(quiet), "{stderr}");
+        assert!(report.contains(loud), "{stderr}");
+        // With `c-alloc` the test binary's own mallocs leak too, so the
+        // total is only known without it.
+        if !cfg!(feature = "c-alloc") {
+            let total = if cfg!(feature = "minimal-diagnostics") {
+                "SAN 7 "
+            } else {
+                "total leaks: 1"
+            };
+            assert_eq!(report.matches(total).count(), 1, "{stderr}");
+        }
+    }
+
+    /// The members of a flat JSON object, checking that every key is a
+    /// string and every value a string, a number or `null`.
+    fn json_members(line: &str) -> vec::Vec<(&str, &str)> {
+        let body = line
+            .strip_prefix('{')
+            .and_then(|l| l.strip_suffix('}'))
+            .unwrap_or_else(|| panic!("not an object: {line}"));
+        body.split(',')
+            .map(|member| {
+                let (key, value) = member.split_once(':').expect(line);
+                let key = key.strip_prefix('"').and_then(|k| k.strip_suffix('"'));
+                let quoted = value.len() >= 2 && value.starts_with('"') && value.ends_with('"');
+                assert!(
+                    quoted || value == "null" || value.parse::<u64>().is_ok(),
+                    "bad value in {line}"
+                );
+                (key.expect(line), value.trim_matches('"'))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn repeated_frees_leave_the_block_out_of_the_leak_report() {
+        const TEST: &str =
+            "sanitize::tracker::tests::repeated_frees_leave_the_block_out_of_the_leak_report";
+        if crate::testing::in_child(TEST) {
+            crate::sanitize::set_halt_on_error(false);
+            let layout = Layout::from_size_align(48, 8).unwrap();
+            // SAFETY: The later frees are the errors under test; recoverable
+            // mode reports them without touching the block again.
+            unsafe {
+                let ptr = crate::sanitize::sanitized_alloc(layout);
+                std::eprintln!("freed={:#018x}", ptr as usize);
+                crate::sanitize::sanitized_dealloc(ptr, layout);
+                crate::sanitize::sanitized_dealloc(ptr, layout);
+                crate::sanitize::quarantine::drain();
+                crate::sanitize::sanitized_dealloc(ptr, layout);
+                assert_eq!(lookup(ptr as usize), None);
+                assert_eq!(remove(ptr as usize), None);
+            }
+            assert_eq!(crate::sanitize::diagnostic::error_count(), 2);
+            std::eprintln!("--- leaks");
+            report_leaks();
+            return;
+        }
+        let child = crate::testing::spawn(TEST, &[]);
+        let stderr = child.stderr();
+        assert_eq!(child.code(), Some(0), "{stderr}");
+        let freed = stderr
+            .lines()
+            .find_map(|l| l.strip_prefix("freed="))
+            .expect(&stderr);
+        let (_, leaks) = stderr.split_once("--- leaks\n").expect(&stderr);
+        assert!(!leaks.contains(freed), "{stderr}");
+        // With `c-alloc` the test binary's own mallocs leak too.
+        if !cfg!(feature = "c-alloc") {
+            assert_eq!(leaks, "", "{stderr}");
+        }
+    }
+
+    #[test]
+    fn jsonl_leak_report_is_one_object_per_leak() {
+        const TEST: &str = "sanitize::tracker::tests::jsonl_leak_report_is_one_object_per_leak";
+        if crate::testing::in_child(TEST) {
+            for size in [24, 48, 72] {
+                let layout = Layout::from_size_align(size, 8).unwrap();
+                // SAFETY: Leaked on purpose.
+                let ptr = unsafe { crate::sanitize::sanitized_alloc(layout) };
+                if size == 48 {
+                    crate::sanitize::tag_allocation(ptr, "io\\\"buf\"\n");
+                }
+                std::eprintln!("leaked={ptr:p}");
+            }
+            set_leak_jsonl(true);
+            report_leaks();
+            return;
+        }
+        let child = crate::testing::spawn(TEST, &[]);
+        let stderr = child.stderr();
+        assert_eq!(child.code(), Some(0), "{stderr}");
+        let leaked: vec::Vec<usize> = stderr
+            .lines()
+            .filter_map(|l| l.strip_prefix("leaked=0x"))
+            .map(|a| usize::from_str_radix(a, 16).unwrap())
+            .collect();
+        assert_eq!(leaked.len(), 3, "{stderr}");
+        let ours = |addr: &str| {
+            let addr = addr.strip_prefix("0x").expect(addr);
+            leaked.contains(&usize::from_str_radix(addr, 16).unwrap())
+        };
+        let report = stderr.lines().filter(|l| !l.starts_with("leaked="));
+        if cfg!(feature = "minimal-diagnostics") {
+            let lines = report.filter_map(|l| l.strip_prefix("SAN 7 "));
+            assert_eq!(lines.filter(|a| ours(a)).count(), 3, "{stderr}");
+            return;
+        }
+        let mut seen = 0;
+        for line in report.filter(|l| l.starts_with('{')) {
+            let members = json_members(line);
+            let keys: vec::Vec<&str> = members.iter().map(|m| m.0).collect();
+            assert_eq!(
+                keys,
+                ["addr", "size", "kind", "tag", "seq", "age"],
+                "{line}"
+            );
+            if ours(members[0].1) {
+                assert!(["24", "48", "72"].contains(&members[1].1), "{line}");
+                assert_eq!(members[2].1, "rust alloc", "{line}");
+                let tag = if members[1].1 == "48" {
+                    r#"io\\\"buf\"\u000a"#
+                } else {
+                    "null"
+                };
+                assert_eq!(members[3].1, tag, "{line}");
+                seen += 1;
+            }
+        }
+        assert_eq!(seen, 3, "{stderr}");
+    }
+
+    #[test]
+    fn scope_report_lists_only_blocks_left_from_the_scope() {
+        const TEST: &str =
+            "sanitize::tracker::tests::scope_report_lists_only_blocks_left_from_the_scope";
+        if crate::testing::in_child(TEST) {
+            let layout = Layout::from_size_align(96, 8).unwrap();
+            // SAFETY: `before` and `kept` are leaked on purpose, `freed` is
+            // freed once.
+            unsafe {
+                let before = crate::sanitize::sanitized_alloc(layout);
+                enter_scope();
+                let kept = crate::sanitize::sanitized_alloc(layout);
+                let freed = crate::sanitize::sanitized_alloc(layout);
+                crate::sanitize::sanitized_dealloc(freed, layout);
+                std::eprintln!(
+                    "before={:#018x} kept={:#018x} freed={:#018x}",
+                    before as usize,
+                    kept as usize,
+                    freed as usize
+                );
+                let leaks = leave_scope_and_report();
+                assert!(leaks >= 1);
+                if !cfg!(feature = "c-alloc") {
+                    assert_eq!(leaks, 1);
+                }
+                // Outside any scope now: nothing more to report.
+                assert_eq!(leave_scope_and_report(), 0);
+            }
+            return;
+        }
+        let child = crate::testing::spawn(TEST, &[]);
+        let stderr = child.stderr();
+        assert_eq!(child.code(), Some(0), "{stderr}");
+        let (addrs, report) = stderr.split_once('\n').unwrap();
+        let addr = |name: &str| {
+            let start = addrs.find(&std::format!("{name}=")).unwrap() + name.len() + 1;
+            &addrs[start..start + 18]
+        };
+        assert!(report.contains(addr("kept")), "{stderr}");
+        assert!(!report.contains(addr("before")), "{stderr}");
+        assert!(!report.contains(addr("freed")), "{stderr}");
+        if !cfg!(feature = "minimal-diagnostics") {
+            assert!(report.contains("scope leak report"), "{stderr}");
+            let total = if cfg!(feature = "c-alloc") {
+                "scope leaks: "
+            } else {
+                "scope leaks: 1\n"
+            };
+            assert!(report.contains(total), "{stderr}");
+        }
+    }
+
+    #[cfg(feature = "cache-aligned")]
+    #[test]
+    fn hot_statics_are_cache_aligned() {
+        let shards = TRACKER.iter().map(|s| s as *const _ as usize);
+        let counters = [&raw const NEXT_SEQ as usize, &raw const LIVE as usize];
+        for addr in shards.chain(counters) {
+            assert!(addr.is_multiple_of(64), "{addr:#x}");
+        }
+    }
+
+    #[test]
+    fn peak_keeps_the_worst_moment() {
+        const TEST: &str = "sanitize::tracker::tests::peak_keeps_the_worst_moment";
+        if crate::testing::in_child(TEST) {
+            let (small, large) = (
+                Layout::from_size_align(100, 8).unwrap(),
+                Layout::from_size_align(5000, 8).unwrap(),
+            );
+            let live_at_start = live_count();
+            // SAFETY: Every block is freed once, with its own layout.
+            unsafe {
+                let mut blocks = vec::Vec::new();
+                for layout in [small; 5].into_iter().chain([large; 3]) {
+                    blocks.push((crate::sanitize::sanitized_alloc(layout), layout));
+                }
+                for (ptr, layout) in blocks.drain(..) {
+                    crate::sanitize::sanitized_dealloc(ptr, layout);
+                }
+                for _ in 0..2 {
+                    blocks.push((crate::sanitize::sanitized_alloc(small), small));
+                }
+                let peak = peak();
+                // With c-alloc the Vec's own buffer is tracked too.
+                if !cfg!(feature = "c-alloc") {
+                    assert_eq!(live_count(), live_at_start + 2);
+                    assert_eq!(peak.count, live_at_start + 8);
+                    assert_eq!(peak.histogram[TrackerInner::bucket(100)], 5);
+                    assert_eq!(peak.histogram[TrackerInner::bucket(5000)], 3);
+                }
+                for (ptr, layout) in blocks {
+                    crate::sanitize::sanitized_dealloc(ptr, layout);
+                }
+            }
+            return;
+        }
+        let child = crate::testing::spawn(TEST, &[]);
+        assert_eq!(child.code(), Some(0), "{}", child.stderr());
+    }
+
+    #[test]
+    fn take_histogram_holds_only_its_own_phase() {
+        const TEST: &str = "sanitize::tracker::tests::take_histogram_holds_only_its_own_phase";
+        if crate::testing::in_child(TEST) {
+            let phase = |size: usize, count: usize| {
+                let layout = Layout::from_size_align(size, 8).unwrap();
+                for _ in 0..count {
+                    // SAFETY: Freed straight away, with its own layout.
+                    unsafe {
+                        let ptr = crate::sanitize::sanitized_alloc(layout);
+                        crate::sanitize::sanitized_dealloc(ptr, layout);
+                    }
+                }
+            };
+            take_histogram();
+            phase(100, 3);
+            let first = take_histogram();
+            phase(5000, 2);
+            let second = take_histogram();
+            let (small, large) = (TrackerInner::bucket(100), TrackerInner::bucket(5000));
+            assert_eq!((first[small], first[large]), (3, 0));
+            assert_eq!((second[small], second[large]), (0, 2));
+            assert_eq!(histogram(), [0; HISTOGRAM_BUCKETS]);
+            return;
+        }
+        let child = crate::testing::spawn(TEST, &[]);
+        assert_eq!(child.code(), Some(0), "{}", child.stderr());
+    }
+
+    #[test]
+    fn hash_spreads_over_the_full_index_range() {
+        for stride in [16, 48, 64, 4096, 1 << 16] {
+            check_distribution(stride);
+        }
+    }
+
+    /// Fake, never dereferenced user addresses whose home is shard 0.
+    fn homed_in_first_shard(count: usize) -> vec::Vec<usize> {
+        (1..)
+            .map(|i| 0x1000_0000 + i * 16)
+            .filter(|&addr| home(addr) == 0)
+            .take(count)
+            .collect()
+    }
+
+    /// Live entries with a user address in `range`. With `c-alloc` the
+    /// test binary's own mallocs are tracked too, so tests count only the
+    /// fake addresses they inserted.
+    fn live_in(range: core::ops::Range<usize>) -> usize {
+        let mut count = 0;
+        for_each_live(|e| count += usize::from(range.contains(&e.addr)));
+        count
+    }
+
+    #[test]
+    #[cfg_attr(
+        feature = "preload",
+        ignore = "libtest's threads allocate through the exported malloc while the table is full"
+    )]
+    fn full_shard_spills_into_the_next_one() {
+        const TEST: &str = "sanitize::tracker::tests::full_shard_spills_into_the_next_one";
+        if crate::testing::in_child(TEST) {
+            let layout = Layout::from_size_align(32, 16).unwrap();
+            let addrs = homed_in_first_shard(SHARD_CAPACITY + 8);
+            for &addr in &addrs {
+                insert(addr, addr, layout, AllocKind::Malloc);
+            }
+            assert!(addrs.iter().all(|&addr| lookup(addr).is_some()));
+
+            // A spilled entry survives a take and restore, and the
+            // restore leaves the counters as they were.
+            let spilled = addrs[SHARD_CAPACITY + 3];
+            let before = stats();
+            let alloc = take(spilled).unwrap();
+            restore(spilled, &alloc);
+            assert_eq!(stats(), before);
+            assert_eq!(live_in(0x1000_0000..0x2000_0000), addrs.len());
+
+            for &addr in &addrs {
+                assert!(remove(addr).is_some());
+            }
+            assert_eq!(live_in(0x1000_0000..0x2000_0000), 0);
+
+            // Past the whole table, blocks go untracked with one note.
+            // Nothing may allocate until the fake entries are gone again.
+            let fake = |i: usize| 0x2000_0000 + i * 16;
+            for i in 0..CAPACITY + 2 {
+                insert(fake(i), 0, layout, AllocKind::Malloc);
+            }
+            let tracked = live_in(fake(0)..fake(CAPACITY + 2));
+            for i in 0..CAPACITY + 2 {
+                remove(fake(i));
+            }
+            assert!(tracked <= CAPACITY, "{tracked}");
+            return;
+        }
+        let child = crate::testing::spawn(TEST, &[]);
+        let stderr = child.stderr();
+        assert_eq!(child.code(), Some(0), "{stderr}");
+        assert_eq!(
+            stderr.matches("allocation tracker full").count(),
+            1,
+            "{stderr}"
+        );
+    }
+
+    /// Eight threads each inserting and removing blocks, a few live at a
+    /// time. Run with `--ignored --nocapture` with and without
+    /// `thread-cache` to compare; the time is printed rather than asserted,
+    /// as it depends on the machine.
+    #[test]
+    #[ignore = "timing benchmark"]
+    fn eight_threads_churning_blocks() {
+        const THREADS: usize = 8;
+        const ROUNDS: usize = 200_000;
+        const LIVE_PER_THREAD: usize = 8;
+        let layout = Layout::from_size_align(48, 16).unwrap();
+        let start = std::time::Instant::now();
+        let threads: vec::Vec<_> = (0..THREADS)
+            .map(|t| {
+                std::thread::spawn(move || {
+                    let addr = |i: usize| 0x6000_0000 + (t * LIVE_PER_THREAD + i) * 64;
+                    for i in 0..ROUNDS {
+                        let slot = addr(i % LIVE_PER_THREAD);
+                        if i >= LIVE_PER_THREAD {
+                            assert!(remove(slot).is_some());
+                        }
+                        insert(slot, slot, layout, AllocKind::Malloc);
+                    }
+                    for i in 0..LIVE_PER_THREAD {
+                        assert!(remove(addr(i)).is_some());
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        std::eprintln!(
+            "thread-cache={}: {:?}",
+            cfg!(feature = "thread-cache"),
+            start.elapsed()
+        );
+    }
+
+    #[test]
+    fn concurrent_inserts_and_removes_keep_the_counters_exact() {
+        const TEST: &str =
+            "sanitize::tracker::tests::concurrent_inserts_and_removes_keep_the_counters_exact";
+        if crate::testing::in_child(TEST) {
+            const THREADS: usize = 8;
+            const ROUNDS: usize = 20_000;
+            const LIVE_PER_THREAD: usize = 64;
+            let layout = Layout::from_size_align(48, 16).unwrap();
+            let threads: vec::Vec<_> = (0..THREADS)
+                .map(|t| {
+                    std::thread::spawn(move || {
+                        let addr = |i: usize| 0x4000_0000 + (t * ROUNDS + i) * 64;
+                        for i in 0..ROUNDS {
+                            insert(addr(i), addr(i), layout, AllocKind::ScalarNew);
+                            if i % 7 == 0 {
+                                let alloc = take(addr(i)).unwrap();
+                                restore(addr(i), &alloc);
+                            }
+                            if i >= LIVE_PER_THREAD {
+                                let old = addr(i - LIVE_PER_THREAD);
+                                assert_eq!(remove(old), Some((48, AllocKind::ScalarNew)));
+                            }
+                        }
+                    })
+                })
+                .collect();
+            for thread in threads {
+                thread.join().unwrap();
+            }
+            let fake = 0x4000_0000..0x4000_0000 + THREADS * ROUNDS * 64;
+            assert_eq!(live_in(fake.clone()), THREADS * LIVE_PER_THREAD);
+            let stats = stats();
+            assert_eq!(live_count(), stats.live_count);
+            assert_eq!(live_bytes(), stats.live_bytes);
+            if !cfg!(feature = "c-alloc") {
+                assert_eq!(stats.live_count, THREADS * LIVE_PER_THREAD);
+                assert_eq!(stats.total_allocs, (THREADS * ROUNDS) as u64);
+                assert_eq!(
+                    stats.total_frees,
+                    (THREADS * (ROUNDS - LIVE_PER_THREAD)) as u64
+                );
+            }
+            return;
+        }
+        let child = crate::testing::spawn(TEST, &[]);
+        assert_eq!(child.code(), Some(0), "{}", child.stderr());
+    }
 }
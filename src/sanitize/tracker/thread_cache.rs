@@ -0,0 +1,430 @@
+//! Per-thread batches of tracker insertions (`thread-cache` feature).
+//!
+//! Each allocating thread claims a slot that holds up to `BATCH` entries
+//! not yet in the shards. Inserting one takes only the slot's own lock,
+//! which other threads rarely want. A full slot is written to the shards
+//! in one pass that locks each shard once. A block its own thread frees
+//! before then never reaches the shards at all: its allocation and free
+//! are only counted, and the counts join a shard with the next flush.
+//!
+//! The shards cannot see what a slot holds, so a lookup, free or walk that
+//! misses in them flushes every slot and tries again. That covers blocks
+//! freed on another thread, at the price of a sweep for every pointer the
+//! tracker never saw. A pthread key finds the calling thread's slot; its
+//! destructor flushes and frees the slot when the thread exits. The
+//! SIGSEGV handler's search cannot flush; it reads the slots in place and
+//! skips any whose lock is held, as it does shards.
+
+use super::{
+    AllocKind, Allocation, Entry, HISTOGRAM_BUCKETS, SHARDS, TRACKER, TrackerInner, home,
+    live_removed, place,
+};
+use crate::platform::cache::CacheAligned;
+use crate::platform::spinlock::SpinLock;
+use core::ffi::{c_uint, c_void};
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
+
+/// Threads that can hold a slot at once; the rest insert directly.
+const SLOTS: usize = 64;
+
+/// Entries a slot holds before it is flushed.
+const BATCH: usize = 32;
+
+const _: () = assert!(BATCH <= u64::BITS as usize);
+
+/// Shard counters of blocks allocated and freed again while still in a
+/// slot. Kept with wrapping arithmetic: `unsettle` can take a block back
+/// after the slot was flushed, leaving a count "negative" until it is
+/// added to a shard.
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Settled {
+    blocks: u64,
+    by_kind: [u64; AllocKind::COUNT],
+    histogram: [u64; HISTOGRAM_BUCKETS],
+}
+
+impl Settled {
+    const ZERO: Self = Self {
+        blocks: 0,
+        by_kind: [0; AllocKind::COUNT],
+        histogram: [0; HISTOGRAM_BUCKETS],
+    };
+
+    /// Count `e` as allocated and freed, or undo that with `delta == -1`.
+    fn add(&mut self, e: &Entry, delta: i64) {
+        let delta = delta as u64;
+        self.blocks = self.blocks.wrapping_add(delta);
+        let kind = &mut self.by_kind[e.kind as usize];
+        *kind = kind.wrapping_add(delta);
+        let bucket = &mut self.histogram[TrackerInner::bucket(e.size)];
+        *bucket = bucket.wrapping_add(delta);
+    }
+}
+
+struct Slot {
+    entries: [Entry; BATCH],
+    len: usize,
+    settled: Settled,
+}
+
+impl Slot {
+    fn position(&self, addr: usize) -> Option<usize> {
+        self.entries[..self.len].iter().position(|e| e.addr == addr)
+    }
+}
+
+struct Cache {
+    /// Set while a thread owns the slot.
+    claimed: AtomicBool,
+    slot: SpinLock<Slot>,
+}
+
+static CACHES: [CacheAligned<Cache>; SLOTS] = [const {
+    CacheAligned::new(Cache {
+        claimed: AtomicBool::new(false),
+        slot: SpinLock::new(Slot {
+            entries: [Entry::EMPTY; BATCH],
+            len: 0,
+            settled: Settled::ZERO,
+        }),
+    })
+}; SLOTS];
+
+unsafe extern "C" {
+    fn pthread_key_create(
+        key: *mut c_uint,
+        destructor: Option<unsafe extern "C" fn(*mut c_void)>,
+    ) -> i32;
+    fn pthread_getspecific(key: c_uint) -> *mut c_void;
+    fn pthread_setspecific(key: c_uint, value: *const c_void) -> i32;
+}
+
+/// Key state: `UNSET` until first used, then `READY` with `KEY` holding
+/// it, or `FAILED` for good. `CREATING` while one thread creates it.
+static KEY_STATE: AtomicU8 = AtomicU8::new(UNSET);
+const UNSET: u8 = 0;
+const CREATING: u8 = 1;
+const READY: u8 = 2;
+const FAILED: u8 = 3;
+static KEY: AtomicUsize = AtomicUsize::new(0);
+
+/// Set while a thread claims a slot; see `claim`.
+static CLAIMING: AtomicBool = AtomicBool::new(false);
+
+/// The pthread key naming each thread's slot. Threads that race the one
+/// creating it get `None` and insert directly meanwhile.
+fn key() -> Option<c_uint> {
+    match KEY_STATE.load(Ordering::Acquire) {
+        READY => return Some(KEY.load(Ordering::Relaxed) as c_uint),
+        UNSET => {}
+        _ => return None,
+    }
+    if KEY_STATE
+        .compare_exchange(UNSET, CREATING, Ordering::Acquire, Ordering::Relaxed)
+        .is_err()
+    {
+        return None;
+    }
+    let mut key = 0;
+    // SAFETY: `release` accepts every value `claim` stores under the key.
+    let created = unsafe { pthread_key_create(&mut key, Some(release)) } == 0;
+    KEY.store(key as usize, Ordering::Relaxed);
+    KEY_STATE.store(if created { READY } else { FAILED }, Ordering::Release);
+    created.then_some(key)
+}
+
+/// Index of the calling thread's slot, if it has claimed one.
+fn current() -> Option<usize> {
+    if KEY_STATE.load(Ordering::Acquire) != READY {
+        return None;
+    }
+    // SAFETY: The key was created; getspecific has no other precondition.
+    let value = unsafe { pthread_getspecific(KEY.load(Ordering::Relaxed) as c_uint) };
+    (value as usize).checked_sub(1)
+}
+
+/// `current`, claiming a free slot on the thread's first insertion.
+/// `pthread_setspecific` may allocate, and so come back here, so only one
+/// claim runs at a time; insertions during it go straight to the shards.
+fn claim() -> Option<usize> {
+    if let Some(index) = current() {
+        return Some(index);
+    }
+    let key = key()?;
+    if CLAIMING.swap(true, Ordering::Acquire) {
+        return None;
+    }
+    let index = CACHES.iter().position(|cache| {
+        !cache.claimed.load(Ordering::Relaxed)
+            && cache
+                .claimed
+                .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+    });
+    let index = index.filter(|&index| {
+        // SAFETY: The key was created; `release` reads the value back.
+        let stored = unsafe { pthread_setspecific(key, (index + 1) as *const c_void) } == 0;
+        if !stored {
+            CACHES[index].claimed.store(false, Ordering::Release);
+        }
+        stored
+    });
+    CLAIMING.store(false, Ordering::Release);
+    index
+}
+
+/// Key destructor: flush the exiting thread's slot and give it up.
+unsafe extern "C" fn release(value: *mut c_void) {
+    let index = value as usize - 1;
+    flush(&mut CACHES[index].slot.lock(), index);
+    CACHES[index].claimed.store(false, Ordering::Release);
+}
+
+/// Write every entry in `slot` to its home shard, locking each shard once,
+/// and add the settled counts to one shard.
+fn flush(slot: &mut Slot, index: usize) {
+    let pending = &slot.entries[..slot.len];
+    let mut placed = 0u64;
+    for (shard, lock) in TRACKER.iter().enumerate() {
+        let mut inner = None;
+        for (i, e) in pending.iter().enumerate() {
+            if home(e.addr) == shard {
+                let inner = inner.get_or_insert_with(|| lock.write());
+                if inner.insert(*e) {
+                    placed |= 1 << i;
+                }
+            }
+        }
+    }
+    // A full home shard: spill to the next one, as a direct insert would.
+    for (i, e) in pending.iter().enumerate() {
+        if placed & 1 << i == 0 && place(*e, home(e.addr), TrackerInner::insert).is_none() {
+            live_removed(e.size);
+        }
+    }
+    slot.len = 0;
+    if slot.settled != Settled::ZERO {
+        let settled = core::mem::replace(&mut slot.settled, Settled::ZERO);
+        let mut inner = TRACKER[index % SHARDS].write();
+        inner.allocs = inner.allocs.wrapping_add(settled.blocks);
+        inner.frees = inner.frees.wrapping_add(settled.blocks);
+        for (kind, n) in inner.by_kind.iter_mut().zip(&settled.by_kind) {
+            kind.total_allocs = kind.total_allocs.wrapping_add(*n);
+        }
+        for (bucket, n) in inner.histogram.iter_mut().zip(&settled.histogram) {
+            *bucket = bucket.wrapping_add(*n);
+        }
+    }
+}
+
+/// Hold `entry` in the calling thread's slot, flushing the slot first if
+/// it is full. Returns `false` if the thread has no slot.
+pub(super) fn push(entry: Entry) -> bool {
+    let Some(index) = claim() else {
+        return false;
+    };
+    let mut slot = CACHES[index].slot.lock();
+    if slot.len == BATCH {
+        flush(&mut slot, index);
+    }
+    let len = slot.len;
+    slot.entries[len] = entry;
+    slot.len += 1;
+    true
+}
+
+/// Run `f` on the calling thread's pending entry for `addr`, if any.
+pub(super) fn with_own<T>(addr: usize, f: impl FnOnce(&mut Entry) -> T) -> Option<T> {
+    let mut slot = CACHES[current()?].slot.lock();
+    let i = slot.position(addr)?;
+    Some(f(&mut slot.entries[i]))
+}
+
+/// Remove `addr` from the calling thread's slot, counting the block as
+/// allocated and freed. The record names the slot as its shard, past the
+/// real ones, for `restore`.
+pub(super) fn take(addr: usize) -> Option<Allocation> {
+    let index = current()?;
+    let mut slot = CACHES[index].slot.lock();
+    let i = slot.position(addr)?;
+    let e = slot.entries[i];
+    slot.len -= 1;
+    slot.entries[i] = slot.entries[slot.len];
+    slot.settled.add(&e, 1);
+    Some(Allocation {
+        shard: SHARDS + index,
+        ..Allocation::of(&e)
+    })
+}
+
+/// Undo the free `take` counted for `e` in slot `index`.
+pub(super) fn unsettle(index: usize, e: &Entry) {
+    CACHES[index].slot.lock().settled.add(e, -1);
+}
+
+/// `update_size` for a pending entry. The allocation histogram counts the
+/// size a block was allocated with, which the flush would miss, so it is
+/// moved back here. Returns the old size.
+pub(super) fn update_size(addr: usize, size: usize) -> Option<usize> {
+    let mut slot = CACHES[current()?].slot.lock();
+    let i = slot.position(addr)?;
+    let old = core::mem::replace(&mut slot.entries[i].size, size);
+    let histogram = &mut slot.settled.histogram;
+    histogram[TrackerInner::bucket(old)] = histogram[TrackerInner::bucket(old)].wrapping_add(1);
+    histogram[TrackerInner::bucket(size)] = histogram[TrackerInner::bucket(size)].wrapping_sub(1);
+    Some(old)
+}
+
+/// The first pending entry matching `pred`, skipping any slot whose lock
+/// is held instead of waiting for it.
+pub(super) fn try_find(pred: impl Fn(&Entry) -> bool) -> Option<(usize, Allocation)> {
+    CACHES.iter().find_map(|cache| {
+        let slot = cache.slot.try_lock()?;
+        let e = slot.entries[..slot.len].iter().find(|e| pred(e))?;
+        Some((e.addr, Allocation::of(e)))
+    })
+}
+
+/// Flush every claimed slot. Returns whether any entry moved.
+pub(super) fn flush_all() -> bool {
+    let mut moved = false;
+    for (index, cache) in CACHES.iter().enumerate() {
+        if cache.claimed.load(Ordering::Acquire) {
+            let mut slot = cache.slot.lock();
+            moved |= slot.len != 0;
+            flush(&mut slot, index);
+        }
+    }
+    moved
+}
+
+/// Drop every pending entry and settled count.
+#[cfg(any(test, feature = "test-hooks"))]
+pub(super) fn clear() {
+    for cache in &CACHES {
+        let mut slot = cache.slot.lock();
+        slot.len = 0;
+        slot.settled = Settled::ZERO;
+    }
+}
+
+/// Free every slot lock, e.g. in a `fork` child.
+///
+/// # Safety
+/// No other thread may be using the caches.
+pub(super) unsafe fn force_unlock() {
+    for cache in &CACHES {
+        // SAFETY: Forwarded from the caller.
+        unsafe { cache.slot.force_unlock() };
+    }
+    CLAIMING.store(false, Ordering::Relaxed);
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::super::{insert, lookup, read_entry, remove, restore, stats, take};
+    use super::*;
+    use core::alloc::Layout;
+    use std::sync::mpsc;
+
+    /// Whether `addr` is in a shard, without flushing any cache.
+    fn in_shards(addr: usize) -> bool {
+        read_entry(addr, |inner| inner.lookup(addr)).is_some()
+    }
+
+    #[test]
+    fn exiting_thread_flushes_its_slot() {
+        const TEST: &str =
+            "sanitize::tracker::thread_cache::tests::exiting_thread_flushes_its_slot";
+        if crate::testing::in_child(TEST) {
+            let layout = Layout::from_size_align(48, 16).unwrap();
+            let addrs = [0x5000_0000, 0x5000_0040, 0x5000_0080];
+            let index = std::thread::spawn(move || {
+                for addr in addrs {
+                    insert(addr, addr, layout, AllocKind::Malloc);
+                }
+                assert!(addrs.iter().all(|&addr| !in_shards(addr)));
+                assert!(addrs.iter().all(|&addr| lookup(addr).is_some()));
+                current().expect("claimed a slot")
+            })
+            .join()
+            .unwrap();
+            assert!(addrs.iter().all(|&addr| in_shards(addr)));
+            // Under preload another thread may claim the slot straight away.
+            if !cfg!(feature = "preload") {
+                assert!(!CACHES[index].claimed.load(Ordering::Relaxed));
+            }
+            return;
+        }
+        let child = crate::testing::spawn(TEST, &[]);
+        assert_eq!(child.code(), Some(0), "{}", child.stderr());
+    }
+
+    #[test]
+    fn block_pending_on_another_thread_can_be_freed() {
+        const TEST: &str =
+            "sanitize::tracker::thread_cache::tests::block_pending_on_another_thread_can_be_freed";
+        if crate::testing::in_child(TEST) {
+            let layout = Layout::from_size_align(48, 16).unwrap();
+            let addr = 0x5000_0000;
+            let (inserted, freed) = (mpsc::channel(), mpsc::channel::<()>());
+            let owner = std::thread::spawn(move || {
+                insert(addr, addr, layout, AllocKind::Malloc);
+                inserted.0.send(()).unwrap();
+                freed.1.recv().unwrap();
+                assert_eq!(lookup(addr), None);
+            });
+            inserted.1.recv().unwrap();
+            assert!(!in_shards(addr));
+            assert_eq!(remove(addr), Some((48, AllocKind::Malloc)));
+            freed.0.send(()).unwrap();
+            owner.join().unwrap();
+            return;
+        }
+        let child = crate::testing::spawn(TEST, &[]);
+        assert_eq!(child.code(), Some(0), "{}", child.stderr());
+    }
+
+    #[test]
+    fn blocks_freed_before_a_flush_are_still_counted() {
+        const TEST: &str =
+            "sanitize::tracker::thread_cache::tests::blocks_freed_before_a_flush_are_still_counted";
+        if crate::testing::in_child(TEST) {
+            let layout = Layout::from_size_align(48, 16).unwrap();
+            let before = stats();
+            std::thread::spawn(move || {
+                let addr = |i: usize| 0x5000_0000 + i * 64;
+                for i in 0..BATCH / 2 {
+                    insert(addr(i), addr(i), layout, AllocKind::ScalarNew);
+                    if i % 3 == 0 {
+                        // A failed free puts the block back.
+                        let alloc = take(addr(i)).unwrap();
+                        restore(addr(i), &alloc);
+                    }
+                }
+                for i in 1..BATCH / 2 {
+                    assert_eq!(remove(addr(i)), Some((48, AllocKind::ScalarNew)));
+                }
+                assert!(in_shards(addr(0)));
+            })
+            .join()
+            .unwrap();
+            let after = stats();
+            if !cfg!(feature = "c-alloc") {
+                let kind = AllocKind::ScalarNew as usize;
+                assert_eq!(after.live_count, before.live_count + 1);
+                assert_eq!(after.total_allocs, before.total_allocs + BATCH as u64 / 2);
+                assert_eq!(after.total_frees, before.total_frees + BATCH as u64 / 2 - 1);
+                assert_eq!(
+                    after.by_kind[kind].total_allocs,
+                    before.by_kind[kind].total_allocs + BATCH as u64 / 2
+                );
+            }
+            return;
+        }
+        let child = crate::testing::spawn(TEST, &[]);
+        assert_eq!(child.code(), Some(0), "{}", child.stderr());
+    }
+}
@@ -0,0 +1,48 @@
+//! Helpers shared by the unit tests.
+//!
+//! Behaviour that ends the process (aborting reports, exit handlers) is
+//! tested in a child: the test re-runs this binary with only itself
+//! selected and `CHILD_ENV` set, takes its child branch there, and the
+//! parent checks the exit status and stderr.
+
+extern crate std;
+
+use std::process::{Command, Output};
+use std::string::String;
+
+const CHILD_ENV: &str = "LIBCPP_TEST_CHILD";
+
+/// Whether this process is the child `spawn` started for `test`.
+pub fn in_child(test: &str) -> bool {
+    std::env::var(CHILD_ENV).is_ok_and(|name| name == test)
+}
+
+/// What a child run left behind.
+pub struct Child {
+    pub output: Output,
+}
+
+impl Child {
+    pub fn stderr(&self) -> String {
+        String::from_utf8_lossy(&self.output.stderr).into_owned()
+    }
+
+    pub fn code(&self) -> Option<i32> {
+        self.output.status.code()
+    }
+}
+
+/// Run `test` (its full path, e.g. `sanitize::exit::tests::leak`) alone in
+/// a fresh copy of this binary, with `env` added to the environment.
+pub fn spawn(test: &str, env: &[(&str, &str)]) -> Child {
+    let exe = std::env::current_exe().expect("test binary path");
+    let output = Command::new(exe)
+        .args([test, "--exact", "--test-threads=1", "--nocapture"])
+        .env(CHILD_ENV, test)
+        .envs(env.iter().copied())
+        .output()
+        .expect("spawn test child");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("running 1 test"), "no test named {test}");
+    Child { output }
+}